@@ -23,12 +23,12 @@ echo -e "${BLUE}Step 2: Generating keys for miners...${NC}"
 # Start utils container to generate keys
 docker-compose run --rm utils bash -c "
     echo 'Generating miner1 keys...'
-    key_gen miner1
+    keytool generate miner1
     mv miner1.pub.pem /keys/miner1/
     mv miner1.priv.cbor /keys/miner1/
     
     echo 'Generating miner2 keys...'
-    key_gen miner2
+    keytool generate miner2
     mv miner2.pub.pem /keys/miner2/
     mv miner2.priv.cbor /keys/miner2/
     
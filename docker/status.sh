@@ -30,3 +30,11 @@ echo "  Node 1 listening: $(docker-compose exec -T node1 sh -c 'netstat -an | gr
 echo "  Node 2 listening: $(docker-compose exec -T node2 sh -c 'netstat -an | grep 9001' 2>/dev/null && echo '✅ Yes' || echo '❌ No')"
 echo "  Node 3 listening: $(docker-compose exec -T node3 sh -c 'netstat -an | grep 9002' 2>/dev/null && echo '✅ Yes' || echo '❌ No')"
 
+echo ""
+echo "⛓️  Chain status:"
+for node in "node1 9000" "node2 9001" "node3 9002"; do
+  set -- $node
+  echo "  $1:"
+  docker-compose exec -T "$1" node status --node "127.0.0.1:$2" 2>/dev/null \
+    | grep -v "configuration" | sed 's/^/    /' || echo "    ❌ not answering"
+done
@@ -0,0 +1,118 @@
+//! Blocking requests to the node, for the UI callbacks
+use anyhow::{anyhow, Result};
+use btclib::auth;
+use btclib::config::BlockchainConfig;
+use btclib::network::{IndexedBlock, IndexedTransaction, MempoolEntry, Message, SupplyReport};
+use btclib::retry::{self, RetryError};
+use btclib::sha256::Hash;
+use btclib::transport::Connection;
+use btclib::types::BlockHeader;
+use std::sync::Mutex;
+use tokio::runtime::Runtime;
+
+/// What a hash turned out to be
+pub enum Found {
+    Block(IndexedBlock),
+    Transaction(IndexedTransaction),
+    Pending(MempoolEntry),
+    Nothing,
+}
+
+/// One connection to the node. Requests block the calling thread, which
+/// is fine for the handful the explorer makes per key press.
+pub struct Client {
+    pub node: String,
+    runtime: Runtime,
+    stream: Mutex<Connection>,
+}
+
+impl Client {
+    pub fn connect(node: &str, encrypt: bool, auth_token: Option<&str>) -> Result<Self> {
+        let runtime = Runtime::new()?;
+        let stream = runtime.block_on(async {
+            let mut stream = Connection::connect(node, encrypt).await?;
+            if let Some(token) = auth_token {
+                auth::authenticate(&mut stream, token).await?;
+            }
+            Ok::<_, anyhow::Error>(stream)
+        })?;
+        Ok(Client {
+            node: node.to_string(),
+            runtime,
+            stream: Mutex::new(stream),
+        })
+    }
+
+    fn request(&self, message: Message) -> Result<Message> {
+        let mut stream = self.stream.lock().expect("Connection lock poisoned");
+        let retry_config = &BlockchainConfig::global().retry;
+        let exchange = async {
+            message.send_async(&mut *stream).await?;
+            Ok::<_, anyhow::Error>(Message::receive_async(&mut *stream).await?)
+        };
+        match self
+            .runtime
+            .block_on(retry::with_timeout(retry_config, exchange))
+        {
+            Ok(Message::Reject { reason, .. }) => Err(anyhow!("Node refused: {}", reason)),
+            Ok(response) => Ok(response),
+            Err(RetryError::Failed(e)) => Err(e),
+            Err(e) => Err(anyhow!("Request to node failed: {}", e)),
+        }
+    }
+
+    /// Up to `count` headers from `start` on, and the chain height
+    pub fn headers(&self, start: u64, count: u32) -> Result<(Vec<BlockHeader>, u64)> {
+        match self.request(Message::FetchHeaders(start, count))? {
+            Message::Headers(headers, height) => Ok((headers, height)),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    pub fn block(&self, hash: Hash) -> Result<Option<IndexedBlock>> {
+        match self.request(Message::FetchBlockByHash(hash))? {
+            Message::BlockFound(found) => Ok(found),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    pub fn transaction(&self, hash: Hash) -> Result<Option<IndexedTransaction>> {
+        match self.request(Message::FetchTransaction(hash))? {
+            Message::TransactionFound(found) => Ok(found),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    pub fn mempool(&self) -> Result<Vec<MempoolEntry>> {
+        match self.request(Message::GetMempool)? {
+            Message::Mempool(entries) => Ok(entries),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    pub fn supply(&self) -> Result<SupplyReport> {
+        match self.request(Message::GetSupply)? {
+            Message::Supply(report) => Ok(report),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    /// Look a hash up as a block, a confirmed transaction and a pending one
+    pub fn search(&self, hash: Hash) -> Result<Found> {
+        if let Some(block) = self.block(hash)? {
+            return Ok(Found::Block(block));
+        }
+        if let Some(transaction) = self.transaction(hash)? {
+            return Ok(Found::Transaction(transaction));
+        }
+        let pending = self
+            .mempool()?
+            .into_iter()
+            .find(|entry| entry.transaction.hash() == hash);
+        Ok(pending.map_or(Found::Nothing, Found::Pending))
+    }
+}
+
+fn unexpected(message: &Message) -> anyhow::Error {
+    anyhow!("Unexpected answer from node: {}", message.kind())
+}
@@ -0,0 +1,30 @@
+//! Terminal block explorer: browse the blocks and transactions of a node,
+//! look at its mempool and search by hash
+use anyhow::Result;
+use clap::Parser;
+
+mod client;
+mod ui;
+
+use client::Client;
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// Node to explore
+    #[arg(short, long, value_name = "ADDRESS", default_value = "127.0.0.1:9000")]
+    node: String,
+    /// Encrypt the connection to the node
+    #[arg(short, long)]
+    encrypt: bool,
+    /// Token for nodes that require authentication
+    #[arg(short, long)]
+    auth_token: Option<String>,
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let client = Client::connect(&cli.node, cli.encrypt, cli.auth_token.as_deref())?;
+    ui::run_ui(client);
+    Ok(())
+}
@@ -0,0 +1,351 @@
+use crate::client::{Client, Found};
+use btclib::crypto::PublicKey;
+use btclib::network::MempoolEntry;
+use btclib::sha256::Hash;
+use btclib::types::{Block, Transaction};
+use chrono::Utc;
+use cursive::event::{Event, Key};
+use cursive::traits::*;
+use cursive::views::{
+    Dialog, EditView, LinearLayout, Panel, ScrollView, SelectView, TextContent, TextView,
+};
+use cursive::Cursive;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// Blocks listed at once
+const PAGE_SIZE: u64 = 20;
+
+/// Where a transaction shown in a dialog lives
+enum Location {
+    /// Height of the block and position in it
+    Block(u64, usize),
+    Mempool(MempoolEntry),
+}
+
+/// State shared by the UI callbacks
+struct Explorer {
+    client: Client,
+    status: TextContent,
+    /// Pages of blocks back from the tip, 0 shows the newest blocks
+    page: Mutex<u64>,
+}
+
+/// Initialize and run the user interface.
+pub fn run_ui(client: Client) {
+    let explorer = Arc::new(Explorer {
+        client,
+        status: TextContent::new(""),
+        page: Mutex::new(0),
+    });
+    let mut siv = cursive::default();
+    siv.set_window_title(format!("Block explorer - {}", explorer.client.node));
+    siv.add_global_callback('q', |s| s.quit());
+    setup_menubar(&mut siv, explorer.clone());
+    setup_layout(&mut siv, explorer.clone());
+    siv.add_global_callback(Event::Key(Key::Esc), |s| s.select_menubar());
+    refresh(&mut siv, &explorer);
+    siv.run();
+}
+
+/// Set up the menu bar with "Refresh", "Search", "Newer", "Older" and "Quit" options.
+fn setup_menubar(siv: &mut Cursive, explorer: Arc<Explorer>) {
+    let search_explorer = explorer.clone();
+    let newer_explorer = explorer.clone();
+    let older_explorer = explorer.clone();
+    siv.menubar()
+        .add_leaf("Refresh", move |s| refresh(s, &explorer))
+        .add_leaf("Search", move |s| show_search(s, search_explorer.clone()))
+        .add_leaf("Newer", move |s| turn_page(s, &newer_explorer, true))
+        .add_leaf("Older", move |s| turn_page(s, &older_explorer, false))
+        .add_leaf("Quit", |s| s.quit());
+    siv.set_autohide_menu(false);
+}
+
+/// Set up the main layout: a status line, the blocks and the mempool.
+fn setup_layout(siv: &mut Cursive, explorer: Arc<Explorer>) {
+    let status = TextView::new_with_content(explorer.status.clone());
+    let block_explorer = explorer.clone();
+    let blocks = SelectView::<Hash>::new()
+        .on_submit(move |s, hash| show_block_by_hash(s, &block_explorer, *hash))
+        .with_name("blocks");
+    let mempool = SelectView::<Hash>::new()
+        .on_submit(move |s, hash| show_search_result(s, &explorer, *hash))
+        .with_name("mempool");
+    let layout = LinearLayout::vertical()
+        .child(TextView::new(
+            "Press Escape to select the top menu, Enter to open an entry",
+        ))
+        .child(status)
+        .child(
+            LinearLayout::horizontal()
+                .child(
+                    Panel::new(ScrollView::new(blocks))
+                        .title("Blocks")
+                        .full_width(),
+                )
+                .child(
+                    Panel::new(ScrollView::new(mempool))
+                        .title("Mempool")
+                        .full_width(),
+                ),
+        );
+    siv.add_layer(layout);
+}
+
+/// Move the page of blocks towards the tip or towards genesis
+fn turn_page(s: &mut Cursive, explorer: &Explorer, newer: bool) {
+    {
+        let mut page = explorer.page.lock().expect("Page lock poisoned");
+        *page = if newer {
+            page.saturating_sub(1)
+        } else {
+            *page + 1
+        };
+    }
+    refresh(s, explorer);
+}
+
+/// Reload the page of blocks and the mempool from the node
+fn refresh(s: &mut Cursive, explorer: &Explorer) {
+    let client = &explorer.client;
+    let result = (|| {
+        let (_, height) = client.headers(0, 0)?;
+        let newest = {
+            let mut page = explorer.page.lock().expect("Page lock poisoned");
+            let tip = height.saturating_sub(1);
+            // stop at the page with the genesis block
+            *page = (*page).min(tip / PAGE_SIZE);
+            tip - *page * PAGE_SIZE
+        };
+        let start = (newest + 1).saturating_sub(PAGE_SIZE);
+        let (headers, _) = client.headers(start, (newest + 1 - start) as u32)?;
+        let mempool = client.mempool()?;
+        let supply = client.supply()?;
+        Ok::<_, anyhow::Error>((height, start, headers, mempool, supply))
+    })();
+    let (height, start, headers, mempool, supply) = match result {
+        Ok(loaded) => loaded,
+        Err(e) => return show_error(s, e),
+    };
+    s.call_on_name("blocks", |view: &mut SelectView<Hash>| {
+        view.clear();
+        for (offset, header) in headers.iter().enumerate().rev() {
+            let hash = header.hash();
+            view.add_item(
+                format!(
+                    "#{:<6} {}  {}",
+                    start + offset as u64,
+                    short(&hash),
+                    header.timestamp.format("%Y-%m-%d %H:%M:%S")
+                ),
+                hash,
+            );
+        }
+    });
+    s.call_on_name("mempool", |view: &mut SelectView<Hash>| {
+        view.clear();
+        for entry in &mempool {
+            let hash = entry.transaction.hash();
+            view.add_item(
+                format!(
+                    "{}  fee {:>8}  {:>5}s old",
+                    short(&hash),
+                    entry.fee.map_or("?".to_string(), |fee| fee.to_string()),
+                    (Utc::now() - entry.received).num_seconds()
+                ),
+                hash,
+            );
+        }
+    });
+    explorer.status.set_content(format!(
+        "Node {}  height {}  showing blocks {} to {}  {} transactions pending\nSupply {} of {} scheduled sats",
+        client.node,
+        height,
+        start,
+        (start + headers.len() as u64).saturating_sub(1),
+        mempool.len(),
+        supply.circulating,
+        supply.scheduled
+    ));
+}
+
+/// Ask for a block or transaction hash and show what it is
+fn show_search(s: &mut Cursive, explorer: Arc<Explorer>) {
+    let submit_explorer = explorer.clone();
+    let layout = LinearLayout::vertical()
+        .child(TextView::new("Block or transaction hash:"))
+        .child(
+            EditView::new()
+                .on_submit(move |s, text| search(s, &submit_explorer, text))
+                .with_name("search")
+                .fixed_width(66),
+        );
+    s.add_layer(
+        Dialog::around(layout)
+            .title("Search")
+            .button("Search", move |s| {
+                let text = s
+                    .call_on_name("search", |view: &mut EditView| view.get_content())
+                    .unwrap_or_default();
+                search(s, &explorer, &text)
+            })
+            .button("Cancel", |s| {
+                s.pop_layer();
+            }),
+    );
+}
+
+fn search(s: &mut Cursive, explorer: &Explorer, text: &str) {
+    let Ok(hash) = Hash::from_str(text.trim()) else {
+        return show_error(s, "Not a hash: expected up to 64 hex digits");
+    };
+    s.pop_layer();
+    show_search_result(s, explorer, hash);
+}
+
+fn show_search_result(s: &mut Cursive, explorer: &Explorer, hash: Hash) {
+    match explorer.client.search(hash) {
+        Ok(Found::Block(found)) => show_block(s, Arc::new(found.block), found.height),
+        Ok(Found::Transaction(found)) => show_transaction(
+            s,
+            &found.transaction,
+            Location::Block(found.block_height, found.position),
+        ),
+        Ok(Found::Pending(entry)) => {
+            let transaction = entry.transaction.clone();
+            show_transaction(s, &transaction, Location::Mempool(entry))
+        }
+        Ok(Found::Nothing) => s.add_layer(Dialog::info(format!(
+            "No block or transaction {} (confirmed transactions need txindex on the node)",
+            hash
+        ))),
+        Err(e) => show_error(s, e),
+    }
+}
+
+fn show_block_by_hash(s: &mut Cursive, explorer: &Explorer, hash: Hash) {
+    match explorer.client.block(hash) {
+        Ok(Some(found)) => show_block(s, Arc::new(found.block), found.height),
+        Ok(None) => show_error(s, format!("Block {} is no longer in the chain", hash)),
+        Err(e) => show_error(s, e),
+    }
+}
+
+/// Header fields and the list of transactions of a block
+fn show_block(s: &mut Cursive, block: Arc<Block>, height: u64) {
+    let header = &block.header;
+    let summary = format!(
+        "Height:    {}\nHash:      {}\nVersion:   {}\nPrevious:  {}\nMerkle:    {}\nTime:      {}\nNonce:     {}\nTarget:    {:#066x}\nUTXO set:  {}\n\nTransactions:",
+        height,
+        block.hash(),
+        header.version,
+        header.prev_block_hash,
+        header.merkle_root,
+        header.timestamp,
+        header.nonce,
+        header.target,
+        header
+            .utxo_commitment
+            .map_or("not committed".to_string(), |hash| hash.to_string()),
+    );
+    let mut transactions = SelectView::<usize>::new();
+    for (position, transaction) in block.transactions.iter().enumerate() {
+        transactions.add_item(
+            format!(
+                "{:>4} {}  {:>12} sats{}",
+                position,
+                short(&transaction.hash()),
+                total_output(transaction),
+                if position == 0 { "  (coinbase)" } else { "" }
+            ),
+            position,
+        );
+    }
+    let selected = block.clone();
+    let transactions = transactions.on_submit(move |s, position| {
+        show_transaction(
+            s,
+            &selected.transactions[*position],
+            Location::Block(height, *position),
+        )
+    });
+    s.add_layer(
+        Dialog::around(
+            LinearLayout::vertical()
+                .child(TextView::new(summary))
+                .child(ScrollView::new(transactions)),
+        )
+        .title(format!("Block #{}", height))
+        .button("Close", |s| {
+            s.pop_layer();
+        }),
+    );
+}
+
+/// Inputs and outputs of a transaction
+fn show_transaction(s: &mut Cursive, transaction: &Transaction, location: Location) {
+    let mut text = format!("Hash:     {}\n", transaction.hash());
+    match &location {
+        Location::Block(height, position) => {
+            text += &format!("Block:    #{}, position {}\n", height, position)
+        }
+        Location::Mempool(entry) => {
+            text += &format!(
+                "Pending:  received {}, fee {}\n",
+                entry.received,
+                entry
+                    .fee
+                    .map_or("unknown".to_string(), |fee| format!("{} sats", fee))
+            )
+        }
+    }
+    text += &format!("\nInputs ({}):\n", transaction.inputs.len());
+    for input in &transaction.inputs {
+        text += &format!("  spends {}\n", input.prev_transaction_output_hash);
+    }
+    if transaction.inputs.is_empty() {
+        text += "  none, this is a coinbase\n";
+    }
+    text += &format!("\nOutputs ({}):\n", transaction.outputs.len());
+    for output in &transaction.outputs {
+        text += &format!(
+            "  {:>12} sats to key {}  output {}\n",
+            output.value,
+            fingerprint(&output.pubkey),
+            short(&output.hash())
+        );
+    }
+    text += &format!("\nTotal out: {} sats", total_output(transaction));
+    s.add_layer(
+        Dialog::around(ScrollView::new(TextView::new(text)))
+            .title("Transaction")
+            .button("Close", |s| {
+                s.pop_layer();
+            }),
+    );
+}
+
+fn show_error(s: &mut Cursive, error: impl std::fmt::Display) {
+    s.add_layer(
+        Dialog::text(format!("{}", error))
+            .title("Error")
+            .button("OK", |s| {
+                s.pop_layer();
+            }),
+    );
+}
+
+fn total_output(transaction: &Transaction) -> u64 {
+    transaction.outputs.iter().map(|output| output.value).sum()
+}
+
+/// First 16 hex digits of a hash, zero padded as in block hashes
+fn short(hash: &Hash) -> String {
+    format!("{:0>64}", hash.to_string())[..16].to_string()
+}
+
+/// The short key identifier the wallet shows
+fn fingerprint(key: &PublicKey) -> String {
+    Hash::hash(key).to_string().chars().take(8).collect()
+}
@@ -0,0 +1,16 @@
+//! Blocks in both their encodings: canonical bytes as relayed between
+//! nodes, and CBOR as saved to files. Whatever decodes must re-encode
+//! to the same bytes.
+#![no_main]
+
+use btclib::encoding::{Decode, Encode};
+use btclib::types::Block;
+use btclib::util::Saveable;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(block) = Block::decode(data) {
+        assert_eq!(block.encode(), data);
+    }
+    let _ = Block::load(data);
+});
@@ -0,0 +1,10 @@
+//! A corrupt or hostile blockchain file
+#![no_main]
+
+use btclib::types::Blockchain;
+use btclib::util::Saveable;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Blockchain::load(data);
+});
@@ -0,0 +1,9 @@
+//! Frames as a peer could send them: length prefix and body
+#![no_main]
+
+use btclib::network::Message;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Message::receive(&mut &data[..]);
+});
@@ -0,0 +1,89 @@
+//! Satoshi amounts with checked arithmetic
+//!
+//! Output values are plain `u64`s on the wire, but every sum and
+//! difference validation works out goes through [`Amount`]: it can't
+//! overflow or go negative, and it never exceeds [`MAX_MONEY`]. Like
+//! Bitcoin's `MoneyRange`, this bounds every single value as well as
+//! the totals of a transaction, so a crafted transaction can't wrap a
+//! sum around and pass as balanced.
+use crate::error::{BtcError, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Most satoshis any value or total may hold: 21 million coins, as in
+/// Bitcoin. Far above what the default emission schedule ever creates.
+pub const MAX_MONEY: u64 = 21_000_000 * 100_000_000;
+
+/// A number of satoshis between zero and [`MAX_MONEY`]
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(try_from = "u64", into = "u64")]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    pub const MAX: Amount = Amount(MAX_MONEY);
+
+    /// Fails if `sats` is more than [`MAX_MONEY`]
+    pub fn from_sat(sats: u64) -> Result<Self> {
+        if sats > MAX_MONEY {
+            return Err(BtcError::InvalidAmount {
+                reason: format!("{} satoshis is more than the maximum {}", sats, MAX_MONEY),
+            });
+        }
+        Ok(Amount(sats))
+    }
+
+    pub fn to_sat(self) -> u64 {
+        self.0
+    }
+
+    /// `None` if the sum is more than [`MAX_MONEY`]
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0
+            .checked_add(other.0)
+            .filter(|&sats| sats <= MAX_MONEY)
+            .map(Amount)
+    }
+
+    /// `None` if `other` is larger
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    /// Total of satoshi values, failing if any of them or the total
+    /// is out of range
+    pub fn total<I: IntoIterator<Item = u64>>(values: I) -> Result<Amount> {
+        values.into_iter().try_fold(Amount::ZERO, |total, sats| {
+            total
+                .checked_add(Amount::from_sat(sats)?)
+                .ok_or_else(|| BtcError::InvalidAmount {
+                    reason: format!("total is more than the maximum {}", MAX_MONEY),
+                })
+        })
+    }
+}
+
+impl TryFrom<u64> for Amount {
+    type Error = BtcError;
+
+    fn try_from(sats: u64) -> Result<Self> {
+        Amount::from_sat(sats)
+    }
+}
+
+impl From<Amount> for u64 {
+    fn from(amount: Amount) -> u64 {
+        amount.0
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} sats", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests;
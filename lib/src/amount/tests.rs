@@ -0,0 +1,45 @@
+#[cfg(test)]
+mod tests {
+    use crate::amount::{Amount, MAX_MONEY};
+    use crate::error::BtcError;
+
+    #[test]
+    fn test_amount_range() {
+        assert_eq!(Amount::from_sat(0).unwrap(), Amount::ZERO);
+        assert_eq!(Amount::from_sat(MAX_MONEY).unwrap(), Amount::MAX);
+        assert!(matches!(
+            Amount::from_sat(MAX_MONEY + 1),
+            Err(BtcError::InvalidAmount { .. })
+        ));
+        // serde enforces the same range
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(&u64::MAX, &mut bytes).unwrap();
+        assert!(ciborium::de::from_reader::<Amount, _>(bytes.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_checked_arithmetic() {
+        let one = Amount::from_sat(1).unwrap();
+        assert_eq!(Amount::MAX.checked_add(one), None);
+        assert_eq!(Amount::MAX.checked_sub(Amount::MAX), Some(Amount::ZERO));
+        assert_eq!(Amount::ZERO.checked_sub(one), None);
+        assert_eq!(
+            Amount::from_sat(2)
+                .unwrap()
+                .checked_add(one)
+                .unwrap()
+                .to_sat(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_total() {
+        assert_eq!(Amount::total([1, 2, 3]).unwrap().to_sat(), 6);
+        assert_eq!(Amount::total([]).unwrap(), Amount::ZERO);
+        assert!(Amount::total([MAX_MONEY, 1]).is_err());
+        // values that would wrap a u64 around
+        assert!(Amount::total([u64::MAX, 2]).is_err());
+        assert!(Amount::total([MAX_MONEY + 1]).is_err());
+    }
+}
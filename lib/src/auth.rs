@@ -0,0 +1,81 @@
+//! Shared-secret authentication of wallets and miners
+//!
+//! A node with `node.auth_token` set only answers wallet and miner
+//! requests on connections that proved they know the token:
+//!
+//! ```text
+//! client                          node
+//!   FetchChallenge        ─►
+//!                         ◄─  Challenge(32 random bytes)
+//!   Authenticate(HMAC)    ─►
+//!                         ◄─  Authenticated(true | false)
+//! ```
+//!
+//! The proof is `HMAC-SHA256(token, challenge)`, so the token itself never
+//! goes over the wire and a recorded proof is useless for another
+//! connection.
+use crate::network::{Message, MessageError};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Length of a challenge, in bytes
+pub const CHALLENGE_LEN: usize = 32;
+
+/// Fresh random challenge for a connection
+pub fn challenge() -> Vec<u8> {
+    let mut challenge = vec![0u8; CHALLENGE_LEN];
+    rand::thread_rng().fill_bytes(&mut challenge);
+    challenge
+}
+
+fn mac(token: &str, challenge: &[u8]) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(token.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(challenge);
+    mac
+}
+
+/// Proof of knowing `token`, for the given challenge
+pub fn proof(token: &str, challenge: &[u8]) -> Vec<u8> {
+    mac(token, challenge).finalize().into_bytes().to_vec()
+}
+
+/// Check a proof in constant time
+pub fn verify(token: &str, challenge: &[u8], proof: &[u8]) -> bool {
+    mac(token, challenge).verify_slice(proof).is_ok()
+}
+
+/// Run the challenge-response exchange as a client
+pub async fn authenticate(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    token: &str,
+) -> Result<(), MessageError> {
+    Message::FetchChallenge.send_async(stream).await?;
+    let challenge = match Message::receive_async(stream).await? {
+        Message::Challenge(challenge) => challenge,
+        other => {
+            return Err(MessageError::Authentication(format!(
+                "expected a challenge, got {}",
+                other.kind()
+            )))
+        }
+    };
+    Message::Authenticate(proof(token, &challenge))
+        .send_async(stream)
+        .await?;
+    match Message::receive_async(stream).await? {
+        Message::Authenticated(true) => Ok(()),
+        Message::Authenticated(false) => Err(MessageError::Authentication(
+            "the node rejected the token".to_string(),
+        )),
+        other => Err(MessageError::Authentication(format!(
+            "expected the authentication result, got {}",
+            other.kind()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests;
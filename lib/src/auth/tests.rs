@@ -0,0 +1,53 @@
+#[cfg(test)]
+mod tests {
+    use crate::auth::{self, CHALLENGE_LEN};
+    use crate::network::{Message, MessageError};
+    use tokio::io::duplex;
+
+    #[test]
+    fn test_proof_depends_on_token_and_challenge() {
+        let challenge = auth::challenge();
+        assert_eq!(challenge.len(), CHALLENGE_LEN);
+        let proof = auth::proof("secret", &challenge);
+
+        assert!(auth::verify("secret", &challenge, &proof));
+        assert!(!auth::verify("wrong", &challenge, &proof));
+        assert!(!auth::verify("secret", &auth::challenge(), &proof));
+        assert!(!auth::verify("secret", &challenge, &proof[1..]));
+    }
+
+    /// Answer one authentication attempt like a node with `token` would
+    async fn respond(mut stream: tokio::io::DuplexStream, token: &'static str) {
+        assert!(matches!(
+            Message::receive_async(&mut stream).await.unwrap(),
+            Message::FetchChallenge
+        ));
+        let challenge = auth::challenge();
+        Message::Challenge(challenge.clone())
+            .send_async(&mut stream)
+            .await
+            .unwrap();
+        let Message::Authenticate(proof) = Message::receive_async(&mut stream).await.unwrap()
+        else {
+            panic!("expected Authenticate");
+        };
+        Message::Authenticated(auth::verify(token, &challenge, &proof))
+            .send_async(&mut stream)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_with_right_and_wrong_token() {
+        let (mut client, server) = duplex(1024);
+        let node = tokio::spawn(respond(server, "secret"));
+        auth::authenticate(&mut client, "secret").await.unwrap();
+        node.await.unwrap();
+
+        let (mut client, server) = duplex(1024);
+        let node = tokio::spawn(respond(server, "secret"));
+        let result = auth::authenticate(&mut client, "guess").await;
+        assert!(matches!(result, Err(MessageError::Authentication(_))));
+        node.await.unwrap();
+    }
+}
@@ -1,6 +1,7 @@
 use std::{env, process::exit, vec};
 
 use btclib::{
+    config::BlockchainConfig,
     crypto::PrivateKey,
     sha256::Hash,
     types::{Block, BlockHeader, Transaction, TransactionOutput},
@@ -42,14 +43,24 @@ fn main() {
     };
 
     let private_key = PrivateKey::new_key();
-    let transactions = vec![Transaction::new(
-        vec![],
-        vec![TransactionOutput {
-            unique_id: Uuid::new_v4(),
-            value: btclib::INITIAL_REWARD * 10u64.pow(8),
-            pubkey: private_key.public_key(),
-        }],
-    )];
+    let network = &BlockchainConfig::global().network;
+    let mut outputs = vec![TransactionOutput {
+        unique_id: Uuid::new_v4(),
+        value: network.block_reward(0),
+        pubkey: private_key.public_key(),
+    }];
+    // premine from config, after the reward output
+    outputs.extend(
+        network
+            .genesis_allocations
+            .iter()
+            .map(|allocation| TransactionOutput {
+                unique_id: Uuid::new_v4(),
+                value: allocation.value,
+                pubkey: allocation.pubkey.clone(),
+            }),
+    );
+    let transactions = vec![Transaction::coinbase(0, outputs)];
     let merkle_root = MerkleRoot::calculate(&transactions);
     let block = Block::new(
         BlockHeader::new(Utc::now(), 0, Hash::zero(), merkle_root, target),
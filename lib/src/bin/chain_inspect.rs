@@ -0,0 +1,242 @@
+/// Print statistics about a saved blockchain and check that it is valid
+///
+/// Loads a `blockchain.cbor` as written by the node, without needing a
+/// running node, and reports the height, the supply held in unspent
+/// outputs, block intervals, the fees collected by every block and the
+/// largest transactions. The chain is also audited with
+/// `Blockchain::verify_chain`, which replays it block by block with the
+/// checks of a node receiving it and compares the saved UTXO set.
+///
+/// The chain is read with the consensus settings of the current config,
+/// so run it with the config the chain was created with.
+///
+/// Usage:
+///   cargo run --bin chain_inspect <blockchain file> [--json]
+///
+/// Examples:
+///   cargo run --bin chain_inspect blockchain.cbor
+///   cargo run --bin chain_inspect blockchain.cbor --json > report.json
+use std::env;
+use std::process::exit;
+
+use btclib::config;
+use btclib::types::{Block, Blockchain, Transaction};
+use btclib::util::Saveable;
+use serde::Serialize;
+
+/// Transactions listed as the largest
+const LARGEST_COUNT: usize = 10;
+
+#[derive(Serialize)]
+struct Report {
+    height: u64,
+    tip: Option<String>,
+    total_supply: u64,
+    utxo_count: usize,
+    /// Mean seconds between consecutive blocks
+    average_block_interval: Option<f64>,
+    ideal_block_time: u64,
+    total_fees: u64,
+    blocks: Vec<BlockStats>,
+    largest_transactions: Vec<TransactionStats>,
+    validation: Validation,
+}
+
+#[derive(Serialize)]
+struct BlockStats {
+    height: u64,
+    hash: String,
+    timestamp: String,
+    transactions: usize,
+    fees: u64,
+}
+
+#[derive(Serialize)]
+struct TransactionStats {
+    hash: String,
+    block_height: u64,
+    inputs: usize,
+    outputs: usize,
+    value: u64,
+}
+
+#[derive(Serialize)]
+struct Validation {
+    valid: bool,
+    /// The first problem found
+    error: Option<String>,
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let (path, json) = match args.as_slice() {
+        [path] => (*path, false),
+        [path, "--json"] | ["--json", path] => (*path, true),
+        _ => {
+            eprintln!("Usage: chain_inspect <blockchain file> [--json]");
+            exit(1);
+        }
+    };
+    let blockchain = Blockchain::load_from_file(path).unwrap_or_else(|e| {
+        eprintln!("Failed to load blockchain {}: {}", path, e);
+        exit(1);
+    });
+
+    let report = inspect(blockchain);
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).expect("Failed to serialize report")
+        );
+    } else {
+        print_report(&report);
+    }
+    if !report.validation.valid {
+        exit(1);
+    }
+}
+
+fn inspect(mut blockchain: Blockchain) -> Report {
+    // audit the UTXO set as saved, before rebuilding it
+    let validation = match blockchain.verify_chain() {
+        Ok(_) => Validation {
+            valid: true,
+            error: None,
+        },
+        Err(e) => Validation {
+            valid: false,
+            error: Some(e.to_string()),
+        },
+    };
+    blockchain.rebuild_utxos();
+    let blocks: Vec<&Block> = blockchain.blocks().collect();
+
+    let average_block_interval = match (blocks.first(), blocks.last()) {
+        (Some(first), Some(last)) if blocks.len() > 1 => {
+            let span = last.header.timestamp - first.header.timestamp;
+            Some(span.num_milliseconds() as f64 / 1000.0 / (blocks.len() - 1) as f64)
+        }
+        _ => None,
+    };
+
+    let block_stats: Vec<BlockStats> = blocks
+        .iter()
+        .enumerate()
+        .map(|(height, block)| BlockStats {
+            height: height as u64,
+            hash: block.hash().to_string(),
+            timestamp: block.header.timestamp.to_rfc3339(),
+            transactions: block.transactions.len(),
+            fees: block_fees(block, height as u64),
+        })
+        .collect();
+
+    let mut largest: Vec<TransactionStats> = blocks
+        .iter()
+        .enumerate()
+        .flat_map(|(height, block)| {
+            // coinbases only hand out the reward, skip them
+            block
+                .transactions
+                .iter()
+                .skip(1)
+                .map(move |transaction| TransactionStats {
+                    hash: transaction.hash().to_string(),
+                    block_height: height as u64,
+                    inputs: transaction.inputs.len(),
+                    outputs: transaction.outputs.len(),
+                    value: total_output(transaction),
+                })
+        })
+        .collect();
+    largest.sort_by_key(|transaction| std::cmp::Reverse(transaction.value));
+    largest.truncate(LARGEST_COUNT);
+
+    Report {
+        height: blocks.len() as u64,
+        tip: blocks.last().map(|block| block.hash().to_string()),
+        total_supply: blockchain
+            .utxos()
+            .values()
+            .map(|(_, output)| output.value)
+            .sum(),
+        utxo_count: blockchain.utxos().len(),
+        average_block_interval,
+        ideal_block_time: config::ideal_block_time(),
+        total_fees: block_stats.iter().map(|stats| stats.fees).sum(),
+        blocks: block_stats,
+        largest_transactions: largest,
+        validation,
+    }
+}
+
+/// What the coinbase claimed beyond the block reward. The genesis block
+/// pays out allocations instead, so it has none.
+fn block_fees(block: &Block, height: u64) -> u64 {
+    match block.transactions.first() {
+        Some(coinbase) if height > 0 => {
+            total_output(coinbase).saturating_sub(config::block_reward(height))
+        }
+        _ => 0,
+    }
+}
+
+fn print_report(report: &Report) {
+    println!("Height:           {}", report.height);
+    if let Some(tip) = &report.tip {
+        println!("Tip:              {}", tip);
+    }
+    println!("Total supply:     {} sats", report.total_supply);
+    println!("UTXOs:            {}", report.utxo_count);
+    match report.average_block_interval {
+        Some(interval) => println!(
+            "Block interval:   {:.1}s average, {}s ideal",
+            interval, report.ideal_block_time
+        ),
+        None => println!("Block interval:   n/a, fewer than two blocks"),
+    }
+    println!("Total fees:       {} sats", report.total_fees);
+
+    // blocks with only a coinbase cannot have collected fees
+    let with_transactions: Vec<&BlockStats> = report
+        .blocks
+        .iter()
+        .filter(|stats| stats.height > 0 && stats.transactions > 1)
+        .collect();
+    println!("\nBlocks with transactions ({}):", with_transactions.len());
+    for stats in with_transactions {
+        println!(
+            "  #{:<6} {:>4} txs  {:>12} sats fees  {}",
+            stats.height,
+            stats.transactions - 1,
+            stats.fees,
+            stats.timestamp
+        );
+    }
+
+    println!("\nLargest transactions:");
+    for transaction in &report.largest_transactions {
+        println!(
+            "  {:>12} sats  #{:<6} {} in, {} out  {}",
+            transaction.value,
+            transaction.block_height,
+            transaction.inputs,
+            transaction.outputs,
+            transaction.hash
+        );
+    }
+    if report.largest_transactions.is_empty() {
+        println!("  none besides coinbases");
+    }
+
+    println!();
+    match &report.validation.error {
+        Some(error) => println!("✗ {}", error),
+        None => println!("✓ Chain is valid"),
+    }
+}
+
+fn total_output(transaction: &Transaction) -> u64 {
+    transaction.outputs.iter().map(|output| output.value).sum()
+}
@@ -3,17 +3,35 @@
 /// This utility generates default configuration files that can be used as templates
 /// for configuring the blockchain system.
 ///
+/// With `--validate`, it checks an existing file instead: unknown settings,
+/// values of the wrong type or out of range, and settings that contradict
+/// each other are listed with their paths. Given a profile too, it checks
+/// the file the way `--network <profile>` loads it.
+///
 /// Usage:
 ///   cargo run --bin config_gen [output_file]
+///   cargo run --bin config_gen --validate <config_file> [profile]
 ///
 /// Examples:
 ///   cargo run --bin config_gen                    # Generates config.default.json
 ///   cargo run --bin config_gen config.json        # Generates config.json
 ///   cargo run --bin config_gen config.testnet.json # Generate testnet config
+///   cargo run --bin config_gen config.toml        # Generates config.toml
+///   cargo run --bin config_gen --validate config.json
+///   cargo run --bin config_gen --validate config.toml testnet
 use btclib::config::BlockchainConfig;
+use std::path::Path;
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("--validate") {
+        let Some(file) = args.get(2) else {
+            eprintln!("Usage: config_gen --validate <config_file> [profile]");
+            std::process::exit(1);
+        };
+        validate(file, args.get(3).map(String::as_str));
+        return;
+    }
     let output_file = args
         .get(1)
         .map(|s| s.as_str())
@@ -41,3 +59,18 @@ fn main() {
         }
     }
 }
+
+/// Check `file` the way the programs load it, on top of the defaults
+fn validate(file: &str, profile: Option<&str>) {
+    if !Path::new(file).exists() {
+        eprintln!("✗ {} does not exist", file);
+        std::process::exit(1);
+    }
+    match BlockchainConfig::load_layered(&[file], profile, std::iter::empty()) {
+        Ok(_) => println!("✓ {} is valid", file),
+        Err(e) => {
+            eprintln!("✗ {}", e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,117 @@
+/// Measure how fast this machine hashes block headers
+///
+/// Runs the same loop as the miner, first on one thread and then on all
+/// cores, and prints the hash rates together with what they mean for the
+/// difficulty settings in the current config.
+///
+/// Usage:
+///   cargo run --release --bin hash_bench [seconds] [threads]
+///
+/// Examples:
+///   cargo run --release --bin hash_bench          # 5 seconds, all cores
+///   cargo run --release --bin hash_bench 10 4     # 10 seconds, 4 threads
+use btclib::config::BlockchainConfig;
+use btclib::difficulty::{expected_hashes, format_hashrate};
+use btclib::sha256::Hash;
+use btclib::types::{BlockHeader, Transaction};
+use btclib::util::MerkleRoot;
+use btclib::U256;
+use chrono::Utc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Nonces tried between looking at the clock
+const BATCH_SIZE: usize = 10_000;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let seconds = match args.get(1).map(|arg| arg.parse::<u64>()) {
+        None => 5,
+        Some(Ok(seconds)) if seconds > 0 => seconds,
+        Some(_) => {
+            eprintln!("Usage: hash_bench [seconds] [threads]");
+            std::process::exit(1);
+        }
+    };
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    let threads = match args.get(2).map(|arg| arg.parse::<usize>()) {
+        None => cores,
+        Some(Ok(threads)) if threads > 0 => threads,
+        Some(_) => {
+            eprintln!("Usage: hash_bench [seconds] [threads]");
+            std::process::exit(1);
+        }
+    };
+    let duration = Duration::from_secs(seconds);
+    let config = BlockchainConfig::global();
+
+    println!("Hashing block headers for {}s per run...", seconds);
+    let single = hashrate(1, duration);
+    println!("  1 thread:   {}", format_hashrate(single));
+    let multi = if threads > 1 {
+        let multi = hashrate(threads, duration);
+        println!("  {} threads: {}", threads, format_hashrate(multi));
+        multi
+    } else {
+        single
+    };
+
+    let min_target = config.min_target();
+    let ideal_block_time = config.network.ideal_block_time;
+    println!();
+    println!("With the current config ({}):", config.network.network_id);
+    println!("  min_target_hex: {:#066x}", min_target);
+    println!(
+        "  expected time to a block at that target: {:.2}s (1 thread), {:.2}s ({} threads)",
+        expected_hashes(min_target) / single,
+        expected_hashes(min_target) / multi,
+        threads
+    );
+    println!(
+        "  min_target_hex for one {}-thread miner to hit the {}s block time:",
+        threads, ideal_block_time
+    );
+    println!("    {:#066x}", target_for(multi, ideal_block_time));
+}
+
+/// Hashes per second over `duration`, split across `threads`
+fn hashrate(threads: usize, duration: Duration) -> f64 {
+    let started = Instant::now();
+    let hashes: u64 = thread::scope(|scope| {
+        let workers = (0..threads)
+            .map(|_| scope.spawn(|| hash_for(duration)))
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("benchmark thread panicked"))
+            .sum()
+    });
+    hashes as f64 / started.elapsed().as_secs_f64()
+}
+
+/// Hash a header with the miner's loop until `duration` is over, and
+/// return how many hashes were computed
+fn hash_for(duration: Duration) -> u64 {
+    // no hash meets a zero target, so every batch runs to the end
+    let mut header = BlockHeader::new(
+        Utc::now(),
+        0,
+        Hash::zero(),
+        MerkleRoot::calculate(&[Transaction::new(vec![], vec![])]),
+        U256::zero(),
+    );
+    let started = Instant::now();
+    let mut hashes = 0;
+    while started.elapsed() < duration {
+        header.mine(BATCH_SIZE);
+        hashes += BATCH_SIZE as u64;
+    }
+    hashes
+}
+
+/// The target a miner with this hash rate meets every `seconds` on
+/// average, i.e. 2^256 / (hashrate * seconds)
+fn target_for(hashes_per_second: f64, seconds: u64) -> U256 {
+    let hashes_per_block = (hashes_per_second * seconds as f64).max(1.0) as u64;
+    U256::MAX / U256::from(hashes_per_block)
+}
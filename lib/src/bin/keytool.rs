@@ -0,0 +1,124 @@
+/// Generate, inspect and convert keys, and sign and verify messages
+///
+/// Private keys are written as `<name>.priv.<format>` next to
+/// `<name>.pub.pem`. The formats are `cbor` (the default), `hex` (64 hex
+/// digits) and `wif` (Wallet Import Format), the latter two as one line of
+/// text. Every tool here loads private keys in any of the three formats.
+///
+/// Public keys can be given as a PEM file, a private key file, or the
+/// 33-byte compressed key in hex as printed by `inspect`. Signatures are
+/// the 64-byte `r || s` pair in hex, over the same domain-separated message
+/// hash as `good-wallet sign-message`, so the two can check each other.
+///
+/// Usage:
+///   cargo run --bin keytool generate <name> [--format cbor|hex|wif]
+///   cargo run --bin keytool inspect <key file>
+///   cargo run --bin keytool convert <private key file> <name> --format cbor|hex|wif
+///   cargo run --bin keytool sign <private key file> <message>
+///   cargo run --bin keytool verify <public key> <signature> <message>
+///
+/// Examples:
+///   cargo run --bin keytool generate alice                # alice.priv.cbor
+///   cargo run --bin keytool convert alice.priv.cbor alice --format wif
+///   cargo run --bin keytool sign alice.priv.cbor "I am Alice"
+///   cargo run --bin keytool verify alice.pub.pem <signature> "I am Alice"
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::exit;
+
+use btclib::crypto::{PrivateKey, PublicKey, Signature};
+use btclib::sha256::Hash;
+use btclib::util::Saveable;
+
+const USAGE: &str = "Usage:
+  keytool generate <name> [--format cbor|hex|wif]
+  keytool inspect <key file>
+  keytool convert <private key file> <name> --format cbor|hex|wif
+  keytool sign <private key file> <message>
+  keytool verify <public key> <signature> <message>";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["generate", name] => save(&PrivateKey::new_key(), name, "cbor"),
+        ["generate", name, "--format", format] => save(&PrivateKey::new_key(), name, format),
+        ["inspect", path] => inspect(path),
+        ["convert", path, name, "--format", format] => save(&load_private_key(path), name, format),
+        ["sign", path, message] => {
+            let signature = Signature::sign_message(message, &load_private_key(path));
+            println!("{}", signature.to_hex());
+        }
+        ["verify", public_key, signature, message] => {
+            let public_key = load_public_key(public_key);
+            let signature = Signature::from_hex(signature)
+                .unwrap_or_else(|_| fail("Signature must be 64 bytes of hex"));
+            if signature.verify_message(message, &public_key) {
+                println!("✓ Valid signature");
+            } else {
+                println!("✗ Invalid signature");
+                exit(1);
+            }
+        }
+        _ => fail(USAGE),
+    }
+}
+
+/// Write `<name>.priv.<format>` and `<name>.pub.pem`
+fn save(private_key: &PrivateKey, name: &str, format: &str) {
+    let private_key_file = format!("{}.priv.{}", name, format);
+    let public_key_file = format!("{}.pub.pem", name);
+    let written = match format {
+        "cbor" => private_key.save_to_file(&private_key_file),
+        "hex" => fs::write(&private_key_file, private_key.to_hex() + "\n"),
+        "wif" => fs::write(&private_key_file, private_key.to_wif() + "\n"),
+        _ => fail(USAGE),
+    };
+    written
+        .and_then(|_| private_key.public_key().save_to_file(&public_key_file))
+        .unwrap_or_else(|e| fail(&format!("Failed to write keys: {}", e)));
+    println!("Wrote {} and {}", private_key_file, public_key_file);
+}
+
+fn inspect(path: &str) {
+    let public_key = match PrivateKey::load_from_file(path) {
+        Ok(private_key) => {
+            println!("Type:        private key");
+            private_key.public_key()
+        }
+        Err(_) => {
+            println!("Type:        public key");
+            load_public_key(path)
+        }
+    };
+    // the wallet shows keys by this fingerprint
+    let fingerprint: String = Hash::hash(&public_key)
+        .to_string()
+        .chars()
+        .take(8)
+        .collect();
+    println!("Public key:  {}", public_key.to_hex());
+    println!("Fingerprint: {}", fingerprint);
+}
+
+fn load_private_key(path: &str) -> PrivateKey {
+    PrivateKey::load_from_file(path)
+        .unwrap_or_else(|e| fail(&format!("Failed to load private key {}: {}", path, e)))
+}
+
+/// A public key from a PEM file, a private key file or hex
+fn load_public_key(arg: &str) -> PublicKey {
+    if !Path::new(arg).exists() {
+        return PublicKey::from_hex(arg)
+            .unwrap_or_else(|_| fail(&format!("{} is neither a key file nor a hex key", arg)));
+    }
+    PublicKey::load_from_file(arg)
+        .or_else(|_| PrivateKey::load_from_file(arg).map(|key| key.public_key()))
+        .unwrap_or_else(|e| fail(&format!("Failed to load public key {}: {}", arg, e)))
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    exit(1);
+}
@@ -0,0 +1,219 @@
+/// Decode a serialized transaction and show what it contains
+///
+/// Takes the raw transaction as hex, or a file holding either the hex or
+/// a CBOR transaction as written by `tx_gen` and the wallet. Like Bitcoin's
+/// `decoderawtransaction`, it lists the inputs and outputs. Given a saved
+/// blockchain with `--utxos`, it also looks up the outputs being spent,
+/// checks the input signatures against them and works out the fee, which
+/// shows why a node rejects a transaction.
+///
+/// Usage:
+///   cargo run --bin tx_decode <hex | tx file> [--utxos <blockchain file>] [--json]
+///
+/// Examples:
+///   cargo run --bin tx_decode pay_bob.hex
+///   cargo run --bin tx_decode pay_bob.cbor --utxos blockchain.cbor --json
+use std::{env, fs, path::Path, process::exit};
+
+use btclib::{
+    encoding::Encode,
+    types::{Blockchain, Transaction},
+    util::Saveable,
+};
+use serde::Serialize;
+
+const USAGE: &str = "Usage: tx_decode <hex | tx file> [--utxos <blockchain file>] [--json]";
+
+#[derive(Serialize)]
+struct Decoded {
+    hash: String,
+    size: usize,
+    version: u32,
+    /// Height of the block a coinbase was made for
+    coinbase_height: Option<u64>,
+    inputs: Vec<DecodedInput>,
+    outputs: Vec<DecodedOutput>,
+    total_output: u64,
+    /// Only known with `--utxos` and when every spent output was found
+    total_input: Option<u64>,
+    fee: Option<i128>,
+}
+
+#[derive(Serialize)]
+struct DecodedInput {
+    spends: String,
+    signature: String,
+    /// The spent output, looked up with `--utxos`
+    prev_output: Option<SpentOutput>,
+}
+
+#[derive(Serialize)]
+struct SpentOutput {
+    value: u64,
+    pubkey: String,
+    signature_valid: bool,
+}
+
+#[derive(Serialize)]
+struct DecodedOutput {
+    hash: String,
+    value: u64,
+    unique_id: String,
+    pubkey: String,
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut source = None;
+    let mut utxos_path = None;
+    let mut json = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--utxos" => utxos_path = Some(args.next().unwrap_or_else(|| fail(USAGE))),
+            _ if source.is_none() => source = Some(arg),
+            _ => fail(USAGE),
+        }
+    }
+    let transaction = load_transaction(source.unwrap_or_else(|| fail(USAGE)));
+    let blockchain = utxos_path.map(|path| {
+        let mut blockchain = Blockchain::load_from_file(path)
+            .unwrap_or_else(|e| fail(&format!("Failed to load blockchain {}: {}", path, e)));
+        blockchain.rebuild_utxos();
+        blockchain
+    });
+
+    let decoded = decode(&transaction, blockchain.as_ref());
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&decoded).expect("Failed to serialize transaction")
+        );
+    } else {
+        print_decoded(&decoded, blockchain.is_some());
+    }
+}
+
+/// A transaction from hex, or a file with hex or CBOR in it
+fn load_transaction(source: &str) -> Transaction {
+    if !Path::new(source).exists() {
+        return Transaction::from_hex(source)
+            .unwrap_or_else(|e| fail(&format!("Not a transaction file or hex: {}", e)));
+    }
+    let bytes =
+        fs::read(source).unwrap_or_else(|e| fail(&format!("Failed to read {}: {}", source, e)));
+    if let Ok(transaction) = Transaction::load(bytes.as_slice()) {
+        return transaction;
+    }
+    let text = String::from_utf8(bytes)
+        .unwrap_or_else(|_| fail(&format!("{} holds neither CBOR nor hex", source)));
+    Transaction::from_hex(&text)
+        .unwrap_or_else(|e| fail(&format!("Failed to decode {}: {}", source, e)))
+}
+
+fn decode(transaction: &Transaction, blockchain: Option<&Blockchain>) -> Decoded {
+    let inputs: Vec<DecodedInput> = transaction
+        .inputs
+        .iter()
+        .map(|input| {
+            let hash = input.prev_transaction_output_hash;
+            let prev_output = blockchain
+                .and_then(|blockchain| blockchain.utxos().get(&hash))
+                .map(|(_, output)| SpentOutput {
+                    value: output.value,
+                    pubkey: output.pubkey.to_hex(),
+                    signature_valid: input.signature.verify(&hash, &output.pubkey),
+                });
+            DecodedInput {
+                spends: hash.to_string(),
+                signature: input.signature.to_hex(),
+                prev_output,
+            }
+        })
+        .collect();
+    let total_output: u64 = transaction.outputs.iter().map(|output| output.value).sum();
+    // coinbases create their outputs, they have no inputs to pay a fee from
+    let total_input: Option<u64> = inputs
+        .iter()
+        .map(|input| input.prev_output.as_ref().map(|output| output.value))
+        .sum::<Option<u64>>()
+        .filter(|_| blockchain.is_some() && !inputs.is_empty());
+    Decoded {
+        hash: transaction.hash().to_string(),
+        size: transaction.encode().len(),
+        version: transaction.version,
+        coinbase_height: transaction.coinbase_height,
+        outputs: transaction
+            .outputs
+            .iter()
+            .map(|output| DecodedOutput {
+                hash: output.hash().to_string(),
+                value: output.value,
+                unique_id: output.unique_id.to_string(),
+                pubkey: output.pubkey.to_hex(),
+            })
+            .collect(),
+        total_output,
+        total_input,
+        fee: total_input.map(|total_input| total_input as i128 - total_output as i128),
+        inputs,
+    }
+}
+
+fn print_decoded(decoded: &Decoded, with_utxos: bool) {
+    println!("Hash:     {}", decoded.hash);
+    println!("Size:     {} bytes", decoded.size);
+    println!("Version:  {}", decoded.version);
+
+    println!("\nInputs ({}):", decoded.inputs.len());
+    for input in &decoded.inputs {
+        println!("  spends {}", input.spends);
+        println!("    signature {}", input.signature);
+        match &input.prev_output {
+            Some(output) => println!(
+                "    {} sats of key {}, signature {}",
+                output.value,
+                output.pubkey,
+                if output.signature_valid {
+                    "valid"
+                } else {
+                    "INVALID"
+                }
+            ),
+            None if with_utxos => println!("    not in the UTXO set (spent or unknown)"),
+            None => {}
+        }
+    }
+    match decoded.coinbase_height {
+        Some(height) => println!("  none, this is the coinbase of block {}", height),
+        None if decoded.inputs.is_empty() => println!("  none, this is a coinbase"),
+        None => {}
+    }
+
+    println!("\nOutputs ({}):", decoded.outputs.len());
+    for output in &decoded.outputs {
+        println!("  {:>12} sats to key {}", output.value, output.pubkey);
+        println!("    output {} ({})", output.hash, output.unique_id);
+    }
+
+    println!("\nTotal out: {} sats", decoded.total_output);
+    match (decoded.total_input, decoded.fee) {
+        (Some(total_input), Some(fee)) => {
+            println!("Total in:  {} sats", total_input);
+            if fee < 0 {
+                println!("Fee:       none, outputs exceed inputs by {} sats", -fee);
+            } else {
+                println!("Fee:       {} sats", fee);
+            }
+        }
+        _ if decoded.inputs.is_empty() => {}
+        _ if with_utxos => println!("Fee:       unknown, not every spent output was found"),
+        _ => println!("Fee:       unknown, pass --utxos <blockchain file> to work it out"),
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    exit(1);
+}
@@ -1,27 +1,168 @@
-use std::{env, process::exit};
+/// Create transactions, either a test coinbase or one built from a spec
+///
+/// Without a spec, writes a transaction with no inputs paying the initial
+/// reward to a new key. With `--spec`, the inputs, outputs and signing
+/// keys are read from a TOML (by extension) or JSON file:
+///
+/// ```toml
+/// [[inputs]]
+/// output = "7d0e…"               # hash of the output being spent
+/// key = "alice.priv.cbor"        # signs the input
+///
+/// [[inputs]]
+/// output = "91ab…"
+/// signature = "3044…"            # used as is instead of signing
+///
+/// [[outputs]]
+/// pubkey = "bob.pub.pem"         # a key file or the hex public key
+/// value = 1000
+/// unique_id = "…"                # optional, random if left out
+/// ```
+///
+/// Nothing is checked against a chain, so specs can describe invalid
+/// transactions on purpose: spending unknown outputs, signing with the
+/// wrong key, paying out more than the inputs hold, and so on.
+///
+/// Usage:
+///   cargo run --bin tx_gen <output_file>
+///   cargo run --bin tx_gen <output_file> --spec <spec file> [--format cbor|hex]
+///
+/// Examples:
+///   cargo run --bin tx_gen coinbase.cbor
+///   cargo run --bin tx_gen pay_bob.hex --spec pay_bob.toml --format hex
+use std::{env, fs, path::Path, process::exit, str::FromStr};
 
 use btclib::{
-    crypto::PrivateKey,
-    types::{Transaction, TransactionOutput},
+    crypto::{PrivateKey, PublicKey, Signature},
+    sha256::Hash,
+    types::{Transaction, TransactionInput, TransactionOutput},
     util::Saveable,
 };
+use serde::Deserialize;
 use uuid::Uuid;
 
+const USAGE: &str = "Usage:
+  tx_gen <output_file>
+  tx_gen <output_file> --spec <spec file> [--format cbor|hex]";
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Spec {
+    #[serde(default)]
+    inputs: Vec<InputSpec>,
+    #[serde(default)]
+    outputs: Vec<OutputSpec>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InputSpec {
+    /// Hash of the spent output, in hex
+    output: String,
+    /// Private key file signing the input
+    key: Option<String>,
+    /// Signature in hex, instead of a key
+    signature: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OutputSpec {
+    /// Public key file, or the compressed key in hex
+    pubkey: String,
+    value: u64,
+    unique_id: Option<Uuid>,
+}
+
 fn main() {
-    let path = if let Some(arg) = env::args().nth(1) {
-        arg
-    } else {
-        eprintln!("Usage: block_print <tx_file>");
-        exit(1);
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let (path, tx, format) = match args.as_slice() {
+        [path] => (*path, coinbase(), "cbor"),
+        [path, "--spec", spec] => (*path, from_spec(spec), "cbor"),
+        [path, "--spec", spec, "--format", format] => (*path, from_spec(spec), *format),
+        _ => fail(USAGE),
     };
+    let written = match format {
+        "cbor" => tx.save_to_file(path),
+        "hex" => fs::write(path, tx.to_hex() + "\n"),
+        _ => fail(USAGE),
+    };
+    written.unwrap_or_else(|e| fail(&format!("Failed to save transaction: {}", e)));
+    println!("Wrote transaction {} to {}", tx.hash(), path);
+}
+
+fn coinbase() -> Transaction {
     let private_key = PrivateKey::new_key();
-    let tx = Transaction::new(
+    Transaction::new(
         vec![],
         vec![TransactionOutput {
             unique_id: Uuid::new_v4(),
             value: btclib::INITIAL_REWARD * 10u64.pow(8),
             pubkey: private_key.public_key(),
         }],
-    );
-    tx.save_to_file(path).expect("Failed to save transaction");
+    )
+}
+
+fn from_spec(path: &str) -> Transaction {
+    let text = fs::read_to_string(path)
+        .unwrap_or_else(|e| fail(&format!("Failed to read spec {}: {}", path, e)));
+    let spec: Spec = if path.ends_with(".toml") {
+        toml::from_str(&text).unwrap_or_else(|e| fail(&format!("Invalid spec: {}", e)))
+    } else {
+        serde_json::from_str(&text).unwrap_or_else(|e| fail(&format!("Invalid spec: {}", e)))
+    };
+
+    let inputs = spec
+        .inputs
+        .iter()
+        .map(|input| {
+            let output = Hash::from_str(&input.output)
+                .unwrap_or_else(|_| fail(&format!("Invalid output hash {}", input.output)));
+            let signature = match (&input.key, &input.signature) {
+                (Some(key), None) => {
+                    let private_key = PrivateKey::load_from_file(key).unwrap_or_else(|e| {
+                        fail(&format!("Failed to load private key {}: {}", key, e))
+                    });
+                    Signature::sign_output(&output, &private_key)
+                }
+                (None, Some(signature)) => Signature::from_hex(signature)
+                    .unwrap_or_else(|_| fail("Signature must be 64 bytes of hex")),
+                _ => fail(&format!(
+                    "Input {} needs either a key or a signature",
+                    input.output
+                )),
+            };
+            TransactionInput {
+                prev_transaction_output_hash: output,
+                signature,
+            }
+        })
+        .collect();
+    let outputs = spec
+        .outputs
+        .iter()
+        .map(|output| TransactionOutput {
+            value: output.value,
+            unique_id: output.unique_id.unwrap_or_else(Uuid::new_v4),
+            pubkey: load_public_key(&output.pubkey),
+        })
+        .collect();
+    Transaction::new(inputs, outputs)
+}
+
+/// A public key from a PEM file, a private key file or hex
+fn load_public_key(arg: &str) -> PublicKey {
+    if !Path::new(arg).exists() {
+        return PublicKey::from_hex(arg)
+            .unwrap_or_else(|_| fail(&format!("{} is neither a key file nor a hex key", arg)));
+    }
+    PublicKey::load_from_file(arg)
+        .or_else(|_| PrivateKey::load_from_file(arg).map(|key| key.public_key()))
+        .unwrap_or_else(|e| fail(&format!("Failed to load public key {}: {}", arg, e)))
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    exit(1);
 }
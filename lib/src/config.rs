@@ -1,23 +1,94 @@
 /// Configuration module for blockchain parameters
 ///
 /// This module provides a centralized configuration system that supports:
-/// - JSON configuration files (primary method)
-/// - Multiple network profiles (mainnet, testnet, devnet)
+/// - JSON and TOML configuration files
+/// - `BTC_<SECTION>_<KEY>` environment variables for every setting
+/// - Multiple network profiles (mainnet, testnet, devnet), as separate
+///   files or as `[testnet]`-style sections of one file
 /// - Hardcoded defaults (fallback)
 ///
-/// Configuration priority:
-/// 1. JSON config file (config.json)
-/// 2. Hardcoded defaults (fallback)
+/// Configuration priority (highest first; command-line arguments of the
+/// binaries override all of these):
+/// 1. Environment variables (e.g. `BTC_NODE_PORT=9001`)
+/// 2. JSON config file (config.json), its selected profile over the rest
+/// 3. TOML config file (config.toml), likewise
+/// 4. Hardcoded defaults (fallback)
+use crate::amount::{Amount, MAX_MONEY};
+use crate::crypto::PublicKey;
+use crate::store::StorageBackend;
+use crate::transport::NOISE_PREAMBLE;
 use crate::U256;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
 use std::path::Path;
-use std::sync::OnceLock;
+use std::sync::{Arc, RwLock};
 
 /// Default configuration file name
 pub const DEFAULT_CONFIG_FILE: &str = "config.json";
 
-/// Global configuration instance
-static CONFIG: OnceLock<BlockchainConfig> = OnceLock::new();
+/// TOML configuration file, read before [`DEFAULT_CONFIG_FILE`] so the
+/// JSON file wins where both set a value
+pub const DEFAULT_TOML_CONFIG_FILE: &str = "config.toml";
+
+/// Prefix of the environment variables overriding settings, followed by
+/// the section and the key, e.g. `BTC_NODE_PORT` or `BTC_NETWORK_NETWORK_ID`
+pub const ENV_PREFIX: &str = "BTC_";
+
+/// Global configuration instance, loaded on first use and replaced by
+/// [`BlockchainConfig::set_global`]
+static CONFIG: RwLock<Option<Arc<BlockchainConfig>>> = RwLock::new(None);
+
+/// Profile of the config files the global configuration is loaded with,
+/// set by [`BlockchainConfig::select_profile`]
+static PROFILE: RwLock<Option<String>> = RwLock::new(None);
+
+/// Node settings naming data files, which get the profile in their name
+/// unless the profile sets them
+const PROFILE_DATA_FILES: &[&str] = &[
+    "blockchain_file",
+    "banlist_file",
+    "peers_file",
+    "mempool_file",
+];
+
+/// Node settings only read when the node starts, which a reload can't
+/// change
+const RESTART_ONLY_NODE_SETTINGS: &[&str] = &[
+    "port",
+    "blockchain_file",
+    "storage_backend",
+    "blockchain_backups",
+    "initial_peers",
+    "banlist_file",
+    "peers_file",
+    "mempool_file",
+    "txindex",
+    "addressindex",
+    "metrics_port",
+    "log_filter",
+    "log_format",
+    "unix_socket",
+    "upnp",
+];
+
+/// `network` settings that don't affect which blocks are valid
+const NON_CONSENSUS_NETWORK_SETTINGS: &[&str] = &["max_message_size", "magic", "seeds"];
+
+/// Result of [`BlockchainConfig::reloaded`]
+#[derive(Debug, Default)]
+pub struct ConfigReload {
+    /// The config to run with from now on
+    pub config: BlockchainConfig,
+    /// Settings that changed and were applied, as `section.key`
+    pub applied: Vec<String>,
+    /// Changed node settings that only take effect after a restart
+    pub restart_required: Vec<String>,
+    /// Changed consensus parameters, ignored
+    pub consensus: Vec<String>,
+}
 
 /// Complete blockchain configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -33,6 +104,10 @@ pub struct BlockchainConfig {
 
     /// Wallet settings
     pub wallet: WalletConfig,
+
+    /// Retry/backoff policy for outbound network clients
+    #[serde(default)]
+    pub retry: RetryConfig,
 }
 
 /// Network consensus parameters
@@ -53,6 +128,14 @@ pub struct NetworkConfig {
     /// Number of blocks between difficulty adjustments
     pub difficulty_update_interval: u64,
 
+    /// How the difficulty target is adjusted
+    #[serde(default)]
+    pub difficulty_algorithm: DifficultyAlgorithm,
+
+    /// How block headers and transactions are hashed
+    #[serde(default)]
+    pub hash_scheme: HashScheme,
+
     /// Maximum age of mempool transactions in seconds
     pub max_mempool_transaction_age: u64,
 
@@ -62,6 +145,83 @@ pub struct NetworkConfig {
     /// Minimum difficulty target (easiest difficulty)
     /// Format: hex string like "0x0000FFFFFFFFFFFF..."
     pub min_target_hex: String,
+
+    /// Largest network message accepted, in bytes. Bigger frames are
+    /// rejected before anything is allocated for them.
+    #[serde(default = "default_max_message_size")]
+    pub max_message_size: u32,
+
+    /// Four bytes in hex put in front of every network message, so
+    /// nodes of different networks refuse each other's messages.
+    /// Derived from `network_id` when not set.
+    #[serde(default)]
+    pub magic: Option<String>,
+
+    /// Seed nodes ("host:port") a node without peers bootstraps from.
+    /// Hostnames may resolve to several addresses, all are tried.
+    #[serde(default)]
+    pub seeds: Vec<String>,
+
+    /// Explicit block rewards in satoshis by height, overriding the
+    /// halving schedule. Each reward applies from its height up to the
+    /// next entry; heights before the first entry use the halving schedule.
+    #[serde(default)]
+    pub subsidy_schedule: BTreeMap<u64, u64>,
+
+    /// Coins paid out by the genesis block on top of its reward (premine)
+    #[serde(default)]
+    pub genesis_allocations: Vec<GenesisAllocation>,
+
+    /// Consensus versions by activation height. From its height on, a
+    /// version is the lowest block version accepted and the highest
+    /// transaction version; before the first entry it is version 1.
+    #[serde(default)]
+    pub version_activations: BTreeMap<u64, u32>,
+}
+
+/// `network_id` of regtest networks, see [`NetworkConfig::is_regtest`]
+pub const REGTEST_NETWORK_ID: &str = "regtest";
+
+/// Difficulty adjustment algorithms
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DifficultyAlgorithm {
+    /// Bitcoin-style: every `difficulty_update_interval` blocks, scale the
+    /// target by how long the interval took, at most 4x either way
+    #[default]
+    Interval,
+    /// Every block, from the average solve time of the last blocks,
+    /// damped and limited to small steps. Reacts within a few blocks
+    /// when miners come and go, which suits tiny networks.
+    #[serde(rename = "digishield")]
+    DigiShield,
+    /// Never adjust: every block uses `min_target_hex`. Meant for regtest,
+    /// where blocks are generated on demand.
+    Fixed,
+}
+
+/// Hash functions for block headers, transactions and Merkle trees
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HashScheme {
+    /// One round of SHA-256
+    #[default]
+    Single,
+    /// SHA-256 of the SHA-256 (SHA256d), as Bitcoin does
+    Double,
+    /// Litecoin's scrypt (N=1024, r=1, p=1), memory-hard so GPUs gain
+    /// less over CPUs. Needs the `scrypt` feature.
+    #[cfg(feature = "scrypt")]
+    Scrypt,
+}
+
+/// An extra output of the genesis block's coinbase
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenesisAllocation {
+    /// Recipient, as the hex-encoded DER of its public key
+    pub pubkey: PublicKey,
+    /// Amount in satoshis
+    pub value: u64,
 }
 
 /// Node operation parameters
@@ -70,9 +230,18 @@ pub struct NodeConfig {
     /// Port to listen on
     pub port: u16,
 
-    /// Blockchain file path
+    /// Blockchain file path (a directory for the sled backend)
     pub blockchain_file: String,
 
+    /// Storage backend for the blockchain ("cbor", "sled" or "blockfiles")
+    #[serde(default)]
+    pub storage_backend: StorageBackend,
+
+    /// Number of previous blockchain file versions to keep as
+    /// `<blockchain_file>.1`..`.N` (cbor backend only, 0 disables)
+    #[serde(default = "default_blockchain_backups")]
+    pub blockchain_backups: usize,
+
     /// Initial peer addresses (comma-separated)
     pub initial_peers: Vec<String>,
 
@@ -84,14 +253,242 @@ pub struct NodeConfig {
 
     /// Maximum number of peer connections
     pub max_peers: usize,
+
+    /// Ban list file path (persisted peer reputation and bans)
+    #[serde(default = "default_banlist_file")]
+    pub banlist_file: String,
+
+    /// Address book file path (known peers with when they were last seen)
+    #[serde(default = "default_peers_file")]
+    pub peers_file: String,
+
+    /// Mempool file path (pending transactions kept across restarts)
+    #[serde(default = "default_mempool_file")]
+    pub mempool_file: String,
+
+    /// Misbehavior score at which a peer gets banned
+    #[serde(default = "default_ban_threshold")]
+    pub ban_threshold: u32,
+
+    /// How long a ban lasts in seconds
+    #[serde(default = "default_ban_duration_secs")]
+    pub ban_duration_secs: u64,
+
+    /// Half-life of a peer's misbehavior score in seconds
+    #[serde(default = "default_ban_score_half_life_secs")]
+    pub ban_score_half_life_secs: u64,
+
+    /// Number of recent blocks considered for fee estimation
+    #[serde(default = "default_fee_estimation_blocks")]
+    pub fee_estimation_blocks: usize,
+
+    /// Fee (satoshis per transaction) suggested when there is no fee data yet
+    #[serde(default = "default_fallback_fee")]
+    pub fallback_fee: u64,
+
+    /// Index confirmed transactions by hash (answers FetchTransaction)
+    #[serde(default)]
+    pub txindex: bool,
+
+    /// Index confirmed transactions by key (answers FetchAddressHistory)
+    #[serde(default)]
+    pub addressindex: bool,
+
+    /// Port of the Prometheus `/metrics` endpoint (node built with
+    /// the `metrics` feature only)
+    #[serde(default = "default_metrics_port")]
+    pub metrics_port: u16,
+
+    /// Log filter in `RUST_LOG` syntax, e.g. `info,node::handler=debug`.
+    /// The `RUST_LOG` environment variable takes precedence.
+    #[serde(default = "default_log_filter")]
+    pub log_filter: String,
+
+    /// Log output format ("text" or "json")
+    #[serde(default)]
+    pub log_format: LogFormat,
+
+    /// How long to wait for in-flight messages on shutdown before
+    /// flushing the chain anyway
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+
+    /// Encrypt connections to other nodes with Noise and refuse
+    /// plaintext ones. Encrypted incoming connections are accepted
+    /// either way.
+    #[serde(default)]
+    pub encrypt_p2p: bool,
+
+    /// Shared secret wallets and miners must prove they know before the
+    /// node serves their requests. Unset, anyone can connect.
+    #[serde(default)]
+    pub auth_token: Option<String>,
+
+    /// Transactions per second accepted from one connection (0 = no limit)
+    #[serde(default = "default_tx_rate_limit")]
+    pub tx_rate_limit: f64,
+
+    /// Transactions a connection may send at once before the rate applies
+    #[serde(default = "default_tx_burst")]
+    pub tx_burst: u32,
+
+    /// Blocks per second accepted from one connection (0 = no limit)
+    #[serde(default = "default_block_rate_limit")]
+    pub block_rate_limit: f64,
+
+    /// Blocks a connection may send at once before the rate applies
+    #[serde(default = "default_block_burst")]
+    pub block_burst: u32,
+
+    /// Blocks requested from a peer at once during initial sync
+    #[serde(default = "default_sync_batch_size")]
+    pub sync_batch_size: usize,
+
+    /// Relayed transactions spending outputs we don't know yet, kept
+    /// until their inputs show up (0 = drop them)
+    #[serde(default = "default_max_orphan_transactions")]
+    pub max_orphan_transactions: usize,
+
+    /// Encoded size the mempool may grow to (0 = no limit)
+    #[serde(default = "default_max_mempool_bytes")]
+    pub max_mempool_bytes: usize,
+
+    /// Transactions the mempool may hold (0 = no limit)
+    #[serde(default = "default_max_mempool_txs")]
+    pub max_mempool_txs: usize,
+
+    /// Unix domain socket to listen on besides the TCP port, for wallets
+    /// and miners on the same machine. Connections on it skip
+    /// `auth_token` and `encrypt_p2p`.
+    #[serde(default)]
+    pub unix_socket: Option<String>,
+
+    /// Forward `port` on the local router with UPnP, or NAT-PMP, and
+    /// advertise the router's external address to peers
+    #[serde(default)]
+    pub upnp: bool,
+
+    /// SOCKS5 proxy for connections to other nodes, e.g. Tor's
+    /// `127.0.0.1:9050`. Unset, peers are connected to directly.
+    #[serde(default)]
+    pub proxy: Option<String>,
+}
+
+/// How the node writes its logs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per event, with span fields, for log collectors
+    Json,
+}
+
+fn default_max_message_size() -> u32 {
+    16 * 1024 * 1024
+}
+
+fn default_blockchain_backups() -> usize {
+    2
+}
+
+fn default_banlist_file() -> String {
+    "./banlist.json".to_string()
+}
+
+fn default_peers_file() -> String {
+    "./peers.json".to_string()
+}
+
+fn default_mempool_file() -> String {
+    "./mempool.cbor".to_string()
+}
+
+fn default_ban_threshold() -> u32 {
+    100
+}
+
+fn default_ban_duration_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_ban_score_half_life_secs() -> u64 {
+    60 * 60
+}
+
+fn default_fee_estimation_blocks() -> usize {
+    10
+}
+
+fn default_fallback_fee() -> u64 {
+    1000
+}
+
+fn default_metrics_port() -> u16 {
+    9100
+}
+
+fn default_log_filter() -> String {
+    "info".to_string()
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    10
+}
+
+fn default_tx_rate_limit() -> f64 {
+    50.0
+}
+
+fn default_tx_burst() -> u32 {
+    200
+}
+
+fn default_block_rate_limit() -> f64 {
+    1.0
+}
+
+fn default_block_burst() -> u32 {
+    10
+}
+
+fn default_sync_batch_size() -> usize {
+    50
+}
+
+fn default_max_orphan_transactions() -> usize {
+    100
+}
+
+fn default_max_mempool_bytes() -> usize {
+    5_000_000
+}
+
+fn default_max_mempool_txs() -> usize {
+    5_000
+}
+
+fn default_batch_target_ms() -> u64 {
+    100
+}
+
+fn default_stats_interval_secs() -> u64 {
+    5
 }
 
 /// Mining configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MiningConfig {
-    /// Number of nonces to try in each mining batch
+    /// Most nonces to try in one mining batch; the miner sizes its
+    /// batches to take about `batch_target_ms` up to this many
     pub mining_batch_size: usize,
 
+    /// Milliseconds a mining batch should take; the miner checks for a
+    /// new template between batches
+    #[serde(default = "default_batch_target_ms")]
+    pub batch_target_ms: u64,
+
     /// Seconds between template fetches/validations
     pub template_fetch_interval_secs: u64,
 
@@ -100,6 +497,45 @@ pub struct MiningConfig {
 
     /// Public key file for receiving rewards
     pub public_key_file: String,
+
+    /// Split each block reward among these keys by weight instead of
+    /// paying it all to `public_key_file`
+    #[serde(default)]
+    pub payouts: Vec<Payout>,
+
+    /// Seconds between the miner's statistics reports
+    #[serde(default = "default_stats_interval_secs")]
+    pub stats_interval_secs: u64,
+
+    /// Port of the plain-text statistics endpoint on `GET /status`,
+    /// off unless set
+    #[serde(default)]
+    pub status_port: Option<u16>,
+
+    /// What hashes block headers
+    #[serde(default)]
+    pub backend: MiningBackend,
+}
+
+/// Hardware the miner hashes block headers on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MiningBackend {
+    /// One CPU thread
+    #[default]
+    Cpu,
+    /// The first OpenCL device, checked against the CPU. Needs the
+    /// miner's `gpu` feature.
+    Gpu,
+}
+
+/// One share of the block reward, see [`MiningConfig::payouts`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Payout {
+    /// Public key file of the payee
+    pub public_key_file: String,
+    /// Share of the reward relative to the other payouts
+    pub weight: u64,
 }
 
 /// Wallet configuration
@@ -118,6 +554,45 @@ pub struct WalletConfig {
     pub config_file: String,
 }
 
+/// Retry, backoff and timeout policy shared by node, wallet and miner clients
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetryConfig {
+    /// Maximum attempts per operation (including the first one)
+    pub max_attempts: u32,
+
+    /// Backoff before the first retry in milliseconds (doubles each retry)
+    pub initial_backoff_ms: u64,
+
+    /// Upper bound for the backoff in milliseconds
+    pub max_backoff_ms: u64,
+
+    /// Random jitter applied to the backoff, as a fraction (0.0 - 1.0)
+    pub jitter: f64,
+
+    /// Timeout for a single attempt in milliseconds
+    pub timeout_ms: u64,
+
+    /// Consecutive failures before the circuit breaker opens (0 disables it)
+    pub circuit_breaker_threshold: u32,
+
+    /// How long the circuit breaker stays open in seconds
+    pub circuit_breaker_cooldown_secs: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff_ms: 200,
+            max_backoff_ms: 10_000,
+            jitter: 0.2,
+            timeout_ms: 10_000,
+            circuit_breaker_threshold: 10,
+            circuit_breaker_cooldown_secs: 30,
+        }
+    }
+}
+
 impl Default for NetworkConfig {
     fn default() -> Self {
         Self {
@@ -126,23 +601,183 @@ impl Default for NetworkConfig {
             halving_interval: crate::HALVING_INTERVAL,
             ideal_block_time: crate::IDEAL_BLOCK_TIME,
             difficulty_update_interval: crate::DIFFICULTY_UPDATE_INTERVAL,
+            difficulty_algorithm: DifficultyAlgorithm::default(),
+            hash_scheme: HashScheme::default(),
             max_mempool_transaction_age: crate::MAX_MEMPOOL_TRANSACTION_AGE,
             block_transaction_cap: crate::BLOCK_TRANSACTION_CAP,
             // Convert U256 constant to hex string
             min_target_hex: format!("0x{:x}", crate::MIN_TARGET),
+            max_message_size: default_max_message_size(),
+            magic: None,
+            seeds: vec![],
+            subsidy_schedule: BTreeMap::new(),
+            genesis_allocations: vec![],
+            version_activations: BTreeMap::new(),
         }
     }
 }
 
+impl NetworkConfig {
+    /// Reward in satoshis for mining the block at `height`, from the
+    /// subsidy schedule if it covers the height, otherwise halving
+    /// `initial_reward` every `halving_interval` blocks
+    pub fn block_reward(&self, height: u64) -> u64 {
+        if let Some((_, reward)) = self.subsidy_schedule.range(..=height).next_back() {
+            return *reward;
+        }
+        let halvings = height / self.halving_interval;
+        u32::try_from(halvings)
+            .ok()
+            .and_then(|halvings| (self.initial_reward * 10u64.pow(8)).checked_shr(halvings))
+            .unwrap_or(0)
+    }
+
+    /// Most satoshis that can exist once `blocks` blocks are mined: the
+    /// rewards of heights `0..blocks` plus the genesis allocations.
+    /// Computed a stretch of equal rewards at a time, so it stays cheap
+    /// for long chains.
+    pub fn scheduled_supply(&self, blocks: u64) -> u64 {
+        if blocks == 0 {
+            return 0;
+        }
+        let mut supply = self.genesis_allocation_total();
+        let mut height = 0;
+        while height < blocks {
+            // the reward stays the same until the next schedule entry or,
+            // before the first entry, the next halving
+            let next_entry = self
+                .subsidy_schedule
+                .range(height + 1..)
+                .next()
+                .map(|(&entry, _)| entry);
+            let next_halving = if self.subsidy_schedule.range(..=height).next().is_none() {
+                Some((height / self.halving_interval + 1) * self.halving_interval)
+            } else {
+                None
+            };
+            let reward = self.block_reward(height);
+            let end = [next_entry, next_halving]
+                .into_iter()
+                .flatten()
+                .min()
+                .map_or(blocks, |change| change.min(blocks));
+            if reward == 0 && next_entry.is_none() {
+                break;
+            }
+            supply = supply.saturating_add(reward.saturating_mul(end - height));
+            height = end;
+        }
+        supply
+    }
+
+    /// Consensus version in force at `height`, see
+    /// [`NetworkConfig::version_activations`]
+    pub fn version_at(&self, height: u64) -> u32 {
+        self.version_activations
+            .range(..=height)
+            .next_back()
+            .map_or(1, |(_, version)| *version)
+    }
+
+    /// Magic bytes of this network: `magic` if set, otherwise the first
+    /// four bytes of the SHA-256 of `network_id`. Never the Noise
+    /// preamble, which must stay distinguishable from a plain message.
+    pub fn magic(&self) -> [u8; 4] {
+        let configured = self.magic.as_ref().and_then(|magic| {
+            let parsed = hex::decode(magic.trim_start_matches("0x"))
+                .ok()
+                .and_then(|bytes| <[u8; 4]>::try_from(bytes).ok());
+            if parsed.is_none() {
+                eprintln!("Warning: Invalid network magic, deriving it from network_id");
+            }
+            parsed
+        });
+        let mut magic = configured.unwrap_or_else(|| {
+            let digest = Sha256::digest(self.network_id.as_bytes());
+            [digest[0], digest[1], digest[2], digest[3]]
+        });
+        if magic == NOISE_PREAMBLE {
+            magic[3] = 0xfe;
+        }
+        magic
+    }
+
+    /// Parse `min_target_hex`, falling back to [`crate::MIN_TARGET`]
+    pub fn min_target(&self) -> U256 {
+        let hex_str = self.min_target_hex.trim_start_matches("0x");
+        U256::from_str_radix(hex_str, 16).unwrap_or_else(|_| {
+            eprintln!("Warning: Invalid MIN_TARGET_HEX, using default");
+            crate::MIN_TARGET
+        })
+    }
+
+    /// Names of the consensus parameters that differ from `other`.
+    /// Settings that don't affect validation, like `seeds`, are left out.
+    pub fn consensus_differences(&self, other: &NetworkConfig) -> Vec<String> {
+        let ours = serde_json::to_value(self).expect("Failed to serialize network config");
+        let theirs = serde_json::to_value(other).expect("Failed to serialize network config");
+        ours.as_object()
+            .into_iter()
+            .flatten()
+            .filter(|(key, _)| !NON_CONSENSUS_NETWORK_SETTINGS.contains(&key.as_str()))
+            .filter(|(key, value)| theirs.get(key.as_str()) != Some(value))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Whether this is a regtest network, where nodes mine blocks on
+    /// demand (`GenerateBlocks`)
+    pub fn is_regtest(&self) -> bool {
+        self.network_id == REGTEST_NETWORK_ID
+    }
+
+    /// Satoshis allocated by the genesis block besides its reward
+    pub fn genesis_allocation_total(&self) -> u64 {
+        self.genesis_allocations
+            .iter()
+            .map(|allocation| allocation.value)
+            .sum()
+    }
+}
+
 impl Default for NodeConfig {
     fn default() -> Self {
         Self {
             port: 9000,
             blockchain_file: "./blockchain.cbor".to_string(),
+            storage_backend: StorageBackend::default(),
+            blockchain_backups: default_blockchain_backups(),
             initial_peers: vec![],
             mempool_cleanup_interval_secs: 30,
             blockchain_save_interval_secs: 15,
             max_peers: 50,
+            banlist_file: default_banlist_file(),
+            peers_file: default_peers_file(),
+            mempool_file: default_mempool_file(),
+            ban_threshold: default_ban_threshold(),
+            ban_duration_secs: default_ban_duration_secs(),
+            ban_score_half_life_secs: default_ban_score_half_life_secs(),
+            fee_estimation_blocks: default_fee_estimation_blocks(),
+            fallback_fee: default_fallback_fee(),
+            txindex: false,
+            addressindex: false,
+            metrics_port: default_metrics_port(),
+            log_filter: default_log_filter(),
+            log_format: LogFormat::default(),
+            shutdown_timeout_secs: default_shutdown_timeout_secs(),
+            encrypt_p2p: false,
+            auth_token: None,
+            tx_rate_limit: default_tx_rate_limit(),
+            tx_burst: default_tx_burst(),
+            block_rate_limit: default_block_rate_limit(),
+            block_burst: default_block_burst(),
+            sync_batch_size: default_sync_batch_size(),
+            max_orphan_transactions: default_max_orphan_transactions(),
+            max_mempool_bytes: default_max_mempool_bytes(),
+            max_mempool_txs: default_max_mempool_txs(),
+            unix_socket: None,
+            upnp: false,
+            proxy: None,
         }
     }
 }
@@ -151,9 +786,14 @@ impl Default for MiningConfig {
     fn default() -> Self {
         Self {
             mining_batch_size: 2_000_000,
+            batch_target_ms: default_batch_target_ms(),
             template_fetch_interval_secs: 5,
             node_address: "127.0.0.1:9000".to_string(),
             public_key_file: "miner.pub.pem".to_string(),
+            payouts: vec![],
+            stats_interval_secs: default_stats_interval_secs(),
+            status_port: None,
+            backend: MiningBackend::default(),
         }
     }
 }
@@ -176,89 +816,653 @@ impl Default for BlockchainConfig {
             node: NodeConfig::default(),
             mining: MiningConfig::default(),
             wallet: WalletConfig::default(),
+            retry: RetryConfig::default(),
         }
     }
 }
 
 impl BlockchainConfig {
-    /// Load configuration from JSON file or use defaults
+    /// Load configuration from the config files and the environment
     ///
     /// Configuration priority:
-    /// 1. JSON config file (config.json) - if it exists and is valid
-    /// 2. Hardcoded defaults (if default config file doesn't exist)
+    /// 1. `BTC_*` environment variables
+    /// 2. JSON config file (config.json) - if it exists
+    /// 3. TOML config file (config.toml) - if it exists
+    /// 4. Hardcoded defaults for everything not set above
     ///
     /// # Panics
     ///
-    /// Panics if the default config file exists but cannot be read or parsed.
-    /// This ensures configuration errors are caught early rather than silently ignored.
+    /// Panics if a config file exists but cannot be read or parsed, or an
+    /// environment variable holds an invalid value. This ensures
+    /// configuration errors are caught early rather than silently ignored.
     pub fn load() -> Self {
-        match Self::load_from_file(DEFAULT_CONFIG_FILE) {
+        match Self::try_load() {
             Ok(config) => config,
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                eprintln!(
-                    "ℹ No config file found at {}, using defaults",
-                    DEFAULT_CONFIG_FILE
-                );
-                BlockchainConfig::default()
-            }
             Err(e) => {
-                eprintln!(
-                    "✗ Error loading configuration from {}: {}",
-                    DEFAULT_CONFIG_FILE, e
-                );
-                eprintln!("  Please fix the configuration file or remove it to use defaults.");
+                eprintln!("✗ Error loading configuration: {}", e);
+                eprintln!("  Please fix the configuration or remove it to use defaults.");
                 panic!("Failed to load configuration");
             }
         }
     }
 
-    /// Load configuration from a specific file path
+    /// [`BlockchainConfig::load`], returning errors instead of panicking
+    pub fn try_load() -> IoResult<Self> {
+        Self::load_layered(
+            &[DEFAULT_TOML_CONFIG_FILE, DEFAULT_CONFIG_FILE],
+            Self::profile().as_deref(),
+            std::env::vars(),
+        )
+    }
+
+    /// The defaults, overlaid with each of `files` that exists in order
+    /// (later files win), then with the `BTC_*` variables among `vars`.
+    /// Files may set only some of the settings.
+    ///
+    /// Besides the sections, a file may hold profiles: tables like
+    /// `[testnet.network]` whose sections apply over the rest of the file
+    /// when `profile` names them. A selected profile also becomes the
+    /// network id, and the data files get its name (`blockchain.testnet.cbor`),
+    /// unless the profile sets them itself.
+    pub fn load_layered<P: AsRef<Path>>(
+        files: &[P],
+        profile: Option<&str>,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> IoResult<Self> {
+        let defaults = to_value(&BlockchainConfig::default());
+        let mut config = defaults.clone();
+        let mut problems = vec![];
+        let mut found = false;
+        let mut profile_settings = None;
+        for path in files {
+            let path = path.as_ref();
+            match read_value(path) {
+                Ok(mut file) => {
+                    let mut profiles = take_profiles(&defaults, &mut file);
+                    let unknown = profiles.iter().flat_map(|(name, settings)| {
+                        unknown_settings(&defaults, settings)
+                            .into_iter()
+                            .map(move |setting| format!("{}.{}", name, setting))
+                    });
+                    problems.extend(
+                        unknown_settings(&defaults, &file)
+                            .into_iter()
+                            .chain(unknown)
+                            .map(|setting| {
+                                format!("{}: unknown setting {}", path.display(), setting)
+                            }),
+                    );
+                    overlay(&mut config, file);
+                    if let Some(settings) = profile.and_then(|name| profiles.remove(name)) {
+                        overlay(&mut config, settings.clone());
+                        overlay(
+                            profile_settings.get_or_insert(Value::Object(Default::default())),
+                            settings,
+                        );
+                    }
+                    eprintln!("✓ Loaded configuration from {}", path.display());
+                    found = true;
+                }
+                Err(e) if e.kind() == IoErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !found {
+            eprintln!("ℹ No config file found, using defaults");
+        }
+        if let Some(profile) = profile {
+            match profile_settings {
+                Some(settings) => apply_profile(&mut config, profile, &settings),
+                None => problems.push(format!(
+                    "profile {}: not defined in any config file",
+                    profile
+                )),
+            }
+        }
+        let overridden = apply_env(&mut config, vars);
+        let parsed = match serde_json::from_value::<BlockchainConfig>(config.clone()) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                let errors = type_errors(&defaults, &config, &overridden);
+                if errors.is_empty() {
+                    problems.push(e.to_string());
+                }
+                problems.extend(errors);
+                return Err(invalid_config(problems));
+            }
+        };
+        if !problems.is_empty() {
+            return Err(invalid_config(problems));
+        }
+        parsed.validate().map_err(invalid_config)?;
+        Ok(parsed)
+    }
+
+    /// Load configuration from a specific file path, TOML if it ends in
+    /// `.toml` and JSON otherwise
     ///
     /// # Errors
     ///
     /// Returns an error if:
     /// - The file does not exist
     /// - The file cannot be read
-    /// - The file contains invalid JSON
-    /// - The JSON does not match the expected configuration structure
-    pub fn load_from_file<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+    /// - The file contains invalid JSON or TOML
+    /// - The contents do not match the expected configuration structure
+    /// - A value fails [`BlockchainConfig::validate`]
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> IoResult<Self> {
         let path = path.as_ref();
-
-        let contents = std::fs::read_to_string(path)?;
-
-        let config = serde_json::from_str::<BlockchainConfig>(&contents).map_err(|e| {
-            std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
+        let config: BlockchainConfig = serde_json::from_value(read_value(path)?).map_err(|e| {
+            IoError::new(
+                IoErrorKind::InvalidData,
                 format!("Failed to parse configuration: {}", e),
             )
         })?;
+        config.validate().map_err(invalid_config)?;
 
         eprintln!("✓ Loaded configuration from {}", path.display());
         Ok(config)
     }
 
-    /// Save configuration to a JSON file
+    /// Save configuration to a file, TOML if it ends in `.toml` and JSON
+    /// otherwise
     pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn std::error::Error>> {
-        let json = serde_json::to_string_pretty(self)?;
-        std::fs::write(path.as_ref(), json)?;
+        let contents = if is_toml(path.as_ref()) {
+            toml::to_string_pretty(self)?
+        } else {
+            serde_json::to_string_pretty(self)?
+        };
+        std::fs::write(path.as_ref(), contents)?;
         Ok(())
     }
 
-    /// Get or initialize the global configuration
-    pub fn global() -> &'static BlockchainConfig {
-        CONFIG.get_or_init(|| BlockchainConfig::load())
+    /// Get or initialize the global configuration. Hold on to the handle
+    /// only as long as needed: a reload replaces it for later callers.
+    pub fn global() -> Arc<BlockchainConfig> {
+        if let Some(config) = CONFIG.read().expect("Config lock poisoned").as_ref() {
+            return config.clone();
+        }
+        CONFIG
+            .write()
+            .expect("Config lock poisoned")
+            .get_or_insert_with(|| Arc::new(BlockchainConfig::load()))
+            .clone()
+    }
+
+    /// Replace the global configuration, e.g. after a reload
+    pub fn set_global(config: BlockchainConfig) {
+        *CONFIG.write().expect("Config lock poisoned") = Some(Arc::new(config));
+    }
+
+    /// Load the global configuration with `profile` of the config files,
+    /// e.g. for a `--network` argument. Reloads keep using it.
+    pub fn select_profile(profile: &str) -> IoResult<()> {
+        let previous = PROFILE
+            .write()
+            .expect("Profile lock poisoned")
+            .replace(profile.to_string());
+        match Self::try_load() {
+            Ok(config) => {
+                Self::set_global(config);
+                Ok(())
+            }
+            Err(e) => {
+                *PROFILE.write().expect("Profile lock poisoned") = previous;
+                Err(e)
+            }
+        }
+    }
+
+    /// The profile selected with [`BlockchainConfig::select_profile`]
+    pub fn profile() -> Option<String> {
+        PROFILE.read().expect("Profile lock poisoned").clone()
+    }
+
+    /// Apply `new` on top of this config the way a running node can:
+    /// consensus parameters (the `network` section) and node settings
+    /// only read at startup keep their current value, everything else
+    /// is taken from `new`
+    pub fn reloaded(&self, new: BlockchainConfig) -> ConfigReload {
+        let current = to_value(self);
+        let mut merged = to_value(&new);
+        let mut reload = ConfigReload::default();
+        for (section, current_settings) in current.as_object().into_iter().flatten() {
+            for (key, current_value) in current_settings.as_object().into_iter().flatten() {
+                let value = &mut merged[section][key];
+                if value == current_value {
+                    continue;
+                }
+                let name = format!("{}.{}", section, key);
+                if section == "network" {
+                    reload.consensus.push(name);
+                } else if section == "node" && RESTART_ONLY_NODE_SETTINGS.contains(&key.as_str()) {
+                    reload.restart_required.push(name);
+                } else {
+                    reload.applied.push(name);
+                    continue;
+                }
+                *value = current_value.clone();
+            }
+        }
+        reload.config = serde_json::from_value(merged).expect("Merged config is invalid");
+        reload
+    }
+
+    /// Check the values for ones the programs can't work with, and for
+    /// settings that contradict each other. Returns every problem found,
+    /// each starting with the path of the setting.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = vec![];
+        let mut check = |ok: bool, setting: &str, problem: &str| {
+            if !ok {
+                problems.push(format!("{}: {}", setting, problem));
+            }
+        };
+        let network = &self.network;
+        check(
+            !network.network_id.is_empty(),
+            "network.network_id",
+            "must not be empty",
+        );
+        check(
+            network.initial_reward <= MAX_MONEY / 100_000_000,
+            "network.initial_reward",
+            "is more coins than can ever exist",
+        );
+        for (setting, value) in [
+            ("network.halving_interval", network.halving_interval),
+            ("network.ideal_block_time", network.ideal_block_time),
+            (
+                "network.difficulty_update_interval",
+                network.difficulty_update_interval,
+            ),
+            (
+                "network.max_mempool_transaction_age",
+                network.max_mempool_transaction_age,
+            ),
+            (
+                "network.block_transaction_cap",
+                network.block_transaction_cap as u64,
+            ),
+            (
+                "node.mempool_cleanup_interval_secs",
+                self.node.mempool_cleanup_interval_secs,
+            ),
+            (
+                "node.blockchain_save_interval_secs",
+                self.node.blockchain_save_interval_secs,
+            ),
+            ("node.max_peers", self.node.max_peers as u64),
+            ("node.ban_threshold", self.node.ban_threshold as u64),
+            ("node.ban_duration_secs", self.node.ban_duration_secs),
+            (
+                "node.ban_score_half_life_secs",
+                self.node.ban_score_half_life_secs,
+            ),
+            (
+                "node.fee_estimation_blocks",
+                self.node.fee_estimation_blocks as u64,
+            ),
+            ("node.sync_batch_size", self.node.sync_batch_size as u64),
+            (
+                "mining.mining_batch_size",
+                self.mining.mining_batch_size as u64,
+            ),
+            ("mining.batch_target_ms", self.mining.batch_target_ms),
+            (
+                "mining.template_fetch_interval_secs",
+                self.mining.template_fetch_interval_secs,
+            ),
+            (
+                "mining.stats_interval_secs",
+                self.mining.stats_interval_secs,
+            ),
+            (
+                "wallet.utxo_update_interval_secs",
+                self.wallet.utxo_update_interval_secs,
+            ),
+            (
+                "wallet.balance_display_update_interval_ms",
+                self.wallet.balance_display_update_interval_ms,
+            ),
+            ("retry.max_attempts", self.retry.max_attempts as u64),
+            ("retry.timeout_ms", self.retry.timeout_ms),
+        ] {
+            check(value > 0, setting, "must be at least 1");
+        }
+        let min_target = U256::from_str_radix(network.min_target_hex.trim_start_matches("0x"), 16);
+        check(
+            min_target.is_ok_and(|target| !target.is_zero()),
+            "network.min_target_hex",
+            "must be a non-zero 256-bit number in hex",
+        );
+        check(
+            network.max_message_size >= 1024,
+            "network.max_message_size",
+            "must be at least 1024 bytes",
+        );
+        if let Some(magic) = &network.magic {
+            let bytes = hex::decode(magic.trim_start_matches("0x")).unwrap_or_default();
+            check(
+                bytes.len() == 4 && bytes != NOISE_PREAMBLE,
+                "network.magic",
+                "must be 4 bytes in hex, other than the Noise preamble",
+            );
+        }
+        check(
+            network
+                .subsidy_schedule
+                .values()
+                .all(|&reward| reward <= MAX_MONEY),
+            "network.subsidy_schedule",
+            "has a reward of more than MAX_MONEY",
+        );
+        check(
+            Amount::total(
+                network
+                    .genesis_allocations
+                    .iter()
+                    .map(|allocation| allocation.value)
+                    .chain([network.block_reward(0)]),
+            )
+            .is_ok(),
+            "network.genesis_allocations",
+            "pay more than MAX_MONEY together with the genesis reward",
+        );
+        let versions: Vec<u32> = network.version_activations.values().copied().collect();
+        check(
+            versions.first().is_none_or(|&first| first >= 1)
+                && versions.windows(2).all(|pair| pair[0] <= pair[1]),
+            "network.version_activations",
+            "versions must be at least 1 and never decrease with height",
+        );
+        check(
+            self.node.metrics_port != self.node.port,
+            "node.metrics_port",
+            "must differ from node.port",
+        );
+        check(
+            self.node
+                .auth_token
+                .as_ref()
+                .is_none_or(|token| !token.is_empty()),
+            "node.auth_token",
+            "must not be empty, leave it out to serve everyone",
+        );
+        check(
+            self.node
+                .unix_socket
+                .as_ref()
+                .is_none_or(|path| !path.is_empty()),
+            "node.unix_socket",
+            "must not be empty, leave it out to only listen on TCP",
+        );
+        check(
+            self.node.proxy.as_ref().is_none_or(|proxy| {
+                proxy
+                    .rsplit_once(':')
+                    .is_some_and(|(_, port)| port.parse::<u16>().is_ok())
+            }),
+            "node.proxy",
+            "must be host:port, leave it out to connect directly",
+        );
+        check(
+            tracing_subscriber::EnvFilter::try_new(&self.node.log_filter).is_ok(),
+            "node.log_filter",
+            "is not a valid RUST_LOG filter",
+        );
+        for (setting, rate) in [
+            ("node.tx_rate_limit", self.node.tx_rate_limit),
+            ("node.block_rate_limit", self.node.block_rate_limit),
+        ] {
+            check(
+                rate.is_finite() && rate >= 0.0,
+                setting,
+                "must be a number, 0 or more",
+            );
+        }
+        let payouts = &self.mining.payouts;
+        check(
+            payouts.is_empty() || payouts.iter().any(|payout| payout.weight > 0),
+            "mining.payouts",
+            "need at least one weight above 0",
+        );
+        check(
+            (0.0..=1.0).contains(&self.retry.jitter),
+            "retry.jitter",
+            "must be between 0.0 and 1.0",
+        );
+        check(
+            self.retry.initial_backoff_ms <= self.retry.max_backoff_ms,
+            "retry.initial_backoff_ms",
+            "must not exceed retry.max_backoff_ms",
+        );
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
     }
 
     /// Parse MIN_TARGET from hex string
     pub fn min_target(&self) -> U256 {
-        let hex_str = self.network.min_target_hex.trim_start_matches("0x");
-        U256::from_str_radix(hex_str, 16).unwrap_or_else(|_| {
-            eprintln!("Warning: Invalid MIN_TARGET_HEX, using default");
-            crate::MIN_TARGET
+        self.network.min_target()
+    }
+}
+
+fn is_toml(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "toml")
+}
+
+fn to_value(config: &BlockchainConfig) -> Value {
+    serde_json::to_value(config).expect("Failed to serialize config")
+}
+
+/// A config file as JSON, whichever format it is in
+fn read_value(path: &Path) -> IoResult<Value> {
+    let contents = std::fs::read_to_string(path)?;
+    let parsed = if is_toml(path) {
+        toml::from_str::<toml::Table>(&contents)
+            .map_err(|e| e.to_string())
+            .and_then(|table| serde_json::to_value(table).map_err(|e| e.to_string()))
+    } else {
+        serde_json::from_str(&contents).map_err(|e| e.to_string())
+    };
+    parsed.map_err(|e| {
+        IoError::new(
+            IoErrorKind::InvalidData,
+            format!("Failed to parse {}: {}", path.display(), e),
+        )
+    })
+}
+
+fn invalid_config(problems: Vec<String>) -> IoError {
+    IoError::new(
+        IoErrorKind::InvalidData,
+        format!("Invalid configuration:\n  {}", problems.join("\n  ")),
+    )
+}
+
+/// Settings in `file` that `known` doesn't have, as `section.key`, with
+/// the closest known name if it looks like a typo
+fn unknown_settings(known: &Value, file: &Value) -> Vec<String> {
+    let mut unknown = vec![];
+    let suggest = |name: &str, candidates: Vec<&String>| {
+        candidates
+            .into_iter()
+            .map(|candidate| (edit_distance(name, candidate), candidate))
+            .filter(|(distance, _)| *distance <= 2)
+            .min()
+            .map_or(String::new(), |(_, candidate)| {
+                format!(", did you mean {}?", candidate)
+            })
+    };
+    for (section, settings) in file.as_object().into_iter().flatten() {
+        let Some(known_settings) = known.get(section).and_then(Value::as_object) else {
+            let sections = known
+                .as_object()
+                .into_iter()
+                .flatten()
+                .map(|(name, _)| name);
+            unknown.push(format!(
+                "{}{}",
+                section,
+                suggest(section, sections.collect())
+            ));
+            continue;
+        };
+        for (key, _) in settings.as_object().into_iter().flatten() {
+            if !known_settings.contains_key(key) {
+                let suggestion = suggest(key, known_settings.keys().collect());
+                unknown.push(format!("{}.{}{}", section, key, suggestion));
+            }
+        }
+    }
+    unknown
+}
+
+/// Levenshtein distance, for suggesting the setting a typo meant
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Settings of `config` with a value of the wrong type, each tried on
+/// its own against the defaults so the error names the exact setting
+fn type_errors(defaults: &Value, config: &Value, overridden: &[String]) -> Vec<String> {
+    let mut errors = vec![];
+    for (section, settings) in config.as_object().into_iter().flatten() {
+        for (key, value) in settings.as_object().into_iter().flatten() {
+            let mut single = defaults.clone();
+            single[section][key] = value.clone();
+            if let Err(e) = serde_json::from_value::<BlockchainConfig>(single) {
+                let env = format!("{}{}_{}", ENV_PREFIX, section, key).to_uppercase();
+                let source = if overridden.contains(&env) {
+                    format!(" (set by {})", env)
+                } else {
+                    String::new()
+                };
+                errors.push(format!("{}.{}{}: {}", section, key, source, e));
+            }
+        }
+    }
+    errors
+}
+
+/// Remove the profiles from `file` and return them by name: tables that
+/// aren't sections and only hold tables
+fn take_profiles(known: &Value, file: &mut Value) -> BTreeMap<String, Value> {
+    let Some(file) = file.as_object_mut() else {
+        return BTreeMap::new();
+    };
+    let names: Vec<String> = file
+        .iter()
+        .filter(|(name, _)| known.get(name.as_str()).is_none())
+        .filter(|(_, value)| {
+            value
+                .as_object()
+                .is_some_and(|table| !table.is_empty() && table.values().all(Value::is_object))
         })
+        .map(|(name, _)| name.clone())
+        .collect();
+    names
+        .into_iter()
+        .filter_map(|name| file.remove(&name).map(|profile| (name, profile)))
+        .collect()
+}
+
+/// Name the network and the data files of `config` after `profile`,
+/// where its `settings` don't. The default network keeps the plain file
+/// names, so an existing chain is still found.
+fn apply_profile(config: &mut Value, profile: &str, settings: &Value) {
+    let sets = |section: &str, key: &str| settings.get(section).and_then(|s| s.get(key)).is_some();
+    if !sets("network", "network_id") {
+        config["network"]["network_id"] = Value::String(profile.to_string());
+    }
+    if profile == NetworkConfig::default().network_id {
+        return;
+    }
+    for key in PROFILE_DATA_FILES {
+        if sets("node", key) {
+            continue;
+        }
+        if let Some(file) = config["node"][*key].as_str() {
+            config["node"][*key] = Value::String(profile_path(file, profile));
+        }
+    }
+}
+
+/// `path` with `profile` before its extension, `./blockchain.cbor`
+/// becoming `./blockchain.testnet.cbor`
+fn profile_path(path: &str, profile: &str) -> String {
+    let path = Path::new(path);
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(format!(".{}", profile));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+/// Replace the settings of `config` that `file` sets, key by key within
+/// each section
+fn overlay(config: &mut Value, file: Value) {
+    let Value::Object(sections) = file else {
+        return;
+    };
+    for (section, settings) in sections {
+        match (config.get_mut(&section), settings) {
+            (Some(Value::Object(current)), Value::Object(settings)) => current.extend(settings),
+            (_, settings) => config[section] = settings,
+        }
     }
 }
 
+/// Apply `BTC_<SECTION>_<KEY>` variables to the settings they name and
+/// return the names of those applied. Strings are taken as they are,
+/// lists may be comma-separated, anything else is parsed as JSON.
+fn apply_env(config: &mut Value, vars: impl IntoIterator<Item = (String, String)>) -> Vec<String> {
+    let vars: BTreeMap<String, String> = vars
+        .into_iter()
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+        .collect();
+    let mut applied = vec![];
+    for (section, settings) in config.as_object_mut().into_iter().flatten() {
+        for (key, value) in settings.as_object_mut().into_iter().flatten() {
+            let name = format!("{}{}_{}", ENV_PREFIX, section, key).to_uppercase();
+            let Some(raw) = vars.get(&name) else {
+                continue;
+            };
+            *value = match value {
+                Value::String(_) => Value::String(raw.clone()),
+                Value::Null if raw != "null" => Value::String(raw.clone()),
+                Value::Array(_) if !raw.trim_start().starts_with('[') => raw
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(|item| Value::String(item.to_string()))
+                    .collect(),
+                _ => serde_json::from_str(raw).unwrap_or_else(|_| Value::String(raw.clone())),
+            };
+            applied.push(name);
+        }
+    }
+    for name in vars.keys().filter(|name| !applied.contains(name)) {
+        eprintln!("⚠ Ignoring {}, it doesn't name a setting", name);
+    }
+    applied
+}
+
 // =============================================================================
 // Helper Functions for Easy Access
 // =============================================================================
@@ -274,6 +1478,26 @@ pub fn halving_interval() -> u64 {
     BlockchainConfig::global().network.halving_interval
 }
 
+/// Get the block reward in satoshis at a height from config
+pub fn block_reward(height: u64) -> u64 {
+    BlockchainConfig::global().network.block_reward(height)
+}
+
+/// Get the most satoshis that can exist after `blocks` blocks from config
+pub fn scheduled_supply(blocks: u64) -> u64 {
+    BlockchainConfig::global().network.scheduled_supply(blocks)
+}
+
+/// Get the consensus version at `height` from config
+pub fn version_at(height: u64) -> u32 {
+    BlockchainConfig::global().network.version_at(height)
+}
+
+/// Get the network magic bytes from config
+pub fn network_magic() -> [u8; 4] {
+    BlockchainConfig::global().network.magic()
+}
+
 /// Get ideal block time from config
 pub fn ideal_block_time() -> u64 {
     BlockchainConfig::global().network.ideal_block_time
@@ -291,6 +1515,16 @@ pub fn difficulty_update_interval() -> u64 {
         .difficulty_update_interval
 }
 
+/// Get the difficulty adjustment algorithm from config
+pub fn difficulty_algorithm() -> DifficultyAlgorithm {
+    BlockchainConfig::global().network.difficulty_algorithm
+}
+
+/// Get the hashing scheme for consensus objects from config
+pub fn hash_scheme() -> HashScheme {
+    BlockchainConfig::global().network.hash_scheme
+}
+
 /// Get max mempool transaction age from config
 pub fn max_mempool_transaction_age() -> u64 {
     BlockchainConfig::global()
@@ -303,6 +1537,11 @@ pub fn block_transaction_cap() -> usize {
     BlockchainConfig::global().network.block_transaction_cap
 }
 
+/// Get the maximum network message size from config
+pub fn max_message_size() -> u32 {
+    BlockchainConfig::global().network.max_message_size
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -320,4 +1559,330 @@ mod tests {
         let target = config.min_target();
         assert!(target > U256::zero());
     }
+
+    #[test]
+    fn test_block_reward_halves_without_schedule() {
+        let network = NetworkConfig {
+            initial_reward: 50,
+            halving_interval: 10,
+            ..Default::default()
+        };
+        assert_eq!(network.block_reward(0), 50 * 100_000_000);
+        assert_eq!(network.block_reward(10), 25 * 100_000_000);
+        assert_eq!(network.block_reward(10 * 64), 0);
+    }
+
+    #[test]
+    fn test_subsidy_schedule_overrides_halving() {
+        let network = NetworkConfig {
+            initial_reward: 50,
+            halving_interval: 10,
+            subsidy_schedule: BTreeMap::from([(5, 1_000), (20, 0)]),
+            ..Default::default()
+        };
+        // before the first entry the halving schedule still applies
+        assert_eq!(network.block_reward(4), 50 * 100_000_000);
+        assert_eq!(network.block_reward(5), 1_000);
+        assert_eq!(network.block_reward(19), 1_000);
+        assert_eq!(network.block_reward(20), 0);
+        assert_eq!(network.block_reward(1_000), 0);
+    }
+
+    #[test]
+    fn test_scheduled_supply_sums_the_rewards() {
+        let key = crate::crypto::PrivateKey::new_key().public_key();
+        let network = NetworkConfig {
+            initial_reward: 50,
+            halving_interval: 10,
+            subsidy_schedule: BTreeMap::from([(25, 1_000), (30, 0)]),
+            genesis_allocations: vec![GenesisAllocation {
+                pubkey: key,
+                value: 7,
+            }],
+            ..Default::default()
+        };
+        let summed = |blocks| {
+            (0..blocks)
+                .map(|height| network.block_reward(height))
+                .sum::<u64>()
+                + 7
+        };
+        assert_eq!(network.scheduled_supply(0), 0);
+        for blocks in [1, 9, 10, 11, 25, 26, 30, 31, 1_000] {
+            assert_eq!(
+                network.scheduled_supply(blocks),
+                summed(blocks),
+                "{}",
+                blocks
+            );
+        }
+
+        // halving forever, a whole era at a time
+        let halving = NetworkConfig {
+            initial_reward: 50,
+            halving_interval: 210_000,
+            ..Default::default()
+        };
+        assert_eq!(halving.scheduled_supply(u64::MAX), 2_099_999_997_690_000);
+    }
+
+    #[test]
+    fn test_version_activations() {
+        let network = NetworkConfig {
+            version_activations: BTreeMap::from([(100, 2), (500, 3)]),
+            ..Default::default()
+        };
+        assert_eq!(network.version_at(0), 1);
+        assert_eq!(network.version_at(99), 1);
+        assert_eq!(network.version_at(100), 2);
+        assert_eq!(network.version_at(499), 2);
+        assert_eq!(network.version_at(u64::MAX), 3);
+        assert_eq!(NetworkConfig::default().version_at(u64::MAX), 1);
+    }
+
+    #[test]
+    fn test_network_magic() {
+        let network = |id: &str, magic: Option<&str>| NetworkConfig {
+            network_id: id.to_string(),
+            magic: magic.map(str::to_string),
+            ..Default::default()
+        };
+        let mainnet = network("mainnet", None).magic();
+        assert_eq!(mainnet, network("mainnet", None).magic());
+        assert_ne!(mainnet, network("testnet", None).magic());
+        assert_eq!(
+            network("mainnet", Some("f9beb4d9")).magic(),
+            [0xf9, 0xbe, 0xb4, 0xd9]
+        );
+        // unusable values fall back to the derived magic
+        assert_eq!(network("mainnet", Some("f9be")).magic(), mainnet);
+        assert_eq!(
+            network("mainnet", Some("ffffffff")).magic(),
+            [0xff, 0xff, 0xff, 0xfe]
+        );
+    }
+
+    #[test]
+    fn test_schedule_and_allocations_from_json() {
+        let key = crate::crypto::PrivateKey::new_key().public_key();
+        let json = format!(
+            r#"{{"subsidy_schedule": {{"0": 500, "100": 50}},
+                "genesis_allocations": [{{"pubkey": {}, "value": 7}}]}}"#,
+            serde_json::to_string(&key).unwrap()
+        );
+        #[derive(Deserialize)]
+        struct Partial {
+            subsidy_schedule: BTreeMap<u64, u64>,
+            genesis_allocations: Vec<GenesisAllocation>,
+        }
+        let partial: Partial = serde_json::from_str(&json).unwrap();
+        assert_eq!(partial.subsidy_schedule.get(&100), Some(&50));
+        assert_eq!(partial.genesis_allocations[0].pubkey, key);
+        assert_eq!(partial.genesis_allocations[0].value, 7);
+    }
+
+    #[test]
+    fn test_reload_applies_only_safe_settings() {
+        let current = BlockchainConfig::default();
+        let mut new = current.clone();
+        new.node.max_peers += 1;
+        new.node.mempool_cleanup_interval_secs += 1;
+        new.node.port += 1;
+        new.network.initial_reward += 1;
+        new.mining.mining_batch_size += 1;
+        let reload = current.reloaded(new.clone());
+        assert_eq!(reload.config.node.max_peers, new.node.max_peers);
+        assert_eq!(
+            reload.config.node.mempool_cleanup_interval_secs,
+            new.node.mempool_cleanup_interval_secs
+        );
+        assert_eq!(
+            reload.config.mining.mining_batch_size,
+            new.mining.mining_batch_size
+        );
+        assert_eq!(reload.config.node.port, current.node.port);
+        assert_eq!(
+            reload.config.network.initial_reward,
+            current.network.initial_reward
+        );
+        assert_eq!(reload.consensus, ["network.initial_reward"]);
+        assert_eq!(reload.restart_required, ["node.port"]);
+        assert_eq!(reload.applied.len(), 3);
+
+        let unchanged = current.reloaded(current.clone());
+        assert!(unchanged.applied.is_empty() && unchanged.consensus.is_empty());
+    }
+
+    #[test]
+    fn test_files_and_environment_are_layered() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml_file = dir.path().join("config.toml");
+        let json_file = dir.path().join("config.json");
+        std::fs::write(
+            &toml_file,
+            "[node]\nport = 1\nmax_peers = 3\n[network]\nnetwork_id = \"tomlnet\"\n",
+        )
+        .unwrap();
+        std::fs::write(&json_file, r#"{"node": {"port": 2}}"#).unwrap();
+        let vars = [
+            ("BTC_NETWORK_NETWORK_ID", "envnet"),
+            ("BTC_NODE_INITIAL_PEERS", "a:1, b:2"),
+            ("BTC_NODE_AUTH_TOKEN", "secret"),
+            ("BTC_MINING_MINING_BATCH_SIZE", "7"),
+            ("PATH", "/bin"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        let config = BlockchainConfig::load_layered(&[&toml_file, &json_file], None, vars).unwrap();
+        assert_eq!(config.node.port, 2);
+        assert_eq!(config.node.max_peers, 3);
+        assert_eq!(config.network.network_id, "envnet");
+        assert_eq!(config.node.initial_peers, ["a:1", "b:2"]);
+        assert_eq!(config.node.auth_token.as_deref(), Some("secret"));
+        assert_eq!(config.mining.mining_batch_size, 7);
+        // everything else keeps its default
+        let defaults = BlockchainConfig::default();
+        assert_eq!(config.node.blockchain_file, defaults.node.blockchain_file);
+
+        let error = BlockchainConfig::load_layered(
+            &[&json_file],
+            None,
+            [("BTC_NODE_PORT".to_string(), "ninety".to_string())],
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("BTC_NODE_PORT"), "{}", error);
+    }
+
+    #[test]
+    fn test_validation_names_the_settings() {
+        assert!(BlockchainConfig::default().validate().is_ok());
+        let mut config = BlockchainConfig::default();
+        config.network.ideal_block_time = 0;
+        config.network.min_target_hex = "0xzz".into();
+        config.node.metrics_port = config.node.port;
+        config.mining.payouts = vec![Payout {
+            public_key_file: "pool.pub.pem".into(),
+            weight: 0,
+        }];
+        config.retry.jitter = 1.5;
+        let problems = config.validate().unwrap_err();
+        let settings: Vec<&str> = problems
+            .iter()
+            .map(|problem| problem.split(':').next().unwrap())
+            .collect();
+        assert_eq!(
+            settings,
+            [
+                "network.ideal_block_time",
+                "network.min_target_hex",
+                "node.metrics_port",
+                "mining.payouts",
+                "retry.jitter"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unknown_and_mistyped_settings_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        std::fs::write(
+            &path,
+            r#"{"node": {"prot": 9000, "max_peers": "many"}, "minig": {}}"#,
+        )
+        .unwrap();
+        let error = BlockchainConfig::load_layered(&[&path], None, []).unwrap_err();
+        let message = error.to_string();
+        assert!(
+            message.contains("unknown setting node.prot, did you mean port?"),
+            "{}",
+            message
+        );
+        assert!(message.contains("unknown setting minig, did you mean mining?"));
+        assert!(message.contains("node.max_peers: invalid type"));
+    }
+
+    #[test]
+    fn test_shipped_profiles_are_valid() {
+        for profile in ["default", "testnet", "devnet", "regtest"] {
+            let path = format!("{}/../config.{}.json", env!("CARGO_MANIFEST_DIR"), profile);
+            if let Err(e) = BlockchainConfig::load_layered(&[&path], None, []) {
+                panic!("{}: {}", path, e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_profiles_of_one_file() {
+        let profiles = format!("{}/../config.profiles.toml", env!("CARGO_MANIFEST_DIR"));
+        for (profile, file) in [
+            ("mainnet", "default"),
+            ("testnet", "testnet"),
+            ("devnet", "devnet"),
+            ("regtest", "regtest"),
+        ] {
+            let path = format!("{}/../config.{}.json", env!("CARGO_MANIFEST_DIR"), file);
+            let separate = BlockchainConfig::load_layered(&[&path], None, []).unwrap();
+            let selected = BlockchainConfig::load_layered(&[&profiles], Some(profile), []).unwrap();
+            assert_eq!(to_value(&selected), to_value(&separate), "{}", profile);
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(
+            &path,
+            "[node]\nmax_peers = 3\nblockchain_file = \"data/chain\"\n\
+             [test.node]\nport = 1\npeers_file = \"peers.json\"\n[test.minig]\n",
+        )
+        .unwrap();
+        let error = BlockchainConfig::load_layered(&[&path], None, []).unwrap_err();
+        assert!(
+            error.to_string().contains("unknown setting test.minig"),
+            "{}",
+            error
+        );
+        std::fs::write(
+            &path,
+            "[node]\nmax_peers = 3\nblockchain_file = \"data/chain\"\n\
+             [test.node]\nport = 1\npeers_file = \"peers.json\"\n",
+        )
+        .unwrap();
+        let base = BlockchainConfig::load_layered(&[&path], None, []).unwrap();
+        assert_eq!(base.node.port, 9000);
+        let config = BlockchainConfig::load_layered(&[&path], Some("test"), []).unwrap();
+        assert_eq!(config.network.network_id, "test");
+        assert_eq!((config.node.port, config.node.max_peers), (1, 3));
+        assert_eq!(config.node.blockchain_file, "data/chain.test");
+        assert_eq!(config.node.banlist_file, "./banlist.test.json");
+        assert_eq!(config.node.peers_file, "peers.json");
+        assert_eq!(config.node.mempool_file, "./mempool.test.cbor");
+        assert!(BlockchainConfig::load_layered(&[&path], Some("tset"), []).is_err());
+    }
+
+    #[test]
+    fn test_toml_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        let mut config = BlockchainConfig::default();
+        config.network.subsidy_schedule = BTreeMap::from([(0, 500), (100, 50)]);
+        config.network.version_activations = BTreeMap::from([(10, 2)]);
+        config.save_to_file(&path).unwrap();
+        let loaded = BlockchainConfig::load_from_file(&path).unwrap();
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&config).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_regtest_profile() {
+        let json = include_str!("../../config.regtest.json");
+        let config: BlockchainConfig = serde_json::from_str(json).unwrap();
+        assert!(config.network.is_regtest());
+        assert_eq!(
+            config.network.difficulty_algorithm,
+            DifficultyAlgorithm::Fixed
+        );
+        assert_eq!(config.min_target(), U256::MAX);
+        assert!(!BlockchainConfig::default().network.is_regtest());
+    }
 }
@@ -1,8 +1,13 @@
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
 
-use crate::{sha256::Hash, util::Saveable};
+use crate::{
+    config::HashScheme,
+    encoding::{read_array, write_varint, Decode, DecodeError, Encode},
+    sha256::Hash,
+    util::Saveable,
+};
 use ecdsa::{
-    signature::{SignerMut, Verifier},
+    signature::{Signer, Verifier},
     Signature as ECDSASignature, SigningKey, VerifyingKey,
 };
 use k256::Secp256k1;
@@ -41,9 +46,25 @@ impl Saveable for PublicKey {
 pub struct PrivateKey(#[serde(with = "signkey_serde")] pub SigningKey<Secp256k1>);
 
 impl Saveable for PrivateKey {
-    fn load<I: Read>(reader: I) -> IoResult<Self> {
-        ciborium::de::from_reader(reader)
-            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to deserialize PrivateKey"))
+    // saved as CBOR, but hex and WIF text files load as well so keys
+    // from other tools can be used directly
+    fn load<I: Read>(mut reader: I) -> IoResult<Self> {
+        let mut buf = vec![];
+        reader.read_to_end(&mut buf)?;
+        if let Ok(key) = ciborium::de::from_reader(buf.as_slice()) {
+            return Ok(key);
+        }
+        std::str::from_utf8(&buf)
+            .ok()
+            .map(str::trim)
+            .and_then(|text| {
+                PrivateKey::from_hex(text)
+                    .or_else(|_| PrivateKey::from_wif(text))
+                    .ok()
+            })
+            .ok_or_else(|| {
+                IoError::new(IoErrorKind::InvalidData, "Failed to deserialize PrivateKey")
+            })
     }
 
     fn save<O: Write>(&self, writer: O) -> IoResult<()> {
@@ -54,9 +75,42 @@ impl Saveable for PrivateKey {
     }
 }
 
+impl Encode for Signature {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.0.to_bytes());
+    }
+}
+
+impl Encode for PublicKey {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.0.to_encoded_point(true).as_bytes());
+    }
+}
+
+impl Decode for Signature {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        ECDSASignature::from_slice(&read_array::<64>(input)?)
+            .map(Signature)
+            .map_err(|_| DecodeError::Invalid("signature"))
+    }
+}
+
+impl Decode for PublicKey {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let bytes = read_array::<33>(input)?;
+        // other SEC1 forms (compact, identity) don't re-encode to the same bytes
+        match VerifyingKey::from_sec1_bytes(&bytes) {
+            Ok(key) if key.to_encoded_point(true).as_bytes() == bytes => Ok(PublicKey(key)),
+            _ => Err(DecodeError::Invalid("public key")),
+        }
+    }
+}
+
 impl Signature {
-    // sign a crate::types::TransactionOutput from its Sha256 hash
-    pub fn sign_output(output_hash: &Hash, private_key: &mut PrivateKey) -> Self {
+    // sign a crate::types::TransactionOutput from its Sha256 hash. The
+    // nonce is derived from the key and the hash (RFC 6979), so signing
+    // needs no randomness and the same input always gets the same signature
+    pub fn sign_output(output_hash: &Hash, private_key: &PrivateKey) -> Self {
         let signature = private_key.0.sign(&output_hash.as_bytes());
         Signature(signature)
     }
@@ -68,6 +122,41 @@ impl Signature {
             .verify(&output_hash.as_bytes(), &self.0)
             .is_ok()
     }
+
+    // sign a text message to prove control of a key without spending
+    // from it. The message is hashed with MESSAGE_MAGIC in front, so the
+    // signature is useless as a transaction signature
+    pub fn sign_message(message: &str, private_key: &PrivateKey) -> Self {
+        Signature::sign_output(&message_hash(message), private_key)
+    }
+
+    pub fn verify_message(&self, message: &str, public_key: &PublicKey) -> bool {
+        self.verify(&message_hash(message), public_key)
+    }
+
+    // the 64-byte r || s pair as hex
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.encode())
+    }
+
+    pub fn from_hex(s: &str) -> Result<Self, DecodeError> {
+        let bytes = hex::decode(s).map_err(|_| DecodeError::Invalid("hex"))?;
+        Signature::decode(&bytes)
+    }
+}
+
+/// Prefix of every signed message, as Bitcoin's "Bitcoin Signed Message:\n"
+pub const MESSAGE_MAGIC: &str = "Custom DLT Signed Message:\n";
+
+/// The hash signed for a text message: double SHA-256 of the magic and
+/// the message, each prefixed with its length as a varint
+pub fn message_hash(message: &str) -> Hash {
+    let mut bytes = vec![];
+    for part in [MESSAGE_MAGIC, message] {
+        write_varint(&mut bytes, part.len() as u64);
+        bytes.extend_from_slice(part.as_bytes());
+    }
+    Hash::digest(HashScheme::Double, &bytes)
 }
 
 impl PrivateKey {
@@ -78,6 +167,75 @@ impl PrivateKey {
     pub fn public_key(&self) -> PublicKey {
         PublicKey(self.0.verifying_key().clone())
     }
+
+    // the 32-byte secret as 64 hex digits
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0.to_bytes())
+    }
+
+    pub fn from_hex(s: &str) -> Result<Self, DecodeError> {
+        let bytes = hex::decode(s).map_err(|_| DecodeError::Invalid("hex"))?;
+        // from_slice would left-pad shorter secrets
+        if bytes.len() != 32 {
+            return Err(DecodeError::Invalid("private key"));
+        }
+        SigningKey::from_slice(&bytes)
+            .map(PrivateKey)
+            .map_err(|_| DecodeError::Invalid("private key"))
+    }
+
+    // Wallet Import Format: Base58Check of the mainnet version byte, the
+    // secret and a 0x01 suffix marking that the public key is compressed,
+    // as it always is here
+    pub fn to_wif(&self) -> String {
+        let mut payload = vec![WIF_MAINNET];
+        payload.extend_from_slice(&self.0.to_bytes());
+        payload.push(WIF_COMPRESSED);
+        bs58::encode(payload).with_check().into_string()
+    }
+
+    // accepts mainnet and testnet WIF, with or without the compression
+    // flag; the key is the same either way
+    pub fn from_wif(s: &str) -> Result<Self, DecodeError> {
+        let payload = bs58::decode(s)
+            .with_check(None)
+            .into_vec()
+            .map_err(|_| DecodeError::Invalid("WIF"))?;
+        let secret = match payload.as_slice() {
+            [WIF_MAINNET | WIF_TESTNET, secret @ .., WIF_COMPRESSED] if secret.len() == 32 => {
+                secret
+            }
+            [WIF_MAINNET | WIF_TESTNET, secret @ ..] if secret.len() == 32 => secret,
+            _ => return Err(DecodeError::Invalid("WIF")),
+        };
+        SigningKey::from_slice(secret)
+            .map(PrivateKey)
+            .map_err(|_| DecodeError::Invalid("private key"))
+    }
+}
+
+const WIF_MAINNET: u8 = 0x80;
+const WIF_TESTNET: u8 = 0xef;
+const WIF_COMPRESSED: u8 = 0x01;
+
+impl PublicKey {
+    // the 33-byte compressed point as hex
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.encode())
+    }
+
+    pub fn from_hex(s: &str) -> Result<Self, DecodeError> {
+        let bytes = hex::decode(s).map_err(|_| DecodeError::Invalid("hex"))?;
+        PublicKey::decode(&bytes)
+    }
+
+    pub(crate) fn from_verifying_key(key: VerifyingKey<Secp256k1>) -> Self {
+        PublicKey(key)
+    }
+
+    pub(crate) fn verifying_key(&self) -> &VerifyingKey<Secp256k1> {
+        &self.0
+    }
 }
 
 mod signkey_serde {
@@ -1,6 +1,7 @@
 #[cfg(test)]
 mod tests {
     use crate::crypto::{PrivateKey, Signature};
+    use crate::encoding::{Decode, Encode};
     use crate::sha256::Hash;
 
     #[test]
@@ -28,12 +29,12 @@ mod tests {
 
     #[test]
     fn test_signature_creation_and_verification() {
-        let mut private_key = PrivateKey::new_key();
+        let private_key = PrivateKey::new_key();
         let public_key = private_key.public_key();
 
         let message_hash = Hash::hash(&"test message");
 
-        let signature = Signature::sign_output(&message_hash, &mut private_key);
+        let signature = Signature::sign_output(&message_hash, &private_key);
 
         // Signature should verify correctly
         let is_valid = signature.verify(&message_hash, &public_key);
@@ -42,13 +43,13 @@ mod tests {
 
     #[test]
     fn test_signature_verification_fails_wrong_message() {
-        let mut private_key = PrivateKey::new_key();
+        let private_key = PrivateKey::new_key();
         let public_key = private_key.public_key();
 
         let message1 = Hash::hash(&"message 1");
         let message2 = Hash::hash(&"message 2");
 
-        let signature = Signature::sign_output(&message1, &mut private_key);
+        let signature = Signature::sign_output(&message1, &private_key);
 
         // Signature should NOT verify for different message
         let is_valid = signature.verify(&message2, &public_key);
@@ -57,16 +58,116 @@ mod tests {
 
     #[test]
     fn test_signature_verification_fails_wrong_key() {
-        let mut private_key1 = PrivateKey::new_key();
+        let private_key1 = PrivateKey::new_key();
         let private_key2 = PrivateKey::new_key();
         let public_key2 = private_key2.public_key();
 
         let message = Hash::hash(&"test message");
 
-        let signature = Signature::sign_output(&message, &mut private_key1);
+        let signature = Signature::sign_output(&message, &private_key1);
 
         // Signature should NOT verify with wrong public key
         let is_valid = signature.verify(&message, &public_key2);
         assert!(!is_valid);
     }
+
+    #[test]
+    fn test_bitcoin_sized_encodings() {
+        let private_key = PrivateKey::new_key();
+        let public_key = private_key.public_key();
+        let signature = Signature::sign_output(&Hash::hash(&"output"), &private_key);
+
+        // compressed secp256k1 point, prefixed with its parity
+        let key_bytes = public_key.encode();
+        assert_eq!(key_bytes.len(), 33);
+        assert!(matches!(key_bytes[0], 0x02 | 0x03));
+        assert_eq!(signature.encode().len(), 64);
+
+        let decoded = crate::crypto::PublicKey::decode(&key_bytes).unwrap();
+        assert_eq!(decoded, public_key);
+        assert!(Signature::decode(&signature.encode())
+            .unwrap()
+            .verify(&Hash::hash(&"output"), &public_key));
+    }
+
+    #[test]
+    fn test_signatures_are_deterministic() {
+        // RFC 6979: the nonce comes from the key and the message
+        let private_key = PrivateKey::new_key();
+        let message = Hash::hash(&"output");
+        let first = Signature::sign_output(&message, &private_key);
+        let second = Signature::sign_output(&message, &private_key);
+        assert_eq!(first.encode(), second.encode());
+        let other = Signature::sign_output(&Hash::hash(&"other output"), &private_key);
+        assert_ne!(first.encode(), other.encode());
+    }
+
+    // the example key from the Bitcoin wiki's WIF page
+    const WIKI_HEX: &str = "0c28fca386c7a227600b2fe50b7cae11ec86d3bf1fbe471be89827e19d72aa1d";
+    const WIKI_WIF: &str = "KwdMAjGmerYanjeui5SHS7JkmpZvVipYvB2LJGU1ZxJwYvP98617";
+    const WIKI_WIF_UNCOMPRESSED: &str = "5HueCGU8rMjxEXxiPuD5BDku4MkFqeZyd4dZ1jvhTVqvbTLvyTJ";
+
+    #[test]
+    fn test_hex_and_wif_vectors() {
+        let private_key = PrivateKey::from_hex(WIKI_HEX).unwrap();
+        assert_eq!(private_key.to_hex(), WIKI_HEX);
+        assert_eq!(private_key.to_wif(), WIKI_WIF);
+        let from_wif = PrivateKey::from_wif(WIKI_WIF).unwrap();
+        assert_eq!(from_wif.public_key(), private_key.public_key());
+        let uncompressed = PrivateKey::from_wif(WIKI_WIF_UNCOMPRESSED).unwrap();
+        assert_eq!(uncompressed.to_hex(), WIKI_HEX);
+
+        let public_key = private_key.public_key();
+        assert_eq!(
+            crate::crypto::PublicKey::from_hex(&public_key.to_hex()).unwrap(),
+            public_key
+        );
+    }
+
+    #[test]
+    fn test_hex_and_wif_rejections() {
+        assert!(PrivateKey::from_hex("zz").is_err());
+        // zero is not a valid secret
+        assert!(PrivateKey::from_hex(&"00".repeat(32)).is_err());
+        assert!(PrivateKey::from_hex(&WIKI_HEX[2..]).is_err());
+        // a changed character breaks the checksum
+        let corrupted = WIKI_WIF.replace('K', "L");
+        assert!(PrivateKey::from_wif(&corrupted).is_err());
+    }
+
+    #[test]
+    fn test_load_text_formats() {
+        use crate::util::Saveable;
+        let private_key = PrivateKey::from_hex(WIKI_HEX).unwrap();
+        for text in [format!("{}\n", WIKI_HEX), format!("{}\n", WIKI_WIF)] {
+            let loaded = PrivateKey::load(text.as_bytes()).unwrap();
+            assert_eq!(loaded.public_key(), private_key.public_key());
+        }
+        let mut cbor = vec![];
+        private_key.save(&mut cbor).unwrap();
+        let loaded = PrivateKey::load(cbor.as_slice()).unwrap();
+        assert_eq!(loaded.to_hex(), WIKI_HEX);
+        assert!(PrivateKey::load("not a key".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_message_signing_is_domain_separated() {
+        use crate::crypto::message_hash;
+        let private_key = PrivateKey::new_key();
+        let public_key = private_key.public_key();
+        let signature = Signature::sign_message("I own this key", &private_key);
+        assert!(signature.verify_message("I own this key", &public_key));
+        assert!(!signature.verify_message("I own that key", &public_key));
+
+        // not the plain hash, so it can't pass as a transaction signature
+        let message = "I own this key";
+        assert_ne!(message_hash(message), Hash::hash(&message));
+        assert!(!signature.verify(&Hash::hash(&message), &public_key));
+
+        let hex = signature.to_hex();
+        assert_eq!(hex.len(), 128);
+        assert!(Signature::from_hex(&hex)
+            .unwrap()
+            .verify_message(message, &public_key));
+    }
 }
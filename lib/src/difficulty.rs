@@ -0,0 +1,57 @@
+//! Human-friendly views of difficulty targets
+//!
+//! Targets are 256-bit numbers where smaller means harder, which is hard
+//! to read at a glance. These helpers turn them into a Bitcoin-style
+//! "difficulty" (how many times harder than the easiest allowed target)
+//! and into hash rates.
+use crate::config;
+use crate::U256;
+
+/// A U256 as the nearest f64, for display only
+pub fn to_f64(value: U256) -> f64 {
+    value
+        .0
+        .iter()
+        .rev()
+        .fold(0.0, |acc, &word| acc * 2f64.powi(64) + word as f64)
+}
+
+/// How many times harder `target` is to meet than the network's easiest
+/// target (`min_target_hex`), which has difficulty 1
+pub fn difficulty(target: U256) -> f64 {
+    difficulty_relative_to(target, config::min_target())
+}
+
+/// Like [`difficulty`], against an explicit easiest target
+pub fn difficulty_relative_to(target: U256, min_target: U256) -> f64 {
+    to_f64(min_target) / to_f64(target.max(U256::one()))
+}
+
+/// Hashes needed on average to find a block hash at or below `target`,
+/// i.e. 2^256 / (target + 1)
+pub fn expected_hashes(target: U256) -> f64 {
+    2f64.powi(256) / (to_f64(target) + 1.0)
+}
+
+/// Hash rate needed to find blocks at `target` every `seconds_per_block`
+pub fn hashrate(target: U256, seconds_per_block: f64) -> f64 {
+    if seconds_per_block <= 0.0 {
+        return 0.0;
+    }
+    expected_hashes(target) / seconds_per_block
+}
+
+/// A hash rate with a unit prefix, e.g. "12.35 kH/s"
+pub fn format_hashrate(hashes_per_second: f64) -> String {
+    const UNITS: [&str; 7] = ["H/s", "kH/s", "MH/s", "GH/s", "TH/s", "PH/s", "EH/s"];
+    let mut value = hashes_per_second;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    format!("{:.2} {}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,39 @@
+#[cfg(test)]
+mod tests {
+    use crate::difficulty::{
+        difficulty_relative_to, expected_hashes, format_hashrate, hashrate, to_f64,
+    };
+    use crate::U256;
+
+    #[test]
+    fn test_to_f64() {
+        assert_eq!(to_f64(U256::from(12345u64)), 12345.0);
+        assert_eq!(to_f64(U256::one() << 200), 2f64.powi(200));
+    }
+
+    #[test]
+    fn test_difficulty_is_relative_to_min_target() {
+        let min_target = U256::MAX >> 16;
+        assert_eq!(difficulty_relative_to(min_target, min_target), 1.0);
+        let harder = min_target >> 4;
+        assert!((difficulty_relative_to(harder, min_target) - 16.0).abs() < 1e-9);
+        // never divides by zero
+        assert!(difficulty_relative_to(U256::zero(), min_target).is_finite());
+    }
+
+    #[test]
+    fn test_expected_hashes_and_hashrate() {
+        // a hash meets a 2^240 - 1 target with probability 2^-16
+        let target = (U256::one() << 240) - U256::one();
+        assert_eq!(expected_hashes(target), 65536.0);
+        assert_eq!(hashrate(target, 10.0), 6553.6);
+        assert_eq!(hashrate(target, 0.0), 0.0);
+    }
+
+    #[test]
+    fn test_format_hashrate() {
+        assert_eq!(format_hashrate(12.0), "12.00 H/s");
+        assert_eq!(format_hashrate(12_345.0), "12.35 kH/s");
+        assert_eq!(format_hashrate(3.5e9), "3.50 GH/s");
+    }
+}
@@ -0,0 +1,306 @@
+//! Canonical byte encoding of consensus objects
+//!
+//! Block and transaction hashes must not depend on how a serialization
+//! library happens to lay out a struct, so they are computed over an
+//! explicit encoding instead of CBOR. Blocks and transactions also travel
+//! in this encoding inside network messages (see [`as_bytes`]).
+//!
+//! - integers are fixed-width little-endian
+//! - lengths of lists are CompactSize varints, as in Bitcoin: values below
+//!   `0xfd` take one byte, larger ones a `0xfd`/`0xfe`/`0xff` marker
+//!   followed by a `u16`/`u32`/`u64`
+//! - hashes and 256-bit numbers are 32 bytes, little-endian
+//! - timestamps are `i64` seconds since the Unix epoch and `u32` nanoseconds
+//! - optional values are a `0` byte when absent, or a `1` byte followed
+//!   by the value
+//! - UUIDs are their 16 bytes, public keys 33-byte compressed SEC1 points
+//!   and signatures 64-byte `r || s`
+//!
+//! Each type writes its fields in declaration order:
+//!
+//! ```text
+//! BlockHeader        version u32 | timestamp | nonce u64 | prev_block_hash | merkle_root
+//!                    | target | utxo_commitment
+//! Block              header | varint n | n transactions
+//! Transaction        version u32 | varint n | n inputs | varint m | m outputs
+//!                    | coinbase_height
+//! TransactionInput   prev_transaction_output_hash | signature
+//! TransactionOutput  value u64 | unique_id | pubkey
+//! ```
+//!
+//! Decoding is strict: every value has exactly one encoding, so varints
+//! must be minimal and nothing may follow the decoded object.
+use crate::U256;
+use chrono::{DateTime, TimeZone, Utc};
+use thiserror::Error;
+use uuid::Uuid;
+
+/// A type with a canonical byte encoding
+pub trait Encode {
+    /// Append the encoding of `self` to `out`
+    fn encode_to(&self, out: &mut Vec<u8>);
+
+    /// The encoding of `self`
+    fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        self.encode_to(&mut out);
+        out
+    }
+}
+
+/// A type that can be read back from its canonical encoding
+pub trait Decode: Sized {
+    /// Read a value from the front of `input`, advancing it past the
+    /// bytes consumed
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError>;
+
+    /// Decode a value that must span all of `bytes`
+    fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut input = bytes;
+        let value = Self::decode_from(&mut input)?;
+        if !input.is_empty() {
+            return Err(DecodeError::TrailingBytes(input.len()));
+        }
+        Ok(value)
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    #[error("unexpected end of data")]
+    UnexpectedEnd,
+    #[error("{0} bytes left over after decoding")]
+    TrailingBytes(usize),
+    #[error("varint is not minimally encoded")]
+    NonCanonicalVarint,
+    #[error("invalid {0}")]
+    Invalid(&'static str),
+}
+
+/// Take the next `N` bytes of `input`
+pub fn read_array<const N: usize>(input: &mut &[u8]) -> Result<[u8; N], DecodeError> {
+    if input.len() < N {
+        return Err(DecodeError::UnexpectedEnd);
+    }
+    let (bytes, rest) = input.split_at(N);
+    *input = rest;
+    Ok(bytes.try_into().expect("split at N"))
+}
+
+/// Append a CompactSize varint
+pub fn write_varint(out: &mut Vec<u8>, value: u64) {
+    match value {
+        0..=0xfc => out.push(value as u8),
+        0xfd..=0xffff => {
+            out.push(0xfd);
+            out.extend_from_slice(&(value as u16).to_le_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(0xfe);
+            out.extend_from_slice(&(value as u32).to_le_bytes());
+        }
+        _ => {
+            out.push(0xff);
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+}
+
+/// Read a CompactSize varint, refusing encodings longer than needed
+pub fn read_varint(input: &mut &[u8]) -> Result<u64, DecodeError> {
+    let [marker] = read_array(input)?;
+    let (value, min) = match marker {
+        0xfd => (u16::from_le_bytes(read_array(input)?) as u64, 0xfd),
+        0xfe => (u32::from_le_bytes(read_array(input)?) as u64, 0x1_0000),
+        0xff => (u64::from_le_bytes(read_array(input)?), 0x1_0000_0000),
+        value => return Ok(value as u64),
+    };
+    if value < min {
+        return Err(DecodeError::NonCanonicalVarint);
+    }
+    Ok(value)
+}
+
+impl Encode for u32 {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Encode for u64 {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Encode for i64 {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Encode for U256 {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_little_endian());
+    }
+}
+
+impl Encode for Uuid {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl Encode for DateTime<Utc> {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.timestamp().encode_to(out);
+        self.timestamp_subsec_nanos().encode_to(out);
+    }
+}
+
+impl<T: Encode> Encode for [T] {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        write_varint(out, self.len() as u64);
+        for item in self {
+            item.encode_to(out);
+        }
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.as_slice().encode_to(out);
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        match self {
+            None => out.push(0),
+            Some(value) => {
+                out.push(1);
+                value.encode_to(out);
+            }
+        }
+    }
+}
+
+impl Decode for u32 {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(u32::from_le_bytes(read_array(input)?))
+    }
+}
+
+impl Decode for u64 {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(u64::from_le_bytes(read_array(input)?))
+    }
+}
+
+impl Decode for i64 {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(i64::from_le_bytes(read_array(input)?))
+    }
+}
+
+impl Decode for U256 {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(U256::from_little_endian(&read_array::<32>(input)?))
+    }
+}
+
+impl Decode for Uuid {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(Uuid::from_bytes(read_array(input)?))
+    }
+}
+
+impl Decode for DateTime<Utc> {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let seconds = i64::decode_from(input)?;
+        let nanos = u32::decode_from(input)?;
+        // chrono also accepts nanos above a second for leap seconds,
+        // which would give the same instant two encodings
+        if nanos >= 1_000_000_000 {
+            return Err(DecodeError::Invalid("timestamp"));
+        }
+        Utc.timestamp_opt(seconds, nanos)
+            .single()
+            .ok_or(DecodeError::Invalid("timestamp"))
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match read_array::<1>(input)? {
+            [0] => Ok(None),
+            [1] => Ok(Some(T::decode_from(input)?)),
+            _ => Err(DecodeError::Invalid("option flag")),
+        }
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let len = read_varint(input)?;
+        // every item takes at least a byte, don't trust the length
+        // prefix for more than that
+        if len > input.len() as u64 {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let mut items = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            items.push(T::decode_from(input)?);
+        }
+        Ok(items)
+    }
+}
+
+/// Serde adapter writing a value as one byte string in its canonical
+/// encoding: `#[serde(with = "crate::encoding::as_bytes")]`.
+///
+/// Reading also accepts the value's regular serde form, as sent by peers
+/// from before protocol version 4.
+pub mod as_bytes {
+    use super::{Decode, Encode};
+    use serde::de::{self, value::MapAccessDeserializer, MapAccess, Visitor};
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::fmt;
+    use std::marker::PhantomData;
+
+    pub fn serialize<T: Encode, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&value.encode())
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: Decode + Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(BytesOrStruct(PhantomData))
+    }
+
+    struct BytesOrStruct<T>(PhantomData<T>);
+
+    impl<'de, T: Decode + Deserialize<'de>> Visitor<'de> for BytesOrStruct<T> {
+        type Value = T;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "canonically encoded bytes or a map")
+        }
+
+        fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<T, E> {
+            T::decode(bytes).map_err(E::custom)
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<T, A::Error> {
+            T::deserialize(MapAccessDeserializer::new(map))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
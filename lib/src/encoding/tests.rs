@@ -0,0 +1,279 @@
+#[cfg(test)]
+mod tests {
+    use crate::config::HashScheme;
+    use crate::crypto::PrivateKey;
+    use crate::encoding::{read_varint, write_varint, Decode, DecodeError, Encode};
+    use crate::sha256::Hash;
+    use crate::test_helpers::{arb_block, arb_transaction, create_test_input, create_test_output};
+    use crate::types::{Block, BlockHeader, Transaction};
+    use crate::util::MerkleRoot;
+    use crate::U256;
+    use chrono::{TimeZone, Utc};
+    use proptest::prelude::*;
+
+    fn varint(value: u64) -> Vec<u8> {
+        let mut out = vec![];
+        write_varint(&mut out, value);
+        out
+    }
+
+    #[test]
+    fn test_varint_sizes() {
+        assert_eq!(varint(0), vec![0]);
+        assert_eq!(varint(0xfc), vec![0xfc]);
+        assert_eq!(varint(0xfd), vec![0xfd, 0xfd, 0x00]);
+        assert_eq!(varint(0x1_0000), vec![0xfe, 0x00, 0x00, 0x01, 0x00]);
+        assert_eq!(varint(u64::MAX), [vec![0xff], vec![0xff; 8]].concat());
+    }
+
+    #[test]
+    fn test_varint_roundtrip_and_minimality() {
+        for value in [
+            0,
+            0xfc,
+            0xfd,
+            0xffff,
+            0x1_0000,
+            u32::MAX as u64 + 1,
+            u64::MAX,
+        ] {
+            assert_eq!(read_varint(&mut varint(value).as_slice()), Ok(value));
+        }
+        // 5 fits in one byte, so the three-byte form is not canonical
+        assert_eq!(
+            read_varint(&mut [0xfd, 0x05, 0x00].as_slice()),
+            Err(DecodeError::NonCanonicalVarint)
+        );
+        assert_eq!(
+            read_varint(&mut [0xfe, 0x01].as_slice()),
+            Err(DecodeError::UnexpectedEnd)
+        );
+    }
+
+    fn sample_block() -> Block {
+        let private_key = PrivateKey::new_key();
+        let spent = create_test_output(500, &private_key);
+        let transactions = vec![
+            Transaction::new(vec![], vec![create_test_output(50, &private_key)]),
+            Transaction::new(
+                vec![create_test_input(&spent.hash(), &private_key)],
+                vec![
+                    create_test_output(300, &private_key),
+                    create_test_output(190, &private_key),
+                ],
+            ),
+        ];
+        let header = BlockHeader::new(
+            Utc::now(),
+            42,
+            Hash::zero(),
+            MerkleRoot::calculate(&transactions),
+            U256::MAX >> 8,
+        );
+        Block::new(header, transactions)
+    }
+
+    #[test]
+    fn test_block_roundtrip() {
+        let block = sample_block();
+        let bytes = block.encode();
+        let decoded = Block::decode(&bytes).unwrap();
+        assert_eq!(decoded.encode(), bytes);
+        assert_eq!(decoded.hash(), block.hash());
+        assert_eq!(decoded.header.timestamp, block.header.timestamp);
+        assert_eq!(decoded.transactions[1].hash(), block.transactions[1].hash());
+    }
+
+    #[test]
+    fn test_transaction_hex_roundtrip() {
+        let transaction = sample_block().transactions[1].clone();
+        let hex = transaction.to_hex();
+        assert_eq!(hex, hex::encode(transaction.encode()));
+        let decoded = Transaction::from_hex(&format!("{}\n", hex)).unwrap();
+        assert_eq!(decoded.hash(), transaction.hash());
+        assert_eq!(
+            Transaction::from_hex("zz").err(),
+            Some(DecodeError::Invalid("hex"))
+        );
+        assert_eq!(
+            Transaction::from_hex(&hex[..hex.len() - 2]).err(),
+            Some(DecodeError::UnexpectedEnd)
+        );
+    }
+
+    #[test]
+    fn test_decoding_is_strict() {
+        let bytes = sample_block().transactions[1].encode();
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert_eq!(
+            Transaction::decode(&longer).err(),
+            Some(DecodeError::TrailingBytes(1))
+        );
+        assert_eq!(
+            Transaction::decode(&bytes[..bytes.len() - 1]).err(),
+            Some(DecodeError::UnexpectedEnd)
+        );
+        // a list claiming more items than there are bytes left
+        assert_eq!(
+            Transaction::decode(&[0xfe, 0xff, 0xff, 0xff, 0x7f]).err(),
+            Some(DecodeError::UnexpectedEnd)
+        );
+        // the public key of the first output is not a curve point
+        let mut bad_key = bytes.clone();
+        let key_start = bytes.len() - 1 - 2 * (8 + 16 + 33) + 8 + 16;
+        bad_key[key_start] = 0x05;
+        assert_eq!(
+            Transaction::decode(&bad_key).err(),
+            Some(DecodeError::Invalid("public key"))
+        );
+    }
+
+    #[test]
+    fn test_header_layout() {
+        let transactions = vec![Transaction::new(vec![], vec![])];
+        let mut header = BlockHeader::new(
+            Utc.timestamp_opt(1_700_000_000, 5).unwrap(),
+            7,
+            Hash::zero(),
+            MerkleRoot::calculate(&transactions),
+            U256::MAX,
+        );
+        let bytes = header.encode();
+        // version 4, timestamp 8 + 4, nonce 8, three 32-byte fields, no
+        // commitment
+        assert_eq!(bytes.len(), 4 + 12 + 8 + 3 * 32 + 1);
+        assert_eq!(&bytes[..4], &1u32.to_le_bytes());
+        assert_eq!(&bytes[4..12], &1_700_000_000i64.to_le_bytes());
+        assert_eq!(&bytes[12..16], &5u32.to_le_bytes());
+        assert_eq!(&bytes[16..24], &7u64.to_le_bytes());
+        assert_eq!(&bytes[bytes.len() - 33..bytes.len() - 1], &[0xff; 32]);
+        assert_eq!(bytes[bytes.len() - 1], 0);
+
+        header.utxo_commitment = Some(Hash::zero());
+        let committed = header.encode();
+        assert_eq!(committed.len(), bytes.len() + 32);
+        assert_eq!(committed[bytes.len() - 1], 1);
+        let decoded = BlockHeader::decode(&committed).unwrap();
+        assert_eq!(decoded.utxo_commitment, Some(Hash::zero()));
+        assert_ne!(decoded.hash(), BlockHeader::decode(&bytes).unwrap().hash());
+
+        let mut bad_flag = bytes.clone();
+        *bad_flag.last_mut().unwrap() = 2;
+        assert_eq!(
+            BlockHeader::decode(&bad_flag).err(),
+            Some(DecodeError::Invalid("option flag"))
+        );
+    }
+
+    #[test]
+    fn test_transaction_layout() {
+        let private_key = PrivateKey::new_key();
+        let output = create_test_output(100, &private_key);
+        let transaction = Transaction::new(vec![], vec![output.clone()]);
+        let bytes = transaction.encode();
+        // version 4, no inputs, one output of value 8 + uuid 16 +
+        // compressed key 33, no coinbase height
+        assert_eq!(bytes.len(), 4 + 1 + 1 + 8 + 16 + 33 + 1);
+        assert_eq!(&bytes[..4], &1u32.to_le_bytes());
+        assert_eq!(&bytes[4..6], &[0, 1]);
+        assert_eq!(&bytes[6..14], &100u64.to_le_bytes());
+        assert_eq!(&bytes[14..30], output.unique_id.as_bytes());
+        assert_eq!(bytes[bytes.len() - 1], 0);
+
+        let mut versioned = transaction.clone();
+        versioned.version = 2;
+        assert_eq!(&versioned.encode()[..4], &2u32.to_le_bytes());
+        assert_ne!(versioned.hash(), transaction.hash());
+
+        let coinbase = Transaction::coinbase(7, vec![output]);
+        let bytes = coinbase.encode();
+        assert_eq!(&bytes[bytes.len() - 9..], &[1, 7, 0, 0, 0, 0, 0, 0, 0]);
+        assert_ne!(coinbase.hash(), transaction.hash());
+    }
+
+    #[test]
+    fn test_hash_schemes_differ() {
+        let data = b"block header";
+        let single = Hash::digest(HashScheme::Single, data);
+        let double = Hash::digest(HashScheme::Double, data);
+        assert_ne!(single, double);
+        assert_eq!(double, Hash::digest(HashScheme::Single, &sha2_bytes(data)));
+    }
+
+    #[test]
+    fn test_hash_parses_its_display_form() {
+        use std::str::FromStr;
+        let hash = sample_block().hash();
+        assert_eq!(Hash::from_str(&hash.to_string()), Ok(hash));
+        // Display drops leading zeros, padded forms parse the same
+        let padded = format!("{:0>64}", Hash::digest(HashScheme::Single, b"x"));
+        assert_eq!(
+            Hash::from_str(&padded),
+            Ok(Hash::digest(HashScheme::Single, b"x"))
+        );
+        assert!(Hash::from_str("").is_err());
+        assert!(Hash::from_str("xyz").is_err());
+        assert!(Hash::from_str(&"f".repeat(65)).is_err());
+    }
+
+    /// SHA-256 of `data` as raw bytes
+    fn sha2_bytes(data: &[u8]) -> Vec<u8> {
+        use sha2::{Digest, Sha256};
+        Sha256::digest(data).to_vec()
+    }
+
+    #[cfg(feature = "scrypt")]
+    #[test]
+    fn test_scrypt_scheme() {
+        let hash = Hash::digest(HashScheme::Scrypt, b"block header");
+        assert_ne!(hash, Hash::digest(HashScheme::Single, b"block header"));
+        assert_eq!(hash, Hash::digest(HashScheme::Scrypt, b"block header"));
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn prop_transaction_roundtrip(transaction in arb_transaction()) {
+            let bytes = transaction.encode();
+            let decoded = Transaction::decode(&bytes).unwrap();
+            prop_assert_eq!(decoded.encode(), bytes);
+            prop_assert_eq!(decoded.hash(), transaction.hash());
+        }
+
+        #[test]
+        fn prop_block_roundtrip(block in arb_block()) {
+            let bytes = block.encode();
+            let decoded = Block::decode(&bytes).unwrap();
+            prop_assert_eq!(decoded.encode(), bytes);
+            prop_assert_eq!(decoded.hash(), block.hash());
+        }
+
+        /// Decoding corrupted bytes never panics, and whatever still
+        /// decodes has no other encoding
+        #[test]
+        fn prop_corrupted_block_decodes_canonically(
+            block in arb_block(),
+            position in any::<prop::sample::Index>(),
+            byte in any::<u8>(),
+        ) {
+            let mut bytes = block.encode();
+            let position = position.index(bytes.len());
+            bytes[position] = byte;
+            if let Ok(decoded) = Block::decode(&bytes) {
+                prop_assert_eq!(decoded.encode(), bytes);
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn prop_decoding_arbitrary_bytes_never_panics(bytes in any::<Vec<u8>>()) {
+            let _ = Transaction::decode(&bytes);
+            let _ = BlockHeader::decode(&bytes);
+            let _ = Block::decode(&bytes);
+            let _ = read_varint(&mut bytes.as_slice());
+        }
+    }
+}
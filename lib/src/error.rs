@@ -1,3 +1,4 @@
+use crate::sha256::Hash;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -14,6 +15,35 @@ pub enum BtcError {
     InvalidTransactionOutput { reason: String },
     #[error("Invalid Merkle root: calculated root does not match block header")]
     InvalidMerkleRoot,
+    #[error("Invalid block: no coinbase transaction")]
+    MissingCoinbase,
+    #[error("Invalid block: coinbase at position {position}, it must come first")]
+    CoinbaseNotFirst { position: usize },
+    #[error("Invalid block: {count} coinbase transactions, only one is allowed")]
+    MultipleCoinbases { count: usize },
+    #[error("Invalid coinbase: a coinbase cannot have inputs")]
+    CoinbaseHasInputs,
+    #[error("Invalid coinbase: pays {value}, more than reward {reward} + fees {fees}")]
+    CoinbaseOverpays { value: u64, reward: u64, fees: u64 },
+    #[error("Invalid block: transaction {hash} appears more than once")]
+    DuplicateTransaction { hash: Hash },
+    #[error("Invalid block: output {output} is spent more than once")]
+    DuplicateSpend { output: Hash },
+    #[error("Invalid block: supply of {supply} would exceed the {allowed} scheduled")]
+    SupplyExceeded { supply: u64, allowed: u64 },
+    #[error("Invalid block: version {version} is obsolete, {required} or later is required")]
+    ObsoleteBlockVersion { version: u32, required: u32 },
+    #[error("Invalid transaction: version {version} is not active, the highest is {active}")]
+    InactiveTransactionVersion { version: u32, active: u32 },
+    #[error("Invalid amount: {reason}")]
+    InvalidAmount { reason: String },
+    #[error("Mempool full: fee {fee} is below the minimum of {min_fee}")]
+    MempoolFull { fee: u64, min_fee: u64 },
+    #[error(
+        "Chain was built under other consensus parameters than configured: {}",
+        parameters.join(", ")
+    )]
+    ConsensusParamsMismatch { parameters: Vec<String> },
     #[error("Invalid hash: {reason}")]
     InvalidHash { reason: String },
     #[error("Invalid signature: signature verification failed")]
@@ -22,6 +52,8 @@ pub enum BtcError {
     InvalidPublicKey { reason: String },
     #[error("Invalid private key: {reason}")]
     InvalidPrivateKey { reason: String },
+    #[error("Reorg to fork height {fork_height} needs blocks pruned below {oldest_block}")]
+    ReorgBelowHorizon { fork_height: u64, oldest_block: u64 },
 }
 
 // Convenience methods for creating errors
@@ -0,0 +1,277 @@
+//! Hierarchical deterministic keys (BIP32-style)
+//!
+//! Instead of keeping one key file per key pair, a wallet only needs to
+//! back up a single random seed. Every key is derived from it:
+//!
+//! ```text
+//! seed ──HMAC-SHA512("Bitcoin seed")──► master (m)
+//!   m/0'        account
+//!   m/0'/0/i    receive keys
+//!   m/0'/1/i    change keys
+//! ```
+//!
+//! Child keys are computed as `k_child = k_parent + IL (mod n)` where
+//! `IL || chain_code = HMAC-SHA512(parent_chain_code, data)`. For hardened
+//! indices (`i >= 2^31`, written `i'`) the data is the parent private key,
+//! otherwise it is the compressed parent public key.
+//!
+//! Normal children can also be derived from the parent public key alone,
+//! `K_child = K_parent + IL·G`: an [`ExtendedPublicKey`] hands out the
+//! receive keys of a branch without being able to spend from them.
+use crate::crypto::{PrivateKey, PublicKey};
+use crate::error::{BtcError, Result};
+use crate::util::Saveable;
+use ecdsa::{SigningKey, VerifyingKey};
+use hmac::{Hmac, Mac};
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use k256::elliptic_curve::PrimeField;
+use k256::{ProjectivePoint, Scalar};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha512;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+
+/// First hardened child index
+pub const HARDENED: u32 = 0x8000_0000;
+
+/// Branch of the account used for keys handed out to payers
+pub const RECEIVE_BRANCH: u32 = 0;
+/// Branch of the account used for change outputs
+pub const CHANGE_BRANCH: u32 = 1;
+
+/// Random wallet seed, the only secret an HD wallet has to back up
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Seed(Vec<u8>);
+
+impl Seed {
+    /// Generate a new random 256-bit seed
+    pub fn generate() -> Self {
+        let mut bytes = vec![0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        Seed(bytes)
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Seed(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Saveable for Seed {
+    fn load<I: Read>(reader: I) -> IoResult<Self> {
+        ciborium::de::from_reader(reader)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to deserialize Seed"))
+    }
+
+    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+        ciborium::ser::into_writer(self, writer)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialize Seed"))?;
+        Ok(())
+    }
+}
+
+/// A private key together with the chain code needed to derive children
+#[derive(Clone)]
+pub struct ExtendedPrivateKey {
+    private_key: PrivateKey,
+    chain_code: [u8; 32],
+    depth: u8,
+}
+
+impl ExtendedPrivateKey {
+    /// Derive the master key from a seed
+    pub fn from_seed(seed: &[u8]) -> Result<Self> {
+        let (key, chain_code) = hmac_sha512(b"Bitcoin seed", &[seed]);
+        let signing_key =
+            SigningKey::from_slice(&key).map_err(|_| BtcError::InvalidPrivateKey {
+                reason: "seed produces an invalid master key".to_string(),
+            })?;
+        Ok(Self {
+            private_key: PrivateKey(signing_key),
+            chain_code,
+            depth: 0,
+        })
+    }
+
+    /// Derive the child key at `index` (hardened if `index >= HARDENED`)
+    pub fn derive_child(&self, index: u32) -> Result<Self> {
+        let parent = self.private_key.0.as_nonzero_scalar();
+        let (tweak, chain_code) = if index >= HARDENED {
+            let key_bytes = self.private_key.0.to_bytes();
+            hmac_sha512(&self.chain_code, &[&[0], &key_bytes, &index.to_be_bytes()])
+        } else {
+            let point = self
+                .private_key
+                .0
+                .verifying_key()
+                .as_affine()
+                .to_encoded_point(true);
+            hmac_sha512(&self.chain_code, &[point.as_bytes(), &index.to_be_bytes()])
+        };
+        // IL must be a valid scalar and the resulting key non-zero; this
+        // happens with probability < 2^-127, callers should skip the index
+        let tweak = Option::<Scalar>::from(Scalar::from_repr(tweak.into())).ok_or_else(|| {
+            BtcError::InvalidPrivateKey {
+                reason: format!("child {} is invalid, use the next index", index),
+            }
+        })?;
+        let child = tweak + parent.as_ref();
+        let signing_key =
+            SigningKey::from_bytes(&child.to_repr()).map_err(|_| BtcError::InvalidPrivateKey {
+                reason: format!("child {} is invalid, use the next index", index),
+            })?;
+        Ok(Self {
+            private_key: PrivateKey(signing_key),
+            chain_code,
+            depth: self.depth.saturating_add(1),
+        })
+    }
+
+    /// Derive a descendant following `path`, e.g. `[HARDENED, 0, 5]` for `m/0'/0/5`
+    pub fn derive_path(&self, path: &[u32]) -> Result<Self> {
+        path.iter()
+            .try_fold(self.clone(), |key, &index| key.derive_child(index))
+    }
+
+    pub fn private_key(&self) -> &PrivateKey {
+        &self.private_key
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.private_key.public_key()
+    }
+
+    pub fn chain_code(&self) -> &[u8; 32] {
+        &self.chain_code
+    }
+
+    pub fn depth(&self) -> u8 {
+        self.depth
+    }
+
+    /// The public half, which derives the same normal children's public keys
+    pub fn extended_public_key(&self) -> ExtendedPublicKey {
+        ExtendedPublicKey {
+            public_key: self.public_key(),
+            chain_code: self.chain_code,
+            depth: self.depth,
+        }
+    }
+}
+
+/// A public key together with the chain code needed to derive the public
+/// keys of its normal (non-hardened) children
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ExtendedPublicKey {
+    public_key: PublicKey,
+    chain_code: [u8; 32],
+    depth: u8,
+}
+
+impl ExtendedPublicKey {
+    /// Derive the child key at `index`, which can't be hardened
+    pub fn derive_child(&self, index: u32) -> Result<Self> {
+        if index >= HARDENED {
+            return Err(BtcError::InvalidPublicKey {
+                reason: format!("hardened child {} needs the private key", index),
+            });
+        }
+        let parent = self.public_key.verifying_key();
+        let point = parent.as_affine().to_encoded_point(true);
+        let (tweak, chain_code) =
+            hmac_sha512(&self.chain_code, &[point.as_bytes(), &index.to_be_bytes()]);
+        let invalid = || BtcError::InvalidPublicKey {
+            reason: format!("child {} is invalid, use the next index", index),
+        };
+        let tweak = Option::<Scalar>::from(Scalar::from_repr(tweak.into())).ok_or_else(invalid)?;
+        let child = ProjectivePoint::GENERATOR * tweak + parent.as_affine();
+        let child = VerifyingKey::from_affine(child.to_affine()).map_err(|_| invalid())?;
+        Ok(Self {
+            public_key: PublicKey::from_verifying_key(child),
+            chain_code,
+            depth: self.depth.saturating_add(1),
+        })
+    }
+
+    /// Derive a descendant following `path`, e.g. `[0, 5]`
+    pub fn derive_path(&self, path: &[u32]) -> Result<Self> {
+        path.iter()
+            .try_fold(self.clone(), |key, &index| key.derive_child(index))
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    pub fn chain_code(&self) -> &[u8; 32] {
+        &self.chain_code
+    }
+
+    pub fn depth(&self) -> u8 {
+        self.depth
+    }
+}
+
+impl Saveable for ExtendedPublicKey {
+    fn load<I: Read>(reader: I) -> IoResult<Self> {
+        ciborium::de::from_reader(reader).map_err(|_| {
+            IoError::new(
+                IoErrorKind::InvalidData,
+                "Failed to deserialize ExtendedPublicKey",
+            )
+        })
+    }
+
+    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+        ciborium::ser::into_writer(self, writer).map_err(|_| {
+            IoError::new(
+                IoErrorKind::InvalidData,
+                "Failed to serialize ExtendedPublicKey",
+            )
+        })?;
+        Ok(())
+    }
+}
+
+/// Parse a derivation path such as `m/0'/1/7` (`h` also marks hardened indices)
+pub fn parse_path(path: &str) -> Result<Vec<u32>> {
+    let invalid = || BtcError::InvalidPrivateKey {
+        reason: format!("invalid derivation path: {}", path),
+    };
+    let mut parts = path.split('/');
+    if parts.next() != Some("m") {
+        return Err(invalid());
+    }
+    parts
+        .map(|part| {
+            let (number, hardened) = match part.strip_suffix(['\'', 'h']) {
+                Some(number) => (number, true),
+                None => (part, false),
+            };
+            let index: u32 = number.parse().map_err(|_| invalid())?;
+            if index >= HARDENED {
+                return Err(invalid());
+            }
+            Ok(if hardened { index + HARDENED } else { index })
+        })
+        .collect()
+}
+
+fn hmac_sha512(key: &[u8], data: &[&[u8]]) -> ([u8; 32], [u8; 32]) {
+    let mut mac = Hmac::<Sha512>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    for chunk in data {
+        mac.update(chunk);
+    }
+    let output = mac.finalize().into_bytes();
+    let mut left = [0u8; 32];
+    let mut right = [0u8; 32];
+    left.copy_from_slice(&output[..32]);
+    right.copy_from_slice(&output[32..]);
+    (left, right)
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,91 @@
+#[cfg(test)]
+mod tests {
+    use crate::hd::{parse_path, ExtendedPrivateKey, ExtendedPublicKey, HARDENED};
+    use crate::util::Saveable;
+
+    // BIP32 test vector 1
+    const SEED: &str = "000102030405060708090a0b0c0d0e0f";
+
+    fn master() -> ExtendedPrivateKey {
+        ExtendedPrivateKey::from_seed(&hex::decode(SEED).unwrap()).unwrap()
+    }
+
+    fn private_hex(key: &ExtendedPrivateKey) -> String {
+        hex::encode(key.private_key().0.to_bytes())
+    }
+
+    #[test]
+    fn test_master_key_from_seed() {
+        let master = master();
+        assert_eq!(
+            private_hex(&master),
+            "e8f32e723decf4051aefac8e2c93c9c5b214313817cdb01a1494b917c8436b35"
+        );
+        assert_eq!(
+            hex::encode(master.chain_code()),
+            "873dff81c02f525623fd1fe5167eac3a55a049de3d314bb42ee227ffed37d508"
+        );
+    }
+
+    #[test]
+    fn test_hardened_and_normal_derivation() {
+        let child = master().derive_child(HARDENED).unwrap();
+        assert_eq!(
+            private_hex(&child),
+            "edb2e14f9ee77d26dd93b4ecede8d16ed408ce149b6cd80b0715a2d911a0afea"
+        );
+        assert_eq!(
+            hex::encode(child.chain_code()),
+            "47fdacbd0f1097043b78c63c20c34ef4ed9a111d980047ad16282c7ae6236141"
+        );
+
+        let grandchild = master().derive_path(&[HARDENED, 1]).unwrap();
+        assert_eq!(
+            private_hex(&grandchild),
+            "3c6cb8d0f6a264c91ea8b5030fadaa8e538b020f0a387421a12de9319dc93368"
+        );
+        assert_eq!(grandchild.depth(), 2);
+    }
+
+    #[test]
+    fn test_derivation_is_deterministic_and_distinct() {
+        let a = master().derive_path(&[HARDENED, 0, 0]).unwrap();
+        let b = master().derive_path(&[HARDENED, 0, 0]).unwrap();
+        let c = master().derive_path(&[HARDENED, 0, 1]).unwrap();
+        assert_eq!(a.public_key(), b.public_key());
+        assert_ne!(a.public_key(), c.public_key());
+    }
+
+    #[test]
+    fn test_public_derivation_matches_private() {
+        let branch = master().derive_path(&[HARDENED, 0]).unwrap();
+        let xpub = branch.extended_public_key();
+        for index in [0, 1, 7] {
+            let private = branch.derive_child(index).unwrap();
+            let public = xpub.derive_child(index).unwrap();
+            assert_eq!(public.public_key(), &private.public_key());
+            assert_eq!(public.chain_code(), private.chain_code());
+        }
+        assert!(xpub.derive_child(HARDENED).is_err());
+
+        let mut saved = vec![];
+        xpub.save(&mut saved).unwrap();
+        let loaded = ExtendedPublicKey::load(saved.as_slice()).unwrap();
+        assert_eq!(
+            loaded.derive_path(&[3]).unwrap().public_key(),
+            &branch.derive_child(3).unwrap().public_key()
+        );
+    }
+
+    #[test]
+    fn test_parse_path() {
+        assert_eq!(parse_path("m").unwrap(), Vec::<u32>::new());
+        assert_eq!(
+            parse_path("m/0'/1/7h").unwrap(),
+            vec![HARDENED, 1, HARDENED + 7]
+        );
+        assert!(parse_path("0/1").is_err());
+        assert!(parse_path("m/x").is_err());
+        assert!(parse_path("m/2147483648").is_err());
+    }
+}
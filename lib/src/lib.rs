@@ -71,11 +71,21 @@ pub const MAX_MEMPOOL_TRANSACTION_AGE: u64 = 600;
 /// **Default value** used when no config.json is provided
 pub const BLOCK_TRANSACTION_CAP: usize = 20;
 
+pub mod amount;
+pub mod auth;
 pub mod config;
 pub mod crypto;
+pub mod difficulty;
+pub mod encoding;
 pub mod error;
+pub mod hd;
 pub mod network;
+pub mod reorg;
+pub mod retry;
 pub mod sha256;
+pub mod simulation;
+pub mod store;
+pub mod transport;
 pub mod util;
 
 #[cfg(test)]
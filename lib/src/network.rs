@@ -1,42 +1,54 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    config,
     crypto::PublicKey,
-    types::{Block, Transaction, TransactionOutput},
+    encoding,
+    error::BtcError,
+    sha256::Hash,
+    types::{Block, BlockHeader, Transaction, TransactionOutput},
 };
 use std::io::{Error as IoError, Read, Write};
+use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum Message {
     /// Fetch all UTXOs belonging to a public key
     FetchUTXOs(PublicKey),
-    /// UTXOs belonging to a public key. Bool determines if marked
-    UTXOs(Vec<(TransactionOutput, bool)>),
+    /// UTXOs belonging to a public key as (output, marked, height of
+    /// the block that created it), plus the height of the chain tip
+    UTXOs(Vec<(TransactionOutput, bool, u64)>, u64),
+    /// Fetch outputs paying a public key from transactions
+    /// still waiting in the mempool
+    FetchPendingOutputs(PublicKey),
+    /// This is the response to FetchPendingOutputs
+    PendingOutputs(Vec<TransactionOutput>),
     /// Send a transaction to the network
-    SubmitTransaction(Transaction),
+    SubmitTransaction(#[serde(with = "encoding::as_bytes")] Transaction),
     /// Broadcast a new transaction to other nodes
-    NewTransaction(Transaction),
+    NewTransaction(#[serde(with = "encoding::as_bytes")] Transaction),
     /// Ask the node to prepare the optimal block template
     /// with the coinbase transaction paying the specified
     /// public key
     FetchTemplate(PublicKey),
     /// The template
-    Template(Block),
+    Template(#[serde(with = "encoding::as_bytes")] Block),
     /// Ask the node to validate a block template.
     /// This is to prevent the node from mining an invalid
     /// block (e.g. if one has been found in the meantime,
     /// or if transactions have been removed from the mempool)
-    ValidateTemplate(Block),
+    ValidateTemplate(#[serde(with = "encoding::as_bytes")] Block),
     /// If template is valid
     TemplateValidity(bool),
     /// Submit a mined block to a node
-    SubmitTemplate(Block),
+    SubmitTemplate(#[serde(with = "encoding::as_bytes")] Block),
     /// Ask a node to report all the other nodes it knows
     /// about
     DiscoverNodes,
     /// This is the response to DiscoverNodes
-    NodeList(Vec<String>),
+    NodeList(Vec<KnownPeer>),
     /// Ask a node whats the highest block it knows about
     /// in comparison to the local blockchain
     AskDifference(u32),
@@ -45,53 +57,491 @@ pub enum Message {
     /// Ask a node to send a block with the specified height
     FetchBlock(usize),
     /// Broadcast a new block to other nodes
-    NewBlock(Block),
+    NewBlock(#[serde(with = "encoding::as_bytes")] Block),
+    /// Ask a node to list the peers it currently bans
+    ListBanned,
+    /// This is the response to ListBanned
+    BannedPeers(Vec<BannedPeer>),
+    /// Ask a node to lift the ban on a peer address
+    Unban(String),
+    /// Ask an archival node for up to `count` blocks starting at
+    /// `height`, e.g. ones a pruned node needs to follow a deep reorg
+    FetchHistoricalBlocks(u64, u32),
+    /// This is the response to FetchHistoricalBlocks, empty if the
+    /// node doesn't keep the first block asked for
+    HistoricalBlocks(Vec<Block>),
+    /// Ask a node for the fee (in satoshis per transaction) needed
+    /// to be mined within the given number of blocks
+    EstimateFee(u32),
+    /// This is the response to EstimateFee
+    FeeEstimate(u64),
+    /// Ask a node for a confirmed transaction by hash
+    /// (needs `txindex` on the node)
+    FetchTransaction(Hash),
+    /// This is the response to FetchTransaction. None if the
+    /// transaction is unknown or the node has no transaction index
+    TransactionFound(Option<IndexedTransaction>),
+    /// Ask a node for the confirmed transactions paying or spending
+    /// from a public key (needs `addressindex` on the node)
+    FetchAddressHistory(PublicKey),
+    /// This is the response to FetchAddressHistory:
+    /// (transaction hash, block height), oldest first
+    AddressHistory(Vec<(Hash, u64)>),
+    /// Ask a node to push notifications about transactions involving
+    /// these public keys. The connection is used for notifications only
+    /// from then on
+    Subscribe(Vec<PublicKey>),
+    /// Pushed to subscribers when a transaction was evicted from the
+    /// mempool because a conflicting transaction replaced it or was mined
+    TransactionConflicted(Hash),
+    /// Pushed to subscribers when a transaction was dropped from the
+    /// mempool for waiting too long, its inputs are spendable again
+    TransactionExpired(Hash),
+    /// Pushed to subscribers when a transaction entering the mempool pays
+    /// one of their keys: the transaction hash, the output paying them
+    /// and the key of the output its first input spends, if known
+    PaymentReceived(Hash, TransactionOutput, Option<PublicKey>),
+    /// Sent by a node that is shutting down, with the port it was
+    /// listening on, so peers stop relaying to it
+    Disconnect(u16),
+    /// Sent by a node right after connecting to a peer, with the address
+    /// it accepts connections on (its router's external address when
+    /// the port is mapped with UPnP), so the peer can connect back
+    Advertise(String),
+    /// Ask a node that requires authentication for a challenge
+    FetchChallenge,
+    /// Random bytes to prove knowledge of the auth token with
+    Challenge(Vec<u8>),
+    /// HMAC-SHA256 of the challenge, keyed with the auth token
+    Authenticate(Vec<u8>),
+    /// Whether the node accepted the proof
+    Authenticated(bool),
+    /// Answer to `SubmitTransaction` or `SubmitTemplate`: the transaction
+    /// or block with this hash was accepted
+    Accepted(Hash),
+    /// Answer to `SubmitTransaction` or `SubmitTemplate`: the transaction
+    /// or block was refused, and why
+    Reject {
+        item_hash: Hash,
+        code: RejectCode,
+        reason: String,
+    },
+    /// Ask a regtest node to mine this many blocks right away, paying
+    /// their rewards to the public key
+    GenerateBlocks(u32, PublicKey),
+    /// This is the response to GenerateBlocks: hashes of the new blocks
+    GeneratedBlocks(Vec<Hash>),
+    /// Ask a node for up to this many block headers, starting at a height
+    FetchHeaders(u64, u32),
+    /// This is the response to FetchHeaders: the headers, plus the height
+    /// of the chain tip
+    Headers(Vec<BlockHeader>, u64),
+    /// Ask a node for a block by its hash
+    FetchBlockByHash(Hash),
+    /// This is the response to FetchBlockByHash. None if the block is not
+    /// in the node's chain
+    BlockFound(Option<IndexedBlock>),
+    /// Ask a node for the transactions in its mempool
+    GetMempool,
+    /// This is the response to GetMempool, highest fee rate first
+    Mempool(Vec<MempoolEntry>),
+    /// Ask a node to drop a transaction from its mempool, for operators
+    EvictTransaction(Hash),
+    /// This is the response to EvictTransaction: whether the transaction
+    /// was pending
+    Evicted(bool),
+    /// Ask a node how many coins are in circulation
+    GetSupply,
+    /// This is the response to GetSupply
+    Supply(SupplyReport),
+    /// Ask a node how it is doing, for operators
+    GetStatus,
+    /// This is the response to GetStatus
+    Status(StatusReport),
 }
 
-// We are going to use length-prefixed encoding for message
-// And we are going to use ciborium (CBOR) for serialization
+/// A confirmed transaction as reported by FetchTransaction
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct IndexedTransaction {
+    pub transaction: Transaction,
+    /// Height of the block containing the transaction
+    pub block_height: u64,
+    /// Position of the transaction in the block (0 is the coinbase)
+    pub position: usize,
+}
+
+/// Money supply as reported by GetSupply
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SupplyReport {
+    /// Chain height the numbers are for
+    pub height: u64,
+    /// Satoshis in unspent outputs
+    pub circulating: u64,
+    /// Most satoshis the emission schedule allows at this height
+    pub scheduled: u64,
+}
+
+/// Node status as reported by GetStatus
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct StatusReport {
+    pub network_id: String,
+    /// Number of blocks in the chain
+    pub height: u64,
+    /// Hash of the newest block, zero without blocks
+    pub best_block: Hash,
+    /// Connected peer nodes
+    pub peers: usize,
+    /// Transactions waiting in the mempool
+    pub mempool: usize,
+    /// Difficulty of the current target, 1 at the easiest target
+    pub difficulty: f64,
+    /// Seconds since the node started
+    pub uptime: u64,
+}
+
+/// A block as reported by FetchBlockByHash
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct IndexedBlock {
+    pub block: Block,
+    /// Height of the block in the chain
+    pub height: u64,
+}
+
+/// A pending transaction as reported by GetMempool
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MempoolEntry {
+    pub transaction: Transaction,
+    /// When the node received the transaction
+    pub received: DateTime<Utc>,
+    /// Fee paid, if all of its inputs are confirmed outputs
+    pub fee: Option<u64>,
+}
+
+/// A banned peer as reported by ListBanned
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BannedPeer {
+    /// Peer address (IP without port)
+    pub address: String,
+    /// When the ban expires
+    pub banned_until: DateTime<Utc>,
+    /// Misbehavior score at the time of the request
+    pub score: f64,
+}
+
+/// Why a node refused a transaction or block, for wallets and miners
+/// to act on; the `Reject` message carries the full reason as text
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RejectCode {
+    /// Spends an output that doesn't exist or was already spent
+    MissingInputs,
+    /// Spends the same output twice
+    DoubleSpend,
+    /// Pays out more than its inputs are worth
+    InsufficientFunds,
+    /// An input is unsigned or its signature doesn't verify
+    InvalidSignature,
+    /// A block that doesn't build on the current tip, usually because
+    /// another block was found first
+    Stale,
+    /// A block whose hash doesn't meet its target
+    InsufficientWork,
+    /// Breaks some other rule
+    Invalid,
+}
+
+impl RejectCode {
+    /// Classify a validation error
+    pub fn from_error(error: &BtcError) -> Self {
+        match error {
+            BtcError::InvalidSignature => RejectCode::InvalidSignature,
+            BtcError::DuplicateSpend { .. } => RejectCode::DoubleSpend,
+            BtcError::InvalidTransaction { reason } => {
+                if reason == "UTXO not found" || reason.contains("non-existent UTXO") {
+                    RejectCode::MissingInputs
+                } else if reason.starts_with("duplicate input") || reason.contains("double-spend") {
+                    RejectCode::DoubleSpend
+                } else if reason == "outputs exceed inputs" || reason.starts_with("output value") {
+                    RejectCode::InsufficientFunds
+                } else if reason.ends_with("is not signed") {
+                    RejectCode::InvalidSignature
+                } else {
+                    RejectCode::Invalid
+                }
+            }
+            BtcError::InvalidBlock { reason } => match reason.as_str() {
+                "prev block hash mismatch" => RejectCode::Stale,
+                "hash doesn't match target" => RejectCode::InsufficientWork,
+                _ => RejectCode::Invalid,
+            },
+            _ => RejectCode::Invalid,
+        }
+    }
+}
+
+/// A peer address shared in `NodeList`, with what the sending node knows
+/// about it so the receiver can rank whom to connect to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "KnownPeerRepr")]
+pub struct KnownPeer {
+    pub address: String,
+    /// When the sending node last talked to it
+    pub last_seen: Option<DateTime<Utc>>,
+    /// Its chain height as last reported
+    pub height: Option<u64>,
+}
+
+impl KnownPeer {
+    pub fn new(address: String) -> Self {
+        Self {
+            address,
+            last_seen: None,
+            height: None,
+        }
+    }
+}
+
+/// Nodes before version 3 send bare addresses in `NodeList`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KnownPeerRepr {
+    Address(String),
+    Full {
+        address: String,
+        #[serde(default)]
+        last_seen: Option<DateTime<Utc>>,
+        #[serde(default)]
+        height: Option<u64>,
+    },
+}
+
+impl From<KnownPeerRepr> for KnownPeer {
+    fn from(repr: KnownPeerRepr) -> Self {
+        match repr {
+            KnownPeerRepr::Address(address) => KnownPeer::new(address),
+            KnownPeerRepr::Full {
+                address,
+                last_seen,
+                height,
+            } => KnownPeer {
+                address,
+                last_seen,
+                height,
+            },
+        }
+    }
+}
+
+/// Errors sending or receiving a framed message
+#[derive(Debug, Error)]
+pub enum MessageError {
+    /// The connection failed or was closed
+    #[error("connection error: {0}")]
+    Io(#[from] IoError),
+    /// The frame starts with another network's magic bytes, the peer
+    /// belongs to a different network
+    #[error(
+        "message for another network (magic {}, expected {})",
+        hex::encode(.magic),
+        hex::encode(.expected)
+    )]
+    WrongNetwork { magic: [u8; 4], expected: [u8; 4] },
+    /// The length prefix exceeds the configured maximum message size
+    #[error("message of {size} bytes exceeds the limit of {max} bytes")]
+    TooLarge { size: u64, max: u32 },
+    #[error("failed to encode message: {0}")]
+    Encode(#[from] ciborium::ser::Error<IoError>),
+    /// The body isn't a valid CBOR encoded message
+    #[error("failed to decode message: {0}")]
+    Decode(#[from] ciborium::de::Error<IoError>),
+    /// The peer speaks a protocol version we have no decoder for
+    #[error("unsupported protocol version {0}")]
+    UnsupportedVersion(u8),
+    /// A well-formed message this release doesn't understand, e.g. a
+    /// variant added in a newer version. The connection is still usable.
+    #[error("unsupported {kind} message (protocol version {version})")]
+    Unsupported { version: u8, kind: String },
+    /// The node refused our auth token, or answered out of turn
+    #[error("authentication failed: {0}")]
+    Authentication(String),
+}
+
+/// Protocol version written in front of every message body. Version 4
+/// sends blocks and transactions in their canonical encoding, version 5
+/// adds the UTXO commitment to block headers, version 6 the block
+/// height to coinbases and version 7 a version field to block headers
+/// and transactions.
+pub const PROTOCOL_VERSION: u8 = 7;
+/// Oldest protocol version we can still decode
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+
+/// Version 1 bodies had no version byte and start directly with the
+/// CBOR encoded enum: a text string (unit variant) or a map
+fn is_legacy_body(first: u8) -> bool {
+    matches!(first >> 5, 3 | 5)
+}
+
+/// Variant name of an externally tagged CBOR enum, if the body is one
+fn variant_name(body: &[u8]) -> Option<String> {
+    match ciborium::from_reader::<ciborium::Value, _>(body).ok()? {
+        ciborium::Value::Text(name) => Some(name),
+        ciborium::Value::Map(entries) if entries.len() == 1 => {
+            entries[0].0.as_text().map(Into::into)
+        }
+        _ => None,
+    }
+}
+
+/// Reject frames of other networks, before reading anything else
+fn check_magic(magic: [u8; 4]) -> Result<(), MessageError> {
+    let expected = config::network_magic();
+    if magic != expected {
+        return Err(MessageError::WrongNetwork { magic, expected });
+    }
+    Ok(())
+}
+
+/// Reject frames larger than `network.max_message_size`
+fn check_length(size: u64) -> Result<u32, MessageError> {
+    let max = config::max_message_size();
+    if size > max as u64 {
+        return Err(MessageError::TooLarge { size, max });
+    }
+    Ok(size as u32)
+}
+
+// Wire format: every message is a frame made of the network's magic
+// bytes, a u32 big-endian length prefix and the message encoded as CBOR
+// (ciborium), with blocks and transactions as canonically encoded byte
+// strings
 impl Message {
-    pub fn encode(&self) -> Result<Vec<u8>, ciborium::ser::Error<IoError>> {
-        let mut bytes = Vec::new();
+    /// Name of the variant, for logs and metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::FetchUTXOs(..) => "FetchUTXOs",
+            Message::UTXOs(..) => "UTXOs",
+            Message::FetchPendingOutputs(..) => "FetchPendingOutputs",
+            Message::PendingOutputs(..) => "PendingOutputs",
+            Message::SubmitTransaction(..) => "SubmitTransaction",
+            Message::NewTransaction(..) => "NewTransaction",
+            Message::FetchTemplate(..) => "FetchTemplate",
+            Message::Template(..) => "Template",
+            Message::ValidateTemplate(..) => "ValidateTemplate",
+            Message::TemplateValidity(..) => "TemplateValidity",
+            Message::SubmitTemplate(..) => "SubmitTemplate",
+            Message::DiscoverNodes => "DiscoverNodes",
+            Message::NodeList(..) => "NodeList",
+            Message::AskDifference(..) => "AskDifference",
+            Message::Difference(..) => "Difference",
+            Message::FetchBlock(..) => "FetchBlock",
+            Message::NewBlock(..) => "NewBlock",
+            Message::ListBanned => "ListBanned",
+            Message::BannedPeers(..) => "BannedPeers",
+            Message::Unban(..) => "Unban",
+            Message::FetchHistoricalBlocks(..) => "FetchHistoricalBlocks",
+            Message::HistoricalBlocks(..) => "HistoricalBlocks",
+            Message::EstimateFee(..) => "EstimateFee",
+            Message::FeeEstimate(..) => "FeeEstimate",
+            Message::FetchTransaction(..) => "FetchTransaction",
+            Message::TransactionFound(..) => "TransactionFound",
+            Message::FetchAddressHistory(..) => "FetchAddressHistory",
+            Message::AddressHistory(..) => "AddressHistory",
+            Message::Subscribe(..) => "Subscribe",
+            Message::TransactionConflicted(..) => "TransactionConflicted",
+            Message::TransactionExpired(..) => "TransactionExpired",
+            Message::PaymentReceived(..) => "PaymentReceived",
+            Message::Disconnect(..) => "Disconnect",
+            Message::Advertise(..) => "Advertise",
+            Message::FetchChallenge => "FetchChallenge",
+            Message::Challenge(..) => "Challenge",
+            Message::Authenticate(..) => "Authenticate",
+            Message::Authenticated(..) => "Authenticated",
+            Message::Accepted(..) => "Accepted",
+            Message::Reject { .. } => "Reject",
+            Message::GenerateBlocks(..) => "GenerateBlocks",
+            Message::GeneratedBlocks(..) => "GeneratedBlocks",
+            Message::FetchHeaders(..) => "FetchHeaders",
+            Message::Headers(..) => "Headers",
+            Message::FetchBlockByHash(..) => "FetchBlockByHash",
+            Message::BlockFound(..) => "BlockFound",
+            Message::GetMempool => "GetMempool",
+            Message::Mempool(..) => "Mempool",
+            Message::EvictTransaction(..) => "EvictTransaction",
+            Message::Evicted(..) => "Evicted",
+            Message::GetSupply => "GetSupply",
+            Message::Supply(..) => "Supply",
+            Message::GetStatus => "GetStatus",
+            Message::Status(..) => "Status",
+        }
+    }
+    /// Encode as the protocol version byte followed by CBOR
+    pub fn encode(&self) -> Result<Vec<u8>, MessageError> {
+        let mut bytes = vec![PROTOCOL_VERSION];
         ciborium::into_writer(self, &mut bytes)?;
         Ok(bytes)
     }
-    pub fn decode(data: &[u8]) -> Result<Self, ciborium::de::Error<IoError>> {
-        ciborium::from_reader(data)
+    /// Decode a body of any version from `MIN_PROTOCOL_VERSION` on.
+    /// Newer versions are decoded as far as the variants are known.
+    pub fn decode(data: &[u8]) -> Result<Self, MessageError> {
+        let (version, body) = match data.split_first() {
+            Some((&first, _)) if is_legacy_body(first) => (1, data),
+            Some((&version, body)) => (version, body),
+            None => (PROTOCOL_VERSION, data),
+        };
+        if version < MIN_PROTOCOL_VERSION {
+            return Err(MessageError::UnsupportedVersion(version));
+        }
+        ciborium::from_reader(body).map_err(|e| match variant_name(body) {
+            Some(kind) => MessageError::Unsupported { version, kind },
+            None => e.into(),
+        })
     }
-    pub fn send(&self, stream: &mut impl Write) -> Result<(), ciborium::ser::Error<IoError>> {
+    /// Encode a message and put the magic and length prefix in front of it
+    fn frame(&self) -> Result<Vec<u8>, MessageError> {
         let bytes = self.encode()?;
-        let len = bytes.len() as u64;
-        stream.write_all(&len.to_be_bytes())?;
-        stream.write_all(&bytes)?;
+        let len = check_length(bytes.len() as u64)?;
+        let mut frame = Vec::with_capacity(8 + bytes.len());
+        frame.extend_from_slice(&config::network_magic());
+        frame.extend_from_slice(&len.to_be_bytes());
+        frame.extend_from_slice(&bytes);
+        Ok(frame)
+    }
+    pub fn send(&self, stream: &mut impl Write) -> Result<(), MessageError> {
+        stream.write_all(&self.frame()?)?;
         Ok(())
     }
-    pub fn receive(stream: &mut impl Read) -> Result<Self, ciborium::de::Error<IoError>> {
-        let mut len_bytes = [0u8; 8];
+    pub fn receive(stream: &mut impl Read) -> Result<Self, MessageError> {
+        let mut magic = [0u8; 4];
+        stream.read_exact(&mut magic)?;
+        check_magic(magic)?;
+        let mut len_bytes = [0u8; 4];
         stream.read_exact(&mut len_bytes)?;
-        let len = u64::from_be_bytes(len_bytes) as usize;
-        let mut data = vec![0u8; len];
+        let len = check_length(u32::from_be_bytes(len_bytes) as u64)?;
+        let mut data = vec![0u8; len as usize];
         stream.read_exact(&mut data)?;
         Self::decode(&data)
     }
     pub async fn send_async(
         &self,
         stream: &mut (impl AsyncWrite + Unpin),
-    ) -> Result<(), ciborium::ser::Error<IoError>> {
-        let bytes = self.encode()?;
-        let len = bytes.len() as u64;
-        stream.write_all(&len.to_be_bytes()).await?;
-        stream.write_all(&bytes).await?;
+    ) -> Result<(), MessageError> {
+        stream.write_all(&self.frame()?).await?;
         Ok(())
     }
     pub async fn receive_async(
         stream: &mut (impl AsyncRead + Unpin),
-    ) -> Result<Self, ciborium::de::Error<IoError>> {
-        let mut len_bytes = [0u8; 8];
+    ) -> Result<Self, MessageError> {
+        let mut magic = [0u8; 4];
+        stream.read_exact(&mut magic).await?;
+        check_magic(magic)?;
+        let mut len_bytes = [0u8; 4];
         stream.read_exact(&mut len_bytes).await?;
-        let len = u64::from_be_bytes(len_bytes) as usize;
-        let mut data = vec![0u8; len];
+        // check the length before allocating anything for the body
+        let len = check_length(u32::from_be_bytes(len_bytes) as u64)?;
+        let mut data = vec![0u8; len as usize];
         stream.read_exact(&mut data).await?;
         Self::decode(&data)
     }
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,249 @@
+#[cfg(test)]
+mod tests {
+    use crate::config;
+    use crate::crypto::PrivateKey;
+    use crate::encoding::Encode;
+    use crate::error::BtcError;
+    use crate::network::{KnownPeer, Message, MessageError, RejectCode, PROTOCOL_VERSION};
+    use crate::sha256::Hash;
+    use crate::test_helpers::{arb_message, create_test_output};
+    use crate::types::Transaction;
+    use proptest::prelude::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_frame_has_magic_and_u32_length_prefix() {
+        let message = Message::AskDifference(42);
+        let mut buffer = vec![];
+        message.send(&mut buffer).unwrap();
+
+        let body = message.encode().unwrap();
+        assert_eq!(buffer.len(), 8 + body.len());
+        assert_eq!(buffer[..4], config::network_magic());
+        assert_eq!(buffer[4..8], (body.len() as u32).to_be_bytes());
+
+        let received = Message::receive(&mut Cursor::new(buffer)).unwrap();
+        assert!(matches!(received, Message::AskDifference(42)));
+    }
+
+    #[test]
+    fn test_other_networks_are_rejected_before_decoding() {
+        let mut other = config::network_magic();
+        other[0] ^= 1;
+        // a length no body follows: the magic is refused first
+        let mut frame = other.to_vec();
+        frame.extend_from_slice(&u32::MAX.to_be_bytes());
+        match Message::receive(&mut Cursor::new(frame)) {
+            Err(MessageError::WrongNetwork { magic, expected }) => {
+                assert_eq!(magic, other);
+                assert_eq!(expected, config::network_magic());
+            }
+            other => panic!("expected WrongNetwork, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_oversized_frame_is_rejected_before_reading_body() {
+        let max = config::max_message_size();
+        // only the prefix is there, a body this size would never be allocated
+        let mut frame = config::network_magic().to_vec();
+        frame.extend_from_slice(&(max + 1).to_be_bytes());
+        match Message::receive(&mut Cursor::new(frame)) {
+            Err(MessageError::TooLarge { size, max: limit }) => {
+                assert_eq!(size, max as u64 + 1);
+                assert_eq!(limit, max);
+            }
+            other => panic!("expected TooLarge, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_roundtrip_and_truncated_frame() {
+        let mut buffer = vec![];
+        Message::DiscoverNodes
+            .send_async(&mut buffer)
+            .await
+            .unwrap();
+        let received = Message::receive_async(&mut buffer.as_slice())
+            .await
+            .unwrap();
+        assert!(matches!(received, Message::DiscoverNodes));
+
+        // a peer hanging up mid-frame is an I/O error, not a decoding error
+        buffer.pop();
+        let result = Message::receive_async(&mut buffer.as_slice()).await;
+        assert!(matches!(result, Err(MessageError::Io(_))));
+    }
+
+    #[test]
+    fn test_body_starts_with_protocol_version() {
+        let body = Message::DiscoverNodes.encode().unwrap();
+        assert_eq!(body[0], PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn test_decodes_version_1_bodies_without_version_byte() {
+        let mut legacy = vec![];
+        ciborium::into_writer(&Message::AskDifference(7), &mut legacy).unwrap();
+        let message = Message::decode(&legacy).unwrap();
+        assert!(matches!(message, Message::AskDifference(7)));
+
+        let mut legacy = vec![];
+        ciborium::into_writer(&Message::DiscoverNodes, &mut legacy).unwrap();
+        assert!(matches!(
+            Message::decode(&legacy).unwrap(),
+            Message::DiscoverNodes
+        ));
+    }
+
+    #[test]
+    fn test_unknown_variant_is_unsupported_not_malformed() {
+        #[derive(serde::Serialize)]
+        enum Future {
+            FetchMempool(u32),
+        }
+        let mut body = vec![PROTOCOL_VERSION + 1];
+        ciborium::into_writer(&Future::FetchMempool(3), &mut body).unwrap();
+        match Message::decode(&body) {
+            Err(MessageError::Unsupported { version, kind }) => {
+                assert_eq!(version, PROTOCOL_VERSION + 1);
+                assert_eq!(kind, "FetchMempool");
+            }
+            other => panic!("expected Unsupported, got {:?}", other),
+        }
+
+        assert!(matches!(
+            Message::decode(&[0, 0xf6]),
+            Err(MessageError::UnsupportedVersion(0))
+        ));
+        assert!(matches!(
+            Message::decode(&[PROTOCOL_VERSION, 0xff]),
+            Err(MessageError::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn test_node_list_accepts_bare_addresses_from_version_2() {
+        #[derive(serde::Serialize)]
+        enum Version2 {
+            NodeList(Vec<String>),
+        }
+        let mut body = vec![2];
+        ciborium::into_writer(&Version2::NodeList(vec!["10.0.0.1:9000".into()]), &mut body)
+            .unwrap();
+        match Message::decode(&body).unwrap() {
+            Message::NodeList(peers) => {
+                assert_eq!(peers, vec![KnownPeer::new("10.0.0.1:9000".into())])
+            }
+            other => panic!("expected NodeList, got {:?}", other),
+        }
+
+        let peer = KnownPeer {
+            address: "10.0.0.2:9000".into(),
+            last_seen: Some(chrono::Utc::now()),
+            height: Some(42),
+        };
+        let body = Message::NodeList(vec![peer.clone()]).encode().unwrap();
+        match Message::decode(&body).unwrap() {
+            Message::NodeList(peers) => assert_eq!(peers, vec![peer]),
+            other => panic!("expected NodeList, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_reject_code_from_validation_errors() {
+        let tx = |reason: &str| BtcError::InvalidTransaction {
+            reason: reason.into(),
+        };
+        let block = |reason: &str| BtcError::InvalidBlock {
+            reason: reason.into(),
+        };
+        assert_eq!(
+            RejectCode::from_error(&tx("UTXO not found")),
+            RejectCode::MissingInputs
+        );
+        assert_eq!(
+            RejectCode::from_error(&tx("duplicate input")),
+            RejectCode::DoubleSpend
+        );
+        assert_eq!(
+            RejectCode::from_error(&BtcError::DuplicateSpend {
+                output: Hash::zero()
+            }),
+            RejectCode::DoubleSpend
+        );
+        assert_eq!(
+            RejectCode::from_error(&tx("outputs exceed inputs")),
+            RejectCode::InsufficientFunds
+        );
+        assert_eq!(
+            RejectCode::from_error(&BtcError::InvalidSignature),
+            RejectCode::InvalidSignature
+        );
+        assert_eq!(
+            RejectCode::from_error(&block("prev block hash mismatch")),
+            RejectCode::Stale
+        );
+        assert_eq!(
+            RejectCode::from_error(&block("hash doesn't match target")),
+            RejectCode::InsufficientWork
+        );
+        assert_eq!(
+            RejectCode::from_error(&tx("something else")),
+            RejectCode::Invalid
+        );
+    }
+
+    #[test]
+    fn test_transactions_travel_canonically_encoded() {
+        let private_key = PrivateKey::new_key();
+        let tx = Transaction::new(vec![], vec![create_test_output(7, &private_key)]);
+        let body = Message::NewTransaction(tx.clone()).encode().unwrap();
+        let value: ciborium::Value = ciborium::from_reader(&body[1..]).unwrap();
+        let payload = value.as_map().unwrap()[0].1.as_bytes().unwrap().clone();
+        assert_eq!(payload, tx.encode());
+        match Message::decode(&body).unwrap() {
+            Message::NewTransaction(decoded) => assert_eq!(decoded.hash(), tx.hash()),
+            other => panic!("expected NewTransaction, got {:?}", other),
+        }
+
+        // version 3 peers send the transaction as a CBOR map
+        #[derive(serde::Serialize)]
+        enum Version3 {
+            NewTransaction(Transaction),
+        }
+        let mut body = vec![3];
+        ciborium::into_writer(&Version3::NewTransaction(tx.clone()), &mut body).unwrap();
+        match Message::decode(&body).unwrap() {
+            Message::NewTransaction(decoded) => assert_eq!(decoded.hash(), tx.hash()),
+            other => panic!("expected NewTransaction, got {:?}", other),
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn prop_message_roundtrip(message in arb_message()) {
+            let mut frame = vec![];
+            message.send(&mut frame).unwrap();
+            let received = Message::receive(&mut Cursor::new(frame)).unwrap();
+            prop_assert_eq!(received.kind(), message.kind());
+            prop_assert_eq!(received.encode().unwrap(), message.encode().unwrap());
+        }
+    }
+
+    proptest! {
+        /// Whatever a peer sends, receiving it fails cleanly at worst
+        #[test]
+        fn prop_receiving_arbitrary_frames_never_panics(body in any::<Vec<u8>>()) {
+            let _ = Message::decode(&body);
+            let mut frame = config::network_magic().to_vec();
+            frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+            frame.extend_from_slice(&body);
+            let _ = Message::receive(&mut Cursor::new(frame));
+            // a length prefix promising more than is there
+            let _ = Message::receive(&mut Cursor::new(body));
+        }
+    }
+}
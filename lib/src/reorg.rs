@@ -0,0 +1,125 @@
+//! Reorganizations deeper than the blocks a node keeps
+//!
+//! Switching to a longer competing chain means rebuilding the UTXO set
+//! at the fork and replaying the new branch on top of it. A node that
+//! pruned old blocks can only do that from blocks it still has, or from
+//! a UTXO snapshot it can replay forward. A fork below both needs the
+//! missing blocks from an archival peer (`FetchHistoricalBlocks`), and
+//! until they arrive the node keeps its current chain rather than
+//! applying half of the new one.
+use crate::error::{BtcError, Result};
+use crate::types::{Block, Blockchain};
+use crate::util::MerkleRoot;
+use std::ops::Range;
+
+/// Most blocks sent in one `HistoricalBlocks` answer
+pub const MAX_HISTORICAL_BLOCKS: u32 = 100;
+
+/// How far back a node can rebuild its chainstate on its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Horizon {
+    /// Height of the oldest block whose transactions are still kept
+    pub oldest_block: u64,
+    /// Height of the UTXO snapshot the node started from, if any
+    pub snapshot_base: Option<u64>,
+}
+
+impl Horizon {
+    /// A node keeping every block since genesis
+    pub fn archival() -> Self {
+        Horizon {
+            oldest_block: 0,
+            snapshot_base: None,
+        }
+    }
+
+    /// Lowest fork height that can be handled locally, `None` if the
+    /// node can't rebuild any past state without help
+    pub fn lowest_fork(&self) -> Option<u64> {
+        if self.oldest_block == 0 {
+            return Some(0);
+        }
+        // a snapshot is only useful if the blocks after it are still kept
+        self.snapshot_base.filter(|base| *base >= self.oldest_block)
+    }
+}
+
+/// What switching to a branch forking at some height takes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReorgPlan {
+    /// The kept blocks are enough
+    Local,
+    /// These pruned blocks have to be fetched from an archival peer first
+    FetchHistory(Range<u64>),
+}
+
+/// Whether a fork at `fork_height` (the number of blocks both chains
+/// share) can be handled with what `horizon` keeps
+pub fn plan(horizon: Horizon, fork_height: u64) -> ReorgPlan {
+    match horizon.lowest_fork() {
+        Some(lowest) if fork_height >= lowest => ReorgPlan::Local,
+        _ => ReorgPlan::FetchHistory(0..horizon.oldest_block.min(fork_height)),
+    }
+}
+
+/// Build the chain made of the first `fork_height` blocks of `chain`
+/// followed by `branch`.
+///
+/// Blocks below the horizon are taken from `history`, which must hold
+/// exactly the range [`plan`] asked for and match the headers the node
+/// kept. The result is built from scratch, so on any error `chain` is
+/// left as it was; a missing or wrong `history` is reported as
+/// [`BtcError::ReorgBelowHorizon`], which fetching it again recovers
+/// from. The mempool is left to the caller, which puts its transactions
+/// back with [`Mempool::restore`](crate::types::Mempool::restore)
+/// against the result.
+pub fn reorganize(
+    chain: &Blockchain,
+    horizon: Horizon,
+    fork_height: u64,
+    history: &[Block],
+    branch: Vec<Block>,
+) -> Result<Blockchain> {
+    if fork_height > chain.block_height() {
+        return Err(BtcError::invalid_block("fork above our tip"));
+    }
+    if fork_height + branch.len() as u64 <= chain.block_height() {
+        return Err(BtcError::invalid_block(
+            "branch is not longer than our chain",
+        ));
+    }
+    let missing = match plan(horizon, fork_height) {
+        ReorgPlan::Local => 0..0,
+        ReorgPlan::FetchHistory(missing) => missing,
+    };
+    let below_horizon = || BtcError::ReorgBelowHorizon {
+        fork_height,
+        oldest_block: horizon.oldest_block,
+    };
+    if history.len() as u64 != missing.end - missing.start {
+        return Err(below_horizon());
+    }
+    for (kept, fetched) in chain.blocks().zip(history) {
+        // pruned blocks keep their header, which commits to the transactions
+        if fetched.header.hash() != kept.header.hash()
+            || MerkleRoot::calculate(&fetched.transactions) != fetched.header.merkle_root
+        {
+            return Err(below_horizon());
+        }
+    }
+
+    let common = chain
+        .blocks()
+        .take(fork_height as usize)
+        .skip(history.len())
+        .cloned();
+    let mut reorganized = Blockchain::new();
+    for block in history.iter().cloned().chain(common).chain(branch) {
+        reorganized.add_block(block)?;
+        reorganized.rebuild_utxos();
+    }
+    Ok(reorganized)
+}
+
+#[cfg(test)]
+mod tests;
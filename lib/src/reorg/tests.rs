@@ -0,0 +1,164 @@
+#[cfg(test)]
+mod tests {
+    use crate::config;
+    use crate::crypto::PrivateKey;
+    use crate::error::BtcError;
+    use crate::reorg::{plan, reorganize, Horizon, ReorgPlan};
+    use crate::sha256::Hash;
+    use crate::test_helpers::{create_test_input, create_test_output};
+    use crate::types::{Block, BlockHeader, Blockchain, Mempool, Transaction};
+    use crate::util::MerkleRoot;
+    use chrono::Utc;
+
+    fn coinbase_block(prev_block_hash: Hash, height: i64, private_key: &PrivateKey) -> Block {
+        let output = create_test_output(config::initial_reward() * 100_000_000, private_key);
+        let transaction = Transaction::coinbase(height as u64, vec![output]);
+        let mut block = Block::new(
+            BlockHeader::new(
+                Utc::now() + chrono::Duration::seconds(height),
+                0,
+                prev_block_hash,
+                MerkleRoot::calculate(std::slice::from_ref(&transaction)),
+                config::min_target(),
+            ),
+            vec![transaction],
+        );
+        while !block.header.hash().matches_target(block.header.target) {
+            block.header.nonce += 1;
+        }
+        block
+    }
+
+    /// `count` blocks following `prev_block_hash` at `height`
+    fn blocks(
+        prev_block_hash: Hash,
+        height: i64,
+        count: i64,
+        private_key: &PrivateKey,
+    ) -> Vec<Block> {
+        let mut prev_block_hash = prev_block_hash;
+        (height..height + count)
+            .map(|height| {
+                let block = coinbase_block(prev_block_hash, height, private_key);
+                prev_block_hash = block.hash();
+                block
+            })
+            .collect()
+    }
+
+    fn chain_of(blocks: Vec<Block>) -> Blockchain {
+        let mut blockchain = Blockchain::new();
+        for block in blocks {
+            blockchain.add_block(block).unwrap();
+            blockchain.rebuild_utxos();
+        }
+        blockchain
+    }
+
+    fn tip(blockchain: &Blockchain) -> Hash {
+        blockchain.blocks().last().unwrap().hash()
+    }
+
+    /// A chain of 4 blocks paying `private_key` and a longer branch
+    /// forking after its first 3
+    fn fork(private_key: &PrivateKey) -> (Blockchain, Vec<Block>) {
+        let blockchain = chain_of(blocks(Hash::zero(), 0, 4, private_key));
+        let common = blockchain.blocks().nth(2).unwrap().hash();
+        (blockchain, blocks(common, 3, 2, &PrivateKey::new_key()))
+    }
+
+    #[test]
+    fn test_plan_detects_forks_below_the_horizon() {
+        assert_eq!(plan(Horizon::archival(), 0), ReorgPlan::Local);
+        let pruned = Horizon {
+            oldest_block: 10,
+            snapshot_base: None,
+        };
+        assert_eq!(plan(pruned, 50), ReorgPlan::FetchHistory(0..10));
+        assert_eq!(plan(pruned, 4), ReorgPlan::FetchHistory(0..4));
+
+        let snapshot = Horizon {
+            oldest_block: 10,
+            snapshot_base: Some(20),
+        };
+        assert_eq!(plan(snapshot, 20), ReorgPlan::Local);
+        assert_eq!(plan(snapshot, 15), ReorgPlan::FetchHistory(0..10));
+        // the blocks after a snapshot older than the pruning were dropped
+        let stale_snapshot = Horizon {
+            oldest_block: 10,
+            snapshot_base: Some(5),
+        };
+        assert_eq!(plan(stale_snapshot, 30), ReorgPlan::FetchHistory(0..10));
+    }
+
+    #[test]
+    fn test_reorg_replays_fetched_history() {
+        let private_key = PrivateKey::new_key();
+        let (blockchain, branch) = fork(&private_key);
+        let branch_tip = branch.last().unwrap().hash();
+        let genesis_output = blockchain.blocks().next().unwrap().transactions[0].outputs[0].clone();
+        let pending = Transaction::new(
+            vec![create_test_input(&genesis_output.hash(), &private_key)],
+            vec![create_test_output(
+                genesis_output.value - 1000,
+                &private_key,
+            )],
+        );
+        let mut mempool = Mempool::default();
+        mempool.add(&blockchain, pending.clone()).unwrap();
+        let horizon = Horizon {
+            oldest_block: 2,
+            snapshot_base: None,
+        };
+        assert_eq!(plan(horizon, 3), ReorgPlan::FetchHistory(0..2));
+
+        // what an archival peer answers to FetchHistoricalBlocks(0, 2)
+        let history: Vec<_> = blockchain.blocks().take(2).cloned().collect();
+        let reorganized = reorganize(&blockchain, horizon, 3, &history, branch).unwrap();
+
+        assert_eq!(reorganized.block_height(), 5);
+        assert_eq!(tip(&reorganized), branch_tip);
+        assert_eq!(reorganized.utxos().len(), 5);
+        // the node puts its pending transactions back on the new chain
+        let mut restored = Mempool::default();
+        assert_eq!(restored.restore(&reorganized, mempool.saved()), 1);
+        assert_eq!(restored.transactions()[0].1.hash(), pending.hash());
+    }
+
+    #[test]
+    fn test_reorg_without_history_leaves_the_chain_as_it_was() {
+        let (blockchain, branch) = fork(&PrivateKey::new_key());
+        let old_tip = tip(&blockchain);
+        let horizon = Horizon {
+            oldest_block: 2,
+            snapshot_base: None,
+        };
+
+        let error = reorganize(&blockchain, horizon, 3, &[], branch.clone()).unwrap_err();
+        assert!(matches!(
+            error,
+            BtcError::ReorgBelowHorizon {
+                fork_height: 3,
+                oldest_block: 2,
+            }
+        ));
+
+        // history that doesn't match the headers we kept is refused too
+        let forged = blocks(Hash::zero(), 0, 2, &PrivateKey::new_key());
+        let error = reorganize(&blockchain, horizon, 3, &forged, branch).unwrap_err();
+        assert!(matches!(error, BtcError::ReorgBelowHorizon { .. }));
+
+        assert_eq!(blockchain.block_height(), 4);
+        assert_eq!(tip(&blockchain), old_tip);
+    }
+
+    #[test]
+    fn test_reorg_requires_a_longer_branch() {
+        let (blockchain, mut branch) = fork(&PrivateKey::new_key());
+        branch.pop();
+        assert!(matches!(
+            reorganize(&blockchain, Horizon::archival(), 3, &[], branch),
+            Err(BtcError::InvalidBlock { .. })
+        ));
+    }
+}
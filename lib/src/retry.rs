@@ -0,0 +1,161 @@
+//! Retry, backoff and timeout helpers shared by all network clients
+//!
+//! Node outbound connections, the wallet and the miner all talk to peers
+//! that may be slow or temporarily unreachable. Instead of `unwrap()`ing
+//! or waiting forever, they wrap network operations in a [`Retrier`]:
+//!
+//! - every attempt is bounded by a timeout
+//! - failed attempts are retried with exponential backoff plus jitter
+//! - after too many consecutive failures the circuit "opens" and calls
+//!   fail fast until a cooldown has passed
+use crate::config::RetryConfig;
+use rand::Rng;
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tracing::{debug, warn};
+
+#[derive(Error, Debug)]
+pub enum RetryError<E> {
+    #[error("operation timed out")]
+    Timeout,
+    #[error("circuit breaker open, not attempting")]
+    CircuitOpen,
+    #[error("{0}")]
+    Failed(E),
+}
+
+/// Run a future with the configured per-attempt timeout
+pub async fn with_timeout<T, E, Fut>(config: &RetryConfig, fut: Fut) -> Result<T, RetryError<E>>
+where
+    Fut: Future<Output = Result<T, E>>,
+{
+    match tokio::time::timeout(Duration::from_millis(config.timeout_ms), fut).await {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(e)) => Err(RetryError::Failed(e)),
+        Err(_) => Err(RetryError::Timeout),
+    }
+}
+
+/// Backoff before the given (1-based) retry, with jitter applied
+pub fn backoff(config: &RetryConfig, attempt: u32) -> Duration {
+    let exponential = config
+        .initial_backoff_ms
+        .saturating_mul(1u64 << attempt.saturating_sub(1).min(16));
+    let capped = exponential.min(config.max_backoff_ms) as f64;
+    let jitter = config.jitter.clamp(0.0, 1.0);
+    let factor = if jitter > 0.0 {
+        rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter)
+    } else {
+        1.0
+    };
+    Duration::from_millis((capped * factor) as u64)
+}
+
+/// Counts consecutive failures and fails fast while "open"
+#[derive(Debug, Default)]
+pub struct CircuitBreaker {
+    consecutive_failures: AtomicU32,
+    open_until: Mutex<Option<Instant>>,
+}
+
+impl CircuitBreaker {
+    /// Whether calls are currently allowed
+    pub fn allows(&self) -> bool {
+        let mut open_until = self
+            .open_until
+            .lock()
+            .expect("circuit breaker lock poisoned");
+        match *open_until {
+            Some(until) if Instant::now() < until => false,
+            Some(_) => {
+                // cooldown over: half-open, let the next call through
+                *open_until = None;
+                true
+            }
+            None => true,
+        }
+    }
+
+    pub fn record_success(&self) {
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+    }
+
+    pub fn record_failure(&self, config: &RetryConfig) {
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if config.circuit_breaker_threshold > 0 && failures >= config.circuit_breaker_threshold {
+            warn!("circuit breaker opened after {} failures", failures);
+            *self
+                .open_until
+                .lock()
+                .expect("circuit breaker lock poisoned") =
+                Some(Instant::now() + Duration::from_secs(config.circuit_breaker_cooldown_secs));
+            self.consecutive_failures.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Retry policy plus circuit breaker state for one remote endpoint
+#[derive(Debug)]
+pub struct Retrier {
+    config: RetryConfig,
+    breaker: CircuitBreaker,
+}
+
+impl Retrier {
+    pub fn new(config: RetryConfig) -> Self {
+        Self {
+            config,
+            breaker: CircuitBreaker::default(),
+        }
+    }
+
+    pub fn config(&self) -> &RetryConfig {
+        &self.config
+    }
+
+    /// Run `op` until it succeeds, retrying failures and timeouts with
+    /// backoff up to `max_attempts` times. Returns the last error.
+    pub async fn run<T, E, F, Fut>(&self, what: &str, mut op: F) -> Result<T, RetryError<E>>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: std::fmt::Display,
+    {
+        if !self.breaker.allows() {
+            return Err(RetryError::CircuitOpen);
+        }
+        let attempts = self.config.max_attempts.max(1);
+        let mut attempt = 1;
+        loop {
+            match with_timeout(&self.config, op()).await {
+                Ok(value) => {
+                    self.breaker.record_success();
+                    return Ok(value);
+                }
+                Err(e) => {
+                    self.breaker.record_failure(&self.config);
+                    if attempt >= attempts {
+                        warn!("{} failed after {} attempts: {}", what, attempt, e);
+                        return Err(e);
+                    }
+                    let delay = backoff(&self.config, attempt);
+                    debug!(
+                        "{} failed (attempt {}/{}): {}, retrying in {:?}",
+                        what, attempt, attempts, e, delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    if !self.breaker.allows() {
+                        return Err(RetryError::CircuitOpen);
+                    }
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
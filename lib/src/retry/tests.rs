@@ -0,0 +1,97 @@
+#[cfg(test)]
+mod tests {
+    use crate::config::RetryConfig;
+    use crate::retry::{backoff, Retrier, RetryError};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::Duration;
+
+    fn fast_config() -> RetryConfig {
+        RetryConfig {
+            max_attempts: 3,
+            initial_backoff_ms: 1,
+            max_backoff_ms: 4,
+            jitter: 0.0,
+            timeout_ms: 50,
+            circuit_breaker_threshold: 0,
+            circuit_breaker_cooldown_secs: 60,
+        }
+    }
+
+    #[test]
+    fn test_backoff_doubles_and_caps() {
+        let config = fast_config();
+        assert_eq!(backoff(&config, 1), Duration::from_millis(1));
+        assert_eq!(backoff(&config, 2), Duration::from_millis(2));
+        assert_eq!(backoff(&config, 3), Duration::from_millis(4));
+        assert_eq!(backoff(&config, 10), Duration::from_millis(4));
+    }
+
+    #[tokio::test]
+    async fn test_retries_until_success() {
+        let retrier = Retrier::new(fast_config());
+        let calls = AtomicU32::new(0);
+        let result: Result<u32, RetryError<String>> = retrier
+            .run("flaky", || async {
+                let call = calls.fetch_add(1, Ordering::Relaxed) + 1;
+                if call < 3 {
+                    Err(format!("failure {}", call))
+                } else {
+                    Ok(call)
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_attempts() {
+        let retrier = Retrier::new(fast_config());
+        let calls = AtomicU32::new(0);
+        let result: Result<(), RetryError<String>> = retrier
+            .run("broken", || async {
+                calls.fetch_add(1, Ordering::Relaxed);
+                Err("nope".to_string())
+            })
+            .await;
+        assert!(matches!(result, Err(RetryError::Failed(_))));
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_slow_attempt_times_out() {
+        let config = RetryConfig {
+            max_attempts: 1,
+            ..fast_config()
+        };
+        let retrier = Retrier::new(config);
+        let result: Result<(), RetryError<String>> = retrier
+            .run("slow", || async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                Ok(())
+            })
+            .await;
+        assert!(matches!(result, Err(RetryError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_fails_fast() {
+        let config = RetryConfig {
+            max_attempts: 2,
+            circuit_breaker_threshold: 2,
+            ..fast_config()
+        };
+        let retrier = Retrier::new(config);
+        let _: Result<(), RetryError<String>> = retrier
+            .run("broken", || async { Err("nope".to_string()) })
+            .await;
+        let calls = AtomicU32::new(0);
+        let result: Result<(), RetryError<String>> = retrier
+            .run("broken", || async {
+                calls.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            })
+            .await;
+        assert!(matches!(result, Err(RetryError::CircuitOpen)));
+        assert_eq!(calls.load(Ordering::Relaxed), 0);
+    }
+}
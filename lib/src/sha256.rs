@@ -1,8 +1,12 @@
+use crate::config::{self, HashScheme};
+use crate::encoding::{read_array, Decode, DecodeError, Encode};
 use crate::U256;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::fmt;
+use std::str::FromStr;
 
-#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Hash(U256);
 
 impl Hash {
@@ -24,6 +28,30 @@ impl Hash {
         Hash(U256::from_big_endian(&hash_array))
     }
 
+    // hash bytes with a hashing scheme
+    pub fn digest(scheme: HashScheme, bytes: &[u8]) -> Self {
+        let hash: [u8; 32] = match scheme {
+            HashScheme::Single => Sha256::digest(bytes).into(),
+            HashScheme::Double => Sha256::digest(Sha256::digest(bytes)).into(),
+            #[cfg(feature = "scrypt")]
+            HashScheme::Scrypt => {
+                // Litecoin's parameters, with the data as its own salt
+                let params = scrypt::Params::new(10, 1, 1, 32).expect("valid scrypt parameters");
+                let mut hash = [0u8; 32];
+                scrypt::scrypt(bytes, bytes, &params, &mut hash)
+                    .expect("32 bytes is a valid scrypt output length");
+                hash
+            }
+        };
+        Hash(U256::from_big_endian(&hash))
+    }
+
+    // hash the canonical encoding of a consensus object with the
+    // network's hashing scheme
+    pub fn consensus<T: Encode + ?Sized>(data: &T) -> Self {
+        Self::digest(config::hash_scheme(), &data.encode())
+    }
+
     // check if a hash matches a target
     pub fn matches_target(&self, target: U256) -> bool {
         self.0 <= target
@@ -40,8 +68,34 @@ impl Hash {
     }
 }
 
+impl Encode for Hash {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.as_bytes());
+    }
+}
+
+impl Decode for Hash {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(Hash(U256::from_little_endian(&read_array::<32>(input)?)))
+    }
+}
+
 impl fmt::Display for Hash {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:x}", self.0)
     }
 }
+
+// parse the hex form printed by Display
+impl FromStr for Hash {
+    type Err = DecodeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s.len() > 64 {
+            return Err(DecodeError::Invalid("hash"));
+        }
+        U256::from_str_radix(s, 16)
+            .map(Hash)
+            .map_err(|_| DecodeError::Invalid("hash"))
+    }
+}
@@ -0,0 +1,406 @@
+//! In-process network simulation for testing distributed behavior
+//!
+//! A [`Simulation`] runs several nodes in one process, each with its own
+//! [`Blockchain`], connected in a full mesh by in-memory links instead of
+//! TCP. Messages are encoded to bytes and decoded again on delivery, like
+//! on a real connection, but travel on a virtual clock: every link has a
+//! latency, and nothing happens until the scenario advances the clock with
+//! [`Simulation::run_for`] or [`Simulation::run_until_idle`]. Runs are
+//! therefore fast and deterministic, apart from random output ids.
+//!
+//! Nodes react to messages the way the node binary does:
+//!
+//! - a block submitted by a miner ([`Simulation::mine`]) or a transaction
+//!   submitted by a wallet ([`Simulation::submit_transaction`]) is
+//!   validated and, if accepted, relayed to every peer
+//! - `NewBlock` and `NewTransaction` from a peer are validated but not
+//!   relayed further
+//! - a block that doesn't extend the tip is rejected, there are no reorgs
+//!
+//! Latency can be set per link, and a partition drops every message
+//! between its groups, including those already in flight, until healed.
+use crate::config::BlockchainConfig;
+use crate::crypto::{PrivateKey, PublicKey};
+use crate::error::Result;
+use crate::network::Message;
+use crate::sha256::Hash;
+use crate::types::{
+    Block, BlockHeader, Blockchain, Mempool, Transaction, TransactionOutput, BLOCK_VERSION,
+};
+use crate::util::MerkleRoot;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use tracing::debug;
+use uuid::Uuid;
+
+/// Index of a node in the simulation
+pub type NodeId = usize;
+
+/// Link latency in milliseconds when none was set
+pub const DEFAULT_LATENCY_MS: u64 = 50;
+
+/// Nonces tried between checks while mining
+const MINING_BATCH: usize = 10_000;
+
+/// A message refused by a node
+#[derive(Debug, Clone)]
+pub struct Rejection {
+    /// Node that sent it, `None` if submitted by a miner or wallet
+    pub from: Option<NodeId>,
+    pub item: Hash,
+    pub reason: String,
+}
+
+/// One simulated node
+#[derive(Debug)]
+pub struct SimNode {
+    pub blockchain: Blockchain,
+    pub mempool: Mempool,
+    pub rejected: Vec<Rejection>,
+}
+
+impl SimNode {
+    /// Hash of the newest block
+    pub fn tip(&self) -> Hash {
+        self.blockchain
+            .blocks()
+            .last()
+            .map(|block| block.hash())
+            .unwrap_or(Hash::zero())
+    }
+}
+
+/// A message on its way between two nodes
+struct InFlight {
+    from: NodeId,
+    to: NodeId,
+    bytes: Vec<u8>,
+}
+
+pub struct Simulation {
+    nodes: Vec<SimNode>,
+    /// Milliseconds since the simulation started
+    clock: u64,
+    start: DateTime<Utc>,
+    /// Messages by delivery time, then by send order
+    in_flight: BTreeMap<(u64, u64), InFlight>,
+    sent: u64,
+    latency: HashMap<(NodeId, NodeId), u64>,
+    /// Group of every node while partitioned
+    partition: Option<Vec<usize>>,
+    /// Owner of the genesis coinbase
+    genesis_key: PrivateKey,
+}
+
+impl Simulation {
+    /// `count` nodes sharing a genesis block whose coinbase pays
+    /// [`Simulation::genesis_key`]
+    pub fn new(count: usize) -> Self {
+        Self::with_config(count, BlockchainConfig::global())
+    }
+
+    /// Like [`Simulation::new`], with every node following `config`
+    /// instead of the global configuration
+    pub fn with_config(count: usize, config: Arc<BlockchainConfig>) -> Self {
+        let genesis_key = PrivateKey::new_key();
+        let start = Utc::now();
+        let genesis = build_block(
+            &Blockchain::with_config(config.clone()),
+            genesis_key.public_key(),
+            start,
+            vec![],
+        )
+        .expect("A genesis block has no fees to get wrong");
+        let nodes = (0..count)
+            .map(|_| {
+                let mut blockchain = Blockchain::with_config(config.clone());
+                blockchain
+                    .add_block(genesis.clone())
+                    .expect("Genesis block is valid");
+                blockchain.rebuild_utxos();
+                SimNode {
+                    blockchain,
+                    mempool: Mempool::default(),
+                    rejected: vec![],
+                }
+            })
+            .collect();
+        Simulation {
+            nodes,
+            clock: 0,
+            start,
+            in_flight: BTreeMap::new(),
+            sent: 0,
+            latency: HashMap::new(),
+            partition: None,
+            genesis_key,
+        }
+    }
+
+    pub fn genesis_key(&self) -> &PrivateKey {
+        &self.genesis_key
+    }
+
+    pub fn node(&self, id: NodeId) -> &SimNode {
+        &self.nodes[id]
+    }
+
+    pub fn nodes(&self) -> &[SimNode] {
+        &self.nodes
+    }
+
+    /// Milliseconds of simulated time passed
+    pub fn clock(&self) -> u64 {
+        self.clock
+    }
+
+    /// Messages sent but not delivered or dropped yet
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Whether every node has the same tip
+    pub fn converged(&self) -> bool {
+        self.nodes
+            .windows(2)
+            .all(|pair| pair[0].tip() == pair[1].tip())
+    }
+
+    /// Set the latency between two nodes, in both directions
+    pub fn set_latency(&mut self, a: NodeId, b: NodeId, milliseconds: u64) {
+        self.latency.insert((a, b), milliseconds);
+        self.latency.insert((b, a), milliseconds);
+    }
+
+    /// Split the network: nodes only reach nodes of their own group.
+    /// Nodes in no group form one more group together.
+    pub fn partition(&mut self, groups: &[&[NodeId]]) {
+        let mut group_of = vec![groups.len(); self.nodes.len()];
+        for (group, members) in groups.iter().enumerate() {
+            for &node in members.iter() {
+                group_of[node] = group;
+            }
+        }
+        self.partition = Some(group_of);
+    }
+
+    /// Reconnect all nodes. Messages dropped by the partition stay lost.
+    pub fn heal(&mut self) {
+        self.partition = None;
+    }
+
+    /// Mine a block on top of `node`'s tip with its mempool, paying
+    /// `pubkey`, and submit it to the node as a miner would
+    pub fn mine(&mut self, node: NodeId, pubkey: PublicKey) -> Result<Block> {
+        let blockchain = &self.nodes[node].blockchain;
+        let transactions = self.nodes[node]
+            .mempool
+            .transactions()
+            .iter()
+            .take(blockchain.params().block_transaction_cap)
+            .map(|(_, tx)| tx.clone())
+            .collect();
+        // block timestamps must increase, even if no time has passed
+        let tip_time = blockchain
+            .blocks()
+            .last()
+            .map(|block| block.header.timestamp + Duration::milliseconds(1));
+        let timestamp = tip_time.map_or(self.now(), |tip_time| tip_time.max(self.now()));
+        let block = build_block(blockchain, pubkey, timestamp, transactions)?;
+        self.submit_block(node, block.clone())?;
+        Ok(block)
+    }
+
+    /// Submit a block to `node` as a miner, relaying it if accepted
+    pub fn submit_block(&mut self, node: NodeId, block: Block) -> Result<()> {
+        let hash = block.hash();
+        let result = self.accept_block(node, None, block.clone());
+        if result.is_ok() {
+            self.broadcast(node, Message::NewBlock(block));
+        }
+        debug!(node, block = %hash, ok = result.is_ok(), "block submitted");
+        result
+    }
+
+    /// Submit a transaction to `node` as a wallet, relaying it if accepted
+    pub fn submit_transaction(&mut self, node: NodeId, transaction: Transaction) -> Result<()> {
+        let result = self.accept_transaction(node, None, transaction.clone());
+        if result.is_ok() {
+            self.broadcast(node, Message::NewTransaction(transaction));
+        }
+        result
+    }
+
+    /// Advance the clock by `milliseconds`, delivering every message due
+    pub fn run_for(&mut self, milliseconds: u64) {
+        let until = self.clock + milliseconds;
+        while let Some(entry) = self.in_flight.first_entry() {
+            let (deliver_at, _) = *entry.key();
+            if deliver_at > until {
+                break;
+            }
+            let message = entry.remove();
+            self.clock = deliver_at;
+            self.deliver(message);
+        }
+        self.clock = until;
+    }
+
+    /// Deliver messages until none are in flight
+    pub fn run_until_idle(&mut self) {
+        while let Some((&(deliver_at, _), _)) = self.in_flight.first_key_value() {
+            self.run_for(deliver_at - self.clock);
+        }
+    }
+
+    fn now(&self) -> DateTime<Utc> {
+        self.start + Duration::milliseconds(self.clock as i64)
+    }
+
+    fn reachable(&self, a: NodeId, b: NodeId) -> bool {
+        self.partition
+            .as_ref()
+            .is_none_or(|group_of| group_of[a] == group_of[b])
+    }
+
+    /// Send `message` from `from` to every other node
+    fn broadcast(&mut self, from: NodeId, message: Message) {
+        let bytes = message.encode().expect("Simulated messages fit in a frame");
+        for to in (0..self.nodes.len()).filter(|&to| to != from) {
+            if !self.reachable(from, to) {
+                continue;
+            }
+            let latency = self
+                .latency
+                .get(&(from, to))
+                .copied()
+                .unwrap_or(DEFAULT_LATENCY_MS);
+            self.sent += 1;
+            self.in_flight.insert(
+                (self.clock + latency, self.sent),
+                InFlight {
+                    from,
+                    to,
+                    bytes: bytes.clone(),
+                },
+            );
+        }
+    }
+
+    fn deliver(&mut self, message: InFlight) {
+        let InFlight { from, to, bytes } = message;
+        // the link broke while the message was on its way
+        if !self.reachable(from, to) {
+            return;
+        }
+        match Message::decode(&bytes).expect("Simulated messages decode") {
+            Message::NewBlock(block) => {
+                let _ = self.accept_block(to, Some(from), block);
+            }
+            Message::NewTransaction(transaction) => {
+                let _ = self.accept_transaction(to, Some(from), transaction);
+            }
+            other => unreachable!(
+                "nodes only relay blocks and transactions, not {}",
+                other.kind()
+            ),
+        }
+    }
+
+    fn accept_block(&mut self, node: NodeId, from: Option<NodeId>, block: Block) -> Result<()> {
+        let hash = block.hash();
+        let node = &mut self.nodes[node];
+        let result = node.blockchain.add_block(block.clone());
+        match &result {
+            Ok(()) => {
+                node.mempool.remove_block(&node.blockchain, &block);
+                node.blockchain.rebuild_utxos();
+            }
+            Err(e) => node.rejected.push(Rejection {
+                from,
+                item: hash,
+                reason: e.to_string(),
+            }),
+        }
+        result
+    }
+
+    fn accept_transaction(
+        &mut self,
+        node: NodeId,
+        from: Option<NodeId>,
+        transaction: Transaction,
+    ) -> Result<()> {
+        let hash = transaction.hash();
+        let now = self.now();
+        let node = &mut self.nodes[node];
+        let result = node.mempool.add_at(&node.blockchain, transaction, now);
+        if let Err(e) = &result {
+            node.rejected.push(Rejection {
+                from,
+                item: hash,
+                reason: e.to_string(),
+            });
+        }
+        result
+    }
+}
+
+/// A mined block on top of `blockchain`'s tip, as the node's templates:
+/// the coinbase pays the reward and fees, plus the genesis allocations
+/// in the first block
+fn build_block(
+    blockchain: &Blockchain,
+    pubkey: PublicKey,
+    timestamp: DateTime<Utc>,
+    mut transactions: Vec<Transaction>,
+) -> Result<Block> {
+    let mut coinbase = Transaction::coinbase(
+        blockchain.block_height(),
+        vec![TransactionOutput {
+            pubkey,
+            unique_id: Uuid::new_v4(),
+            value: 0,
+        }],
+    );
+    if blockchain.block_height() == 0 {
+        let allocations = &blockchain.params().genesis_allocations;
+        coinbase
+            .outputs
+            .extend(allocations.iter().map(|allocation| TransactionOutput {
+                pubkey: allocation.pubkey.clone(),
+                unique_id: Uuid::new_v4(),
+                value: allocation.value,
+            }));
+    }
+    transactions.insert(0, coinbase);
+    let prev_block_hash = blockchain
+        .blocks()
+        .last()
+        .map(|block| block.hash())
+        .unwrap_or(Hash::zero());
+    let mut block = Block::new(
+        BlockHeader::new(
+            timestamp,
+            0,
+            prev_block_hash,
+            MerkleRoot::calculate(&transactions),
+            blockchain.target(),
+        ),
+        transactions,
+    );
+    block.header.version = blockchain
+        .params()
+        .version_at(blockchain.block_height())
+        .max(BLOCK_VERSION);
+    block.header.utxo_commitment = Some(blockchain.utxo_commitment());
+    let fees = block.calculate_miner_fees(blockchain.utxos())?;
+    block.transactions[0].outputs[0].value = blockchain.calculate_block_reward() + fees;
+    block.header.merkle_root = MerkleRoot::calculate(&block.transactions);
+    while !block.header.mine(MINING_BATCH) {}
+    Ok(block)
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,160 @@
+#[cfg(test)]
+mod tests {
+    use crate::config::{self, BlockchainConfig};
+    use crate::crypto::PrivateKey;
+    use crate::error::BtcError;
+    use crate::simulation::{Simulation, DEFAULT_LATENCY_MS};
+    use crate::test_helpers::{create_test_input, create_test_output};
+    use crate::types::{Blockchain, Transaction};
+    use std::sync::Arc;
+
+    /// Spend the genesis coinbase to a new key, leaving `fee`
+    fn spend_genesis(sim: &Simulation, fee: u64) -> Transaction {
+        let genesis = sim.node(0).blockchain.blocks().next().unwrap();
+        let output = &genesis.transactions[0].outputs[0];
+        Transaction::new(
+            vec![create_test_input(&output.hash(), sim.genesis_key())],
+            vec![create_test_output(
+                output.value - fee,
+                &PrivateKey::new_key(),
+            )],
+        )
+    }
+
+    #[test]
+    fn test_blocks_and_transactions_propagate() {
+        let mut sim = Simulation::new(3);
+        let transaction = spend_genesis(&sim, 100);
+        sim.submit_transaction(1, transaction.clone()).unwrap();
+        sim.run_for(DEFAULT_LATENCY_MS - 1);
+        assert!(sim.node(0).mempool.is_empty());
+        sim.run_for(1);
+        assert_eq!(sim.node(0).mempool.len(), 1);
+
+        let miner = PrivateKey::new_key().public_key();
+        let block = sim.mine(0, miner).unwrap();
+        assert_eq!(block.transactions[1].hash(), transaction.hash());
+        sim.run_until_idle();
+        assert!(sim.converged());
+        for node in sim.nodes() {
+            assert_eq!(node.blockchain.block_height(), 2);
+            assert!(node.mempool.is_empty());
+            assert!(node.rejected.is_empty());
+            // the fee went to the miner, nothing was created or lost
+            assert_eq!(node.blockchain.total_supply(), config::scheduled_supply(2));
+        }
+    }
+
+    #[test]
+    fn test_double_spend_resolves_with_the_mined_block() {
+        let mut sim = Simulation::new(3);
+        let first = spend_genesis(&sim, 100);
+        let second = spend_genesis(&sim, 200);
+        sim.submit_transaction(0, first.clone()).unwrap();
+        sim.submit_transaction(2, second.clone()).unwrap();
+        sim.run_until_idle();
+        // replace-by-fee takes whichever arrives last, so the two
+        // spends swap places between the nodes that received them
+        let pending = |sim: &Simulation, node| sim.node(node).mempool.transactions()[0].1.hash();
+        assert_eq!(pending(&sim, 0), second.hash());
+        assert_eq!(pending(&sim, 2), first.hash());
+
+        let block = sim.mine(2, PrivateKey::new_key().public_key()).unwrap();
+        sim.run_until_idle();
+        assert!(sim.converged());
+        assert_eq!(block.transactions[1].hash(), first.hash());
+        for node in sim.nodes() {
+            assert!(node.mempool.is_empty());
+            let utxos = node.blockchain.utxos();
+            assert!(utxos.contains_key(&first.outputs[0].hash()));
+            assert!(!utxos.contains_key(&second.outputs[0].hash()));
+        }
+    }
+
+    #[test]
+    fn test_fork_race_keeps_the_first_block_seen() {
+        let mut sim = Simulation::new(3);
+        sim.set_latency(0, 2, 10);
+        sim.set_latency(1, 2, 200);
+        let zero = sim.mine(0, PrivateKey::new_key().public_key()).unwrap();
+        let one = sim.mine(1, PrivateKey::new_key().public_key()).unwrap();
+        sim.run_until_idle();
+
+        assert!(!sim.converged());
+        assert_eq!(sim.node(0).tip(), zero.hash());
+        assert_eq!(sim.node(1).tip(), one.hash());
+        assert_eq!(sim.node(2).tip(), zero.hash());
+        let rejected = &sim.node(2).rejected;
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].from, Some(1));
+        assert_eq!(rejected[0].item, one.hash());
+
+        // the losing node can't follow the winning branch without reorgs
+        sim.mine(0, PrivateKey::new_key().public_key()).unwrap();
+        sim.run_until_idle();
+        assert_eq!(sim.node(2).tip(), sim.node(0).tip());
+        assert_eq!(sim.node(1).tip(), one.hash());
+    }
+
+    #[test]
+    fn test_partition_splits_the_chain() {
+        let mut sim = Simulation::new(3);
+        sim.partition(&[&[0, 1]]);
+        sim.mine(0, PrivateKey::new_key().public_key()).unwrap();
+        sim.mine(2, PrivateKey::new_key().public_key()).unwrap();
+        sim.mine(2, PrivateKey::new_key().public_key()).unwrap();
+        sim.run_until_idle();
+        assert_eq!(sim.node(1).tip(), sim.node(0).tip());
+        assert_eq!(sim.node(2).blockchain.block_height(), 3);
+
+        sim.heal();
+        let block = sim.mine(2, PrivateKey::new_key().public_key()).unwrap();
+        sim.run_until_idle();
+        // the longer branch doesn't win, the other side rejects it
+        for node in [0, 1] {
+            assert_eq!(sim.node(node).blockchain.block_height(), 2);
+            let rejection = sim.node(node).rejected.last().unwrap();
+            assert_eq!(rejection.item, block.hash());
+        }
+    }
+
+    #[test]
+    fn test_partition_drops_messages_in_flight() {
+        let mut sim = Simulation::new(2);
+        sim.mine(0, PrivateKey::new_key().public_key()).unwrap();
+        assert_eq!(sim.in_flight(), 1);
+        sim.partition(&[&[0]]);
+        sim.run_until_idle();
+        sim.heal();
+        assert_eq!(sim.in_flight(), 0);
+        assert_eq!(sim.node(1).blockchain.block_height(), 1);
+        assert!(!sim.converged());
+    }
+
+    #[test]
+    fn test_networks_with_different_params_coexist() {
+        let mut config = BlockchainConfig::default();
+        config.network.initial_reward = 7;
+        let config = Arc::new(config);
+        let mut custom = Simulation::with_config(2, config.clone());
+        let default = Simulation::new(2);
+        custom.mine(0, PrivateKey::new_key().public_key()).unwrap();
+        custom.run_until_idle();
+        assert!(custom.converged());
+        assert_eq!(
+            custom.node(1).blockchain.total_supply(),
+            2 * 7 * 100_000_000
+        );
+        assert_eq!(
+            default.node(0).blockchain.total_supply(),
+            config::scheduled_supply(1)
+        );
+
+        // the default genesis pays more than the custom network allows
+        let genesis = default.node(0).blockchain.blocks().next().unwrap().clone();
+        assert!(matches!(
+            Blockchain::with_config(config).add_block(genesis),
+            Err(BtcError::SupplyExceeded { .. })
+        ));
+    }
+}
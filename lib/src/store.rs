@@ -0,0 +1,403 @@
+//! Blockchain storage backends
+//!
+//! The node used to serialize the whole `Blockchain` into a single CBOR
+//! file on every save, which gets slower with every block. The `ChainStore`
+//! trait abstracts persistence so that backends can write incrementally:
+//!
+//! - [`CborFileStore`]: the original whole-file format (simple, portable)
+//! - [`SledStore`]: an embedded key-value database storing blocks by
+//!   height and hash plus the UTXO set, updated block by block
+//! - [`BlockFileStore`]: append-only `blk*.dat` files with a height index
+//!   (see [`BlockFiles`]); the UTXO set is rebuilt from blocks on load
+//!
+//! Every backend stores the consensus parameters along with the chain
+//! and refuses to load a chain saved under other ones (see
+//! [`Blockchain::check_params`]).
+use crate::config::NetworkConfig;
+use crate::sha256::Hash;
+use crate::types::{Block, Blockchain, TransactionOutput};
+use crate::util::{replace_file, suffixed_path, BlockFiles, Saveable};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::Transactional;
+use std::collections::HashMap;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::warn;
+
+/// Available storage backends
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// Whole blockchain in a single CBOR file
+    #[default]
+    Cbor,
+    /// Incremental sled database (the path is a directory)
+    Sled,
+    /// Append-only block files with an index (the path is a directory)
+    #[serde(rename = "blockfiles")]
+    BlockFiles,
+}
+
+/// Persistence for a `Blockchain`
+pub trait ChainStore: Send + Sync {
+    /// Load the stored blockchain, or `None` if nothing was stored yet.
+    /// Fails if it was stored under other consensus parameters.
+    fn load(&self) -> IoResult<Option<Blockchain>>;
+    /// Persist the current state of the blockchain
+    fn save(&self, blockchain: &Blockchain) -> IoResult<()>;
+    /// Replace everything stored with `blockchain`, e.g. after
+    /// [`Blockchain::reindex`], where `save` may only append what is new
+    fn rewrite(&self, blockchain: &Blockchain) -> IoResult<()> {
+        self.save(blockchain)
+    }
+}
+
+/// Open the configured storage backend at `path`.
+/// `backups` is the number of previous versions kept by the whole-file
+/// CBOR backend; the incremental backends ignore it.
+pub fn open_store<P: AsRef<Path>>(
+    backend: StorageBackend,
+    path: P,
+    backups: usize,
+) -> IoResult<Box<dyn ChainStore>> {
+    Ok(match backend {
+        StorageBackend::Cbor => Box::new(CborFileStore::new(path).with_backups(backups)),
+        StorageBackend::Sled => Box::new(SledStore::open(path)?),
+        StorageBackend::BlockFiles => Box::new(BlockFileStore::open(path)?),
+    })
+}
+
+/// Whole-file CBOR storage (the original format)
+///
+/// Saves are atomic (see [`Saveable::save_to_file`]). Optionally the
+/// previous versions are kept as `<file>.1`..`<file>.N`, and loading
+/// falls back to them if the main file can't be read.
+pub struct CborFileStore {
+    path: PathBuf,
+    backups: usize,
+}
+
+impl CborFileStore {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            backups: 0,
+        }
+    }
+
+    /// Keep this many previous versions of the file
+    pub fn with_backups(mut self, backups: usize) -> Self {
+        self.backups = backups;
+        self
+    }
+}
+
+impl ChainStore for CborFileStore {
+    fn load(&self) -> IoResult<Option<Blockchain>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let error = match Blockchain::load_from_file(&self.path) {
+            Ok(blockchain) => return checked(blockchain),
+            Err(e) => e,
+        };
+        for i in 1..=self.backups {
+            let backup = suffixed_path(&self.path, &i.to_string());
+            if !backup.exists() {
+                break;
+            }
+            match Blockchain::load_from_file(&backup) {
+                Ok(blockchain) => {
+                    warn!(
+                        "{} is unreadable ({}), recovered from {}",
+                        self.path.display(),
+                        error,
+                        backup.display()
+                    );
+                    return checked(blockchain);
+                }
+                Err(e) => warn!("backup {} is unreadable: {}", backup.display(), e),
+            }
+        }
+        Err(error)
+    }
+
+    fn save(&self, blockchain: &Blockchain) -> IoResult<()> {
+        blockchain.save_to_file_with_backups(&self.path, self.backups)
+    }
+}
+
+/// Incremental storage on top of sled
+///
+/// Layout:
+/// - `blocks`: height (u64 big-endian) → CBOR block
+/// - `block_index`: block hash → height
+/// - `utxos`: output hash → CBOR `TransactionOutput`
+/// - default tree: `height`, `target` and consensus `params` metadata
+///
+/// Each block is written together with its UTXO changes and the new
+/// height in a single transaction, so the store is never half-updated.
+pub struct SledStore {
+    db: sled::Db,
+    blocks: sled::Tree,
+    block_index: sled::Tree,
+    utxos: sled::Tree,
+}
+
+const HEIGHT_KEY: &[u8] = b"height";
+const TARGET_KEY: &[u8] = b"target";
+const PARAMS_KEY: &[u8] = b"params";
+
+impl SledStore {
+    /// Open (or create) a sled database in the directory `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> IoResult<Self> {
+        Self::from_db(sled::open(path)?)
+    }
+
+    /// Use an already opened sled database
+    pub fn from_db(db: sled::Db) -> IoResult<Self> {
+        Ok(Self {
+            blocks: db.open_tree("blocks")?,
+            block_index: db.open_tree("block_index")?,
+            utxos: db.open_tree("utxos")?,
+            db,
+        })
+    }
+
+    /// Number of blocks currently stored
+    pub fn stored_height(&self) -> IoResult<u64> {
+        Ok(match self.db.get(HEIGHT_KEY)? {
+            Some(bytes) => decode_height(&bytes)?,
+            None => 0,
+        })
+    }
+
+    /// Fetch a single block by height without loading the whole chain
+    pub fn block_at(&self, height: u64) -> IoResult<Option<Block>> {
+        self.blocks
+            .get(height.to_be_bytes())?
+            .map(|bytes| from_cbor(&bytes))
+            .transpose()
+    }
+
+    /// Fetch a single block by hash without loading the whole chain
+    pub fn block_by_hash(&self, hash: &Hash) -> IoResult<Option<Block>> {
+        match self.block_index.get(hash.as_bytes())? {
+            Some(height) => self.block_at(decode_height(&height)?),
+            None => Ok(None),
+        }
+    }
+
+    fn clear(&self) -> IoResult<()> {
+        self.blocks.clear()?;
+        self.block_index.clear()?;
+        self.utxos.clear()?;
+        self.db.remove(HEIGHT_KEY)?;
+        Ok(())
+    }
+
+    /// Write one block and its UTXO delta atomically
+    fn append_block(&self, height: u64, block: &Block) -> IoResult<()> {
+        let block_bytes = to_cbor(block)?;
+        let mut spent = vec![];
+        let mut created = vec![];
+        for transaction in &block.transactions {
+            for input in &transaction.inputs {
+                spent.push(input.prev_transaction_output_hash.as_bytes());
+            }
+            for output in &transaction.outputs {
+                created.push((output.hash().as_bytes(), to_cbor(output)?));
+            }
+        }
+        let hash = block.hash().as_bytes();
+        let meta: &sled::Tree = &self.db;
+        (&self.blocks, &self.block_index, &self.utxos, meta)
+            .transaction(|(blocks, block_index, utxos, meta)| {
+                blocks.insert(&height.to_be_bytes(), block_bytes.as_slice())?;
+                block_index.insert(&hash, &height.to_be_bytes())?;
+                for key in &spent {
+                    utxos.remove(key)?;
+                }
+                for (key, value) in &created {
+                    utxos.insert(key, value.as_slice())?;
+                }
+                meta.insert(HEIGHT_KEY, &(height + 1).to_be_bytes())?;
+                Ok::<_, ConflictableTransactionError<IoError>>(())
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => e.into(),
+            })
+    }
+}
+
+impl ChainStore for SledStore {
+    fn load(&self) -> IoResult<Option<Blockchain>> {
+        let height = self.stored_height()?;
+        if height == 0 {
+            return Ok(None);
+        }
+        let blocks = self
+            .blocks
+            .iter()
+            .values()
+            .map(|bytes| from_cbor::<Block>(&bytes?))
+            .collect::<IoResult<Vec<_>>>()?;
+        if blocks.len() as u64 != height {
+            return Err(IoError::new(
+                IoErrorKind::InvalidData,
+                "stored block count doesn't match stored height",
+            ));
+        }
+        let mut utxos = HashMap::new();
+        for bytes in self.utxos.iter().values() {
+            let output: TransactionOutput = from_cbor(&bytes?)?;
+            utxos.insert(output.hash(), (false, output));
+        }
+        let target = match self.db.get(TARGET_KEY)? {
+            Some(bytes) => from_cbor(&bytes)?,
+            None => crate::config::min_target(),
+        };
+        let mut blockchain = Blockchain::from_parts(blocks, utxos, target);
+        blockchain.set_saved_params(
+            self.db
+                .get(PARAMS_KEY)?
+                .map(|bytes| from_cbor(&bytes))
+                .transpose()?,
+        );
+        checked(blockchain)
+    }
+
+    fn save(&self, blockchain: &Blockchain) -> IoResult<()> {
+        let mut stored = self.stored_height()?;
+        // If the stored tip is not part of the current chain (or the chain
+        // got shorter), start over instead of appending to a stale history
+        if stored > 0 {
+            let stored_tip = self.block_at(stored - 1)?.map(|block| block.hash());
+            let chain_block = blockchain
+                .blocks()
+                .nth(stored as usize - 1)
+                .map(|block| block.hash());
+            if stored_tip.is_none() || stored_tip != chain_block {
+                self.clear()?;
+                stored = 0;
+            }
+        }
+        for (height, block) in blockchain.blocks().enumerate().skip(stored as usize) {
+            self.append_block(height as u64, block)?;
+        }
+        self.db.insert(TARGET_KEY, to_cbor(&blockchain.target())?)?;
+        self.db.insert(PARAMS_KEY, to_cbor(blockchain.params())?)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// The UTXO tree is only ever updated by block deltas, start over
+    fn rewrite(&self, blockchain: &Blockchain) -> IoResult<()> {
+        self.clear()?;
+        self.save(blockchain)
+    }
+}
+
+/// Append-only block file storage
+///
+/// Only raw blocks are stored, plus the consensus parameters in
+/// `params.cbor`. On load the UTXO set is rebuilt and the target is
+/// recovered from the tip header (plus a possible retarget).
+pub struct BlockFileStore {
+    files: Mutex<BlockFiles>,
+    params_path: PathBuf,
+}
+
+impl BlockFileStore {
+    pub fn open<P: AsRef<Path>>(dir: P) -> IoResult<Self> {
+        Ok(Self {
+            files: Mutex::new(BlockFiles::open(&dir)?),
+            params_path: dir.as_ref().join("params.cbor"),
+        })
+    }
+}
+
+impl ChainStore for BlockFileStore {
+    fn load(&self) -> IoResult<Option<Blockchain>> {
+        let files = self.files.lock().expect("block files lock poisoned");
+        if files.is_empty() {
+            return Ok(None);
+        }
+        let mut blocks = Vec::with_capacity(files.len() as usize);
+        for height in 0..files.len() {
+            blocks.push(files.read(height)?.ok_or_else(|| {
+                IoError::new(IoErrorKind::InvalidData, "indexed block is missing")
+            })?);
+        }
+        let target = blocks
+            .last()
+            .map(|block| block.header.target)
+            .expect("BUG: checked non-empty above");
+        let mut blockchain = Blockchain::from_parts(blocks, HashMap::new(), target);
+        if self.params_path.exists() {
+            let params: NetworkConfig = from_cbor(&std::fs::read(&self.params_path)?)?;
+            blockchain.set_saved_params(Some(params));
+            // retargeting below already uses the configured rules
+            blockchain.check_params().map_err(invalid_data)?;
+        }
+        blockchain.rebuild_utxos();
+        blockchain.try_adjust_target();
+        Ok(Some(blockchain))
+    }
+
+    fn save(&self, blockchain: &Blockchain) -> IoResult<()> {
+        let mut files = self.files.lock().expect("block files lock poisoned");
+        let chain: Vec<&Block> = blockchain.blocks().collect();
+        // after a reorg only the blocks above the fork are rewritten
+        let mut fork = files.len().min(chain.len() as u64);
+        while fork > 0 {
+            let stored = files.read(fork - 1)?.map(|block| block.hash());
+            if stored == Some(chain[fork as usize - 1].hash()) {
+                break;
+            }
+            fork -= 1;
+        }
+        if fork < files.len() {
+            files.truncate(fork)?;
+        }
+        for block in &chain[fork as usize..] {
+            files.append(block)?;
+        }
+        let params = to_cbor(blockchain.params())?;
+        replace_file(&self.params_path, 0, |writer| writer.write_all(&params))
+    }
+}
+
+/// `blockchain`, unless it was stored under other consensus parameters
+fn checked(blockchain: Blockchain) -> IoResult<Option<Blockchain>> {
+    blockchain.check_params().map_err(invalid_data)?;
+    Ok(Some(blockchain))
+}
+
+fn invalid_data(error: impl ToString) -> IoError {
+    IoError::new(IoErrorKind::InvalidData, error.to_string())
+}
+
+fn decode_height(bytes: &[u8]) -> IoResult<u64> {
+    let bytes: [u8; 8] = bytes
+        .try_into()
+        .map_err(|_| IoError::new(IoErrorKind::InvalidData, "invalid stored height"))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn to_cbor<T: Serialize>(value: &T) -> IoResult<Vec<u8>> {
+    let mut bytes = vec![];
+    ciborium::into_writer(value, &mut bytes)
+        .map_err(|e| IoError::new(IoErrorKind::InvalidData, e.to_string()))?;
+    Ok(bytes)
+}
+
+fn from_cbor<T: DeserializeOwned>(bytes: &[u8]) -> IoResult<T> {
+    ciborium::from_reader(bytes).map_err(|e| IoError::new(IoErrorKind::InvalidData, e.to_string()))
+}
+
+#[cfg(test)]
+mod tests;
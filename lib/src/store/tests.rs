@@ -0,0 +1,191 @@
+#[cfg(test)]
+mod tests {
+    use crate::config::{self, BlockchainConfig};
+    use crate::crypto::PrivateKey;
+    use crate::sha256::Hash;
+    use crate::store::{BlockFileStore, CborFileStore, ChainStore, SledStore};
+    use crate::test_helpers::create_test_output;
+    use crate::types::{Block, BlockHeader, Blockchain, Transaction};
+    use crate::util::MerkleRoot;
+    use chrono::Utc;
+    use std::io::ErrorKind;
+    use std::sync::Arc;
+
+    fn temporary_store() -> SledStore {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        SledStore::from_db(db).unwrap()
+    }
+
+    fn coinbase_block(prev_block_hash: Hash, height: u64) -> Block {
+        let private_key = PrivateKey::new_key();
+        let output = create_test_output(config::initial_reward() * 100_000_000, &private_key);
+        let transaction = Transaction::coinbase(height, vec![output]);
+        let mut block = Block::new(
+            BlockHeader::new(
+                Utc::now() + chrono::Duration::seconds(height as i64),
+                0,
+                prev_block_hash,
+                MerkleRoot::calculate(std::slice::from_ref(&transaction)),
+                config::min_target(),
+            ),
+            vec![transaction],
+        );
+        while !block.header.hash().matches_target(block.header.target) {
+            block.header.nonce += 1;
+        }
+        block
+    }
+
+    #[test]
+    fn test_empty_store_loads_nothing() {
+        let store = temporary_store();
+        assert!(store.load().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_save_and_load_roundtrip() {
+        let store = temporary_store();
+        let mut blockchain = Blockchain::new();
+        blockchain
+            .add_block(coinbase_block(Hash::zero(), 0))
+            .unwrap();
+        blockchain.rebuild_utxos();
+
+        store.save(&blockchain).unwrap();
+        let loaded = store.load().unwrap().expect("chain was saved");
+
+        assert_eq!(loaded.block_height(), 1);
+        assert_eq!(loaded.utxos().len(), 1);
+        assert_eq!(loaded.target(), blockchain.target());
+    }
+
+    #[test]
+    fn test_incremental_save_appends_blocks() {
+        let store = temporary_store();
+        let mut blockchain = Blockchain::new();
+        let genesis = coinbase_block(Hash::zero(), 0);
+        let genesis_hash = genesis.hash();
+        blockchain.add_block(genesis).unwrap();
+        blockchain.rebuild_utxos();
+        store.save(&blockchain).unwrap();
+
+        blockchain
+            .add_block(coinbase_block(genesis_hash, 1))
+            .unwrap();
+        blockchain.rebuild_utxos();
+        store.save(&blockchain).unwrap();
+
+        assert_eq!(store.stored_height().unwrap(), 2);
+        let loaded = store.load().unwrap().unwrap();
+        assert_eq!(loaded.block_height(), 2);
+        assert_eq!(loaded.utxos().len(), 2);
+        assert!(store.block_by_hash(&genesis_hash).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_rewrite_replaces_stored_utxos() {
+        let store = temporary_store();
+        let mut blockchain = Blockchain::new();
+        blockchain
+            .add_block(coinbase_block(Hash::zero(), 0))
+            .unwrap();
+        blockchain.rebuild_utxos();
+        store.save(&blockchain).unwrap();
+        // an output no block created, e.g. from a corrupted database
+        let stale = create_test_output(1000, &PrivateKey::new_key());
+        store
+            .utxos
+            .insert(
+                stale.hash().as_bytes(),
+                crate::store::to_cbor(&stale).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(store.load().unwrap().unwrap().utxos().len(), 2);
+
+        let mut reloaded = store.load().unwrap().unwrap();
+        reloaded.reindex(false, false, |_, _| {});
+        store.rewrite(&reloaded).unwrap();
+
+        let loaded = store.load().unwrap().unwrap();
+        assert_eq!(loaded.block_height(), 1);
+        assert_eq!(loaded.utxos().len(), 1);
+        assert!(!loaded.utxos().contains_key(&stale.hash()));
+    }
+
+    #[test]
+    fn test_cbor_store_recovers_from_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blockchain.cbor");
+        let store = CborFileStore::new(&path).with_backups(1);
+        let mut blockchain = Blockchain::new();
+        blockchain
+            .add_block(coinbase_block(Hash::zero(), 0))
+            .unwrap();
+        store.save(&blockchain).unwrap();
+        store.save(&blockchain).unwrap();
+
+        // simulate a corrupted main file
+        std::fs::write(&path, b"garbage").unwrap();
+        let loaded = store.load().unwrap().expect("recovered from backup");
+        assert_eq!(loaded.block_height(), 1);
+    }
+
+    #[test]
+    fn test_block_file_store_keeps_blocks_below_the_fork() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = BlockFileStore::open(dir.path().join("blocks")).unwrap();
+        let genesis = coinbase_block(Hash::zero(), 0);
+        let genesis_hash = genesis.hash();
+        let mut blockchain = Blockchain::new();
+        blockchain.add_block(genesis.clone()).unwrap();
+        blockchain
+            .add_block(coinbase_block(genesis_hash, 1))
+            .unwrap();
+        store.save(&blockchain).unwrap();
+
+        let mut reorged = Blockchain::new();
+        reorged.add_block(genesis).unwrap();
+        let branch = coinbase_block(genesis_hash, 1);
+        let branch_hash = branch.hash();
+        reorged.add_block(branch).unwrap();
+        reorged.add_block(coinbase_block(branch_hash, 2)).unwrap();
+        let genesis_location = store.files.lock().unwrap().location(0);
+        store.save(&reorged).unwrap();
+
+        let files = store.files.lock().unwrap();
+        assert_eq!(files.len(), 3);
+        assert_eq!(files.location(0), genesis_location);
+        drop(files);
+        let loaded = store.load().unwrap().unwrap();
+        let hashes: Vec<_> = loaded.blocks().map(|block| block.hash()).collect();
+        let expected: Vec<_> = reorged.blocks().map(|block| block.hash()).collect();
+        assert_eq!(hashes, expected);
+    }
+
+    #[test]
+    fn test_chains_saved_under_other_params_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let stores: Vec<Box<dyn ChainStore>> = vec![
+            Box::new(temporary_store()),
+            Box::new(CborFileStore::new(dir.path().join("blockchain.cbor"))),
+            Box::new(BlockFileStore::open(dir.path().join("blocks")).unwrap()),
+        ];
+        let mut config = BlockchainConfig::default();
+        config.network.halving_interval += 1;
+        let config = Arc::new(config);
+        for store in stores {
+            let genesis = coinbase_block(Hash::zero(), 0);
+            let mut blockchain = Blockchain::new();
+            blockchain.add_block(genesis.clone()).unwrap();
+            store.save(&blockchain).unwrap();
+            assert_eq!(store.load().unwrap().unwrap().block_height(), 1);
+
+            let mut other = Blockchain::with_config(config.clone());
+            other.add_block(genesis).unwrap();
+            store.save(&other).unwrap();
+            let error = store.load().unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+            assert!(error.to_string().contains("halving_interval"), "{}", error);
+        }
+    }
+}
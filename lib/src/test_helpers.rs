@@ -4,12 +4,18 @@
 //! across test modules in the codebase.
 
 use crate::crypto::{PrivateKey, Signature};
+use crate::encoding::Decode;
+use crate::network::{Message, RejectCode};
 use crate::sha256::Hash;
-use crate::types::{TransactionInput, TransactionOutput};
+use crate::types::{Block, BlockHeader, Transaction, TransactionInput, TransactionOutput};
+use crate::util::MerkleRoot;
+use crate::U256;
+use chrono::{TimeZone, Utc};
+use proptest::prelude::*;
 use uuid::Uuid;
 
 /// Create a test transaction output
-pub fn create_test_output(value: u64, private_key: &mut PrivateKey) -> TransactionOutput {
+pub fn create_test_output(value: u64, private_key: &PrivateKey) -> TransactionOutput {
     TransactionOutput {
         value,
         unique_id: Uuid::new_v4(),
@@ -18,13 +24,109 @@ pub fn create_test_output(value: u64, private_key: &mut PrivateKey) -> Transacti
 }
 
 /// Create a test transaction input with signature
-pub fn create_test_input(output_hash: &Hash, private_key: &mut PrivateKey) -> TransactionInput {
+pub fn create_test_input(output_hash: &Hash, private_key: &PrivateKey) -> TransactionInput {
     TransactionInput {
         prev_transaction_output_hash: *output_hash,
         signature: Signature::sign_output(output_hash, private_key),
     }
 }
 
+// proptest strategies for the consensus types. Keys come from a small
+// seed so that generating them stays cheap; signatures are real ones
+// over random hashes, which decoding doesn't tell apart from valid ones.
+
+pub fn arb_hash() -> impl Strategy<Value = Hash> {
+    any::<[u8; 32]>().prop_map(|bytes| Hash::decode(&bytes).expect("32 bytes are a hash"))
+}
+
+pub fn arb_private_key() -> impl Strategy<Value = PrivateKey> {
+    any::<u8>().prop_map(|seed| {
+        let secret = Hash::digest(crate::config::HashScheme::Single, &[seed]);
+        PrivateKey::from_hex(&hex::encode(secret.as_bytes())).expect("Valid secret")
+    })
+}
+
+pub fn arb_output() -> impl Strategy<Value = TransactionOutput> {
+    (any::<u64>(), any::<u128>(), arb_private_key()).prop_map(|(value, id, key)| {
+        TransactionOutput {
+            value,
+            unique_id: Uuid::from_u128(id),
+            pubkey: key.public_key(),
+        }
+    })
+}
+
+pub fn arb_input() -> impl Strategy<Value = TransactionInput> {
+    (arb_hash(), arb_private_key()).prop_map(|(hash, key)| create_test_input(&hash, &key))
+}
+
+pub fn arb_transaction() -> impl Strategy<Value = Transaction> {
+    (
+        prop::collection::vec(arb_input(), 0..4),
+        prop::collection::vec(arb_output(), 0..4),
+        prop::option::of(any::<u64>()),
+        any::<u32>(),
+    )
+        .prop_map(|(inputs, outputs, coinbase_height, version)| Transaction {
+            version,
+            coinbase_height,
+            ..Transaction::new(inputs, outputs)
+        })
+}
+
+pub fn arb_block() -> impl Strategy<Value = Block> {
+    (
+        any::<u32>(),
+        0..i64::from(i32::MAX),
+        0..1_000_000_000u32,
+        any::<u64>(),
+        arb_hash(),
+        any::<[u64; 4]>(),
+        prop::option::of(arb_hash()),
+        prop::collection::vec(arb_transaction(), 0..4),
+    )
+        .prop_map(
+            |(version, seconds, nanos, nonce, prev, target, utxo_commitment, transactions)| {
+                let mut header = BlockHeader::new(
+                    Utc.timestamp_opt(seconds, nanos).unwrap(),
+                    nonce,
+                    prev,
+                    MerkleRoot::calculate(&transactions),
+                    U256(target),
+                );
+                header.version = version;
+                header.utxo_commitment = utxo_commitment;
+                Block::new(header, transactions)
+            },
+        )
+}
+
+/// Messages carrying each kind of payload: numbers, strings, bytes,
+/// keys, hashes, transactions and blocks
+pub fn arb_message() -> impl Strategy<Value = Message> {
+    prop_oneof![
+        any::<u32>().prop_map(Message::AskDifference),
+        any::<i32>().prop_map(Message::Difference),
+        any::<usize>().prop_map(Message::FetchBlock),
+        (any::<u64>(), any::<u32>()).prop_map(|(start, count)| Message::FetchHeaders(start, count)),
+        ".*".prop_map(Message::Unban),
+        any::<Vec<u8>>().prop_map(Message::Challenge),
+        arb_private_key().prop_map(|key| Message::FetchUTXOs(key.public_key())),
+        arb_hash().prop_map(Message::Accepted),
+        (arb_hash(), ".*").prop_map(|(item_hash, reason)| Message::Reject {
+            item_hash,
+            code: RejectCode::Invalid,
+            reason,
+        }),
+        arb_transaction().prop_map(Message::NewTransaction),
+        arb_block().prop_map(Message::NewBlock),
+        Just(Message::GetMempool),
+        arb_hash().prop_map(Message::EvictTransaction),
+        Just(Message::GetSupply),
+        Just(Message::GetStatus),
+    ]
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -32,8 +134,8 @@ mod tests {
 
     #[test]
     fn test_create_test_output() {
-        let mut private_key = PrivateKey::new_key();
-        let output = create_test_output(100, &mut private_key);
+        let private_key = PrivateKey::new_key();
+        let output = create_test_output(100, &private_key);
 
         assert_eq!(output.value, 100);
         assert_eq!(output.pubkey, private_key.public_key());
@@ -41,10 +143,10 @@ mod tests {
 
     #[test]
     fn test_create_test_input() {
-        let mut private_key = PrivateKey::new_key();
-        let output = create_test_output(100, &mut private_key);
+        let private_key = PrivateKey::new_key();
+        let output = create_test_output(100, &private_key);
         let hash = output.hash();
-        let input = create_test_input(&hash, &mut private_key);
+        let input = create_test_input(&hash, &private_key);
 
         assert_eq!(input.prev_transaction_output_hash, hash);
         // Verify the signature is valid
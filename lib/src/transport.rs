@@ -0,0 +1,550 @@
+//! Optional Noise encryption for node and wallet connections
+//!
+//! A [`Connection`] is either plain TCP or a `Noise_XX` session running
+//! on top of it. The connecting side decides: to ask for encryption it
+//! opens with [`NOISE_PREAMBLE`], four `0xff` bytes that can't start a
+//! plaintext frame (no network uses them as its magic), and then runs the
+//! handshake. The accepting side reads the first four bytes and either
+//! answers the handshake or replays them as the start of a plain frame.
+//!
+//! ```text
+//! initiator                          responder
+//!   ff ff ff ff
+//!   len | -> e
+//!                        len | <- e, ee, s, es
+//!   len | -> s, se
+//!   len | ciphertext ...   len | ciphertext ...
+//! ```
+//!
+//! Every Noise message travels with a u16 big-endian length prefix. Static
+//! keys are generated per connection: the session is encrypted and
+//! integrity protected, but peers aren't authenticated against anything.
+//!
+//! Either kind runs over TCP or, for addresses starting with `unix://`,
+//! over a Unix domain socket, so wallets and miners on the node's machine
+//! don't need a TCP port. TCP connections can also go through a SOCKS5
+//! proxy such as Tor (RFC 1928, without authentication), which then
+//! resolves the hostname.
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+/// Noise protocol name used for the handshake
+pub const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+/// First bytes sent by a client that wants an encrypted connection
+pub const NOISE_PREAMBLE: [u8; 4] = [0xff; 4];
+
+/// Prefix of addresses naming a Unix domain socket, e.g.
+/// `unix:///run/btc/node.sock`
+pub const UNIX_PREFIX: &str = "unix://";
+
+const MAX_NOISE_MESSAGE: usize = 65535;
+const TAG_LEN: usize = 16;
+/// Largest plaintext chunk that fits in one Noise message
+const MAX_CHUNK: usize = MAX_NOISE_MESSAGE - TAG_LEN;
+
+/// A node or wallet connection, encrypted or not
+pub enum Connection {
+    Plain(PlainStream),
+    Noise(Box<NoiseStream<Socket>>),
+}
+
+impl Connection {
+    /// Connect to `address`, a `host:port` or a `unix://` socket path,
+    /// running the Noise handshake if `encrypt` is set
+    pub async fn connect(address: &str, encrypt: bool) -> Result<Self> {
+        let stream = match address.strip_prefix(UNIX_PREFIX) {
+            #[cfg(unix)]
+            Some(path) => Socket::Unix(UnixStream::connect(path).await?),
+            #[cfg(not(unix))]
+            Some(_) => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "Unix domain sockets need a Unix system",
+                ))
+            }
+            None => Socket::Tcp(TcpStream::connect(address).await?),
+        };
+        Self::start(stream, encrypt).await
+    }
+
+    /// Connect to the `host:port` `address` through the SOCKS5 proxy at
+    /// `proxy`, e.g. Tor's `127.0.0.1:9050`. Hostnames, `.onion` ones
+    /// included, are resolved by the proxy.
+    pub async fn connect_via(proxy: &str, address: &str, encrypt: bool) -> Result<Self> {
+        let mut stream = TcpStream::connect(proxy).await?;
+        socks5_connect(&mut stream, address).await?;
+        Self::start(Socket::Tcp(stream), encrypt).await
+    }
+
+    async fn start(stream: Socket, encrypt: bool) -> Result<Self> {
+        if encrypt {
+            Ok(Connection::Noise(Box::new(
+                NoiseStream::initiate(stream).await?,
+            )))
+        } else {
+            Ok(Connection::Plain(PlainStream::new(stream)))
+        }
+    }
+
+    /// Accept an incoming connection, answering the handshake if the
+    /// client asked for encryption
+    pub async fn accept(stream: impl Into<Socket>) -> Result<Self> {
+        let mut stream = stream.into();
+        let mut preamble = [0u8; 4];
+        let mut read = 0;
+        while read < preamble.len() {
+            let n = stream.read(&mut preamble[read..]).await?;
+            if n == 0 {
+                break;
+            }
+            read += n;
+        }
+        if read == preamble.len() && preamble == NOISE_PREAMBLE {
+            return Ok(Connection::Noise(Box::new(
+                NoiseStream::respond(stream).await?,
+            )));
+        }
+        Ok(Connection::Plain(PlainStream {
+            stream,
+            replay: preamble[..read].to_vec(),
+        }))
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self, Connection::Noise(_))
+    }
+
+    /// Whether this runs over a Unix domain socket, i.e. the other side
+    /// is on this machine
+    pub fn is_local(&self) -> bool {
+        self.socket().is_local()
+    }
+
+    /// Address of the other side, an error for Unix domain sockets
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        self.socket().peer_addr()
+    }
+
+    fn socket(&self) -> &Socket {
+        match self {
+            Connection::Plain(plain) => &plain.stream,
+            Connection::Noise(noise) => &noise.stream,
+        }
+    }
+}
+
+/// Ask the SOCKS5 proxy on the other end of `stream` to connect to
+/// `address`. Once this returns, the stream leads to `address`.
+pub async fn socks5_connect<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    address: &str,
+) -> Result<()> {
+    let invalid =
+        |reason: &str| Error::new(ErrorKind::InvalidInput, format!("{}: {}", address, reason));
+    let (host, port) = address
+        .rsplit_once(':')
+        .ok_or_else(|| invalid("expected host:port"))?;
+    let port: u16 = port.parse().map_err(|_| invalid("invalid port"))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    // version 5, CONNECT, reserved, then the address
+    let mut request = vec![5, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let name = u8::try_from(host.len()).map_err(|_| invalid("hostname too long"))?;
+            request.push(3);
+            request.push(name);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+
+    // version 5, one method: no authentication
+    stream.write_all(&[5, 1, 0]).await?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice != [5, 0] {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "the SOCKS5 proxy requires authentication",
+        ));
+    }
+    stream.write_all(&request).await?;
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 5 {
+        return Err(Error::new(ErrorKind::InvalidData, "not a SOCKS5 proxy"));
+    }
+    if reply[1] != 0 {
+        let reason = match reply[1] {
+            2 => "connection not allowed by ruleset",
+            3 => "network unreachable",
+            4 => "host unreachable",
+            5 => "connection refused",
+            6 => "TTL expired",
+            7 => "command not supported",
+            8 => "address type not supported",
+            _ => "general failure",
+        };
+        return Err(Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("SOCKS5 proxy can't reach {}: {}", address, reason),
+        ));
+    }
+    // the address the proxy bound, which we don't need
+    let bound = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+        _ => return Err(Error::new(ErrorKind::InvalidData, "invalid SOCKS5 reply")),
+    };
+    let mut skipped = vec![0u8; bound + 2];
+    stream.read_exact(&mut skipped).await?;
+    Ok(())
+}
+
+/// The byte stream under a connection
+pub enum Socket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Socket {
+    pub fn is_local(&self) -> bool {
+        match self {
+            Socket::Tcp(_) => false,
+            #[cfg(unix)]
+            Socket::Unix(_) => true,
+        }
+    }
+
+    /// Address of the other side, an error for Unix domain sockets
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        match self {
+            Socket::Tcp(tcp) => tcp.peer_addr(),
+            #[cfg(unix)]
+            Socket::Unix(_) => Err(Error::new(
+                ErrorKind::Unsupported,
+                "Unix domain sockets have no peer address",
+            )),
+        }
+    }
+}
+
+impl From<TcpStream> for Socket {
+    fn from(stream: TcpStream) -> Self {
+        Socket::Tcp(stream)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixStream> for Socket {
+    fn from(stream: UnixStream) -> Self {
+        Socket::Unix(stream)
+    }
+}
+
+impl AsyncRead for Socket {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        match self.get_mut() {
+            Socket::Tcp(tcp) => Pin::new(tcp).poll_read(cx, buf),
+            #[cfg(unix)]
+            Socket::Unix(unix) => Pin::new(unix).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Socket {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        match self.get_mut() {
+            Socket::Tcp(tcp) => Pin::new(tcp).poll_write(cx, buf),
+            #[cfg(unix)]
+            Socket::Unix(unix) => Pin::new(unix).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match self.get_mut() {
+            Socket::Tcp(tcp) => Pin::new(tcp).poll_flush(cx),
+            #[cfg(unix)]
+            Socket::Unix(unix) => Pin::new(unix).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match self.get_mut() {
+            Socket::Tcp(tcp) => Pin::new(tcp).poll_shutdown(cx),
+            #[cfg(unix)]
+            Socket::Unix(unix) => Pin::new(unix).poll_shutdown(cx),
+        }
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(plain) => Pin::new(plain).poll_read(cx, buf),
+            Connection::Noise(noise) => Pin::new(noise.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        match self.get_mut() {
+            Connection::Plain(plain) => Pin::new(&mut plain.stream).poll_write(cx, buf),
+            Connection::Noise(noise) => Pin::new(noise.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(plain) => Pin::new(&mut plain.stream).poll_flush(cx),
+            Connection::Noise(noise) => Pin::new(noise.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(plain) => Pin::new(&mut plain.stream).poll_shutdown(cx),
+            Connection::Noise(noise) => Pin::new(noise.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// A plain socket, with the bytes read while looking for the preamble
+/// put back
+pub struct PlainStream {
+    stream: Socket,
+    replay: Vec<u8>,
+}
+
+impl PlainStream {
+    fn new(stream: Socket) -> Self {
+        Self {
+            stream,
+            replay: vec![],
+        }
+    }
+}
+
+impl AsyncRead for PlainStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        if !this.replay.is_empty() {
+            let n = this.replay.len().min(buf.remaining());
+            buf.put_slice(&this.replay[..n]);
+            this.replay.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.stream).poll_read(cx, buf)
+    }
+}
+
+/// A Noise transport session over any byte stream
+///
+/// Writes are split into Noise messages of at most `MAX_CHUNK` plaintext
+/// bytes. A chunk is only reported as written once all of its ciphertext
+/// is on the wire, so `write_all` works as with a plain stream.
+pub struct NoiseStream<S> {
+    stream: S,
+    state: snow::TransportState,
+    /// Decrypted bytes not handed to the reader yet
+    plaintext: Vec<u8>,
+    plaintext_pos: usize,
+    /// Noise message being read: the length prefix, then the ciphertext
+    incoming: Vec<u8>,
+    incoming_len: Option<usize>,
+    /// Noise message being written, and how much plaintext it carries
+    outgoing: Vec<u8>,
+    outgoing_pos: usize,
+    outgoing_plain: usize,
+}
+
+fn noise_error(e: snow::Error) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("noise: {}", e))
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> NoiseStream<S> {
+    /// Run the handshake as the connecting side, preamble included
+    pub async fn initiate(mut stream: S) -> Result<Self> {
+        stream.write_all(&NOISE_PREAMBLE).await?;
+        Self::handshake(stream, true).await
+    }
+
+    /// Run the handshake as the accepting side, after the preamble
+    pub async fn respond(stream: S) -> Result<Self> {
+        Self::handshake(stream, false).await
+    }
+
+    async fn handshake(mut stream: S, initiator: bool) -> Result<Self> {
+        let builder = snow::Builder::new(NOISE_PARAMS.parse().map_err(noise_error)?);
+        let keypair = builder.generate_keypair().map_err(noise_error)?;
+        let builder = builder.local_private_key(&keypair.private);
+        let mut handshake = if initiator {
+            builder.build_initiator()
+        } else {
+            builder.build_responder()
+        }
+        .map_err(noise_error)?;
+        let mut message = vec![0u8; MAX_NOISE_MESSAGE];
+        let mut payload = vec![0u8; MAX_NOISE_MESSAGE];
+        while !handshake.is_handshake_finished() {
+            if handshake.is_my_turn() {
+                let len = handshake
+                    .write_message(&[], &mut message)
+                    .map_err(noise_error)?;
+                stream.write_all(&(len as u16).to_be_bytes()).await?;
+                stream.write_all(&message[..len]).await?;
+            } else {
+                let mut len = [0u8; 2];
+                stream.read_exact(&mut len).await?;
+                let len = u16::from_be_bytes(len) as usize;
+                stream.read_exact(&mut message[..len]).await?;
+                handshake
+                    .read_message(&message[..len], &mut payload)
+                    .map_err(noise_error)?;
+            }
+        }
+        Ok(Self {
+            stream,
+            state: handshake.into_transport_mode().map_err(noise_error)?,
+            plaintext: vec![],
+            plaintext_pos: 0,
+            incoming: vec![],
+            incoming_len: None,
+            outgoing: vec![],
+            outgoing_pos: 0,
+            outgoing_plain: 0,
+        })
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for NoiseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.plaintext_pos < this.plaintext.len() {
+                let available = &this.plaintext[this.plaintext_pos..];
+                let n = available.len().min(buf.remaining());
+                buf.put_slice(&available[..n]);
+                this.plaintext_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            let needed = this.incoming_len.unwrap_or(2);
+            while this.incoming.len() < needed {
+                let start = this.incoming.len();
+                this.incoming.resize(needed, 0);
+                let mut read_buf = ReadBuf::new(&mut this.incoming[start..]);
+                let polled = Pin::new(&mut this.stream).poll_read(cx, &mut read_buf);
+                let read = read_buf.filled().len();
+                this.incoming.truncate(start + read);
+                ready!(polled)?;
+                if read == 0 {
+                    if start == 0 && this.incoming_len.is_none() {
+                        // clean end of stream between messages
+                        return Poll::Ready(Ok(()));
+                    }
+                    return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+                }
+            }
+            match this.incoming_len {
+                None => {
+                    this.incoming_len =
+                        Some(u16::from_be_bytes([this.incoming[0], this.incoming[1]]) as usize);
+                    this.incoming.clear();
+                }
+                Some(_) => {
+                    this.plaintext.resize(MAX_NOISE_MESSAGE, 0);
+                    let len = this
+                        .state
+                        .read_message(&this.incoming, &mut this.plaintext)
+                        .map_err(noise_error)?;
+                    this.plaintext.truncate(len);
+                    this.plaintext_pos = 0;
+                    this.incoming.clear();
+                    this.incoming_len = None;
+                }
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for NoiseStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        if this.outgoing.is_empty() {
+            if buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            let chunk = &buf[..buf.len().min(MAX_CHUNK)];
+            this.outgoing.resize(2 + chunk.len() + TAG_LEN, 0);
+            let len = this
+                .state
+                .write_message(chunk, &mut this.outgoing[2..])
+                .map_err(noise_error)?;
+            this.outgoing.truncate(2 + len);
+            this.outgoing[..2].copy_from_slice(&(len as u16).to_be_bytes());
+            this.outgoing_pos = 0;
+            this.outgoing_plain = chunk.len();
+        }
+        // a pending chunk belongs to the buffer of the previous call,
+        // which the caller has to pass again after `Poll::Pending`
+        while this.outgoing_pos < this.outgoing.len() {
+            let written = ready!(
+                Pin::new(&mut this.stream).poll_write(cx, &this.outgoing[this.outgoing_pos..])
+            )?;
+            if written == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            this.outgoing_pos += written;
+        }
+        this.outgoing.clear();
+        Poll::Ready(Ok(this.outgoing_plain))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,131 @@
+#[cfg(test)]
+mod tests {
+    use crate::network::Message;
+    use crate::transport::{socks5_connect, Connection, NoiseStream, NOISE_PREAMBLE, UNIX_PREFIX};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_noise_roundtrip_over_several_chunks() {
+        let (client, mut server) = duplex(4096);
+        let responder = tokio::spawn(async move {
+            let mut preamble = [0u8; 4];
+            server.read_exact(&mut preamble).await.unwrap();
+            assert_eq!(preamble, NOISE_PREAMBLE);
+            let mut stream = NoiseStream::respond(server).await.unwrap();
+            let mut received = vec![0u8; 200_000];
+            stream.read_exact(&mut received).await.unwrap();
+            stream.write_all(b"ack").await.unwrap();
+            received
+        });
+
+        let mut stream = NoiseStream::initiate(client).await.unwrap();
+        // bigger than one Noise message, so it's split and reassembled
+        let sent: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        stream.write_all(&sent).await.unwrap();
+        let mut ack = [0u8; 3];
+        stream.read_exact(&mut ack).await.unwrap();
+
+        assert_eq!(&ack, b"ack");
+        assert_eq!(responder.await.unwrap(), sent);
+    }
+
+    #[tokio::test]
+    async fn test_accept_negotiates_noise_and_falls_back_to_plain() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let mut encrypted = vec![];
+            for _ in 0..2 {
+                let (socket, _) = listener.accept().await.unwrap();
+                let mut connection = Connection::accept(socket).await.unwrap();
+                let message = Message::receive_async(&mut connection).await.unwrap();
+                assert!(matches!(message, Message::AskDifference(7)));
+                encrypted.push(connection.is_encrypted());
+            }
+            encrypted
+        });
+
+        for encrypt in [true, false] {
+            let mut connection = Connection::connect(&address, encrypt).await.unwrap();
+            assert_eq!(connection.is_encrypted(), encrypt);
+            Message::AskDifference(7)
+                .send_async(&mut connection)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(server.await.unwrap(), vec![true, false]);
+    }
+
+    #[tokio::test]
+    async fn test_socks5_connect_sends_hostname_and_reports_refusals() {
+        for (status, accepted) in [(0u8, true), (5, false)] {
+            let (mut client, mut proxy) = duplex(1024);
+            let server = tokio::spawn(async move {
+                let mut greeting = [0u8; 3];
+                proxy.read_exact(&mut greeting).await.unwrap();
+                assert_eq!(greeting, [5, 1, 0]);
+                proxy.write_all(&[5, 0]).await.unwrap();
+                let mut request = vec![0u8; 5 + "seed.example.onion".len() + 2];
+                proxy.read_exact(&mut request).await.unwrap();
+                // the proxy resolves the name, not us
+                assert_eq!(&request[..5], &[5, 1, 0, 3, 18]);
+                assert_eq!(&request[5..23], b"seed.example.onion");
+                assert_eq!(&request[23..], &9000u16.to_be_bytes());
+                proxy
+                    .write_all(&[5, status, 0, 1, 127, 0, 0, 1, 0x23, 0x28])
+                    .await
+                    .unwrap();
+                proxy.write_all(b"after").await.unwrap();
+            });
+
+            let result = socks5_connect(&mut client, "seed.example.onion:9000").await;
+            server.await.unwrap();
+            assert_eq!(result.is_ok(), accepted);
+            if accepted {
+                // the bound address was consumed, the stream is the peer's
+                let mut after = [0u8; 5];
+                client.read_exact(&mut after).await.unwrap();
+                assert_eq!(&after, b"after");
+            } else {
+                assert!(result
+                    .unwrap_err()
+                    .to_string()
+                    .contains("connection refused"));
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_connect_over_unix_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("node.sock");
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        let server = tokio::spawn(async move {
+            let mut local = vec![];
+            for _ in 0..2 {
+                let (socket, _) = listener.accept().await.unwrap();
+                let mut connection = Connection::accept(socket).await.unwrap();
+                let message = Message::receive_async(&mut connection).await.unwrap();
+                assert!(matches!(message, Message::AskDifference(7)));
+                assert!(connection.peer_addr().is_err());
+                local.push((connection.is_local(), connection.is_encrypted()));
+            }
+            local
+        });
+
+        let address = format!("{}{}", UNIX_PREFIX, path.display());
+        for encrypt in [true, false] {
+            let mut connection = Connection::connect(&address, encrypt).await.unwrap();
+            assert!(connection.is_local());
+            Message::AskDifference(7)
+                .send_async(&mut connection)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(server.await.unwrap(), vec![(true, true), (true, false)]);
+    }
+}
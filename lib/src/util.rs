@@ -1,9 +1,14 @@
+use crate::config;
+use crate::encoding::{Decode, DecodeError, Encode};
 use crate::sha256::Hash;
-use crate::types::Transaction;
+use crate::types::{Block, Transaction};
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::{Read, Result as IoResult, Write};
-use std::path::Path;
+use std::fs::{self, File, OpenOptions};
+use std::io::{
+    BufWriter, Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Seek,
+    SeekFrom, Write,
+};
+use std::path::{Path, PathBuf};
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct MerkleRoot(Hash);
@@ -48,13 +53,19 @@ impl MerkleRoot {
     /// - **Efficient verification**: Can prove a transaction is in block with O(log n) hashes
     /// - **Tamper evidence**: Changing any transaction changes the root
     /// - **Light clients**: Don't need all transactions to verify inclusion
+    ///
+    /// No transactions give the all-zero root. Valid blocks always have a
+    /// coinbase, but blocks from peers are hashed before that is checked.
     pub fn calculate(transactions: &[Transaction]) -> MerkleRoot {
+        if transactions.is_empty() {
+            return MerkleRoot(Hash::zero());
+        }
         // STEP 1: Create the bottom layer (leaf nodes)
         // =============================================
-        // Hash each transaction to form the leaves of the tree
+        // The transaction hashes are the leaves of the tree
         let mut layer: Vec<Hash> = vec![];
         for transaction in transactions {
-            layer.push(Hash::hash(transaction));
+            layer.push(transaction.hash());
         }
 
         // STEP 2: Build tree bottom-up until we have a single root
@@ -73,7 +84,7 @@ impl MerkleRoot {
 
                 // Combine the pair by hashing them together
                 // H(left || right) where || means concatenation
-                new_layer.push(Hash::hash(&[left, *right]));
+                new_layer.push(Self::combine(left, *right));
             }
 
             // Move up one level in the tree
@@ -84,6 +95,32 @@ impl MerkleRoot {
         // ===============================================
         MerkleRoot(layer[0])
     }
+
+    /// Hash of two sibling nodes: their 64 bytes, hashed with the
+    /// network's hashing scheme
+    pub(crate) fn combine(left: Hash, right: Hash) -> Hash {
+        let mut bytes = left.encode();
+        right.encode_to(&mut bytes);
+        Hash::digest(config::hash_scheme(), &bytes)
+    }
+}
+
+impl std::fmt::Display for MerkleRoot {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Encode for MerkleRoot {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.0.encode_to(out);
+    }
+}
+
+impl Decode for MerkleRoot {
+    fn decode_from(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(MerkleRoot(Hash::decode_from(input)?))
+    }
 }
 
 pub trait Saveable
@@ -92,9 +129,16 @@ where
 {
     fn load<I: Read>(reader: I) -> IoResult<Self>;
     fn save<O: Write>(&self, writer: O) -> IoResult<()>;
+    /// Save to `path` atomically: the data is written to a temporary
+    /// file next to it, fsynced and renamed over the target, so a crash
+    /// mid-write leaves either the old or the new file, never a torn one
     fn save_to_file<P: AsRef<Path>>(&self, path: P) -> IoResult<()> {
-        let file = File::create(&path)?;
-        self.save(file)
+        self.save_to_file_with_backups(path, 0)
+    }
+    /// Like `save_to_file`, but keeps the `backups` previous versions
+    /// of the file as `path.1` (newest) to `path.N` (oldest)
+    fn save_to_file_with_backups<P: AsRef<Path>>(&self, path: P, backups: usize) -> IoResult<()> {
+        replace_file(path.as_ref(), backups, |writer| self.save(writer))
     }
     fn load_from_file<P: AsRef<Path>>(path: P) -> IoResult<Self> {
         let file = File::open(&path)?;
@@ -102,5 +146,247 @@ where
     }
 }
 
+/// Write `path` through a temporary file next to it, fsynced and renamed
+/// over the target, keeping `backups` previous versions
+pub(crate) fn replace_file<F>(path: &Path, backups: usize, write: F) -> IoResult<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> IoResult<()>,
+{
+    let tmp = suffixed_path(path, "tmp");
+    let written = File::create(&tmp).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        write(&mut writer)?;
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()
+    });
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    if backups > 0 && path.exists() {
+        rotate_backups(path, backups)?;
+    }
+    fs::rename(&tmp, path)?;
+    sync_parent_dir(path)
+}
+
+/// `path` with `.suffix` appended to its file name
+pub fn suffixed_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Shift `path.1`..`path.N-1` up by one and make the current file `path.1`.
+/// The current file is hard-linked rather than moved, so `path` keeps
+/// existing until the new version is renamed over it.
+fn rotate_backups(path: &Path, backups: usize) -> IoResult<()> {
+    for i in (1..backups).rev() {
+        let from = suffixed_path(path, &i.to_string());
+        if from.exists() {
+            fs::rename(&from, suffixed_path(path, &(i + 1).to_string()))?;
+        }
+    }
+    let newest = suffixed_path(path, "1");
+    if newest.exists() {
+        fs::remove_file(&newest)?;
+    }
+    if fs::hard_link(path, &newest).is_err() {
+        // filesystems without hard links: fall back to a copy
+        fs::copy(path, &newest)?;
+    }
+    Ok(())
+}
+
+/// Make a rename inside the parent directory durable
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> IoResult<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => File::open(parent)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> IoResult<()> {
+    Ok(())
+}
+
+/// Maximum size of a single block file before rolling over to the next one
+pub const MAX_BLOCK_FILE_SIZE: u64 = 128 * 1024 * 1024;
+
+/// Size of one entry in the block index file
+const INDEX_ENTRY_SIZE: usize = 16;
+
+/// Location of a block inside the block files
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockLocation {
+    /// Number of the `blkNNNNN.dat` file
+    pub file: u32,
+    /// Byte offset of the block record inside that file
+    pub offset: u64,
+    /// Length of the CBOR-encoded block
+    pub len: u32,
+}
+
+impl BlockLocation {
+    fn to_bytes(self) -> [u8; INDEX_ENTRY_SIZE] {
+        let mut bytes = [0u8; INDEX_ENTRY_SIZE];
+        bytes[0..4].copy_from_slice(&self.file.to_le_bytes());
+        bytes[4..12].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.len.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        BlockLocation {
+            file: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            offset: u64::from_le_bytes(bytes[4..12].try_into().unwrap()),
+            len: u32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+        }
+    }
+}
+
+/// Append-only block storage, similar to Bitcoin Core's `blk*.dat` files.
+///
+/// Blocks are appended to `blk00000.dat`, `blk00001.dat`, ... as they
+/// arrive, each record being a 4-byte little-endian length followed by the
+/// CBOR-encoded block. A separate `index.dat` holds one fixed-size
+/// [`BlockLocation`] per height, so block N is found with a single seek:
+///
+/// ```text
+/// index.dat:    [height 0][height 1][height 2]...   (16 bytes each)
+///                   |         |         |
+/// blk00000.dat: [len|block][len|block][len|block]...
+/// ```
+///
+/// Nothing is ever rewritten: saving a new block is one append to the
+/// current block file plus one append to the index.
+pub struct BlockFiles {
+    dir: PathBuf,
+    index: Vec<BlockLocation>,
+}
+
+impl BlockFiles {
+    /// Open (or create) block files in `dir`.
+    ///
+    /// Index entries pointing past the end of their block file (e.g. after
+    /// a crash between the two appends) are dropped, and so is a partial
+    /// entry torn by a crash mid-append, so the next append stays aligned.
+    pub fn open<P: AsRef<Path>>(dir: P) -> IoResult<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+        let mut index = vec![];
+        let mut torn = false;
+        let index_path = dir.join("index.dat");
+        if index_path.exists() {
+            let bytes = std::fs::read(&index_path)?;
+            let entries = bytes.chunks_exact(INDEX_ENTRY_SIZE);
+            torn = !entries.remainder().is_empty();
+            for entry in entries {
+                index.push(BlockLocation::from_bytes(entry));
+            }
+        }
+        let mut block_files = BlockFiles { dir, index };
+        let valid = block_files
+            .index
+            .iter()
+            .take_while(|location| {
+                let file_len = std::fs::metadata(block_files.block_file_path(location.file))
+                    .map(|metadata| metadata.len())
+                    .unwrap_or(0);
+                location.offset + 4 + location.len as u64 <= file_len
+            })
+            .count();
+        if torn || valid < block_files.index.len() {
+            block_files.truncate(valid as u64)?;
+        }
+        Ok(block_files)
+    }
+
+    /// Number of stored blocks
+    pub fn len(&self) -> u64 {
+        self.index.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Location of the block at `height`
+    pub fn location(&self, height: u64) -> Option<BlockLocation> {
+        self.index.get(height as usize).copied()
+    }
+
+    fn block_file_path(&self, file: u32) -> PathBuf {
+        self.dir.join(format!("blk{:05}.dat", file))
+    }
+
+    /// Append a block, returning its location
+    pub fn append(&mut self, block: &Block) -> IoResult<BlockLocation> {
+        let mut bytes = vec![];
+        ciborium::into_writer(block, &mut bytes)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialize Block"))?;
+        let (mut file_number, mut offset) = match self.index.last() {
+            Some(last) => (last.file, last.offset + 4 + last.len as u64),
+            None => (0, 0),
+        };
+        if offset > 0 && offset + 4 + bytes.len() as u64 > MAX_BLOCK_FILE_SIZE {
+            file_number += 1;
+            offset = 0;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(self.block_file_path(file_number))?;
+        // overwrite anything left behind by a crash after the last indexed block
+        file.set_len(offset)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&(bytes.len() as u32).to_le_bytes())?;
+        file.write_all(&bytes)?;
+        file.sync_data()?;
+
+        let location = BlockLocation {
+            file: file_number,
+            offset,
+            len: bytes.len() as u32,
+        };
+        let mut index = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join("index.dat"))?;
+        index.write_all(&location.to_bytes())?;
+        index.sync_data()?;
+        self.index.push(location);
+        Ok(location)
+    }
+
+    /// Read the block at `height`
+    pub fn read(&self, height: u64) -> IoResult<Option<Block>> {
+        let Some(location) = self.location(height) else {
+            return Ok(None);
+        };
+        let mut file = File::open(self.block_file_path(location.file))?;
+        file.seek(SeekFrom::Start(location.offset + 4))?;
+        let mut bytes = vec![0u8; location.len as usize];
+        file.read_exact(&mut bytes)?;
+        Block::load(bytes.as_slice()).map(Some)
+    }
+
+    /// Drop all blocks from `height` onwards (e.g. when the chain was
+    /// replaced). The index is rewritten atomically, like
+    /// [`Saveable::save_to_file`], so a crash leaves the old or the new one.
+    pub fn truncate(&mut self, height: u64) -> IoResult<()> {
+        self.index.truncate(height as usize);
+        replace_file(&self.dir.join("index.dat"), 0, |writer| {
+            for location in &self.index {
+                writer.write_all(&location.to_bytes())?;
+            }
+            Ok(())
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests;
@@ -1,30 +1,38 @@
 #[cfg(test)]
 mod tests {
     use crate::crypto::PrivateKey;
-    use crate::sha256::Hash;
     use crate::test_helpers::create_test_output;
     use crate::types::Transaction;
     use crate::util::MerkleRoot;
 
+    #[test]
+    fn test_merkle_root_no_transactions() {
+        // peers can send empty blocks, hashing them must not panic
+        assert_eq!(
+            MerkleRoot::calculate(&[]).to_string(),
+            crate::sha256::Hash::zero().to_string()
+        );
+    }
+
     #[test]
     fn test_merkle_root_single_transaction() {
-        let mut private_key = PrivateKey::new_key();
-        let output = create_test_output(100, &mut private_key);
+        let private_key = PrivateKey::new_key();
+        let output = create_test_output(100, &private_key);
         let transaction = Transaction::new(vec![], vec![output]);
         let transactions = vec![transaction];
 
         let merkle_root = MerkleRoot::calculate(&transactions);
 
         // Single transaction: root should be hash of that transaction
-        assert_eq!(merkle_root.0, Hash::hash(&transactions[0]));
+        assert_eq!(merkle_root.0, transactions[0].hash());
     }
 
     #[test]
     fn test_merkle_root_two_transactions() {
-        let mut private_key = PrivateKey::new_key();
+        let private_key = PrivateKey::new_key();
 
-        let output1 = create_test_output(100, &mut private_key);
-        let output2 = create_test_output(200, &mut private_key);
+        let output1 = create_test_output(100, &private_key);
+        let output2 = create_test_output(200, &private_key);
 
         let tx1 = Transaction::new(vec![], vec![output1]);
         let tx2 = Transaction::new(vec![], vec![output2]);
@@ -33,9 +41,9 @@ mod tests {
         let merkle_root = MerkleRoot::calculate(&transactions);
 
         // Two transactions: root should be hash of [H(tx1), H(tx2)]
-        let hash1 = Hash::hash(&transactions[0]);
-        let hash2 = Hash::hash(&transactions[1]);
-        let expected = Hash::hash(&[hash1, hash2]);
+        let hash1 = transactions[0].hash();
+        let hash2 = transactions[1].hash();
+        let expected = MerkleRoot::combine(hash1, hash2);
 
         assert_eq!(merkle_root.0, expected);
     }
@@ -43,11 +51,11 @@ mod tests {
     #[test]
     fn test_merkle_root_three_transactions() {
         // Tests the odd number duplication behavior
-        let mut private_key = PrivateKey::new_key();
+        let private_key = PrivateKey::new_key();
 
-        let output1 = create_test_output(100, &mut private_key);
-        let output2 = create_test_output(200, &mut private_key);
-        let output3 = create_test_output(300, &mut private_key);
+        let output1 = create_test_output(100, &private_key);
+        let output2 = create_test_output(200, &private_key);
+        let output3 = create_test_output(300, &private_key);
 
         let tx1 = Transaction::new(vec![], vec![output1]);
         let tx2 = Transaction::new(vec![], vec![output2]);
@@ -57,29 +65,29 @@ mod tests {
         let merkle_root = MerkleRoot::calculate(&transactions);
 
         // Three transactions: should duplicate the last one
-        let hash1 = Hash::hash(&transactions[0]);
-        let hash2 = Hash::hash(&transactions[1]);
-        let hash3 = Hash::hash(&transactions[2]);
+        let hash1 = transactions[0].hash();
+        let hash2 = transactions[1].hash();
+        let hash3 = transactions[2].hash();
 
         // First level: [(H1, H2), (H3, H3)]
-        let left = Hash::hash(&[hash1, hash2]);
-        let right = Hash::hash(&[hash3, hash3]);
+        let left = MerkleRoot::combine(hash1, hash2);
+        let right = MerkleRoot::combine(hash3, hash3);
 
         // Second level: hash of the two results
-        let expected = Hash::hash(&[left, right]);
+        let expected = MerkleRoot::combine(left, right);
 
         assert_eq!(merkle_root.0, expected);
     }
 
     #[test]
     fn test_merkle_root_four_transactions() {
-        let mut private_key = PrivateKey::new_key();
+        let private_key = PrivateKey::new_key();
 
         let outputs = vec![
-            create_test_output(100, &mut private_key),
-            create_test_output(200, &mut private_key),
-            create_test_output(300, &mut private_key),
-            create_test_output(400, &mut private_key),
+            create_test_output(100, &private_key),
+            create_test_output(200, &private_key),
+            create_test_output(300, &private_key),
+            create_test_output(400, &private_key),
         ];
 
         let transactions: Vec<Transaction> = outputs
@@ -91,14 +99,14 @@ mod tests {
 
         // Should handle even number cleanly
         // Structure: H(H(H1,H2), H(H3,H4))
-        let hash1 = Hash::hash(&transactions[0]);
-        let hash2 = Hash::hash(&transactions[1]);
-        let hash3 = Hash::hash(&transactions[2]);
-        let hash4 = Hash::hash(&transactions[3]);
+        let hash1 = transactions[0].hash();
+        let hash2 = transactions[1].hash();
+        let hash3 = transactions[2].hash();
+        let hash4 = transactions[3].hash();
 
-        let left = Hash::hash(&[hash1, hash2]);
-        let right = Hash::hash(&[hash3, hash4]);
-        let expected = Hash::hash(&[left, right]);
+        let left = MerkleRoot::combine(hash1, hash2);
+        let right = MerkleRoot::combine(hash3, hash4);
+        let expected = MerkleRoot::combine(left, right);
 
         assert_eq!(merkle_root.0, expected);
     }
@@ -106,10 +114,10 @@ mod tests {
     #[test]
     fn test_merkle_root_consistency() {
         // Same transactions should produce same root
-        let mut private_key = PrivateKey::new_key();
+        let private_key = PrivateKey::new_key();
 
-        let output1 = create_test_output(100, &mut private_key);
-        let output2 = create_test_output(200, &mut private_key);
+        let output1 = create_test_output(100, &private_key);
+        let output2 = create_test_output(200, &private_key);
 
         let tx1 = Transaction::new(vec![], vec![output1]);
         let tx2 = Transaction::new(vec![], vec![output2]);
@@ -126,10 +134,10 @@ mod tests {
     #[test]
     fn test_merkle_root_different_transactions_different_root() {
         // Different transactions should produce different roots
-        let mut private_key = PrivateKey::new_key();
+        let private_key = PrivateKey::new_key();
 
-        let tx1 = Transaction::new(vec![], vec![create_test_output(100, &mut private_key)]);
-        let tx2 = Transaction::new(vec![], vec![create_test_output(200, &mut private_key)]);
+        let tx1 = Transaction::new(vec![], vec![create_test_output(100, &private_key)]);
+        let tx2 = Transaction::new(vec![], vec![create_test_output(200, &private_key)]);
 
         let transactions1 = vec![tx1.clone()];
         let transactions2 = vec![tx2];
@@ -140,3 +148,142 @@ mod tests {
         assert_ne!(root1, root2);
     }
 }
+
+#[cfg(test)]
+mod block_files_tests {
+    use crate::crypto::PrivateKey;
+    use crate::sha256::Hash;
+    use crate::test_helpers::create_test_output;
+    use crate::types::{Block, BlockHeader, Transaction};
+    use crate::util::{BlockFiles, MerkleRoot};
+    use crate::U256;
+    use chrono::Utc;
+
+    fn test_block(value: u64) -> Block {
+        let private_key = PrivateKey::new_key();
+        let transaction = Transaction::new(vec![], vec![create_test_output(value, &private_key)]);
+        Block::new(
+            BlockHeader::new(
+                Utc::now(),
+                0,
+                Hash::zero(),
+                MerkleRoot::calculate(std::slice::from_ref(&transaction)),
+                U256::MAX,
+            ),
+            vec![transaction],
+        )
+    }
+
+    #[test]
+    fn test_append_and_read_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut files = BlockFiles::open(dir.path()).unwrap();
+        let first = test_block(100);
+        let second = test_block(200);
+        files.append(&first).unwrap();
+        let location = files.append(&second).unwrap();
+
+        assert_eq!(files.len(), 2);
+        assert_eq!(location.file, 0);
+        assert!(location.offset > 0);
+        assert_eq!(files.read(0).unwrap().unwrap().hash(), first.hash());
+        assert_eq!(files.read(1).unwrap().unwrap().hash(), second.hash());
+        assert!(files.read(2).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_index_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let block = test_block(100);
+        {
+            let mut files = BlockFiles::open(dir.path()).unwrap();
+            files.append(&block).unwrap();
+        }
+        let files = BlockFiles::open(dir.path()).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files.read(0).unwrap().unwrap().hash(), block.hash());
+    }
+
+    #[test]
+    fn test_truncate_then_append_overwrites_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut files = BlockFiles::open(dir.path()).unwrap();
+        files.append(&test_block(100)).unwrap();
+        files.append(&test_block(200)).unwrap();
+        files.truncate(1).unwrap();
+        let replacement = test_block(300);
+        files.append(&replacement).unwrap();
+
+        let files = BlockFiles::open(dir.path()).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files.read(1).unwrap().unwrap().hash(), replacement.hash());
+    }
+
+    #[test]
+    fn test_torn_index_entry_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = test_block(100);
+        {
+            let mut files = BlockFiles::open(dir.path()).unwrap();
+            files.append(&first).unwrap();
+        }
+        // a crash in the middle of appending the next index entry
+        let index_path = dir.path().join("index.dat");
+        let mut index = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&index_path)
+            .unwrap();
+        std::io::Write::write_all(&mut index, &[1, 2, 3, 4, 5]).unwrap();
+        drop(index);
+
+        let mut files = BlockFiles::open(dir.path()).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(std::fs::metadata(&index_path).unwrap().len(), 16);
+        let second = test_block(200);
+        files.append(&second).unwrap();
+
+        let files = BlockFiles::open(dir.path()).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files.read(0).unwrap().unwrap().hash(), first.hash());
+        assert_eq!(files.read(1).unwrap().unwrap().hash(), second.hash());
+    }
+}
+
+#[cfg(test)]
+mod atomic_save_tests {
+    use crate::crypto::PrivateKey;
+    use crate::util::{suffixed_path, Saveable};
+
+    #[test]
+    fn test_save_replaces_file_without_leftovers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key.cbor");
+        let first = PrivateKey::new_key();
+        let second = PrivateKey::new_key();
+        first.save_to_file(&path).unwrap();
+        second.save_to_file(&path).unwrap();
+
+        let loaded = PrivateKey::load_from_file(&path).unwrap();
+        assert_eq!(loaded.public_key(), second.public_key());
+        assert!(!suffixed_path(&path, "tmp").exists());
+        assert!(!suffixed_path(&path, "1").exists());
+    }
+
+    #[test]
+    fn test_backups_rotate_and_keep_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key.cbor");
+        let keys: Vec<_> = (0..4).map(|_| PrivateKey::new_key()).collect();
+        for key in &keys {
+            key.save_to_file_with_backups(&path, 2).unwrap();
+        }
+
+        let current = PrivateKey::load_from_file(&path).unwrap();
+        let newest = PrivateKey::load_from_file(suffixed_path(&path, "1")).unwrap();
+        let oldest = PrivateKey::load_from_file(suffixed_path(&path, "2")).unwrap();
+        assert_eq!(current.public_key(), keys[3].public_key());
+        assert_eq!(newest.public_key(), keys[2].public_key());
+        assert_eq!(oldest.public_key(), keys[1].public_key());
+        assert!(!suffixed_path(&path, "3").exists());
+    }
+}
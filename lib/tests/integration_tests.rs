@@ -98,7 +98,7 @@
 //! - See `docker/README.md` for instructions
 
 use btclib::crypto::PrivateKey;
-use btclib::types::{Block, BlockHeader, Blockchain, Transaction, TransactionOutput};
+use btclib::types::{Block, BlockHeader, Blockchain, Mempool, Transaction, TransactionOutput};
 use btclib::util::MerkleRoot;
 use btclib::config;
 use chrono::Utc;
@@ -113,7 +113,7 @@ use uuid::Uuid;
 ///
 /// # Returns
 /// A `TransactionOutput` ready to be included in a transaction
-fn create_test_output(value: u64, private_key: &mut PrivateKey) -> TransactionOutput {
+fn create_test_output(value: u64, private_key: &PrivateKey) -> TransactionOutput {
     TransactionOutput {
         value,
         unique_id: Uuid::new_v4(),
@@ -134,14 +134,14 @@ fn create_test_output(value: u64, private_key: &mut PrivateKey) -> TransactionOu
 /// A tuple of (blockchain instance, miner private key) ready for testing
 fn create_blockchain_with_genesis(_initial_balance: u64) -> (Blockchain, PrivateKey) {
     let mut blockchain = Blockchain::new();
-    let mut miner_key = PrivateKey::new_key();
+    let miner_key = PrivateKey::new_key();
     
     // Get the initial reward from config
     let reward = config::initial_reward() * 100_000_000;
     
     // Create genesis block with a proper coinbase transaction
     // For genesis, we create a coinbase transaction (no inputs) that creates UTXOs
-    let genesis_output = create_test_output(reward, &mut miner_key);
+    let genesis_output = create_test_output(reward, &miner_key);
     let genesis_tx = Transaction::new(vec![], vec![genesis_output]);
     
     let genesis_block = Block::new(
@@ -219,30 +219,29 @@ fn test_genesis_block_creation() {
 #[test]
 fn test_add_transaction_to_mempool() {
     // Start with a fresh blockchain (has genesis block with UTXOs)
-    let (mut blockchain, miner_key) = create_blockchain_with_genesis(1000);
+    let (blockchain, miner_key) = create_blockchain_with_genesis(1000);
     
     // Get the first available UTXO from the genesis block
     let utxo_hash = blockchain.utxos().keys().next().unwrap().clone();
     
     // Create a valid transaction that spends the UTXO
     let recipient_key = PrivateKey::new_key();
-    let mut miner_key_copy = miner_key; // Copy for signing
     let tx_input = btclib::types::TransactionInput {
         prev_transaction_output_hash: utxo_hash,
-        signature: btclib::crypto::Signature::sign_output(&utxo_hash, &mut miner_key_copy),
+        signature: btclib::crypto::Signature::sign_output(&utxo_hash, &miner_key),
     };
     
-    let mut recipient_key_copy = recipient_key;
-    let tx_output = create_test_output(500, &mut recipient_key_copy);
+    let tx_output = create_test_output(500, &recipient_key);
     
     let transaction = Transaction::new(vec![tx_input], vec![tx_output]);
     
     // Add to mempool - should succeed if UTXO is valid
-    let result = blockchain.add_to_mempool(transaction);
+    let mut mempool = Mempool::default();
+    let result = mempool.add(&blockchain, transaction);
     assert!(result.is_ok(), "Transaction should be added to mempool");
     
     // Verify mempool has 1 transaction
-    assert_eq!(blockchain.mempool().len(), 1);
+    assert_eq!(mempool.len(), 1);
 }
 
 /// Test #3: Multiple Blocks
@@ -280,12 +279,12 @@ fn test_multiple_blocks() {
     // For integration tests, we're verifying the blockchain structure works
     // Full transaction validation is already tested in unit tests
     let prev_hash = blockchain.blocks().last().unwrap().hash();
-    let mut new_miner_key = PrivateKey::new_key();
+    let new_miner_key = PrivateKey::new_key();
     
     // Use same reward as genesis for simplicity
     let block_reward = config::initial_reward() * 100_000_000;
-    let coinbase_output = create_test_output(block_reward, &mut new_miner_key);
-    let coinbase_tx = Transaction::new(vec![], vec![coinbase_output]);
+    let coinbase_output = create_test_output(block_reward, &new_miner_key);
+    let coinbase_tx = Transaction::coinbase(1, vec![coinbase_output]);
     
     let mut block = Block::new(
         BlockHeader::new(
@@ -1,11 +1,15 @@
-use super::{Transaction, TransactionInput, TransactionOutput};
+use super::{Transaction, TransactionOutput};
+use crate::amount::Amount;
+use crate::config::NetworkConfig;
+use crate::crypto::PublicKey;
+use crate::encoding::{Decode, DecodeError, Encode};
 use crate::error::{BtcError, Result};
 use crate::sha256::Hash;
 use crate::util::{MerkleRoot, Saveable};
-use crate::{config, U256};
+use crate::U256;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -22,16 +26,18 @@ impl Block {
         }
     }
 
+    /// The block's hash, which is its header's hash as in Bitcoin. The
+    /// header commits to the transactions through the Merkle root.
     pub fn hash(&self) -> Hash {
-        Hash::hash(&self)
+        self.header.hash()
     }
 
     pub fn verify_transactions(
         &self,
+        params: &NetworkConfig,
         predicted_block_height: u64,
         utxos: &HashMap<Hash, (bool, TransactionOutput)>,
     ) -> Result<()> {
-        let mut inputs: HashMap<Hash, TransactionInput> = HashMap::new();
         // reject completely empty blocks
         if self.transactions.is_empty() {
             return Err(BtcError::InvalidTransaction {
@@ -39,12 +45,13 @@ impl Block {
             });
         }
 
+        self.verify_no_duplicates()?;
+
         // verify coinbase transaction
-        self.verify_coinbase_transaction(predicted_block_height, utxos)?;
+        self.verify_coinbase_transaction(params, predicted_block_height, utxos)?;
 
         for transaction in self.transactions.iter().skip(1) {
-            let mut input_value = 0;
-            let mut output_value = 0;
+            let mut input_values = Vec::with_capacity(transaction.inputs.len());
             for input in &transaction.inputs {
                 let prev_output = utxos
                     .get(&input.prev_transaction_output_hash)
@@ -58,12 +65,6 @@ impl Block {
                     });
                 }
                 let prev_output = prev_output.unwrap();
-                // prevent same-block double-spending
-                if inputs.contains_key(&input.prev_transaction_output_hash) {
-                    return Err(BtcError::InvalidTransaction {
-                        reason: "double-spend detected within same block".into(),
-                    });
-                }
                 // check if the signature is valid
                 if !input
                     .signature
@@ -71,12 +72,10 @@ impl Block {
                 {
                     return Err(BtcError::InvalidSignature);
                 }
-                input_value += prev_output.value;
-                inputs.insert(input.prev_transaction_output_hash, input.clone());
-            }
-            for output in &transaction.outputs {
-                output_value += output.value;
+                input_values.push(prev_output.value);
             }
+            let input_value = Amount::total(input_values)?;
+            let output_value = transaction.output_value()?;
             // It is fine for output value to be less than input value
             // as the difference is the fee for the miner
             if input_value < output_value {
@@ -91,39 +90,170 @@ impl Block {
         Ok(())
     }
 
+    /// The header may not fall behind the consensus version at `height`
+    /// and no transaction may use a version that hasn't activated yet
+    pub fn verify_versions(&self, params: &NetworkConfig, height: u64) -> Result<()> {
+        let required = params.version_at(height);
+        if self.header.version < required {
+            return Err(BtcError::ObsoleteBlockVersion {
+                version: self.header.version,
+                required,
+            });
+        }
+        for transaction in &self.transactions {
+            verify_transaction_version(params, transaction, height)?;
+        }
+        Ok(())
+    }
+
+    /// No transaction may appear twice in the block, and no output may be
+    /// spent twice, whether by one transaction or by two different ones
+    fn verify_no_duplicates(&self) -> Result<()> {
+        let mut transactions: HashSet<Hash> = HashSet::new();
+        let mut spent: HashSet<Hash> = HashSet::new();
+        for transaction in &self.transactions {
+            let hash = transaction.hash();
+            if !transactions.insert(hash) {
+                return Err(BtcError::DuplicateTransaction { hash });
+            }
+            for input in &transaction.inputs {
+                let output = input.prev_transaction_output_hash;
+                if !spent.insert(output) {
+                    return Err(BtcError::DuplicateSpend { output });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Split the miner's coinbase output among `payees` in proportion to
+    /// their weights, e.g. to pay out a small pool, and recompute the
+    /// Merkle root. The first payee takes over the miner's output along
+    /// with what rounding leaves; the others are appended after the other
+    /// coinbase outputs, so genesis allocations stay where they are.
+    pub fn split_coinbase(&mut self, payees: &[(PublicKey, u64)]) -> Result<()> {
+        let total_weight = payees
+            .iter()
+            .try_fold(0u64, |total, (_, weight)| total.checked_add(*weight))
+            .ok_or_else(|| BtcError::InvalidBlock {
+                reason: "coinbase split weights add up past u64".into(),
+            })?;
+        if total_weight == 0 {
+            return Err(BtcError::InvalidBlock {
+                reason: "coinbase split needs payees with a weight above 0".into(),
+            });
+        }
+        let coinbase = self
+            .transactions
+            .first_mut()
+            .filter(|coinbase| !coinbase.outputs.is_empty())
+            .ok_or(BtcError::MissingCoinbase)?;
+        let reward = coinbase.outputs[0].value;
+        let shares: Vec<u64> = payees
+            .iter()
+            .map(|(_, weight)| (reward as u128 * *weight as u128 / total_weight as u128) as u64)
+            .collect();
+        let leftover = reward - shares.iter().sum::<u64>();
+        for (index, ((pubkey, _), share)) in payees.iter().zip(shares).enumerate() {
+            if index == 0 {
+                coinbase.outputs[0].pubkey = pubkey.clone();
+                coinbase.outputs[0].value = share + leftover;
+            } else if share > 0 {
+                coinbase.outputs.push(TransactionOutput {
+                    value: share,
+                    unique_id: uuid::Uuid::new_v4(),
+                    pubkey: pubkey.clone(),
+                });
+            }
+        }
+        self.header.merkle_root = MerkleRoot::calculate(&self.transactions);
+        Ok(())
+    }
+
+    /// The genesis block must pay the configured genesis allocations, in
+    /// order, right after the miner's own coinbase output
+    pub fn verify_genesis_allocations(&self, params: &NetworkConfig) -> Result<()> {
+        let allocations = &params.genesis_allocations;
+        if allocations.is_empty() {
+            return Ok(());
+        }
+        let paid = self
+            .transactions
+            .first()
+            .and_then(|coinbase| coinbase.outputs.get(1..=allocations.len()));
+        let matches = paid.is_some_and(|paid| {
+            paid.iter().zip(allocations).all(|(output, allocation)| {
+                output.pubkey == allocation.pubkey && output.value == allocation.value
+            })
+        });
+        if !matches {
+            return Err(BtcError::InvalidBlock {
+                reason: "genesis block doesn't pay the genesis allocations".into(),
+            });
+        }
+        Ok(())
+    }
+
+    /// The coinbase rules: exactly one coinbase, first in the block,
+    /// without inputs, committing to the block height and paying at most
+    /// the block reward plus the fees. A transaction counts as a coinbase
+    /// if it has no inputs or carries a block height.
     fn verify_coinbase_transaction(
         &self,
+        params: &NetworkConfig,
         predicted_block_height: u64,
         utxos: &HashMap<Hash, (bool, TransactionOutput)>,
     ) -> Result<()> {
-        // coinbase tx is the first transaction in the block
+        let coinbases: Vec<usize> = self
+            .transactions
+            .iter()
+            .enumerate()
+            .filter(|(_, transaction)| is_coinbase(transaction))
+            .map(|(position, _)| position)
+            .collect();
+        match coinbases.as_slice() {
+            [] => return Err(BtcError::MissingCoinbase),
+            [0] => {}
+            [position] => {
+                return Err(BtcError::CoinbaseNotFirst {
+                    position: *position,
+                })
+            }
+            _ => {
+                return Err(BtcError::MultipleCoinbases {
+                    count: coinbases.len(),
+                })
+            }
+        }
         let coinbase_transaction = &self.transactions[0];
-        if coinbase_transaction.inputs.len() != 0 {
-            return Err(BtcError::InvalidTransaction {
-                reason: "coinbase transaction cannot have inputs".into(),
-            });
+        if !coinbase_transaction.inputs.is_empty() {
+            return Err(BtcError::CoinbaseHasInputs);
         }
         if coinbase_transaction.outputs.len() == 0 {
             return Err(BtcError::InvalidTransaction {
                 reason: "coinbase transaction has no outputs".into(),
             });
         }
-        let miner_fees = self.calculate_miner_fees(utxos)?;
-        let block_reward = config::initial_reward() * 10u64.pow(8)
-            / 2u64.pow((predicted_block_height / config::halving_interval()) as u32);
-        let total_coinbase_outputs: u64 = coinbase_transaction
-            .outputs
-            .iter()
-            .map(|output| output.value)
-            .sum();
-        if total_coinbase_outputs != block_reward + miner_fees {
+        if coinbase_transaction.coinbase_height != Some(predicted_block_height) {
             return Err(BtcError::InvalidTransaction {
                 reason: format!(
-                    "coinbase output value {} doesn't match reward {} + fees {}",
-                    total_coinbase_outputs, block_reward, miner_fees
+                    "coinbase must commit to block height {}",
+                    predicted_block_height
                 ),
             });
         }
+        let miner_fees = self.calculate_miner_fees(utxos)?;
+        let block_reward = params.block_reward(predicted_block_height);
+        let total_coinbase_outputs = coinbase_transaction.output_value()?;
+        let allowed = Amount::from_sat(block_reward)?.checked_add(Amount::from_sat(miner_fees)?);
+        // paying less is allowed, the rest is never created
+        if allowed.is_none_or(|allowed| total_coinbase_outputs > allowed) {
+            return Err(BtcError::CoinbaseOverpays {
+                value: total_coinbase_outputs.to_sat(),
+                reward: block_reward,
+                fees: miner_fees,
+            });
+        }
         Ok(())
     }
 
@@ -170,11 +300,55 @@ impl Block {
                 outputs.insert(output.hash(), output.clone());
             }
         }
-        let input_value: u64 = inputs.values().map(|output| output.value).sum();
+        let input_value = Amount::total(inputs.values().map(|output| output.value))?;
+        let output_value = Amount::total(outputs.values().map(|output| output.value))?;
+        let fees =
+            input_value
+                .checked_sub(output_value)
+                .ok_or_else(|| BtcError::InvalidTransaction {
+                    reason: format!(
+                        "outputs of {} exceed inputs of {} in fee calculation",
+                        output_value, input_value
+                    ),
+                })?;
+        Ok(fees.to_sat())
+    }
+}
 
-        let output_value: u64 = outputs.values().map(|output| output.value).sum();
+/// Versions start at 1 and may not exceed the consensus version at
+/// `height`
+pub(crate) fn verify_transaction_version(
+    params: &NetworkConfig,
+    transaction: &Transaction,
+    height: u64,
+) -> Result<()> {
+    let active = params.version_at(height);
+    if transaction.version == 0 || transaction.version > active {
+        return Err(BtcError::InactiveTransactionVersion {
+            version: transaction.version,
+            active,
+        });
+    }
+    Ok(())
+}
+
+fn is_coinbase(transaction: &Transaction) -> bool {
+    transaction.inputs.is_empty() || transaction.coinbase_height.is_some()
+}
 
-        Ok(input_value - output_value)
+impl Encode for Block {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.header.encode_to(out);
+        self.transactions.encode_to(out);
+    }
+}
+
+impl Decode for Block {
+    fn decode_from(input: &mut &[u8]) -> std::result::Result<Self, DecodeError> {
+        Ok(Block {
+            header: BlockHeader::decode_from(input)?,
+            transactions: Vec::decode_from(input)?,
+        })
     }
 }
 
@@ -190,8 +364,20 @@ impl Saveable for Block {
     }
 }
 
+/// Version of the blocks this release creates
+pub const BLOCK_VERSION: u32 = 1;
+
+fn default_block_version() -> u32 {
+    BLOCK_VERSION
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct BlockHeader {
+    /// Block format, at least the consensus version in force (see
+    /// [`NetworkConfig::version_at`]). Higher versions are accepted, so miners
+    /// can upgrade before a new version activates.
+    #[serde(default = "default_block_version")]
+    pub version: u32,
     /// Timestamp of the block
     pub timestamp: DateTime<Utc>,
     /// Nonce used to mine the block
@@ -202,6 +388,39 @@ pub struct BlockHeader {
     pub merkle_root: MerkleRoot,
     /// target
     pub target: U256,
+    /// Commitment to the UTXO set this block builds on, the one left by
+    /// its parent (see [`Blockchain::utxo_commitment`]). Optional, but
+    /// checked when present.
+    ///
+    /// [`Blockchain::utxo_commitment`]: super::Blockchain::utxo_commitment
+    #[serde(default)]
+    pub utxo_commitment: Option<Hash>,
+}
+
+impl Encode for BlockHeader {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.version.encode_to(out);
+        self.timestamp.encode_to(out);
+        self.nonce.encode_to(out);
+        self.prev_block_hash.encode_to(out);
+        self.merkle_root.encode_to(out);
+        self.target.encode_to(out);
+        self.utxo_commitment.encode_to(out);
+    }
+}
+
+impl Decode for BlockHeader {
+    fn decode_from(input: &mut &[u8]) -> std::result::Result<Self, DecodeError> {
+        Ok(BlockHeader {
+            version: u32::decode_from(input)?,
+            timestamp: DateTime::decode_from(input)?,
+            nonce: u64::decode_from(input)?,
+            prev_block_hash: Hash::decode_from(input)?,
+            merkle_root: MerkleRoot::decode_from(input)?,
+            target: U256::decode_from(input)?,
+            utxo_commitment: Option::decode_from(input)?,
+        })
+    }
 }
 
 impl BlockHeader {
@@ -213,16 +432,18 @@ impl BlockHeader {
         target: U256,
     ) -> Self {
         Self {
+            version: BLOCK_VERSION,
             timestamp,
             nonce,
             prev_block_hash,
             merkle_root,
             target,
+            utxo_commitment: None,
         }
     }
 
     pub fn hash(&self) -> Hash {
-        Hash::hash(self)
+        Hash::consensus(self)
     }
 
     pub fn mine(&mut self, steps: usize) -> bool {
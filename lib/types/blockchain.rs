@@ -1,42 +1,201 @@
-use super::{Block, Transaction, TransactionOutput};
+use super::{Block, ChainIndex, Mempool, Transaction, TransactionOutput};
+use crate::config::{BlockchainConfig, DifficultyAlgorithm, NetworkConfig};
+use crate::difficulty;
 use crate::error::{BtcError, Result};
 use crate::sha256::Hash;
 use crate::util::{MerkleRoot, Saveable};
-use crate::{config, U256};
-use bigdecimal::BigDecimal;
-use chrono::{DateTime, Utc};
+use crate::U256;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+use std::sync::Arc;
 use tracing::warn;
 
+/// Blocks whose solve times DigiShield averages
+const DIGISHIELD_WINDOW: usize = 17;
+
+/// Summary of a chain that passed [`Blockchain::verify_chain`]
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct ChainAuditReport {
+    pub blocks: u64,
+    pub transactions: u64,
+    /// Hash of the newest block, zero for an empty chain
+    pub tip: Hash,
+    pub utxos: usize,
+    /// Sum of all unspent outputs
+    pub total_supply: u64,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Blockchain {
+    /// The flag is always false: outputs spent by pending transactions
+    /// are reserved in the [`Mempool`]. It is kept so saved chains load.
     utxos: HashMap<Hash, (bool, TransactionOutput)>,
     target: U256,
     blocks: Vec<Block>,
-    #[serde(default, skip_serializing)]
-    mempool: Vec<(DateTime<Utc>, Transaction)>,
+    #[serde(skip)]
+    index: ChainIndex,
+    /// Height of the block each UTXO was created in
+    #[serde(skip)]
+    utxo_heights: HashMap<Hash, u64>,
+    /// Configuration whose consensus parameters this chain follows.
+    /// Its network section is saved with the chain.
+    #[serde(
+        rename = "params",
+        serialize_with = "serialize_params",
+        skip_deserializing,
+        default = "BlockchainConfig::global"
+    )]
+    config: Arc<BlockchainConfig>,
+    /// Consensus parameters the chain was saved with, `None` for chains
+    /// saved before they were recorded
+    #[serde(rename = "params", default, skip_serializing)]
+    saved_params: Option<NetworkConfig>,
+}
+
+fn serialize_params<S: serde::Serializer>(
+    config: &Arc<BlockchainConfig>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    config.network.serialize(serializer)
 }
 
 impl Blockchain {
+    /// An empty chain following the global configuration
     pub fn new() -> Self {
+        Self::with_config(BlockchainConfig::global())
+    }
+
+    /// An empty chain following `config`, independent of the global
+    /// configuration, so chains of different networks can coexist
+    pub fn with_config(config: Arc<BlockchainConfig>) -> Self {
         Blockchain {
             utxos: HashMap::new(),
             blocks: vec![],
-            target: config::min_target(),
-            mempool: vec![],
+            target: config.network.min_target(),
+            index: ChainIndex::default(),
+            utxo_heights: HashMap::new(),
+            config,
+            saved_params: None,
         }
     }
 
+    /// Reassemble a blockchain from persisted parts (used by storage backends)
+    pub(crate) fn from_parts(
+        blocks: Vec<Block>,
+        utxos: HashMap<Hash, (bool, TransactionOutput)>,
+        target: U256,
+    ) -> Self {
+        Blockchain {
+            utxos,
+            target,
+            blocks,
+            index: ChainIndex::default(),
+            utxo_heights: HashMap::new(),
+            config: BlockchainConfig::global(),
+            saved_params: None,
+        }
+    }
+
+    /// The configuration this chain follows
+    pub fn config(&self) -> &Arc<BlockchainConfig> {
+        &self.config
+    }
+
+    /// Follow `config` from now on, e.g. for a chain loaded from disk
+    pub fn set_config(&mut self, config: Arc<BlockchainConfig>) {
+        self.config = config;
+    }
+
+    /// The consensus parameters this chain is validated with
+    pub fn params(&self) -> &NetworkConfig {
+        &self.config.network
+    }
+
+    /// Record the consensus parameters a chain was stored with (used by
+    /// storage backends)
+    pub(crate) fn set_saved_params(&mut self, params: Option<NetworkConfig>) {
+        self.saved_params = params;
+    }
+
+    /// Fails if the chain was saved under other consensus parameters than
+    /// it now follows: its blocks were validated with other rules, and
+    /// would be validated with the wrong ones from here on. Chains saved
+    /// without parameters pass.
+    pub fn check_params(&self) -> Result<()> {
+        let Some(saved) = &self.saved_params else {
+            return Ok(());
+        };
+        let parameters = saved.consensus_differences(self.params());
+        if !parameters.is_empty() {
+            return Err(BtcError::ConsensusParamsMismatch { parameters });
+        }
+        Ok(())
+    }
+
     pub fn utxos(&self) -> &HashMap<Hash, (bool, TransactionOutput)> {
         &self.utxos
     }
 
+    /// Satoshis in circulation: the sum of all unspent outputs. Never
+    /// more than [`NetworkConfig::scheduled_supply`] of the chain's height,
+    /// which `add_block` enforces.
+    pub fn total_supply(&self) -> u64 {
+        self.utxos
+            .values()
+            .map(|(_, output)| output.value)
+            .fold(0, u64::saturating_add)
+    }
+
+    /// Hash of the UTXO set: the hashes of all unspent outputs, sorted,
+    /// in their canonical encoding. Each output's hash covers its value,
+    /// id and key, so two sets share a commitment only if they are equal.
+    /// A node handed a UTXO snapshot can check it against the commitment
+    /// in the next block's header instead of replaying every block.
+    pub fn utxo_commitment(&self) -> Hash {
+        let mut outputs: Vec<Hash> = self.utxos.keys().copied().collect();
+        outputs.sort_unstable();
+        Hash::consensus(&outputs)
+    }
+
+    /// Height of the block a UTXO was created in
+    pub fn utxo_height(&self, hash: &Hash) -> Option<u64> {
+        self.utxo_heights.get(hash).copied()
+    }
+
+    /// Height of the last block, 0 for an empty chain
+    pub fn tip_height(&self) -> u64 {
+        self.block_height().saturating_sub(1)
+    }
+
     pub fn target(&self) -> U256 {
         self.target
     }
 
+    /// Difficulty of the current target, see [`difficulty::difficulty`]
+    pub fn difficulty(&self) -> f64 {
+        difficulty::difficulty_relative_to(self.target, self.params().min_target())
+    }
+
+    /// Hashes per second the network spent on the last `window` blocks:
+    /// the work their targets required over the time they took. `None`
+    /// until there are two blocks to measure between.
+    pub fn estimated_hashrate(&self, window: usize) -> Option<f64> {
+        let window = window.min(self.blocks.len().saturating_sub(1));
+        if window == 0 {
+            return None;
+        }
+        let recent = &self.blocks[self.blocks.len() - 1 - window..];
+        let seconds = (recent[window].header.timestamp - recent[0].header.timestamp)
+            .num_milliseconds() as f64
+            / 1000.0;
+        let work: f64 = recent[1..]
+            .iter()
+            .map(|block| difficulty::expected_hashes(block.header.target))
+            .sum();
+        Some(if seconds > 0.0 { work / seconds } else { 0.0 })
+    }
+
     pub fn blocks(&self) -> impl Iterator<Item = &Block> {
         self.blocks.iter()
     }
@@ -46,195 +205,155 @@ impl Blockchain {
         self.blocks.len() as u64
     }
 
-    pub fn mempool(&self) -> &[(DateTime<Utc>, Transaction)] {
-        // later, we will also need to keep track of time
-        &self.mempool
+    pub fn index(&self) -> &ChainIndex {
+        &self.index
+    }
+
+    /// Turn on the requested indexes and build them from the whole chain.
+    /// Afterwards they are kept up to date as blocks are added.
+    pub fn enable_indexes(&mut self, txindex: bool, addressindex: bool) {
+        let mut index = ChainIndex::new(txindex, addressindex);
+        for (height, block) in self.blocks.iter().enumerate() {
+            index.add_block(height as u64, block);
+        }
+        self.index = index;
+    }
+
+    /// Look up a confirmed transaction with its block height and position
+    /// in the block. Needs the transaction index.
+    pub fn find_transaction(&self, hash: &Hash) -> Option<(&Transaction, u64, usize)> {
+        let (height, position) = self.index.locate(hash)?;
+        let transaction = self
+            .blocks
+            .get(height as usize)?
+            .transactions
+            .get(position)?;
+        Some((transaction, height, position))
+    }
+
+    /// Throw away everything derived from the blocks, the UTXO set with
+    /// the heights of its outputs and the indexes, and derive it again
+    /// block by block with the requested indexes. `progress` is called
+    /// after each block with the number of blocks done and the total.
+    pub fn reindex(
+        &mut self,
+        txindex: bool,
+        addressindex: bool,
+        mut progress: impl FnMut(u64, u64),
+    ) {
+        let total = self.blocks.len() as u64;
+        self.utxos.clear();
+        self.utxo_heights.clear();
+        self.index = ChainIndex::new(txindex, addressindex);
+        for height in 0..self.blocks.len() {
+            self.apply_to_utxos(height);
+            self.index.add_block(height as u64, &self.blocks[height]);
+            progress(height as u64 + 1, total);
+        }
     }
 
     // Rebuild UTXO set from the blockchain
     pub fn rebuild_utxos(&mut self) {
-        for block in &self.blocks {
-            for transaction in &block.transactions {
-                for input in &transaction.inputs {
-                    self.utxos.remove(&input.prev_transaction_output_hash);
-                }
-
-                for output in transaction.outputs.iter() {
-                    self.utxos.insert(output.hash(), (false, output.clone()));
-                }
-            }
+        for height in 0..self.blocks.len() {
+            self.apply_to_utxos(height);
         }
     }
 
-    /// Adds a transaction to the mempool after validation.
-    ///
-    /// This function implements Replace-By-Fee (RBF) logic by allowing new transactions
-    /// to replace existing ones in the mempool if they try to spend the same UTXOs.
-    ///
-    /// # Validation Steps:
-    /// 1. Verify all inputs reference existing UTXOs
-    /// 2. Ensure no duplicate inputs within the transaction
-    /// 3. Handle UTXO marking conflicts (RBF logic)
-    /// 4. Verify input sum ≥ output sum
-    /// 5. Mark UTXOs as "in use" by this mempool transaction
-    /// 6. Sort mempool by fee (highest first)
-    ///
-    /// # UTXO Marking System:
-    /// Each UTXO in the HashMap has a boolean flag:
-    /// - false: UTXO is unspent and not reserved by any mempool transaction
-    /// - true: UTXO is reserved by a pending transaction in mempool
-    ///
-    /// This prevents wallets from creating conflicting transactions.
-    pub fn add_to_mempool(&mut self, transaction: Transaction) -> Result<()> {
-        // STEP 1: Basic validation - check all inputs exist and are unique
-        // =================================================================
-        // We need to ensure:
-        // a) Every input references a real UTXO
-        // b) No input is used twice in the same transaction (internal double-spend)
-        let mut known_inputs: HashSet<Hash> = HashSet::new();
-        for input in &transaction.inputs {
-            // Check UTXO exists in our set
-            if !self.utxos.contains_key(&input.prev_transaction_output_hash) {
-                return Err(BtcError::InvalidTransaction {
-                    reason: "UTXO not found".into(),
-                });
-            }
-            // Check this input isn't duplicated
-            if known_inputs.contains(&input.prev_transaction_output_hash) {
-                return Err(BtcError::InvalidTransaction {
-                    reason: "duplicate input".into(),
-                });
-            }
-            known_inputs.insert(input.prev_transaction_output_hash);
+    /// Bring the UTXO set up to date with the block just added, without
+    /// replaying the whole chain like `rebuild_utxos`. A block committing
+    /// to the UTXO set only validates on top of an up to date one.
+    pub fn apply_tip_to_utxos(&mut self) {
+        if let Some(height) = self.blocks.len().checked_sub(1) {
+            self.apply_to_utxos(height);
         }
+    }
+
+    /// Spend the inputs and add the outputs of the block at `height`
+    fn apply_to_utxos(&mut self, height: usize) {
+        for transaction in &self.blocks[height].transactions {
+            for input in &transaction.inputs {
+                self.utxos.remove(&input.prev_transaction_output_hash);
+                self.utxo_heights
+                    .remove(&input.prev_transaction_output_hash);
+            }
 
-        // STEP 2: Handle Replace-By-Fee (RBF) logic
-        // ==========================================
-        // If any UTXO we're trying to spend is already marked (reserved by another
-        // mempool transaction), we implement RBF: remove the old transaction and
-        // accept the new one.
-        //
-        // Example scenario:
-        // - Alice creates Transaction A using UTXO #1
-        // - Transaction A enters mempool, UTXO #1 is marked
-        // - Alice creates Transaction B also using UTXO #1 (with higher fee)
-        // - We remove Transaction A from mempool and unmark its UTXOs
-        // - Transaction B replaces it
-        for input in &transaction.inputs {
-            if let Some((true, _)) = self.utxos.get(&input.prev_transaction_output_hash) {
-                // This UTXO is already marked - find which mempool transaction has it
-                // We search for a transaction whose OUTPUT hash matches our INPUT hash
-                let referencing_transaction =
-                    self.mempool.iter().enumerate().find(|(_, (_, tx))| {
-                        tx.outputs
-                            .iter()
-                            .any(|output| output.hash() == input.prev_transaction_output_hash)
-                    });
-
-                // Found the conflicting transaction - remove it and unmark all its UTXOs
-                if let Some((idx, (_, referencing_transaction))) = referencing_transaction {
-                    for input in &referencing_transaction.inputs {
-                        // Unmark all UTXOs that the old transaction was trying to spend
-                        self.utxos
-                            .entry(input.prev_transaction_output_hash)
-                            .and_modify(|(marked, _)| {
-                                *marked = false;
-                            });
-                    }
-                    // Remove the old transaction from mempool (it's being replaced)
-                    self.mempool.remove(idx);
-                } else {
-                    // Edge case: UTXO is marked but we can't find the transaction
-                    // This shouldn't happen, but we handle it gracefully by unmarking
-                    self.utxos
-                        .entry(input.prev_transaction_output_hash)
-                        .and_modify(|(marked, _)| {
-                            *marked = false;
-                        });
-                }
+            for output in transaction.outputs.iter() {
+                self.utxos.insert(output.hash(), (false, output.clone()));
+                self.utxo_heights.insert(output.hash(), height as u64);
             }
         }
-        // STEP 3: Economic validation - verify transaction is financially valid
-        // ======================================================================
-        // The sum of all inputs must be ≥ sum of all outputs
-        // The difference is the transaction fee for the miner
-        //
-        // Example:
-        // Inputs: [10 BTC, 5 BTC] = 15 BTC total
-        // Outputs: [12 BTC, 2.99 BTC] = 14.99 BTC total
-        // Fee: 15 - 14.99 = 0.01 BTC (goes to miner)
-        let all_inputs = transaction
-            .inputs
-            .iter()
-            .map(|input| {
-                self.utxos
-                    .get(&input.prev_transaction_output_hash)
-                    .expect("BUG: impossible - we validated this exists above")
-                    .1
-                    .value
-            })
-            .sum::<u64>();
-        let all_outputs = transaction
-            .outputs
-            .iter()
-            .map(|output| output.value)
-            .sum::<u64>();
+    }
 
-        if all_inputs < all_outputs {
-            return Err(BtcError::InvalidTransaction {
-                reason: "outputs exceed inputs".into(),
-            });
+    /// Re-validate the whole chain from genesis: linkage, proof of work,
+    /// Merkle roots, timestamps, transactions and coinbase amounts, by
+    /// replaying every block into an empty chain the way `add_block`
+    /// would accept it. The UTXO set must then match this chain's own.
+    ///
+    /// Fails on the first problem, naming the height of the block.
+    pub fn verify_chain(&self) -> Result<ChainAuditReport> {
+        let mut replayed = Blockchain::with_config(self.config.clone());
+        for (height, block) in self.blocks.iter().enumerate() {
+            replayed.add_block(block.clone()).map_err(|e| {
+                BtcError::invalid_block(format!("block {} failed the audit: {}", height, e))
+            })?;
+            replayed.apply_to_utxos(height);
         }
 
-        // STEP 4: Mark UTXOs as reserved by this transaction
-        // ===================================================
-        // Set the boolean flag to true for each UTXO this transaction spends
-        // This prevents double-spending within the mempool
-        for input in &transaction.inputs {
-            self.utxos
-                .entry(input.prev_transaction_output_hash)
-                .and_modify(|(marked, _)| {
-                    *marked = true;
-                });
+        let missing = replayed
+            .utxos
+            .keys()
+            .filter(|hash| !self.utxos.contains_key(hash))
+            .count();
+        let unexpected = self
+            .utxos
+            .iter()
+            .filter(|(hash, (_, output))| {
+                replayed
+                    .utxos
+                    .get(hash)
+                    .is_none_or(|(_, replayed)| replayed.value != output.value)
+            })
+            .count();
+        if missing > 0 || unexpected > 0 {
+            return Err(BtcError::invalid_block(format!(
+                "UTXO set doesn't match the blocks: {} missing, {} unexpected",
+                missing, unexpected
+            )));
         }
 
-        // STEP 5: Add to mempool with timestamp
-        // ======================================
-        // Timestamp is used for cleanup (removing old transactions)
-        self.mempool.push((Utc::now(), transaction));
-
-        // STEP 6: Sort mempool by transaction fee (highest first)
-        // ========================================================
-        // Miners will prefer transactions with higher fees
-        // This prioritization happens every time a transaction is added
-        //
-        // Note: This is inefficient (O(n log n) on every insert)
-        // Production systems use priority queues instead
-        self.mempool.sort_by_key(|(_, tx)| {
-            // Calculate fee for this transaction
-            let all_inputs = tx
-                .inputs
+        Ok(ChainAuditReport {
+            blocks: self.blocks.len() as u64,
+            transactions: self
+                .blocks
                 .iter()
-                .map(|input| {
-                    self.utxos
-                        .get(&input.prev_transaction_output_hash)
-                        .unwrap()
-                        .1
-                        .value
-                })
-                .sum::<u64>();
-            let all_outputs = tx.outputs.iter().map(|output| output.value).sum::<u64>();
-            let miner_fee = all_inputs - all_outputs;
-            miner_fee
-        });
-        Ok(())
+                .map(|block| block.transactions.len() as u64)
+                .sum(),
+            tip: self
+                .blocks
+                .last()
+                .map(|block| block.hash())
+                .unwrap_or(Hash::zero()),
+            utxos: replayed.utxos.len(),
+            total_supply: replayed.total_supply(),
+        })
     }
 
     // try to add a new block to the blockchain,
     // return an error if it is not valid to insert this
-    // block to this blockchain
+    // block to this blockchain. The mempool learns about
+    // it through Mempool::remove_block
     pub fn add_block(&mut self, block: Block) -> Result<()> {
+        // a committed UTXO set must be the one the block builds on
+        if let Some(commitment) = block.header.utxo_commitment {
+            if commitment != self.utxo_commitment() {
+                warn!("Block rejected: UTXO commitment doesn't match");
+                return Err(BtcError::InvalidBlock {
+                    reason: "UTXO commitment doesn't match the UTXO set".into(),
+                });
+            }
+        }
         // check if the block is valid
+        block.verify_versions(self.params(), self.block_height())?;
         if self.blocks.is_empty() {
             // if this is the first block, check if the
             // block's prev_block_hash is all zeroes
@@ -244,6 +363,7 @@ impl Blockchain {
                     reason: "genesis block hash must be zero".into(),
                 });
             }
+            block.verify_genesis_allocations(self.params())?;
         } else {
             // if this is not the first block, check if the
             // block's prev_block_hash is the hash of the last block
@@ -277,37 +397,54 @@ impl Blockchain {
                 });
             }
             // Verify all transactions in the block
-            block.verify_transactions(self.block_height(), &self.utxos)?;
+            block.verify_transactions(self.params(), self.block_height(), &self.utxos)?;
         }
-        // Remove transactions from mempool that are now in the block
-        let block_transactions: HashSet<_> =
-            block.transactions.iter().map(|tx| tx.hash()).collect();
-
-        // Unmark UTXOs for transactions that are being removed from mempool
-        let transactions_to_remove: Vec<_> = self
-            .mempool
+        // whatever the rules above missed, the block must not create
+        // more coins than the emission schedule allows
+        let spent: u64 = block
+            .transactions
             .iter()
-            .filter(|(_, tx)| block_transactions.contains(&tx.hash()))
-            .map(|(_, tx)| tx.clone())
-            .collect();
-
-        for tx in transactions_to_remove {
-            for input in &tx.inputs {
-                self.utxos
-                    .entry(input.prev_transaction_output_hash)
-                    .and_modify(|(marked, _)| {
-                        *marked = false;
-                    });
+            .flat_map(|tx| tx.inputs.iter())
+            .filter_map(|input| self.utxos.get(&input.prev_transaction_output_hash))
+            .map(|(_, output)| output.value)
+            .fold(0, u64::saturating_add);
+        let created: u64 = block
+            .transactions
+            .iter()
+            .flat_map(|tx| tx.outputs.iter())
+            .map(|output| output.value)
+            .fold(0, u64::saturating_add);
+        let supply = self
+            .total_supply()
+            .saturating_add(created)
+            .saturating_sub(spent);
+        let allowed = self.params().scheduled_supply(self.block_height() + 1);
+        if supply > allowed {
+            warn!("Block rejected: supply would exceed the emission schedule");
+            return Err(BtcError::SupplyExceeded { supply, allowed });
+        }
+        let height = self.blocks.len() as u64;
+        for transaction in &block.transactions {
+            for output in &transaction.outputs {
+                self.utxo_heights.insert(output.hash(), height);
             }
         }
-
-        self.mempool
-            .retain(|(_, tx)| !block_transactions.contains(&tx.hash()));
+        self.index.add_block(height, &block);
         self.blocks.push(block);
         self.try_adjust_target();
         Ok(())
     }
 
+    /// Adjusts the mining difficulty target after a block is added, with the
+    /// algorithm selected by `network.difficulty_algorithm`.
+    pub fn try_adjust_target(&mut self) {
+        match self.params().difficulty_algorithm {
+            DifficultyAlgorithm::Interval => self.adjust_target_every_interval(),
+            DifficultyAlgorithm::DigiShield => self.adjust_target_digishield(),
+            DifficultyAlgorithm::Fixed => {}
+        }
+    }
+
     /// Adjusts the mining difficulty target to maintain consistent block times.
     ///
     /// This function implements Bitcoin's difficulty adjustment algorithm. It runs
@@ -317,9 +454,12 @@ impl Blockchain {
     /// # Algorithm:
     ///
     /// ```text
-    /// new_target = current_target × (actual_time / target_time)
+    /// new_target = current_target × actual_time / target_time
     /// ```
     ///
+    /// computed on `U256` integers, multiplying first so nothing is lost
+    /// but the final rounding down.
+    ///
     /// # Example:
     ///
     /// Target: 500 seconds for 50 blocks (10 seconds per block)
@@ -337,14 +477,14 @@ impl Blockchain {
     /// # Safety Limits:
     /// - Maximum adjustment: 4x easier or 4x harder per adjustment
     /// - Never easier than MIN_TARGET (maximum difficulty floor)
-    pub fn try_adjust_target(&mut self) {
+    fn adjust_target_every_interval(&mut self) {
         // Early return if blockchain is empty
         if self.blocks.is_empty() {
             return;
         }
 
         // Only adjust every DIFFICULTY_UPDATE_INTERVAL blocks (e.g., every 50 blocks)
-        let difficulty_interval = config::difficulty_update_interval() as usize;
+        let difficulty_interval = self.params().difficulty_update_interval as usize;
         if self.blocks.len() % difficulty_interval != 0 {
             return;
         }
@@ -368,92 +508,155 @@ impl Blockchain {
         // We want IDEAL_BLOCK_TIME (10 seconds) per block
         // Over DIFFICULTY_UPDATE_INTERVAL blocks, that's:
         // 10 seconds/block × 50 blocks = 500 seconds total
-        let target_seconds = config::ideal_block_time() * config::difficulty_update_interval();
-
-        // STEP 3: Calculate new target with adjustment ratio
-        // ===================================================
-        // Formula: new_target = current_target × (actual_time / target_time)
-        //
-        // We use BigDecimal for precision since U256 doesn't support division
-        let new_target = BigDecimal::parse_bytes(&self.target.to_string().as_bytes(), 10)
-            .expect("BUG: impossible")
-            * (BigDecimal::from(time_diff_seconds) / BigDecimal::from(target_seconds));
-
-        // STEP 4: Convert back to U256
-        // =============================
-        // Truncate decimal places (we only need the integer part)
-        let new_target_str = new_target
-            .to_string()
-            .split('.')
-            .next()
-            .expect("BUG: Expected a decimal point")
-            .to_owned();
-        let new_target: U256 = U256::from_str_radix(&new_target_str, 10).expect("BUG: impossible");
-
-        // STEP 5: Apply safety clamps
-        // ============================
-        // Prevent extreme difficulty swings by limiting adjustment to 4x in either direction
-        // This prevents a single adjustment from making mining impossibly hard or trivially easy
-        // We use division by shifting to avoid overflow issues
-        let target_half = self.target / U256::from(2);
-        let target_quarter = if target_half > U256::from(0) {
-            target_half / U256::from(2)
-        } else {
-            U256::from(1) // Minimum target
-        };
-
-        // Calculate max target (4x easier) safely
-        let max_new_target = self.target * U256::from(2) * U256::from(2);
+        let target_seconds =
+            self.params().ideal_block_time * self.params().difficulty_update_interval;
 
-        let new_target = if new_target < target_quarter {
-            // Don't make it more than 4x harder
-            target_quarter
-        } else if new_target > max_new_target {
-            // Don't make it more than 4x easier
-            max_new_target
-        } else {
-            new_target
-        };
+        // STEP 3: Scale the target by actual_time / target_time,
+        // limited to 4x in either direction
+        let new_target = Self::retarget(self.target, time_diff_seconds, target_seconds);
 
-        // STEP 6: Apply absolute maximum (difficulty floor)
+        // STEP 4: Apply absolute maximum (difficulty floor)
         // ==================================================
         // Never allow target to exceed MIN_TARGET (the easiest allowed difficulty)
-        self.target = new_target.min(config::min_target());
-    }
-
-    // Cleanup mempool - remove transactions older than
-    // MAX_MEMPOOL_TRANSACTION_AGE
-    pub fn cleanup_mempool(&mut self) {
-        let now = Utc::now();
-        let mut utxo_hashes_to_unmark: Vec<Hash> = vec![];
-        self.mempool.retain(|(timestamp, transaction)| {
-            if now - *timestamp
-                > chrono::Duration::seconds(config::max_mempool_transaction_age() as i64)
-            {
-                // push all utxos to unmark to the vector
-                // so we can unmark them later
-                utxo_hashes_to_unmark.extend(
-                    transaction
-                        .inputs
-                        .iter()
-                        .map(|input| input.prev_transaction_output_hash),
-                );
-                false
-            } else {
-                true
-            }
-        });
-        // unmark all of the UTXOs
-        for hash in utxo_hashes_to_unmark {
-            self.utxos.entry(hash).and_modify(|(marked, _)| {
-                *marked = false;
-            });
+        self.target = new_target.min(self.params().min_target());
+    }
+
+    /// The target for the next interval: `current × actual_seconds /
+    /// target_seconds`, in integer arithmetic and rounded down, but at
+    /// most 4x easier and at least 4x harder than `current`
+    pub(crate) fn retarget(current: U256, actual_seconds: i64, target_seconds: u64) -> U256 {
+        let target_seconds = target_seconds.max(1);
+        // anything slower than 4x ends up at the 4x clamp anyway, and this
+        // keeps the multiplication small
+        let actual_seconds = (actual_seconds.max(0) as u64).min(target_seconds.saturating_mul(4));
+        let actual = U256::from(actual_seconds);
+        let divisor = U256::from(target_seconds);
+        let new_target = match current.checked_mul(actual) {
+            Some(product) => product / divisor,
+            // only for targets close to 2^256; dividing first loses at
+            // most `actual_seconds` of precision
+            None => (current / divisor).saturating_mul(actual),
+        };
+
+        // Prevent extreme difficulty swings by limiting adjustment to 4x in either direction
+        // This prevents a single adjustment from making mining impossibly hard or trivially easy
+        let min_new_target = (current / U256::from(4)).max(U256::one());
+        let max_new_target = current.saturating_mul(U256::from(4)).max(min_new_target);
+        new_target.clamp(min_new_target, max_new_target)
+    }
+
+    /// DigiShield v3 style adjustment, run after every block.
+    ///
+    /// Looks at how long the last `DIGISHIELD_WINDOW` blocks took (fewer
+    /// while the chain is shorter), so one lucky or unlucky block only
+    /// moves the average a little. The deviation from the ideal time is
+    /// damped to a quarter and each step is limited, so the target
+    /// follows changes in hash power smoothly within a few blocks instead
+    /// of waiting for the next interval.
+    fn adjust_target_digishield(&mut self) {
+        let window = DIGISHIELD_WINDOW.min(self.blocks.len().saturating_sub(1));
+        if window == 0 {
+            return;
+        }
+        let end_time = self.blocks[self.blocks.len() - 1].header.timestamp;
+        let start_time = self.blocks[self.blocks.len() - 1 - window].header.timestamp;
+        let actual_seconds = (end_time - start_time).num_seconds();
+        let target_seconds = self.params().ideal_block_time * window as u64;
+        let new_target = Self::digishield_retarget(self.target, actual_seconds, target_seconds);
+        self.target = new_target.min(self.params().min_target());
+    }
+
+    /// The next target under DigiShield: the time the window took is
+    /// moved only a quarter of the way from the ideal time, then limited
+    /// to 32% easier or 16% harder, and the target scaled by the result
+    pub(crate) fn digishield_retarget(
+        current: U256,
+        actual_seconds: i64,
+        target_seconds: u64,
+    ) -> U256 {
+        let target_seconds = target_seconds.max(1) as i64;
+        let damped =
+            target_seconds.saturating_add(actual_seconds.saturating_sub(target_seconds) / 4);
+        let damped = damped.clamp(target_seconds * 84 / 100, target_seconds * 132 / 100);
+        let damped = U256::from(damped.max(1) as u64);
+        let divisor = U256::from(target_seconds as u64);
+        match current.checked_mul(damped) {
+            Some(product) => product / divisor,
+            None => (current / divisor).saturating_mul(damped),
         }
+        .max(U256::one())
     }
+
     pub fn calculate_block_reward(&self) -> u64 {
-        let block_height = self.block_height();
-        let halvings = block_height / config::halving_interval();
-        (config::initial_reward() * 10u64.pow(8)) >> halvings
+        self.params().block_reward(self.block_height())
+    }
+
+    /// Estimate the fee (in satoshis per transaction) needed for a
+    /// transaction to be mined within `target_blocks` blocks.
+    ///
+    /// Blocks are capped by transaction count rather than size, so fees
+    /// are compared per transaction. Two signals are combined:
+    ///
+    /// 1. the median of the average fee paid in each of the last
+    ///    `lookback` blocks that contained transactions
+    /// 2. the mempool: if more transactions are waiting than fit into
+    ///    `target_blocks` blocks, we have to outbid the one at the cut-off
+    ///
+    /// Returns `None` if there is no data to base an estimate on.
+    pub fn estimate_fee(
+        &self,
+        mempool: &Mempool,
+        target_blocks: u32,
+        lookback: usize,
+    ) -> Option<u64> {
+        let start = self.blocks.len().saturating_sub(lookback);
+        let mut block_fees: Vec<u64> = self
+            .blocks
+            .iter()
+            .enumerate()
+            .skip(start)
+            .filter(|(_, block)| block.transactions.len() > 1)
+            .map(|(height, block)| {
+                let coinbase: u64 = block.transactions[0]
+                    .outputs
+                    .iter()
+                    .map(|output| output.value)
+                    .sum();
+                let fees = coinbase.saturating_sub(self.params().block_reward(height as u64));
+                fees / (block.transactions.len() as u64 - 1)
+            })
+            .collect();
+        block_fees.sort_unstable();
+        let recent = block_fees.get(block_fees.len() / 2).copied();
+
+        // the mempool is kept sorted by fee rate, highest first
+        let capacity = self.params().block_transaction_cap * target_blocks.max(1) as usize;
+        let competing = mempool
+            .transactions()
+            .get(capacity.saturating_sub(1))
+            .filter(|_| mempool.len() >= capacity)
+            .and_then(|(_, transaction)| self.mempool_fee(transaction))
+            .map(|fee| fee + 1);
+
+        match (recent, competing) {
+            (None, None) => None,
+            (recent, competing) => Some(recent.unwrap_or(0).max(competing.unwrap_or(0))),
+        }
+    }
+
+    /// Fee paid by a transaction spending outputs of the UTXO set
+    pub fn mempool_fee(&self, transaction: &Transaction) -> Option<u64> {
+        let inputs = transaction
+            .inputs
+            .iter()
+            .map(|input| {
+                self.utxos
+                    .get(&input.prev_transaction_output_hash)
+                    .map(|(_, output)| output.value)
+            })
+            .sum::<Option<u64>>()?;
+        let outputs: u64 = transaction.outputs.iter().map(|output| output.value).sum();
+        inputs.checked_sub(outputs)
     }
 }
 
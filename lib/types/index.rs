@@ -0,0 +1,91 @@
+use super::Block;
+use crate::crypto::PublicKey;
+use crate::sha256::Hash;
+use std::collections::{BTreeMap, HashMap};
+
+/// Optional lookup indexes over the confirmed chain
+///
+/// Both indexes are disabled by default since they grow with the chain;
+/// nodes turn them on with the `txindex` and `addressindex` settings.
+#[derive(Debug, Clone, Default)]
+pub struct ChainIndex {
+    /// Transaction hash → (block height, position in block)
+    transactions: Option<HashMap<Hash, (u64, usize)>>,
+    addresses: Option<AddressIndex>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct AddressIndex {
+    /// Owner of every output seen so far, to attribute inputs spending it
+    output_owners: HashMap<Hash, PublicKey>,
+    /// Key → (transaction hash, block height), oldest first
+    history: BTreeMap<PublicKey, Vec<(Hash, u64)>>,
+}
+
+impl ChainIndex {
+    pub fn new(txindex: bool, addressindex: bool) -> Self {
+        Self {
+            transactions: txindex.then(HashMap::new),
+            addresses: addressindex.then(AddressIndex::default),
+        }
+    }
+
+    pub fn has_txindex(&self) -> bool {
+        self.transactions.is_some()
+    }
+
+    pub fn has_addressindex(&self) -> bool {
+        self.addresses.is_some()
+    }
+
+    /// Index the transactions of the block at `height`
+    pub fn add_block(&mut self, height: u64, block: &Block) {
+        for (position, transaction) in block.transactions.iter().enumerate() {
+            let hash = transaction.hash();
+            if let Some(transactions) = &mut self.transactions {
+                transactions.insert(hash, (height, position));
+            }
+            if let Some(addresses) = &mut self.addresses {
+                let mut keys: Vec<PublicKey> = transaction
+                    .inputs
+                    .iter()
+                    .filter_map(|input| {
+                        addresses
+                            .output_owners
+                            .get(&input.prev_transaction_output_hash)
+                            .cloned()
+                    })
+                    .collect();
+                for output in &transaction.outputs {
+                    addresses
+                        .output_owners
+                        .insert(output.hash(), output.pubkey.clone());
+                    keys.push(output.pubkey.clone());
+                }
+                keys.sort();
+                keys.dedup();
+                for key in keys {
+                    addresses
+                        .history
+                        .entry(key)
+                        .or_default()
+                        .push((hash, height));
+                }
+            }
+        }
+    }
+
+    /// Where a transaction was confirmed, if the transaction index is enabled
+    pub fn locate(&self, hash: &Hash) -> Option<(u64, usize)> {
+        self.transactions.as_ref()?.get(hash).copied()
+    }
+
+    /// Transactions paying or spending from `key` as (hash, block height),
+    /// empty if the address index is disabled
+    pub fn history(&self, key: &PublicKey) -> Vec<(Hash, u64)> {
+        self.addresses
+            .as_ref()
+            .and_then(|addresses| addresses.history.get(key).cloned())
+            .unwrap_or_default()
+    }
+}
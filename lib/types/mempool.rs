@@ -0,0 +1,590 @@
+use super::block::verify_transaction_version;
+use super::{Block, Blockchain, Transaction};
+use crate::amount::Amount;
+use crate::crypto::PublicKey;
+use crate::encoding::Encode;
+use crate::error::{BtcError, Result};
+use crate::sha256::Hash;
+use crate::util::Saveable;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+use tracing::warn;
+
+/// How large the mempool may grow, 0 meaning no limit
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MempoolLimits {
+    /// Encoded size of all transactions
+    pub max_bytes: usize,
+    pub max_transactions: usize,
+}
+
+impl MempoolLimits {
+    fn exceeded(&self, bytes: usize, transactions: usize) -> bool {
+        (self.max_bytes > 0 && bytes > self.max_bytes)
+            || (self.max_transactions > 0 && transactions > self.max_transactions)
+    }
+}
+
+/// Fee paid per encoded byte, which is what pending transactions are
+/// ranked by: a large transaction takes the room of several small ones
+#[derive(Clone, Copy, Debug)]
+pub struct FeeRate {
+    pub fee: u64,
+    /// Encoded size of the transaction
+    pub bytes: usize,
+}
+
+impl FeeRate {
+    pub fn new(fee: u64, bytes: usize) -> Self {
+        Self { fee, bytes }
+    }
+
+    /// Rate of a transaction taken into the mempool of `chain`
+    fn of(chain: &Blockchain, transaction: &Transaction) -> Self {
+        Self::new(
+            chain.mempool_fee(transaction).unwrap_or(0),
+            transaction.encode().len(),
+        )
+    }
+
+    /// Lowest fee a transaction of `bytes` has to pay to beat this rate
+    pub fn fee_to_beat(&self, bytes: usize) -> u64 {
+        let fee = self.fee as u128 * bytes as u128 / self.bytes.max(1) as u128;
+        u64::try_from(fee).unwrap_or(u64::MAX).saturating_add(1)
+    }
+}
+
+impl Ord for FeeRate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // fee / bytes compared without rounding
+        (self.fee as u128 * other.bytes as u128).cmp(&(other.fee as u128 * self.bytes as u128))
+    }
+}
+
+impl PartialOrd for FeeRate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for FeeRate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for FeeRate {}
+
+/// Pending transactions with when they were received, saved apart from
+/// the chain (which never holds them) so they survive a node restart
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SavedMempool {
+    pub transactions: Vec<(DateTime<Utc>, Transaction)>,
+}
+
+/// Transactions waiting to be mined
+///
+/// Kept apart from the [`Blockchain`] so a node can lock the two
+/// separately: taking in a transaction only needs to read the chain.
+/// Everything checked against the UTXO set takes the chain it applies
+/// to, and [`Mempool::remove_block`] has to see every block added to it.
+#[derive(Clone, Debug, Default)]
+pub struct Mempool {
+    /// Sorted by fee rate, highest first
+    transactions: Vec<(DateTime<Utc>, Transaction)>,
+    /// Encoded size of `transactions`
+    bytes: usize,
+    /// Relayed transactions spending outputs we don't know yet, with
+    /// when they arrived, oldest first
+    orphans: Vec<(DateTime<Utc>, Transaction)>,
+    /// Outputs spent by a pending transaction
+    reserved: HashSet<Hash>,
+    /// Transactions evicted by a conflicting transaction, with the keys
+    /// they spend from or pay to, until taken by the node
+    conflicts: Vec<(Hash, Vec<PublicKey>)>,
+    /// Transactions dropped for being too old, with the keys involved,
+    /// until taken by the node
+    expired: Vec<(Hash, Vec<PublicKey>)>,
+}
+
+/// Keys a transaction spends from or pays to, which is who hears about
+/// it leaving the mempool
+fn involved_keys(chain: &Blockchain, transaction: &Transaction) -> Vec<PublicKey> {
+    let mut keys: Vec<PublicKey> = transaction
+        .inputs
+        .iter()
+        .filter_map(|input| chain.utxos().get(&input.prev_transaction_output_hash))
+        .map(|(_, output)| output.pubkey.clone())
+        .chain(
+            transaction
+                .outputs
+                .iter()
+                .map(|output| output.pubkey.clone()),
+        )
+        .collect();
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+impl Mempool {
+    pub fn transactions(&self) -> &[(DateTime<Utc>, Transaction)] {
+        &self.transactions
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    pub fn orphans(&self) -> &[(DateTime<Utc>, Transaction)] {
+        &self.orphans
+    }
+
+    /// Whether a pending transaction spends the output `hash`
+    pub fn is_reserved(&self, hash: &Hash) -> bool {
+        self.reserved.contains(hash)
+    }
+
+    /// Take the transactions evicted by conflicting transactions since
+    /// the last call, with the keys involved in each
+    pub fn take_conflicts(&mut self) -> Vec<(Hash, Vec<PublicKey>)> {
+        std::mem::take(&mut self.conflicts)
+    }
+
+    /// Take the transactions that expired since the last call, with the
+    /// keys involved in each
+    pub fn take_expired(&mut self) -> Vec<(Hash, Vec<PublicKey>)> {
+        std::mem::take(&mut self.expired)
+    }
+
+    /// Remember an evicted transaction so subscribers can be told
+    fn record_conflict(&mut self, chain: &Blockchain, transaction: &Transaction) {
+        let keys = involved_keys(chain, transaction);
+        self.conflicts.push((transaction.hash(), keys));
+    }
+
+    /// Adds a transaction after validating it against `chain`.
+    ///
+    /// This function implements Replace-By-Fee (RBF) logic by allowing new transactions
+    /// to replace existing ones in the mempool if they try to spend the same UTXOs.
+    ///
+    /// # Validation Steps:
+    /// 1. Verify all inputs reference existing UTXOs
+    /// 2. Ensure no duplicate inputs within the transaction
+    /// 3. Handle reserved output conflicts (RBF logic)
+    /// 4. Verify input sum ≥ output sum
+    /// 5. Reserve the outputs for this transaction
+    /// 6. Insert it in order of fee rate (highest first)
+    ///
+    /// # Reserved Outputs:
+    /// Every UTXO spent by a pending transaction is reserved until that
+    /// transaction leaves the mempool, see [`Mempool::is_reserved`].
+    ///
+    /// This prevents wallets from creating conflicting transactions.
+    pub fn add(&mut self, chain: &Blockchain, transaction: Transaction) -> Result<()> {
+        self.add_at(chain, transaction, Utc::now())
+    }
+
+    /// [`Mempool::add`], received at `now` rather than the current time,
+    /// so tests and simulations control mempool expiry
+    pub fn add_at(
+        &mut self,
+        chain: &Blockchain,
+        transaction: Transaction,
+        now: DateTime<Utc>,
+    ) -> Result<()> {
+        // STEP 1: Basic validation - check all inputs exist and are unique
+        // =================================================================
+        // We need to ensure:
+        // a) Every input references a real UTXO
+        // b) No input is used twice in the same transaction (internal double-spend)
+        if transaction.coinbase_height.is_some() {
+            return Err(BtcError::InvalidTransaction {
+                reason: "coinbase transactions can't be relayed".into(),
+            });
+        }
+        // it has to be valid in the next block
+        verify_transaction_version(chain.params(), &transaction, chain.block_height())?;
+        let mut known_inputs: HashSet<Hash> = HashSet::new();
+        for input in &transaction.inputs {
+            // Check UTXO exists in our set
+            if !chain
+                .utxos()
+                .contains_key(&input.prev_transaction_output_hash)
+            {
+                return Err(BtcError::InvalidTransaction {
+                    reason: "UTXO not found".into(),
+                });
+            }
+            // Check this input isn't duplicated
+            if known_inputs.contains(&input.prev_transaction_output_hash) {
+                return Err(BtcError::InvalidTransaction {
+                    reason: "duplicate input".into(),
+                });
+            }
+            known_inputs.insert(input.prev_transaction_output_hash);
+        }
+
+        // STEP 2: Handle Replace-By-Fee (RBF) logic
+        // ==========================================
+        // If any UTXO we're trying to spend is already reserved by another
+        // mempool transaction, we implement RBF: remove the old transaction and
+        // accept the new one.
+        //
+        // Example scenario:
+        // - Alice creates Transaction A using UTXO #1
+        // - Transaction A enters mempool, UTXO #1 is reserved
+        // - Alice creates Transaction B also using UTXO #1 (with higher fee)
+        // - We remove Transaction A from mempool and release its UTXOs
+        // - Transaction B replaces it
+        for input in &transaction.inputs {
+            if self.reserved.contains(&input.prev_transaction_output_hash) {
+                // This UTXO is already reserved - find which mempool transaction has it
+                // We search for a transaction with an INPUT spending the same UTXO
+                let referencing_transaction = self.transactions.iter().position(|(_, tx)| {
+                    tx.inputs.iter().any(|other| {
+                        other.prev_transaction_output_hash == input.prev_transaction_output_hash
+                    })
+                });
+
+                // Found the conflicting transaction - remove it and release all its UTXOs
+                if let Some(idx) = referencing_transaction {
+                    // Remove the old transaction from mempool (it's being replaced)
+                    let (_, replaced) = self.transactions.remove(idx);
+                    self.bytes -= replaced.encode().len();
+                    self.release(chain, &replaced);
+                } else {
+                    // Edge case: UTXO is reserved but we can't find the transaction
+                    // This shouldn't happen, but we handle it gracefully by releasing it
+                    self.reserved.remove(&input.prev_transaction_output_hash);
+                }
+            }
+        }
+        // STEP 3: Economic validation - verify transaction is financially valid
+        // ======================================================================
+        // The sum of all inputs must be ≥ sum of all outputs
+        // The difference is the transaction fee for the miner
+        //
+        // Example:
+        // Inputs: [10 BTC, 5 BTC] = 15 BTC total
+        // Outputs: [12 BTC, 2.99 BTC] = 14.99 BTC total
+        // Fee: 15 - 14.99 = 0.01 BTC (goes to miner)
+        //
+        // Both sums are checked: no value or total may exceed MAX_MONEY,
+        // so outputs can't wrap around to look smaller than the inputs
+        let all_inputs = Amount::total(transaction.inputs.iter().map(|input| {
+            chain
+                .utxos()
+                .get(&input.prev_transaction_output_hash)
+                .expect("BUG: impossible - we validated this exists above")
+                .1
+                .value
+        }))?;
+        let all_outputs = transaction.output_value()?;
+
+        if all_inputs < all_outputs {
+            return Err(BtcError::InvalidTransaction {
+                reason: "outputs exceed inputs".into(),
+            });
+        }
+
+        // STEP 4: Reserve the UTXOs for this transaction
+        // ===============================================
+        // This prevents double-spending within the mempool
+        for input in &transaction.inputs {
+            self.reserved.insert(input.prev_transaction_output_hash);
+        }
+
+        // STEP 5: Add to mempool with timestamp, by fee rate (highest first)
+        // ===================================================================
+        // Timestamp is used for cleanup (removing old transactions)
+        // Miners will prefer transactions paying more per byte, and a full
+        // mempool evicts those paying the least
+        //
+        // The mempool stays sorted, so a binary search finds the position,
+        // after those paying the same rate
+        let rate = FeeRate::new(
+            all_inputs.to_sat() - all_outputs.to_sat(),
+            transaction.encode().len(),
+        );
+        let position = self
+            .transactions
+            .partition_point(|(_, tx)| FeeRate::of(chain, tx) >= rate);
+        self.bytes += rate.bytes;
+        self.transactions.insert(position, (now, transaction));
+        Ok(())
+    }
+
+    /// Keep a transaction whose inputs aren't in the UTXO set yet, e.g.
+    /// one relayed before the block creating them, to be tried again by
+    /// [`Mempool::retry_orphans`]. Beyond `max_orphans` the oldest are
+    /// dropped.
+    pub fn add_orphan(&mut self, transaction: Transaction, max_orphans: usize) {
+        self.add_orphan_at(transaction, max_orphans, Utc::now())
+    }
+
+    /// [`Mempool::add_orphan`], received at `now`
+    pub fn add_orphan_at(
+        &mut self,
+        transaction: Transaction,
+        max_orphans: usize,
+        now: DateTime<Utc>,
+    ) {
+        let hash = transaction.hash();
+        if self.orphans.iter().any(|(_, orphan)| orphan.hash() == hash) {
+            return;
+        }
+        self.orphans.push((now, transaction));
+        let excess = self.orphans.len().saturating_sub(max_orphans);
+        self.orphans.drain(..excess);
+    }
+
+    /// Try the orphans again, after a block added outputs to `chain`. The
+    /// ones whose inputs are still unknown stay, the invalid ones are
+    /// dropped. Returns those that entered the mempool, to be relayed.
+    pub fn retry_orphans(&mut self, chain: &Blockchain) -> Vec<Transaction> {
+        let mut admitted = vec![];
+        for (received, transaction) in std::mem::take(&mut self.orphans) {
+            let missing_inputs = transaction.inputs.iter().any(|input| {
+                !chain
+                    .utxos()
+                    .contains_key(&input.prev_transaction_output_hash)
+            });
+            if missing_inputs {
+                self.orphans.push((received, transaction));
+            } else if self.add_at(chain, transaction.clone(), received).is_ok() {
+                admitted.push(transaction);
+            }
+        }
+        admitted
+    }
+
+    /// Drop the transactions `block` mined, and evict the others spending
+    /// the same outputs as conflicts, since they can never be mined. Call
+    /// it once the block is added to `chain`, before its outputs are
+    /// applied to the UTXO set, so evicted transactions still find the
+    /// keys they spend from.
+    pub fn remove_block(&mut self, chain: &Blockchain, block: &Block) {
+        let block_transactions: HashSet<Hash> =
+            block.transactions.iter().map(|tx| tx.hash()).collect();
+        let block_inputs: HashSet<Hash> = block
+            .transactions
+            .iter()
+            .flat_map(|tx| tx.inputs.iter())
+            .map(|input| input.prev_transaction_output_hash)
+            .collect();
+        let (mined, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.transactions)
+            .into_iter()
+            .partition(|(_, tx)| block_transactions.contains(&tx.hash()));
+        let (conflicting, kept): (Vec<_>, Vec<_>) = kept.into_iter().partition(|(_, tx)| {
+            tx.inputs
+                .iter()
+                .any(|input| block_inputs.contains(&input.prev_transaction_output_hash))
+        });
+        self.transactions = kept;
+        self.bytes -= mined
+            .iter()
+            .chain(&conflicting)
+            .map(|(_, tx)| tx.encode().len())
+            .sum::<usize>();
+        for (_, tx) in &mined {
+            for input in &tx.inputs {
+                self.reserved.remove(&input.prev_transaction_output_hash);
+            }
+        }
+        for (_, tx) in &conflicting {
+            self.release(chain, tx);
+        }
+        self.orphans
+            .retain(|(_, tx)| !block_transactions.contains(&tx.hash()));
+    }
+
+    /// The mempool, to be saved with [`Saveable`]
+    pub fn saved(&self) -> SavedMempool {
+        SavedMempool {
+            transactions: self.transactions.clone(),
+        }
+    }
+
+    /// Put saved transactions back into the mempool, oldest first, each
+    /// validated against `chain` again. Transactions mined, double spent
+    /// or expired in the meantime are dropped. Returns how many made it
+    /// back.
+    pub fn restore(&mut self, chain: &Blockchain, saved: SavedMempool) -> usize {
+        let mut transactions = saved.transactions;
+        transactions.sort_by_key(|(received, _)| *received);
+        for (received, transaction) in transactions {
+            let txid = transaction.hash();
+            if let Err(e) = self.add_at(chain, transaction, received) {
+                warn!("dropping saved mempool transaction {}: {}", txid, e);
+            }
+        }
+        self.cleanup(chain);
+        // nobody is subscribed yet
+        self.conflicts.clear();
+        self.expired.clear();
+        self.transactions.len()
+    }
+
+    // Cleanup mempool - remove transactions (and orphans) older than
+    // MAX_MEMPOOL_TRANSACTION_AGE
+    pub fn cleanup(&mut self, chain: &Blockchain) {
+        self.cleanup_at(chain, Utc::now())
+    }
+
+    /// [`Mempool::cleanup`] as of `now`
+    pub fn cleanup_at(&mut self, chain: &Blockchain, now: DateTime<Utc>) {
+        let max_age = chrono::Duration::seconds(chain.params().max_mempool_transaction_age as i64);
+        let (expired, kept) = std::mem::take(&mut self.transactions)
+            .into_iter()
+            .partition(|(timestamp, _)| now - *timestamp > max_age);
+        self.transactions = kept;
+        self.orphans
+            .retain(|(received, _)| now - *received <= max_age);
+        for (_, transaction) in expired {
+            self.bytes -= transaction.encode().len();
+            let keys = involved_keys(chain, &transaction);
+            self.expired.push((transaction.hash(), keys));
+            for input in &transaction.inputs {
+                self.reserved.remove(&input.prev_transaction_output_hash);
+            }
+        }
+    }
+
+    /// Drop a transaction on an operator's request and release the
+    /// outputs it reserved. Subscribers learn about it like about a
+    /// conflict. Returns the transaction, if it was pending.
+    pub fn evict(&mut self, chain: &Blockchain, hash: &Hash) -> Option<Transaction> {
+        let position = self
+            .transactions
+            .iter()
+            .position(|(_, transaction)| transaction.hash() == *hash)?;
+        let (_, transaction) = self.transactions.remove(position);
+        self.bytes -= transaction.encode().len();
+        self.release(chain, &transaction);
+        Some(transaction)
+    }
+
+    /// [`Mempool::add`], keeping the mempool within `limits`: once it is
+    /// full a transaction has to pay a higher fee rate than the cheapest
+    /// pending one, which is evicted to make room. Returns the hashes of
+    /// the evicted transactions.
+    pub fn add_bounded(
+        &mut self,
+        chain: &Blockchain,
+        transaction: Transaction,
+        limits: MempoolLimits,
+    ) -> Result<Vec<Hash>> {
+        // unknown inputs are reported by add
+        if let Some(fee) = chain.mempool_fee(&transaction) {
+            let rate = FeeRate::new(fee, transaction.encode().len());
+            let min_rate = self.min_fee_rate(chain, limits);
+            // checked up front: a replacement evicted by trim would have
+            // already pushed out the transaction it replaced
+            if min_rate.is_some_and(|min_rate| rate <= min_rate)
+                || !self.fits(chain, &transaction, rate, limits)
+            {
+                return Err(BtcError::MempoolFull {
+                    fee,
+                    min_fee: min_rate.map_or(0, |min_rate| min_rate.fee_to_beat(rate.bytes)),
+                });
+            }
+        }
+        self.add(chain, transaction)?;
+        Ok(self.trim(chain, limits))
+    }
+
+    /// Whether `transaction`, paying `rate`, would survive [`Mempool::trim`]
+    /// once added: it is ranked after the pending transactions paying at
+    /// least as much, minus those it replaces, and these must fit with it
+    fn fits(
+        &self,
+        chain: &Blockchain,
+        transaction: &Transaction,
+        rate: FeeRate,
+        limits: MempoolLimits,
+    ) -> bool {
+        let spent: HashSet<Hash> = transaction
+            .inputs
+            .iter()
+            .map(|input| input.prev_transaction_output_hash)
+            .collect();
+        let (bytes, count) = self
+            .transactions
+            .iter()
+            .filter(|(_, pending)| {
+                !pending
+                    .inputs
+                    .iter()
+                    .any(|input| spent.contains(&input.prev_transaction_output_hash))
+            })
+            .map(|(_, pending)| FeeRate::of(chain, pending))
+            .filter(|pending| *pending >= rate)
+            .fold((rate.bytes, 1), |(bytes, count), pending| {
+                (bytes + pending.bytes, count + 1)
+            });
+        !limits.exceeded(bytes, count)
+    }
+
+    /// Encoded size of the pending transactions
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Fee rate a new transaction has to beat to enter the mempool: once
+    /// it is full, that of the cheapest pending transaction, `None` before
+    pub fn min_fee_rate(&self, chain: &Blockchain, limits: MempoolLimits) -> Option<FeeRate> {
+        if !limits.exceeded(self.bytes + 1, self.transactions.len() + 1) {
+            return None;
+        }
+        // the mempool is kept sorted by fee rate, highest first
+        self.transactions
+            .last()
+            .map(|(_, transaction)| FeeRate::of(chain, transaction))
+    }
+
+    /// Evict the transactions paying the lowest fee rate until the
+    /// mempool is within `limits`, releasing the outputs they reserved.
+    /// Subscribers learn about them like about conflicts. Returns their
+    /// hashes.
+    pub fn trim(&mut self, chain: &Blockchain, limits: MempoolLimits) -> Vec<Hash> {
+        let mut evicted = vec![];
+        while limits.exceeded(self.bytes, self.transactions.len()) {
+            let Some((_, transaction)) = self.transactions.pop() else {
+                break;
+            };
+            self.bytes -= transaction.encode().len();
+            self.release(chain, &transaction);
+            evicted.push(transaction.hash());
+        }
+        evicted
+    }
+
+    /// Release the outputs a transaction leaving the mempool reserved
+    /// and tell its subscribers
+    fn release(&mut self, chain: &Blockchain, transaction: &Transaction) {
+        self.record_conflict(chain, transaction);
+        for input in &transaction.inputs {
+            self.reserved.remove(&input.prev_transaction_output_hash);
+        }
+    }
+}
+
+impl Saveable for SavedMempool {
+    fn load<I: Read>(reader: I) -> IoResult<Self> {
+        ciborium::de::from_reader(reader)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to deserialize mempool"))
+    }
+
+    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+        ciborium::ser::into_writer(self, writer)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialize mempool"))
+    }
+}
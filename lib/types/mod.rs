@@ -1,9 +1,13 @@
 mod block;
 mod blockchain;
+mod index;
+mod mempool;
 mod transaction;
 
 pub use block::*;
 pub use blockchain::*;
+pub use index::*;
+pub use mempool::*;
 pub use transaction::*;
 
 #[cfg(test)]
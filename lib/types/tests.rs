@@ -2,12 +2,12 @@
 mod transaction_tests {
     use crate::crypto::PrivateKey;
     use crate::test_helpers::{create_test_input, create_test_output};
-    use crate::types::Transaction;
+    use crate::types::{Transaction, UnsignedTransaction};
 
     #[test]
     fn test_transaction_creation() {
-        let mut private_key = PrivateKey::new_key();
-        let output = create_test_output(1000, &mut private_key);
+        let private_key = PrivateKey::new_key();
+        let output = create_test_output(1000, &private_key);
         let output_hash = output.hash();
 
         let transaction = Transaction::new(vec![], vec![output.clone()]);
@@ -19,8 +19,8 @@ mod transaction_tests {
 
     #[test]
     fn test_transaction_hashing() {
-        let mut private_key = PrivateKey::new_key();
-        let output = create_test_output(1000, &mut private_key);
+        let private_key = PrivateKey::new_key();
+        let output = create_test_output(1000, &private_key);
 
         let tx = Transaction::new(vec![], vec![output.clone()]);
 
@@ -30,9 +30,9 @@ mod transaction_tests {
 
     #[test]
     fn test_transaction_different_hashes() {
-        let mut private_key = PrivateKey::new_key();
-        let output1 = create_test_output(1000, &mut private_key);
-        let output2 = create_test_output(1000, &mut private_key);
+        let private_key = PrivateKey::new_key();
+        let output1 = create_test_output(1000, &private_key);
+        let output2 = create_test_output(1000, &private_key);
 
         let tx1 = Transaction::new(vec![], vec![output1]);
         let tx2 = Transaction::new(vec![], vec![output2]);
@@ -44,8 +44,8 @@ mod transaction_tests {
 
     #[test]
     fn test_transaction_output_hashing() {
-        let mut private_key = PrivateKey::new_key();
-        let output = create_test_output(1000, &mut private_key);
+        let private_key = PrivateKey::new_key();
+        let output = create_test_output(1000, &private_key);
 
         let hash1 = output.hash();
         let hash2 = output.hash();
@@ -56,41 +56,72 @@ mod transaction_tests {
 
     #[test]
     fn test_transaction_output_value() {
-        let mut private_key = PrivateKey::new_key();
-        let output = create_test_output(5000, &mut private_key);
+        let private_key = PrivateKey::new_key();
+        let output = create_test_output(5000, &private_key);
 
         assert_eq!(output.value, 5000);
     }
 
     #[test]
     fn test_transaction_with_inputs() {
-        let mut private_key = PrivateKey::new_key();
-        let output = create_test_output(1000, &mut private_key);
+        let private_key = PrivateKey::new_key();
+        let output = create_test_output(1000, &private_key);
         let output_hash = output.hash();
 
-        let input = create_test_input(&output_hash, &mut private_key);
+        let input = create_test_input(&output_hash, &private_key);
 
         let transaction =
-            Transaction::new(vec![input], vec![create_test_output(800, &mut private_key)]);
+            Transaction::new(vec![input], vec![create_test_output(800, &private_key)]);
 
         assert_eq!(transaction.inputs.len(), 1);
         assert_eq!(transaction.outputs.len(), 1);
     }
+
+    #[test]
+    fn test_unsigned_transaction_sign_and_finalize() {
+        let cold_key = PrivateKey::new_key();
+        let other_key = PrivateKey::new_key();
+        let spent = vec![
+            create_test_output(1000, &cold_key),
+            create_test_output(500, &other_key),
+        ];
+        let mut unsigned =
+            UnsignedTransaction::new(spent.clone(), vec![create_test_output(1400, &other_key)]);
+        assert_eq!(unsigned.fee(), 100);
+
+        // each key only signs its own inputs
+        assert_eq!(unsigned.sign(&cold_key), 1);
+        assert!(!unsigned.is_signed());
+        assert!(unsigned.clone().finalize().is_err());
+        assert_eq!(unsigned.sign(&other_key), 1);
+        assert!(unsigned.is_signed());
+
+        let transaction = unsigned.finalize().unwrap();
+        assert_eq!(transaction.inputs.len(), 2);
+        for (input, output) in transaction.inputs.iter().zip(&spent) {
+            assert_eq!(input.prev_transaction_output_hash, output.hash());
+            assert!(input.signature.verify(&output.hash(), &output.pubkey));
+        }
+    }
 }
 
 #[cfg(test)]
 mod block_tests {
-    use crate::config;
+    use crate::amount::MAX_MONEY;
+    use crate::config::{self, NetworkConfig};
     use crate::crypto::PrivateKey;
-    use crate::test_helpers::create_test_output;
+    use crate::error::BtcError;
+    use crate::sha256::Hash;
+    use crate::test_helpers::{create_test_input, create_test_output};
     use crate::types::{Block, BlockHeader, Transaction};
     use crate::util::MerkleRoot;
     use chrono::Utc;
+    use std::collections::{BTreeMap, HashMap};
 
     #[test]
     fn test_block_creation() {
-        let mut private_key = PrivateKey::new_key();
-        let output = create_test_output(1000, &mut private_key);
+        let private_key = PrivateKey::new_key();
+        let output = create_test_output(1000, &private_key);
         let transaction = Transaction::new(vec![], vec![output]);
 
         let block = Block::new(
@@ -109,8 +140,8 @@ mod block_tests {
 
     #[test]
     fn test_block_hashing() {
-        let mut private_key = PrivateKey::new_key();
-        let output = create_test_output(1000, &mut private_key);
+        let private_key = PrivateKey::new_key();
+        let output = create_test_output(1000, &private_key);
         let transaction = Transaction::new(vec![], vec![output]);
 
         let block = Block::new(
@@ -130,8 +161,8 @@ mod block_tests {
 
     #[test]
     fn test_block_header_hash() {
-        let mut private_key = PrivateKey::new_key();
-        let output = create_test_output(1000, &mut private_key);
+        let private_key = PrivateKey::new_key();
+        let output = create_test_output(1000, &private_key);
         let transaction = Transaction::new(vec![], vec![output]);
 
         let header = BlockHeader::new(
@@ -149,8 +180,8 @@ mod block_tests {
 
     #[test]
     fn test_block_header_different_nonces_different_hash() {
-        let mut private_key = PrivateKey::new_key();
-        let output = create_test_output(1000, &mut private_key);
+        let private_key = PrivateKey::new_key();
+        let output = create_test_output(1000, &private_key);
         let transaction = Transaction::new(vec![], vec![output]);
 
         let header1 = BlockHeader::new(
@@ -172,16 +203,231 @@ mod block_tests {
         // Different nonces should produce different hashes
         assert_ne!(header1.hash(), header2.hash());
     }
+
+    fn block_with(transactions: Vec<Transaction>) -> Block {
+        Block::new(
+            BlockHeader::new(
+                Utc::now(),
+                0,
+                crate::sha256::Hash::zero(),
+                MerkleRoot::calculate(&transactions),
+                config::min_target(),
+            ),
+            transactions,
+        )
+    }
+
+    #[test]
+    fn test_coinbase_commits_to_height() {
+        let params = NetworkConfig::default();
+        let output = create_test_output(params.block_reward(5), &PrivateKey::new_key());
+        let coinbase = Transaction::coinbase(5, vec![output.clone()]);
+        let utxos = HashMap::new();
+        assert!(block_with(vec![coinbase.clone()])
+            .verify_transactions(&params, 5, &utxos)
+            .is_ok());
+
+        // the same payout at another height is another transaction
+        assert_ne!(
+            Transaction::coinbase(6, vec![output.clone()]).hash(),
+            coinbase.hash()
+        );
+        let wrong_height = Transaction::coinbase(6, vec![output.clone()]);
+        let no_height = Transaction::new(vec![], vec![output.clone()]);
+        for coinbase in [wrong_height, no_height] {
+            let error = block_with(vec![coinbase])
+                .verify_transactions(&params, 5, &utxos)
+                .unwrap_err();
+            assert!(error.to_string().contains("block height 5"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn test_coinbase_rules() {
+        let params = NetworkConfig::default();
+        let key = PrivateKey::new_key();
+        let reward = params.block_reward(1);
+        let coinbase = |value| Transaction::coinbase(1, vec![create_test_output(value, &key)]);
+        let spend = Transaction::new(
+            vec![create_test_input(&Hash::zero(), &key)],
+            vec![create_test_output(1, &key)],
+        );
+        let verify = |transactions| {
+            block_with(transactions).verify_transactions(&params, 1, &HashMap::new())
+        };
+
+        assert!(matches!(
+            verify(vec![spend.clone()]),
+            Err(BtcError::MissingCoinbase)
+        ));
+        assert!(matches!(
+            verify(vec![spend.clone(), coinbase(reward)]),
+            Err(BtcError::CoinbaseNotFirst { position: 1 })
+        ));
+        assert!(matches!(
+            verify(vec![coinbase(reward), coinbase(0), coinbase(0)]),
+            Err(BtcError::MultipleCoinbases { count: 3 })
+        ));
+        // a transaction without inputs is a coinbase, height or not
+        assert!(matches!(
+            verify(vec![coinbase(reward), Transaction::new(vec![], vec![])]),
+            Err(BtcError::MultipleCoinbases { count: 2 })
+        ));
+        let mut with_inputs = coinbase(reward);
+        with_inputs.inputs = spend.inputs.clone();
+        assert!(matches!(
+            verify(vec![with_inputs]),
+            Err(BtcError::CoinbaseHasInputs)
+        ));
+        assert!(matches!(
+            verify(vec![coinbase(reward + 1)]),
+            Err(BtcError::CoinbaseOverpays { value, reward: r, fees: 0 })
+                if value == reward + 1 && r == reward
+        ));
+        // paying less than allowed burns the difference
+        assert!(verify(vec![coinbase(reward - 1)]).is_ok());
+    }
+
+    #[test]
+    fn test_duplicates_within_a_block() {
+        let params = NetworkConfig::default();
+        let key = PrivateKey::new_key();
+        let coinbase =
+            Transaction::coinbase(1, vec![create_test_output(params.block_reward(1), &key)]);
+        let spend = |output: &Hash, value| {
+            Transaction::new(
+                vec![create_test_input(output, &key)],
+                vec![create_test_output(value, &key)],
+            )
+        };
+        let first = Hash::zero();
+        let second = coinbase.outputs[0].hash();
+        let verify = |transactions: Vec<Transaction>| {
+            block_with(transactions).verify_transactions(&params, 1, &HashMap::new())
+        };
+
+        let twice = spend(&first, 1);
+        assert!(matches!(
+            verify(vec![coinbase.clone(), twice.clone(), twice.clone()]),
+            Err(BtcError::DuplicateTransaction { hash }) if hash == twice.hash()
+        ));
+        // two different transactions spending the same output
+        assert!(matches!(
+            verify(vec![coinbase.clone(), spend(&first, 1), spend(&first, 2)]),
+            Err(BtcError::DuplicateSpend { output }) if output == first
+        ));
+        // or one transaction spending it twice
+        let mut both = spend(&second, 1);
+        both.inputs.push(create_test_input(&first, &key));
+        both.inputs.push(create_test_input(&second, &key));
+        assert!(matches!(
+            verify(vec![coinbase, both]),
+            Err(BtcError::DuplicateSpend { output }) if output == second
+        ));
+    }
+
+    #[test]
+    fn test_versions_follow_activations() {
+        let params = NetworkConfig::default();
+        let key = PrivateKey::new_key();
+        let coinbase =
+            Transaction::coinbase(1, vec![create_test_output(params.block_reward(1), &key)]);
+        // no activations configured, version 1 is in force everywhere
+        assert_eq!(params.version_at(1), 1);
+        assert!(block_with(vec![coinbase.clone()])
+            .verify_versions(&params, 1)
+            .is_ok());
+
+        let mut obsolete = block_with(vec![coinbase.clone()]);
+        obsolete.header.version = 0;
+        assert!(matches!(
+            obsolete.verify_versions(&params, 1),
+            Err(BtcError::ObsoleteBlockVersion {
+                version: 0,
+                required: 1
+            })
+        ));
+        // miners may upgrade ahead of the network
+        let mut ahead = block_with(vec![coinbase.clone()]);
+        ahead.header.version = 2;
+        assert!(ahead.verify_versions(&params, 1).is_ok());
+
+        for version in [0, 2] {
+            let mut transaction = coinbase.clone();
+            transaction.version = version;
+            assert!(matches!(
+                block_with(vec![transaction]).verify_versions(&params, 1),
+                Err(BtcError::InactiveTransactionVersion { version: v, active: 1 }) if v == version
+            ));
+        }
+
+        // the same block under parameters that activated version 2
+        let upgraded = NetworkConfig {
+            version_activations: BTreeMap::from([(1, 2)]),
+            ..Default::default()
+        };
+        assert!(matches!(
+            block_with(vec![coinbase.clone()]).verify_versions(&upgraded, 1),
+            Err(BtcError::ObsoleteBlockVersion {
+                version: 1,
+                required: 2
+            })
+        ));
+        assert!(ahead.verify_versions(&upgraded, 1).is_ok());
+    }
+
+    #[test]
+    fn test_amounts_are_range_checked() {
+        let params = NetworkConfig::default();
+        let key = PrivateKey::new_key();
+        let coinbase =
+            Transaction::coinbase(1, vec![create_test_output(params.block_reward(1), &key)]);
+        let funding = create_test_output(1000, &key);
+        let mut utxos = HashMap::new();
+        utxos.insert(funding.hash(), (false, funding.clone()));
+        let verify = |transactions: Vec<Transaction>| {
+            block_with(transactions).verify_transactions(&params, 1, &utxos)
+        };
+
+        // two outputs whose sum wraps around to less than the input
+        let half = u64::MAX / 2 + 1;
+        let wrapping = Transaction::new(
+            vec![create_test_input(&funding.hash(), &key)],
+            vec![
+                create_test_output(half, &key),
+                create_test_output(half + 500, &key),
+            ],
+        );
+        assert!(matches!(
+            verify(vec![coinbase.clone(), wrapping]),
+            Err(BtcError::InvalidAmount { .. })
+        ));
+        let oversized = Transaction::coinbase(1, vec![create_test_output(MAX_MONEY + 1, &key)]);
+        assert!(matches!(
+            verify(vec![oversized]),
+            Err(BtcError::InvalidAmount { .. })
+        ));
+        assert!(verify(vec![coinbase]).is_ok());
+    }
 }
 
 #[cfg(test)]
 mod blockchain_tests {
-    use crate::crypto::PrivateKey;
-    use crate::test_helpers::create_test_output;
-    use crate::types::{Block, BlockHeader, Blockchain, Transaction};
-    use crate::util::MerkleRoot;
+    use crate::config::DifficultyAlgorithm;
+    use crate::crypto::{PrivateKey, Signature};
+    use crate::encoding::Encode;
+    use crate::error::BtcError;
+    use crate::sha256::Hash;
+    use crate::test_helpers::{arb_block, create_test_output};
+    use crate::types::{
+        Block, BlockHeader, Blockchain, FeeRate, Mempool, MempoolLimits, SavedMempool, Transaction,
+        TransactionInput, TransactionOutput,
+    };
+    use crate::util::{MerkleRoot, Saveable};
     use crate::{config, U256};
-    use chrono::Utc;
+    use chrono::{TimeZone, Utc};
+    use proptest::prelude::*;
+    use std::collections::HashMap;
 
     #[test]
     fn test_blockchain_initialization() {
@@ -195,9 +441,9 @@ mod blockchain_tests {
     #[test]
     fn test_blockchain_add_genesis_block() {
         let mut blockchain = Blockchain::new();
-        let mut private_key = PrivateKey::new_key();
+        let private_key = PrivateKey::new_key();
 
-        let output = create_test_output(config::initial_reward() * 100_000_000, &mut private_key);
+        let output = create_test_output(config::initial_reward() * 100_000_000, &private_key);
         let transaction = Transaction::new(vec![], vec![output]);
 
         let block = Block::new(
@@ -229,6 +475,95 @@ mod blockchain_tests {
         assert!(reward > 0);
     }
 
+    #[test]
+    fn test_retarget_scales_by_elapsed_time() {
+        let target = U256::from(1_000_000u64);
+        // twice as slow: twice as easy
+        assert_eq!(
+            Blockchain::retarget(target, 1000, 500),
+            U256::from(2_000_000u64)
+        );
+        // twice as fast: twice as hard
+        assert_eq!(
+            Blockchain::retarget(target, 250, 500),
+            U256::from(500_000u64)
+        );
+        // rounds down instead of going through decimals
+        assert_eq!(
+            Blockchain::retarget(U256::from(10u64), 1, 3),
+            U256::from(3u64)
+        );
+        assert_eq!(Blockchain::retarget(target, 500, 500), target);
+    }
+
+    #[test]
+    fn test_retarget_is_clamped_to_four_times() {
+        let target = U256::from(1_000_000u64);
+        assert_eq!(Blockchain::retarget(target, 1, 500), U256::from(250_000u64));
+        assert_eq!(Blockchain::retarget(target, 0, 500), U256::from(250_000u64));
+        assert_eq!(
+            Blockchain::retarget(target, i64::MAX, 500),
+            U256::from(4_000_000u64)
+        );
+        // huge targets don't overflow
+        assert_eq!(Blockchain::retarget(U256::MAX, 100_000, 500), U256::MAX);
+        assert_eq!(
+            Blockchain::retarget(U256::MAX, 1, 500),
+            U256::MAX / U256::from(4u64)
+        );
+    }
+
+    #[test]
+    fn test_digishield_damps_and_limits_steps() {
+        let target = U256::from(1_000_000u64);
+        assert_eq!(Blockchain::digishield_retarget(target, 200, 200), target);
+        // twice as slow moves only a quarter of the way: 25% easier
+        assert_eq!(
+            Blockchain::digishield_retarget(target, 400, 200),
+            U256::from(1_250_000u64)
+        );
+        // far too slow: at most 32% easier
+        assert_eq!(
+            Blockchain::digishield_retarget(target, 100_000, 100),
+            U256::from(1_320_000u64)
+        );
+        // far too fast: at most 16% harder
+        assert_eq!(
+            Blockchain::digishield_retarget(target, 0, 100),
+            U256::from(840_000u64)
+        );
+        assert_eq!(
+            Blockchain::digishield_retarget(U256::MAX, 100_000, 100),
+            U256::MAX
+        );
+    }
+
+    #[test]
+    fn test_estimated_hashrate_from_block_times() {
+        // every block takes 2^16 hashes on average
+        let target = (U256::one() << 240) - U256::one();
+        let start = Utc::now();
+        let blocks = (0..3)
+            .map(|i| {
+                Block::new(
+                    BlockHeader::new(
+                        start + chrono::Duration::seconds(10 * i),
+                        0,
+                        crate::sha256::Hash::zero(),
+                        MerkleRoot::calculate(&[Transaction::new(vec![], vec![])]),
+                        target,
+                    ),
+                    vec![],
+                )
+            })
+            .collect::<Vec<_>>();
+        let blockchain = Blockchain::from_parts(blocks, HashMap::new(), target);
+        // two blocks of 65536 hashes in 20 seconds
+        assert_eq!(blockchain.estimated_hashrate(10), Some(6553.6));
+        assert_eq!(blockchain.estimated_hashrate(1), Some(6553.6));
+        assert_eq!(Blockchain::new().estimated_hashrate(10), None);
+    }
+
     #[test]
     fn test_blockchain_target() {
         let blockchain = Blockchain::new();
@@ -237,4 +572,815 @@ mod blockchain_tests {
         // Target should not be zero
         assert_ne!(target, U256::from(0));
     }
+
+    #[test]
+    fn test_estimate_fee_from_recent_blocks() {
+        assert_eq!(
+            Blockchain::new().estimate_fee(&Mempool::default(), 1, 10),
+            None
+        );
+
+        let private_key = PrivateKey::new_key();
+        let reward = config::initial_reward() * 100_000_000;
+        // coinbase claims the reward plus 600 satoshis of fees from 3 transactions
+        let mut transactions = vec![Transaction::new(
+            vec![],
+            vec![create_test_output(reward + 600, &private_key)],
+        )];
+        for _ in 0..3 {
+            transactions.push(Transaction::new(
+                vec![],
+                vec![create_test_output(1, &private_key)],
+            ));
+        }
+        let block = Block::new(
+            BlockHeader::new(
+                Utc::now(),
+                0,
+                crate::sha256::Hash::zero(),
+                MerkleRoot::calculate(&transactions),
+                config::min_target(),
+            ),
+            transactions,
+        );
+        let blockchain = Blockchain::from_parts(vec![block], HashMap::new(), config::min_target());
+
+        assert_eq!(
+            blockchain.estimate_fee(&Mempool::default(), 1, 10),
+            Some(200)
+        );
+        assert_eq!(blockchain.estimate_fee(&Mempool::default(), 1, 0), None);
+    }
+
+    #[test]
+    fn test_indexes_track_added_blocks() {
+        let private_key = PrivateKey::new_key();
+        let coinbase = Transaction::new(
+            vec![],
+            vec![create_test_output(
+                config::initial_reward() * 100_000_000,
+                &private_key,
+            )],
+        );
+        let block = Block::new(
+            BlockHeader::new(
+                Utc::now(),
+                0,
+                crate::sha256::Hash::zero(),
+                MerkleRoot::calculate(std::slice::from_ref(&coinbase)),
+                config::min_target(),
+            ),
+            vec![coinbase.clone()],
+        );
+
+        // indexes are off by default
+        let mut blockchain = Blockchain::new();
+        blockchain.add_block(block.clone()).unwrap();
+        assert!(blockchain.find_transaction(&coinbase.hash()).is_none());
+        assert!(blockchain
+            .index()
+            .history(&private_key.public_key())
+            .is_empty());
+
+        // enabling them indexes the existing chain
+        blockchain.enable_indexes(true, true);
+        let (found, height, position) = blockchain.find_transaction(&coinbase.hash()).unwrap();
+        assert_eq!(found.hash(), coinbase.hash());
+        assert_eq!((height, position), (0, 0));
+        assert_eq!(
+            blockchain.index().history(&private_key.public_key()),
+            vec![(coinbase.hash(), 0)]
+        );
+
+        // and blocks added afterwards are indexed as they come in
+        let mut indexed = Blockchain::new();
+        indexed.enable_indexes(true, false);
+        indexed.add_block(block).unwrap();
+        assert!(indexed.find_transaction(&coinbase.hash()).is_some());
+        assert!(!indexed.index().has_addressindex());
+    }
+
+    #[test]
+    fn test_utxo_heights() {
+        let private_key = PrivateKey::new_key();
+        let output = create_test_output(config::initial_reward() * 100_000_000, &private_key);
+        let coinbase = Transaction::new(vec![], vec![output.clone()]);
+        let block = Block::new(
+            BlockHeader::new(
+                Utc::now(),
+                0,
+                crate::sha256::Hash::zero(),
+                MerkleRoot::calculate(std::slice::from_ref(&coinbase)),
+                config::min_target(),
+            ),
+            vec![coinbase],
+        );
+        let mut blockchain = Blockchain::new();
+        blockchain.add_block(block.clone()).unwrap();
+        assert_eq!(blockchain.tip_height(), 0);
+        assert_eq!(blockchain.utxo_height(&output.hash()), Some(0));
+
+        // heights are not persisted, rebuilding the UTXO set restores them
+        let mut reloaded =
+            Blockchain::from_parts(vec![block], HashMap::new(), config::min_target());
+        assert_eq!(reloaded.utxo_height(&output.hash()), None);
+        reloaded.rebuild_utxos();
+        assert_eq!(reloaded.utxo_height(&output.hash()), Some(0));
+    }
+
+    #[test]
+    fn test_reindex_discards_stale_state() {
+        let private_key = PrivateKey::new_key();
+        let output = create_test_output(config::initial_reward() * 100_000_000, &private_key);
+        let coinbase = Transaction::new(vec![], vec![output.clone()]);
+        let block = Block::new(
+            BlockHeader::new(
+                Utc::now(),
+                0,
+                crate::sha256::Hash::zero(),
+                MerkleRoot::calculate(std::slice::from_ref(&coinbase)),
+                config::min_target(),
+            ),
+            vec![coinbase.clone()],
+        );
+        // a stored UTXO set with an output no block created
+        let stale = create_test_output(1000, &private_key);
+        let mut utxos = HashMap::new();
+        utxos.insert(stale.hash(), (true, stale.clone()));
+        let mut blockchain = Blockchain::from_parts(vec![block], utxos, config::min_target());
+
+        let mut progress = vec![];
+        blockchain.reindex(true, false, |done, total| progress.push((done, total)));
+        assert_eq!(progress, vec![(1, 1)]);
+        assert!(!blockchain.utxos().contains_key(&stale.hash()));
+        assert!(!blockchain.utxos()[&output.hash()].0);
+        assert_eq!(blockchain.utxo_height(&output.hash()), Some(0));
+        assert_eq!(blockchain.index().locate(&coinbase.hash()), Some((0, 0)));
+        assert!(!blockchain.index().has_addressindex());
+    }
+
+    #[test]
+    fn test_replaced_mempool_transaction_is_a_conflict() {
+        let private_key = PrivateKey::new_key();
+        let output = create_test_output(1000, &private_key);
+        let spend = |value| {
+            Transaction::new(
+                vec![TransactionInput {
+                    prev_transaction_output_hash: output.hash(),
+                    signature: Signature::sign_output(&output.hash(), &private_key.clone()),
+                }],
+                vec![create_test_output(value, &PrivateKey::new_key())],
+            )
+        };
+        let mut utxos = HashMap::new();
+        utxos.insert(output.hash(), (false, output.clone()));
+        let blockchain = Blockchain::from_parts(vec![], utxos, config::min_target());
+        let mut mempool = Mempool::default();
+
+        let first = spend(900);
+        mempool.add(&blockchain, first.clone()).unwrap();
+        assert!(mempool.take_conflicts().is_empty());
+
+        mempool.add(&blockchain, spend(800)).unwrap();
+        let conflicts = mempool.take_conflicts();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].0, first.hash());
+        assert!(conflicts[0].1.contains(&private_key.public_key()));
+        assert!(mempool.take_conflicts().is_empty());
+    }
+
+    #[test]
+    fn test_mempool_checks_amounts_and_versions() {
+        let private_key = PrivateKey::new_key();
+        let output = create_test_output(1000, &private_key);
+        let mut utxos = HashMap::new();
+        utxos.insert(output.hash(), (false, output.clone()));
+        let blockchain = Blockchain::from_parts(vec![], utxos, config::min_target());
+        let mut mempool = Mempool::default();
+        let inputs = vec![TransactionInput {
+            prev_transaction_output_hash: output.hash(),
+            signature: Signature::sign_output(&output.hash(), &private_key),
+        }];
+        let half = u64::MAX / 2 + 1;
+        let transaction = Transaction::new(
+            inputs.clone(),
+            vec![
+                create_test_output(half, &private_key),
+                create_test_output(half + 500, &private_key),
+            ],
+        );
+        assert!(matches!(
+            mempool.add(&blockchain, transaction),
+            Err(BtcError::InvalidAmount { .. })
+        ));
+
+        let mut future = Transaction::new(inputs, vec![create_test_output(900, &private_key)]);
+        future.version = 2;
+        assert!(matches!(
+            mempool.add(&blockchain, future),
+            Err(BtcError::InactiveTransactionVersion { version: 2, .. })
+        ));
+        assert!(mempool.transactions().is_empty());
+        assert!(!mempool.is_reserved(&output.hash()));
+    }
+
+    #[test]
+    fn test_mempool_expiry_without_waiting() {
+        let private_key = PrivateKey::new_key();
+        let output = create_test_output(1000, &private_key);
+        let mut utxos = HashMap::new();
+        utxos.insert(output.hash(), (false, output.clone()));
+        let blockchain = Blockchain::from_parts(vec![], utxos, config::min_target());
+        let mut mempool = Mempool::default();
+        let transaction = Transaction::new(
+            vec![TransactionInput {
+                prev_transaction_output_hash: output.hash(),
+                signature: Signature::sign_output(&output.hash(), &private_key),
+            }],
+            vec![create_test_output(900, &PrivateKey::new_key())],
+        );
+
+        let received = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let txid = transaction.hash();
+        mempool.add_at(&blockchain, transaction, received).unwrap();
+        assert!(mempool.is_reserved(&output.hash()));
+        let max_age = chrono::Duration::seconds(config::max_mempool_transaction_age() as i64);
+
+        mempool.cleanup_at(&blockchain, received + max_age);
+        assert_eq!(mempool.transactions().len(), 1);
+        assert!(mempool.take_expired().is_empty());
+        mempool.cleanup_at(
+            &blockchain,
+            received + max_age + chrono::Duration::seconds(1),
+        );
+        assert!(mempool.transactions().is_empty());
+        assert!(!mempool.is_reserved(&output.hash()));
+        // the sender hears about it, not as a conflict
+        let expired = mempool.take_expired();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].0, txid);
+        assert!(expired[0].1.contains(&output.pubkey));
+        assert!(mempool.take_conflicts().is_empty());
+    }
+
+    #[test]
+    fn test_saved_mempool_is_revalidated() {
+        let private_key = PrivateKey::new_key();
+        let kept = create_test_output(1000, &private_key);
+        let spent = create_test_output(2000, &private_key);
+        let spend = |output: &TransactionOutput| {
+            Transaction::new(
+                vec![TransactionInput {
+                    prev_transaction_output_hash: output.hash(),
+                    signature: Signature::sign_output(&output.hash(), &private_key),
+                }],
+                vec![create_test_output(
+                    output.value - 100,
+                    &PrivateKey::new_key(),
+                )],
+            )
+        };
+        let mut utxos = HashMap::new();
+        utxos.insert(kept.hash(), (false, kept.clone()));
+        utxos.insert(spent.hash(), (false, spent.clone()));
+        let blockchain = Blockchain::from_parts(vec![], utxos, config::min_target());
+        let mut mempool = Mempool::default();
+        let pending = spend(&kept);
+        let received = Utc::now() - chrono::Duration::seconds(10);
+        mempool
+            .add_at(&blockchain, pending.clone(), received)
+            .unwrap();
+        mempool.add(&blockchain, spend(&spent)).unwrap();
+
+        let mut bytes = vec![];
+        mempool.saved().save(&mut bytes).unwrap();
+        let saved = SavedMempool::load(bytes.as_slice()).unwrap();
+        assert_eq!(saved.transactions.len(), 2);
+
+        // after the restart, one output has been spent by a block
+        let mut utxos = HashMap::new();
+        utxos.insert(kept.hash(), (false, kept.clone()));
+        let restarted = Blockchain::from_parts(vec![], utxos, config::min_target());
+        let mut restarted_mempool = Mempool::default();
+        assert_eq!(restarted_mempool.restore(&restarted, saved), 1);
+        assert_eq!(restarted_mempool.transactions()[0].0, received);
+        assert_eq!(restarted_mempool.transactions()[0].1.hash(), pending.hash());
+        assert!(restarted_mempool.is_reserved(&kept.hash()));
+        assert!(restarted_mempool.take_conflicts().is_empty());
+    }
+
+    #[test]
+    fn test_orphans_wait_for_their_inputs() {
+        let private_key = PrivateKey::new_key();
+        let output = create_test_output(config::block_reward(0), &private_key);
+        let transactions = vec![Transaction::coinbase(0, vec![output.clone()])];
+        let block = Block::new(
+            BlockHeader::new(
+                Utc::now(),
+                0,
+                Hash::zero(),
+                MerkleRoot::calculate(&transactions),
+                U256::MAX,
+            ),
+            transactions,
+        );
+        let mut blockchain = Blockchain::from_parts(vec![block], HashMap::new(), U256::MAX);
+        let mut mempool = Mempool::default();
+        let spend = |value| {
+            Transaction::new(
+                vec![TransactionInput {
+                    prev_transaction_output_hash: output.hash(),
+                    signature: Signature::sign_output(&output.hash(), &private_key),
+                }],
+                vec![create_test_output(value, &PrivateKey::new_key())],
+            )
+        };
+        let orphan = spend(output.value - 100);
+        assert!(mempool.add(&blockchain, orphan.clone()).is_err());
+
+        let received = Utc::now();
+        mempool.add_orphan_at(orphan.clone(), 2, received);
+        mempool.add_orphan_at(orphan.clone(), 2, received);
+        assert_eq!(mempool.orphans().len(), 1);
+        assert!(mempool.retry_orphans(&blockchain).is_empty());
+        assert_eq!(mempool.orphans().len(), 1);
+
+        // the block creating its input arrives
+        blockchain.rebuild_utxos();
+        let admitted = mempool.retry_orphans(&blockchain);
+        assert_eq!(admitted.len(), 1);
+        assert_eq!(admitted[0].hash(), orphan.hash());
+        assert!(mempool.orphans().is_empty());
+        assert_eq!(mempool.transactions()[0].0, received);
+
+        // the pool is bounded, and expires like the mempool
+        for value in [10, 20, 30] {
+            mempool.add_orphan_at(spend(value), 2, received);
+        }
+        assert_eq!(mempool.orphans().len(), 2);
+        assert_eq!(mempool.orphans()[0].1.outputs[0].value, 20);
+        let max_age = chrono::Duration::seconds(config::max_mempool_transaction_age() as i64);
+        mempool.cleanup_at(
+            &blockchain,
+            received + max_age + chrono::Duration::seconds(1),
+        );
+        assert!(mempool.orphans().is_empty());
+    }
+
+    #[test]
+    fn test_bounded_mempool_keeps_the_highest_fees() {
+        let private_key = PrivateKey::new_key();
+        let outputs: Vec<_> = (0..4)
+            .map(|_| create_test_output(1000, &private_key))
+            .collect();
+        let mut utxos = HashMap::new();
+        for output in &outputs {
+            utxos.insert(output.hash(), (false, output.clone()));
+        }
+        let blockchain = Blockchain::from_parts(vec![], utxos, config::min_target());
+        let mut mempool = Mempool::default();
+        let spend = |output: &TransactionOutput, fee: u64| {
+            Transaction::new(
+                vec![TransactionInput {
+                    prev_transaction_output_hash: output.hash(),
+                    signature: Signature::sign_output(&output.hash(), &private_key),
+                }],
+                vec![create_test_output(1000 - fee, &PrivateKey::new_key())],
+            )
+        };
+        let limits = MempoolLimits {
+            max_bytes: 0,
+            max_transactions: 2,
+        };
+        let cheap = spend(&outputs[0], 200);
+        mempool
+            .add_bounded(&blockchain, cheap.clone(), limits)
+            .unwrap();
+        mempool
+            .add_bounded(&blockchain, spend(&outputs[1], 300), limits)
+            .unwrap();
+        assert_eq!(
+            mempool
+                .min_fee_rate(&blockchain, limits)
+                .map(|rate| rate.fee),
+            Some(200)
+        );
+
+        assert!(matches!(
+            mempool.add_bounded(&blockchain, spend(&outputs[2], 100), limits),
+            Err(BtcError::MempoolFull {
+                fee: 100,
+                min_fee: 201
+            })
+        ));
+        assert!(!blockchain.utxos()[&outputs[2].hash()].0);
+
+        let evicted = mempool
+            .add_bounded(&blockchain, spend(&outputs[3], 250), limits)
+            .unwrap();
+        assert_eq!(evicted, vec![cheap.hash()]);
+        assert!(!blockchain.utxos()[&outputs[0].hash()].0);
+        assert_eq!(mempool.take_conflicts()[0].0, cheap.hash());
+        assert_eq!(
+            mempool
+                .min_fee_rate(&blockchain, limits)
+                .map(|rate| rate.fee),
+            Some(250)
+        );
+
+        // by size, the cheapest goes first too
+        let limits = MempoolLimits {
+            max_bytes: mempool.bytes() - 1,
+            max_transactions: 0,
+        };
+        assert_eq!(mempool.trim(&blockchain, limits).len(), 1);
+        assert_eq!(
+            blockchain.mempool_fee(&mempool.transactions()[0].1),
+            Some(300)
+        );
+        assert_eq!(
+            mempool.min_fee_rate(&blockchain, MempoolLimits::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_bounded_mempool_evicts_the_lowest_fee_rate_first() {
+        let private_key = PrivateKey::new_key();
+        let outputs: Vec<_> = (0..3)
+            .map(|_| create_test_output(1000, &private_key))
+            .collect();
+        let mut utxos = HashMap::new();
+        for output in &outputs {
+            utxos.insert(output.hash(), (false, output.clone()));
+        }
+        let blockchain = Blockchain::from_parts(vec![], utxos, config::min_target());
+        let mut mempool = Mempool::default();
+        let spend = |output: &TransactionOutput, fee: u64, payments: u64| {
+            Transaction::new(
+                vec![TransactionInput {
+                    prev_transaction_output_hash: output.hash(),
+                    signature: Signature::sign_output(&output.hash(), &private_key),
+                }],
+                (0..payments)
+                    .map(|_| create_test_output((1000 - fee) / payments, &PrivateKey::new_key()))
+                    .collect(),
+            )
+        };
+        let limits = MempoolLimits {
+            max_bytes: 0,
+            max_transactions: 2,
+        };
+        // the highest fee, but spread over many outputs
+        let large = spend(&outputs[0], 500, 10);
+        let small = spend(&outputs[1], 300, 1);
+        assert!(FeeRate::new(500, large.encode().len()) < FeeRate::new(300, small.encode().len()));
+        mempool
+            .add_bounded(&blockchain, large.clone(), limits)
+            .unwrap();
+        mempool
+            .add_bounded(&blockchain, small.clone(), limits)
+            .unwrap();
+        assert_eq!(mempool.transactions()[0].1.hash(), small.hash());
+        assert_eq!(
+            mempool.min_fee_rate(&blockchain, limits),
+            Some(FeeRate::new(500, large.encode().len()))
+        );
+
+        // a lower fee than the large one's, at a higher rate
+        let evicted = mempool
+            .add_bounded(&blockchain, spend(&outputs[2], 250, 1), limits)
+            .unwrap();
+        assert_eq!(evicted, vec![large.hash()]);
+        assert!(mempool
+            .transactions()
+            .iter()
+            .any(|(_, tx)| tx.hash() == small.hash()));
+        assert_eq!(
+            mempool.bytes(),
+            mempool
+                .transactions()
+                .iter()
+                .map(|(_, tx)| tx.encode().len())
+                .sum::<usize>()
+        );
+    }
+
+    #[test]
+    fn test_bounded_mempool_keeps_the_original_of_a_replacement_too_large() {
+        let private_key = PrivateKey::new_key();
+        let output = create_test_output(1000, &private_key);
+        let mut utxos = HashMap::new();
+        utxos.insert(output.hash(), (false, output.clone()));
+        let blockchain = Blockchain::from_parts(vec![], utxos, config::min_target());
+        let mut mempool = Mempool::default();
+        let spend = |fee: u64, payments: u64| {
+            Transaction::new(
+                vec![TransactionInput {
+                    prev_transaction_output_hash: output.hash(),
+                    signature: Signature::sign_output(&output.hash(), &private_key),
+                }],
+                (0..payments)
+                    .map(|_| create_test_output((1000 - fee) / payments, &PrivateKey::new_key()))
+                    .collect(),
+            )
+        };
+        let original = spend(100, 1);
+        let limits = MempoolLimits {
+            max_bytes: original.encode().len() * 2,
+            max_transactions: 0,
+        };
+        mempool
+            .add_bounded(&blockchain, original.clone(), limits)
+            .unwrap();
+
+        let replacement = spend(500, 10);
+        assert!(replacement.encode().len() > limits.max_bytes);
+        assert!(matches!(
+            mempool.add_bounded(&blockchain, replacement, limits),
+            Err(BtcError::MempoolFull { fee: 500, .. })
+        ));
+        assert_eq!(mempool.transactions().len(), 1);
+        assert_eq!(mempool.transactions()[0].1.hash(), original.hash());
+        assert!(mempool.is_reserved(&output.hash()));
+        assert!(mempool.take_conflicts().is_empty());
+        assert_eq!(mempool.bytes(), original.encode().len());
+    }
+
+    #[test]
+    fn test_evict_from_mempool() {
+        let private_key = PrivateKey::new_key();
+        let output = create_test_output(1000, &private_key);
+        let mut utxos = HashMap::new();
+        utxos.insert(output.hash(), (false, output.clone()));
+        let blockchain = Blockchain::from_parts(vec![], utxos, config::min_target());
+        let mut mempool = Mempool::default();
+        let transaction = Transaction::new(
+            vec![TransactionInput {
+                prev_transaction_output_hash: output.hash(),
+                signature: Signature::sign_output(&output.hash(), &private_key),
+            }],
+            vec![create_test_output(900, &PrivateKey::new_key())],
+        );
+        mempool.add(&blockchain, transaction.clone()).unwrap();
+
+        assert!(mempool.evict(&blockchain, &Hash::zero()).is_none());
+        assert_eq!(mempool.transactions().len(), 1);
+
+        let evicted = mempool.evict(&blockchain, &transaction.hash()).unwrap();
+        assert_eq!(evicted.hash(), transaction.hash());
+        assert!(mempool.transactions().is_empty());
+        assert!(!mempool.is_reserved(&output.hash()));
+        let conflicts = mempool.take_conflicts();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].0, transaction.hash());
+        assert!(conflicts[0].1.contains(&private_key.public_key()));
+    }
+
+    #[test]
+    fn test_block_removes_mined_and_conflicting_transactions() {
+        let private_key = PrivateKey::new_key();
+        let outputs: Vec<_> = (0..2)
+            .map(|_| create_test_output(1000, &private_key))
+            .collect();
+        let mut utxos = HashMap::new();
+        for output in &outputs {
+            utxos.insert(output.hash(), (false, output.clone()));
+        }
+        let blockchain = Blockchain::from_parts(vec![], utxos, config::min_target());
+        let mut mempool = Mempool::default();
+        let spend = |output: &TransactionOutput, value| {
+            Transaction::new(
+                vec![TransactionInput {
+                    prev_transaction_output_hash: output.hash(),
+                    signature: Signature::sign_output(&output.hash(), &private_key),
+                }],
+                vec![create_test_output(value, &PrivateKey::new_key())],
+            )
+        };
+        let mined = spend(&outputs[0], 900);
+        let replaced = spend(&outputs[1], 900);
+        mempool.add(&blockchain, mined.clone()).unwrap();
+        mempool.add(&blockchain, replaced.clone()).unwrap();
+
+        // the block mines one and spends the other's input differently
+        let transactions = vec![mined, spend(&outputs[1], 800)];
+        let block = Block::new(
+            BlockHeader::new(
+                Utc::now(),
+                0,
+                Hash::zero(),
+                MerkleRoot::calculate(&transactions),
+                config::min_target(),
+            ),
+            transactions,
+        );
+        mempool.remove_block(&blockchain, &block);
+        assert!(mempool.is_empty());
+        assert!(!mempool.is_reserved(&outputs[0].hash()));
+        assert!(!mempool.is_reserved(&outputs[1].hash()));
+        let conflicts = mempool.take_conflicts();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].0, replaced.hash());
+    }
+
+    #[test]
+    fn test_difficulty_follows_block_timestamps() {
+        // blocks twice as fast as intended, timestamps are all that counts
+        let interval = config::difficulty_update_interval();
+        let spacing = config::ideal_block_time() as i64 / 2;
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let blocks = (0..interval as i64)
+            .map(|height| {
+                let transactions = vec![Transaction::new(vec![], vec![])];
+                Block::new(
+                    BlockHeader::new(
+                        start + chrono::Duration::seconds(height * spacing),
+                        0,
+                        Hash::zero(),
+                        MerkleRoot::calculate(&transactions),
+                        U256::MAX,
+                    ),
+                    transactions,
+                )
+            })
+            .collect();
+        let mut blockchain = Blockchain::from_parts(blocks, HashMap::new(), config::min_target());
+        blockchain.try_adjust_target();
+        if config::difficulty_algorithm() == DifficultyAlgorithm::Interval {
+            assert!(blockchain.target() < config::min_target() / U256::from(2) + 1);
+            assert!(blockchain.target() >= config::min_target() / U256::from(4));
+        }
+    }
+
+    /// Genesis and one more block paying the reward, neither needing work
+    fn two_block_chain() -> Vec<Block> {
+        let mut blocks: Vec<Block> = vec![];
+        for height in 0..2 {
+            let transactions = vec![Transaction::coinbase(
+                height,
+                vec![create_test_output(
+                    config::block_reward(height),
+                    &PrivateKey::new_key(),
+                )],
+            )];
+            let prev = blocks.last().map_or(Hash::zero(), |block| block.hash());
+            blocks.push(Block::new(
+                BlockHeader::new(
+                    Utc.timestamp_opt(1_700_000_000 + height as i64, 0).unwrap(),
+                    0,
+                    prev,
+                    MerkleRoot::calculate(&transactions),
+                    U256::MAX,
+                ),
+                transactions,
+            ));
+        }
+        blocks
+    }
+
+    #[test]
+    fn test_verify_chain() {
+        let blocks = two_block_chain();
+        let mut blockchain = Blockchain::from_parts(blocks.clone(), HashMap::new(), U256::MAX);
+        blockchain.rebuild_utxos();
+        let report = blockchain.verify_chain().unwrap();
+        assert_eq!(report.blocks, 2);
+        assert_eq!(report.transactions, 2);
+        assert_eq!(report.tip, blocks[1].hash());
+        assert_eq!(report.utxos, 2);
+        assert_eq!(
+            report.total_supply,
+            config::block_reward(0) + config::block_reward(1)
+        );
+
+        // a stale UTXO set
+        let stale = Blockchain::from_parts(blocks.clone(), HashMap::new(), U256::MAX);
+        let error = stale.verify_chain().unwrap_err().to_string();
+        assert!(error.contains("2 missing"), "{}", error);
+
+        // a coinbase paying itself more than the reward
+        let mut inflated = blocks;
+        inflated[1].transactions[0].outputs[0].value += 1;
+        inflated[1].header.merkle_root = MerkleRoot::calculate(&inflated[1].transactions);
+        let mut blockchain = Blockchain::from_parts(inflated, HashMap::new(), U256::MAX);
+        blockchain.rebuild_utxos();
+        let error = blockchain.verify_chain().unwrap_err().to_string();
+        assert!(error.contains("block 1"), "{}", error);
+    }
+
+    #[test]
+    fn test_supply_never_exceeds_the_schedule() {
+        let blocks = two_block_chain();
+        let mut blockchain = Blockchain::new();
+        for block in &blocks {
+            blockchain.add_block(block.clone()).unwrap();
+            blockchain.rebuild_utxos();
+            assert_eq!(
+                blockchain.total_supply(),
+                config::scheduled_supply(blockchain.block_height())
+            );
+        }
+
+        // nothing else checks what the genesis coinbase pays
+        let mut inflated = blocks[0].clone();
+        inflated.transactions[0].outputs[0].value += 1;
+        inflated.header.merkle_root = MerkleRoot::calculate(&inflated.transactions);
+        let allowed = config::block_reward(0);
+        assert!(matches!(
+            Blockchain::new().add_block(inflated),
+            Err(BtcError::SupplyExceeded { supply, allowed: a }) if supply == allowed + 1 && a == allowed
+        ));
+    }
+
+    #[test]
+    fn test_split_coinbase_pays_by_weight_and_stays_valid() {
+        let mut blocks = two_block_chain();
+        let keys: Vec<_> = (0..3).map(|_| PrivateKey::new_key().public_key()).collect();
+        let reward = config::block_reward(1);
+        blocks[1]
+            .split_coinbase(&[
+                (keys[0].clone(), 2),
+                (keys[1].clone(), 1),
+                (keys[2].clone(), 0),
+            ])
+            .unwrap();
+        let outputs = &blocks[1].transactions[0].outputs;
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(outputs[0].pubkey, keys[0]);
+        assert_eq!(outputs[1].pubkey, keys[1]);
+        assert_eq!(outputs[1].value, reward / 3);
+        assert_eq!(outputs[0].value + outputs[1].value, reward);
+
+        let mut blockchain = Blockchain::new();
+        for block in blocks.clone() {
+            blockchain.add_block(block).unwrap();
+        }
+        assert!(blocks[1]
+            .clone()
+            .split_coinbase(&[(keys[0].clone(), 0)])
+            .is_err());
+        assert!(matches!(
+            blocks[1]
+                .clone()
+                .split_coinbase(&[(keys[0].clone(), u64::MAX), (keys[1].clone(), u64::MAX)]),
+            Err(BtcError::InvalidBlock { .. })
+        ));
+    }
+
+    #[test]
+    fn test_utxo_commitment() {
+        let blocks = two_block_chain();
+        let mut blockchain = Blockchain::new();
+        blockchain.add_block(blocks[0].clone()).unwrap();
+        blockchain.rebuild_utxos();
+        let after_genesis = blockchain.utxo_commitment();
+        assert_ne!(after_genesis, Blockchain::new().utxo_commitment());
+
+        // the same outputs commit the same way, whatever the map order
+        let mut copy = Blockchain::from_parts(vec![blocks[0].clone()], HashMap::new(), U256::MAX);
+        copy.rebuild_utxos();
+        assert_eq!(copy.utxo_commitment(), after_genesis);
+
+        let mut wrong = blocks[1].clone();
+        wrong.header.utxo_commitment = Some(Hash::zero());
+        let error = blockchain.add_block(wrong).unwrap_err().to_string();
+        assert!(error.contains("UTXO commitment"), "{}", error);
+
+        let mut committed = blocks[1].clone();
+        committed.header.utxo_commitment = Some(after_genesis);
+        blockchain.add_block(committed.clone()).unwrap();
+        blockchain.rebuild_utxos();
+        assert!(blockchain.verify_chain().is_ok());
+
+        // blocks added one after another, as during a sync, keep the set
+        // current without replaying the chain
+        let mut synced = Blockchain::new();
+        synced.add_block(blocks[0].clone()).unwrap();
+        synced.apply_tip_to_utxos();
+        assert_eq!(synced.utxo_commitment(), after_genesis);
+        synced.add_block(committed).unwrap();
+        synced.apply_tip_to_utxos();
+        assert_eq!(synced.utxo_commitment(), blockchain.utxo_commitment());
+    }
+
+    proptest! {
+        /// A corrupt blockchain file is an error, not a crash
+        #[test]
+        fn prop_loading_arbitrary_bytes_never_panics(bytes in any::<Vec<u8>>()) {
+            let _ = Blockchain::load(bytes.as_slice());
+            let _ = Block::load(bytes.as_slice());
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn prop_saved_blocks_load_back(block in arb_block()) {
+            let mut bytes = vec![];
+            block.save(&mut bytes).unwrap();
+            prop_assert_eq!(Block::load(bytes.as_slice()).unwrap().hash(), block.hash());
+        }
+    }
 }
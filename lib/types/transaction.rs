@@ -1,22 +1,94 @@
-use crate::crypto::{PublicKey, Signature};
+use crate::amount::Amount;
+use crate::crypto::{PrivateKey, PublicKey, Signature};
+use crate::encoding::{Decode, DecodeError, Encode};
+use crate::error::{BtcError, Result};
 use crate::sha256::Hash;
 use crate::util::Saveable;
 use serde::{Deserialize, Serialize};
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
 use uuid::Uuid;
 
+/// Version of the transactions this release creates
+pub const TRANSACTION_VERSION: u32 = 1;
+
+fn default_transaction_version() -> u32 {
+    TRANSACTION_VERSION
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Transaction {
+    /// Transaction format, at most the consensus version in force (see
+    /// [`NetworkConfig::version_at`](crate::config::NetworkConfig::version_at)). New output
+    /// conditions come with a new version and are only valid once it
+    /// has activated.
+    #[serde(default = "default_transaction_version")]
+    pub version: u32,
     pub inputs: Vec<TransactionInput>,
     pub outputs: Vec<TransactionOutput>,
+    /// Height of the block a coinbase belongs to, as in Bitcoin's BIP34.
+    /// Coinbases at different heights therefore never share a hash, even
+    /// if they pay the same outputs. `None` for every other transaction.
+    #[serde(default)]
+    pub coinbase_height: Option<u64>,
 }
 
 impl Transaction {
     pub fn new(inputs: Vec<TransactionInput>, outputs: Vec<TransactionOutput>) -> Self {
-        Self { inputs, outputs }
+        Self {
+            version: TRANSACTION_VERSION,
+            inputs,
+            outputs,
+            coinbase_height: None,
+        }
+    }
+
+    /// The coinbase of the block at `height`
+    pub fn coinbase(height: u64, outputs: Vec<TransactionOutput>) -> Self {
+        Self {
+            version: TRANSACTION_VERSION,
+            inputs: vec![],
+            outputs,
+            coinbase_height: Some(height),
+        }
     }
     pub fn hash(&self) -> Hash {
-        Hash::hash(self)
+        Hash::consensus(self)
+    }
+
+    /// Total paid by the outputs, failing if a value or the total is
+    /// more than [`MAX_MONEY`](crate::amount::MAX_MONEY)
+    pub fn output_value(&self) -> Result<Amount> {
+        Amount::total(self.outputs.iter().map(|output| output.value))
+    }
+
+    /// The consensus encoding as hex, as in Bitcoin's raw transactions
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.encode())
+    }
+
+    pub fn from_hex(s: &str) -> std::result::Result<Self, DecodeError> {
+        let bytes = hex::decode(s.trim()).map_err(|_| DecodeError::Invalid("hex"))?;
+        Transaction::decode(&bytes)
+    }
+}
+
+impl Encode for Transaction {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.version.encode_to(out);
+        self.inputs.encode_to(out);
+        self.outputs.encode_to(out);
+        self.coinbase_height.encode_to(out);
+    }
+}
+
+impl Decode for Transaction {
+    fn decode_from(input: &mut &[u8]) -> std::result::Result<Self, DecodeError> {
+        Ok(Transaction {
+            version: u32::decode_from(input)?,
+            inputs: Vec::decode_from(input)?,
+            outputs: Vec::decode_from(input)?,
+            coinbase_height: Option::decode_from(input)?,
+        })
     }
 }
 
@@ -50,8 +122,151 @@ pub struct TransactionOutput {
     pub pubkey: PublicKey,
 }
 
+impl Encode for TransactionInput {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.prev_transaction_output_hash.encode_to(out);
+        self.signature.encode_to(out);
+    }
+}
+
+impl Decode for TransactionInput {
+    fn decode_from(input: &mut &[u8]) -> std::result::Result<Self, DecodeError> {
+        Ok(TransactionInput {
+            prev_transaction_output_hash: Hash::decode_from(input)?,
+            signature: Signature::decode_from(input)?,
+        })
+    }
+}
+
+impl Encode for TransactionOutput {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.value.encode_to(out);
+        self.unique_id.encode_to(out);
+        self.pubkey.encode_to(out);
+    }
+}
+
+impl Decode for TransactionOutput {
+    fn decode_from(input: &mut &[u8]) -> std::result::Result<Self, DecodeError> {
+        Ok(TransactionOutput {
+            value: u64::decode_from(input)?,
+            unique_id: Uuid::decode_from(input)?,
+            pubkey: PublicKey::decode_from(input)?,
+        })
+    }
+}
+
 impl TransactionOutput {
     pub fn hash(&self) -> Hash {
-        Hash::hash(self)
+        Hash::consensus(self)
+    }
+}
+
+/// A transaction that still has to be signed, for air-gapped signing
+///
+/// Unlike [`Transaction`], every input carries the output it spends, so
+/// an offline signer can tell which key has to sign it and verify the
+/// amounts and fee without access to the UTXO set.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UnsignedTransaction {
+    pub inputs: Vec<UnsignedInput>,
+    pub outputs: Vec<TransactionOutput>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UnsignedInput {
+    /// The output being spent
+    pub prev_output: TransactionOutput,
+    pub signature: Option<Signature>,
+}
+
+impl UnsignedTransaction {
+    pub fn new(prev_outputs: Vec<TransactionOutput>, outputs: Vec<TransactionOutput>) -> Self {
+        Self {
+            inputs: prev_outputs
+                .into_iter()
+                .map(|prev_output| UnsignedInput {
+                    prev_output,
+                    signature: None,
+                })
+                .collect(),
+            outputs,
+        }
+    }
+
+    /// Sign every unsigned input spending an output of `private_key`.
+    /// Returns the number of inputs signed.
+    pub fn sign(&mut self, private_key: &PrivateKey) -> usize {
+        let public_key = private_key.public_key();
+        let mut signed = 0;
+        for input in &mut self.inputs {
+            if input.signature.is_none() && input.prev_output.pubkey == public_key {
+                input.signature = Some(Signature::sign_output(
+                    &input.prev_output.hash(),
+                    private_key,
+                ));
+                signed += 1;
+            }
+        }
+        signed
+    }
+
+    pub fn is_signed(&self) -> bool {
+        self.inputs.iter().all(|input| input.signature.is_some())
+    }
+
+    /// Sum of the spent outputs minus the new outputs
+    pub fn fee(&self) -> u64 {
+        let inputs: u64 = self
+            .inputs
+            .iter()
+            .map(|input| input.prev_output.value)
+            .sum();
+        let outputs: u64 = self.outputs.iter().map(|output| output.value).sum();
+        inputs.saturating_sub(outputs)
+    }
+
+    /// Turn a fully signed transaction into one that can be broadcast
+    pub fn finalize(self) -> Result<Transaction> {
+        let inputs = self
+            .inputs
+            .into_iter()
+            .map(|input| {
+                let prev_transaction_output_hash = input.prev_output.hash();
+                let signature = input
+                    .signature
+                    .ok_or_else(|| BtcError::InvalidTransaction {
+                        reason: format!("input {} is not signed", prev_transaction_output_hash),
+                    })?;
+                if !signature.verify(&prev_transaction_output_hash, &input.prev_output.pubkey) {
+                    return Err(BtcError::InvalidSignature);
+                }
+                Ok(TransactionInput {
+                    prev_transaction_output_hash,
+                    signature,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Transaction::new(inputs, self.outputs))
+    }
+}
+
+impl Saveable for UnsignedTransaction {
+    fn load<I: Read>(reader: I) -> IoResult<Self> {
+        ciborium::de::from_reader(reader).map_err(|_| {
+            IoError::new(
+                IoErrorKind::InvalidData,
+                "Failed to deserialize UnsignedTransaction",
+            )
+        })
+    }
+
+    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+        ciborium::ser::into_writer(self, writer).map_err(|_| {
+            IoError::new(
+                IoErrorKind::InvalidData,
+                "Failed to serialize UnsignedTransaction",
+            )
+        })
     }
 }
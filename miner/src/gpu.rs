@@ -0,0 +1,179 @@
+//! OpenCL backend, built with `--features gpu`
+//!
+//! Runs one work item per nonce of a batch with the kernels in
+//! `sha256.cl`. The GPU can't be trusted blindly: whenever the template
+//! changes a few nonces are hashed on both sides and compared, and every
+//! block the GPU finds is hashed again on the CPU before it is sent.
+use anyhow::{anyhow, bail, Result};
+use btclib::{config::HashScheme, encoding::Encode, types::BlockHeader};
+use ocl::{flags::MemFlags, Buffer, ProQue};
+
+const KERNELS: &str = include_str!("sha256.cl");
+
+/// Must match `MAX_HEADER` in the kernels
+const MAX_HEADER: usize = 160;
+
+/// Nonces compared with the CPU for every new template
+const CHECKED_NONCES: usize = 8;
+
+pub struct GpuMiner {
+    program: ProQue,
+    double_hash: u32,
+    /// Encoding of the header the checks last passed for, minus the nonce
+    checked: Option<Vec<u8>>,
+}
+
+impl GpuMiner {
+    pub fn new(scheme: HashScheme) -> Result<Self> {
+        let double_hash = match scheme {
+            HashScheme::Single => 0,
+            HashScheme::Double => 1,
+            #[cfg(feature = "scrypt")]
+            HashScheme::Scrypt => bail!("the GPU backend only hashes with SHA-256"),
+        };
+        let program = ProQue::builder()
+            .src(KERNELS)
+            .dims(1)
+            .build()
+            .map_err(opencl)?;
+        Ok(Self {
+            program,
+            double_hash,
+            checked: None,
+        })
+    }
+
+    /// Name of the OpenCL device, for the logs
+    pub fn device(&self) -> String {
+        self.program
+            .device()
+            .name()
+            .unwrap_or_else(|_| "unknown device".to_string())
+    }
+
+    /// Try `steps` nonces after the header's, like
+    /// [`BlockHeader::mine`]: on success the header holds the winning
+    /// nonce, otherwise the last one tried
+    pub fn mine(&mut self, header: &mut BlockHeader, steps: usize) -> Result<bool> {
+        // a batch is one dispatch, ids are 32-bit
+        let steps = steps.min(u32::MAX as usize) as u64;
+        if header.nonce.checked_add(steps).is_none() {
+            // the CPU wraps the nonce around and bumps the timestamp
+            return Ok(header.mine(steps as usize));
+        }
+        self.check(header)?;
+        let first_nonce = header.nonce + 1;
+        let encoded = header.encode();
+        let header_buffer = self.input(&encoded)?;
+        let target_buffer = self.input(&header.target.to_big_endian())?;
+        let result = Buffer::<u32>::builder()
+            .queue(self.program.queue().clone())
+            .len(2)
+            .fill_val(0)
+            .build()
+            .map_err(opencl)?;
+        let kernel = self
+            .program
+            .kernel_builder("search")
+            .arg(&header_buffer)
+            .arg(encoded.len() as u32)
+            .arg(&target_buffer)
+            .arg(first_nonce)
+            .arg(self.double_hash)
+            .arg(&result)
+            .build()
+            .map_err(opencl)?;
+        unsafe {
+            kernel
+                .cmd()
+                .global_work_size(steps as usize)
+                .enq()
+                .map_err(opencl)?;
+        }
+        let mut found = vec![0u32; 2];
+        result.read(&mut found).enq().map_err(opencl)?;
+        if found[0] == 0 {
+            header.nonce = first_nonce + steps - 1;
+            return Ok(false);
+        }
+        header.nonce = first_nonce + found[1] as u64;
+        if !header.hash().matches_target(header.target) {
+            bail!(
+                "the GPU found nonce {}, whose hash misses the target on the CPU",
+                header.nonce
+            );
+        }
+        Ok(true)
+    }
+
+    /// Compare GPU and CPU hashes of a few nonces, once per template
+    fn check(&mut self, header: &BlockHeader) -> Result<()> {
+        let mut unchanged = header.clone();
+        unchanged.nonce = 0;
+        let key = unchanged.encode();
+        if self.checked.as_ref() == Some(&key) {
+            return Ok(());
+        }
+        if key.len() > MAX_HEADER {
+            bail!(
+                "headers of {} bytes are too long for the GPU kernel",
+                key.len()
+            );
+        }
+        let encoded = header.encode();
+        let header_buffer = self.input(&encoded)?;
+        let out = Buffer::<u8>::builder()
+            .queue(self.program.queue().clone())
+            .len(CHECKED_NONCES * 32)
+            .build()
+            .map_err(opencl)?;
+        let kernel = self
+            .program
+            .kernel_builder("hashes")
+            .arg(&header_buffer)
+            .arg(encoded.len() as u32)
+            .arg(header.nonce)
+            .arg(self.double_hash)
+            .arg(&out)
+            .build()
+            .map_err(opencl)?;
+        unsafe {
+            kernel
+                .cmd()
+                .global_work_size(CHECKED_NONCES)
+                .enq()
+                .map_err(opencl)?;
+        }
+        let mut digests = vec![0u8; CHECKED_NONCES * 32];
+        out.read(&mut digests).enq().map_err(opencl)?;
+        let mut expected = header.clone();
+        for (i, digest) in digests.chunks(32).enumerate() {
+            expected.nonce = header.nonce + i as u64;
+            // the kernel writes big-endian digests, hashes are little-endian
+            let mut cpu = expected.hash().as_bytes();
+            cpu.reverse();
+            if digest != cpu.as_slice() {
+                bail!(
+                    "the GPU hash of nonce {} differs from the CPU's",
+                    expected.nonce
+                );
+            }
+        }
+        self.checked = Some(key);
+        Ok(())
+    }
+
+    fn input(&self, bytes: &[u8]) -> Result<Buffer<u8>> {
+        Buffer::<u8>::builder()
+            .queue(self.program.queue().clone())
+            .flags(MemFlags::new().read_only())
+            .len(bytes.len())
+            .copy_host_slice(bytes)
+            .build()
+            .map_err(opencl)
+    }
+}
+
+fn opencl(e: ocl::Error) -> anyhow::Error {
+    anyhow!("OpenCL: {}", e)
+}
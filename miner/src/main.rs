@@ -1,23 +1,43 @@
 use std::{
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
+    path::PathBuf,
+    sync::Arc,
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Result};
 use btclib::{
-    config::BlockchainConfig, crypto::PublicKey, network::Message, types::Block, util::Saveable,
+    auth,
+    config::{BlockchainConfig, MiningBackend},
+    crypto::PublicKey,
+    network::Message,
+    retry::{self, Retrier, RetryError},
+    transport::Connection,
+    types::{Block, BlockHeader},
+    util::Saveable,
 };
 use clap::Parser;
-use tokio::{net::TcpStream, sync::Mutex, time::interval};
+use tokio::{
+    runtime::Handle,
+    sync::{watch, Mutex},
+    time::interval,
+};
 use tracing::{debug, info, warn};
 
+#[cfg(feature = "gpu")]
+mod gpu;
+mod rotation;
+mod stats;
+use rotation::Rotation;
+use stats::Stats;
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    #[arg(long)]
+    /// Profile of the config files to use, e.g. testnet
+    network: Option<String>,
+
     #[arg(short, long)]
     /// Node address to connect to (defaults to MINER_NODE_ADDRESS env var)
     address: Option<String>,
@@ -25,37 +45,81 @@ struct Cli {
     #[arg(short, long)]
     /// Public key file for receiving rewards (defaults to MINER_PUBLIC_KEY env var)
     public_key_file: Option<String>,
+
+    #[arg(long, value_name = "XPUB_FILE")]
+    /// Pay every block to a new key derived from this extended public key
+    /// (see the wallet's export-xpub) instead of the public key file
+    address_rotation: Option<PathBuf>,
+}
+
+/// Size of the first mining batch, which benchmarks the machine, and the
+/// smallest batch after it so batches stay long enough to time
+const MIN_BATCH_NONCES: usize = 1_000;
+
+/// Who the coinbase of each template pays
+enum Payee {
+    Key(PublicKey),
+    Rotating(Rotation),
 }
 
 struct Miner {
-    public_key: PublicKey,
-    stream: Mutex<TcpStream>,
-    current_template: Arc<std::sync::Mutex<Option<Block>>>,
-    mining: Arc<AtomicBool>,
+    payee: Payee,
+    /// Keys sharing each reward, with their weights; empty to pay it
+    /// all to `payee`
+    payouts: Vec<(PublicKey, u64)>,
+    stream: Mutex<Connection>,
+    /// Template being mined, `None` while waiting for a fresh one. The
+    /// hashing thread drops its work as soon as this changes
+    template: watch::Sender<Option<Block>>,
+    stats: Arc<Stats>,
     mined_block_sender: flume::Sender<Block>,
     mined_block_receiver: flume::Receiver<Block>,
 }
 impl Miner {
-    async fn new(address: String, public_key: PublicKey) -> Result<Self> {
-        let stream = TcpStream::connect(&address).await?;
+    async fn new(address: String, payee: Payee, payouts: Vec<(PublicKey, u64)>) -> Result<Self> {
+        let config = BlockchainConfig::global();
+        let retrier = Retrier::new(config.retry.clone());
+        // nodes with encrypt_p2p only talk Noise, miners included
+        let mut stream = retrier
+            .run(&format!("connecting to {}", address), || {
+                Connection::connect(&address, config.node.encrypt_p2p)
+            })
+            .await?;
+        // likewise for the auth token, shared with the node's config
+        if let Some(token) = &config.node.auth_token {
+            auth::authenticate(&mut stream, token).await?;
+        }
         let (mined_block_sender, mined_block_receiver) = flume::unbounded();
 
         Ok(Self {
-            public_key,
+            payee,
+            payouts,
             stream: Mutex::new(stream),
-            current_template: Arc::new(std::sync::Mutex::new(None)),
-            mining: Arc::new(AtomicBool::new(false)),
+            template: watch::Sender::new(None),
+            stats: Arc::new(Stats::default()),
             mined_block_sender,
             mined_block_receiver,
         })
     }
 
-    async fn run(&self) -> Result<()> {
+    async fn run(&self, backend: Backend) -> Result<()> {
         let config = BlockchainConfig::global();
-        self.spawn_mining_thread();
+        self.spawn_mining_thread(backend);
+        if let Some(port) = config.mining.status_port {
+            let stats = self.stats.clone();
+            tokio::spawn(async move {
+                if let Err(e) = stats::serve(stats, port).await {
+                    warn!("status endpoint stopped: {}", e);
+                }
+            });
+        }
         let mut template_interval = interval(Duration::from_secs(
             config.mining.template_fetch_interval_secs,
         ));
+        let mut stats_interval = interval(Duration::from_secs(config.mining.stats_interval_secs));
+        // the first tick is immediate, nothing to report yet
+        stats_interval.tick().await;
+        let mut last_report = Instant::now();
 
         loop {
             let receiver_clone = self.mined_block_receiver.clone();
@@ -63,6 +127,10 @@ impl Miner {
                 _ = template_interval.tick() => {
                     self.fetch_and_validate_template().await?
                 }
+                _ = stats_interval.tick() => {
+                    self.stats.report(last_report.elapsed());
+                    last_report = Instant::now();
+                }
                 Ok(mined_block) = receiver_clone.recv_async() => {
                     self.submit_block(mined_block).await?;
                 }
@@ -70,36 +138,54 @@ impl Miner {
         }
     }
 
-    fn spawn_mining_thread(&self) -> thread::JoinHandle<()> {
+    fn spawn_mining_thread(&self, mut backend: Backend) -> thread::JoinHandle<()> {
         let config = BlockchainConfig::global().clone();
-        let template = self.current_template.clone();
-        let mining = self.mining.clone();
+        let mut template = self.template.subscribe();
+        let stats = self.stats.clone();
         let sender = self.mined_block_sender.clone();
-        thread::spawn(move || {
+        let runtime = Handle::current();
+        let mut batch = BatchTuner::new(
+            config.mining.mining_batch_size,
+            Duration::from_millis(config.mining.batch_target_ms),
+        );
+        thread::spawn(move || loop {
+            let Some(mut block) = template.borrow_and_update().clone() else {
+                // nothing to mine until the next template
+                if runtime.block_on(template.changed()).is_err() {
+                    return;
+                }
+                continue;
+            };
+            debug!("Mining block with target: {}", block.header.target);
             loop {
-                if mining.load(Ordering::Relaxed) {
-                    // Release the lock immediately after cloning
-                    let block = template
-                        .lock()
-                        .expect("Template mutex lock poisoned - thread panicked while holding lock")
-                        .clone();
-
-                    if let Some(mut block) = block {
-                        debug!("Mining block with target: {}", block.header.target);
-                        if block.header.mine(config.mining.mining_batch_size) {
-                            info!("Block mined: {}", block.hash());
-                            sender.send(block).expect("Failed to send mined block");
-                            mining.store(false, Ordering::Relaxed);
-                        }
+                let first_nonce = block.header.nonce;
+                let started = Instant::now();
+                let found = backend.mine(&mut block.header, batch.size());
+                // a batch that finds the block stops early, count what it tried
+                let tried = block.header.nonce.wrapping_sub(first_nonce);
+                batch.record(tried, started.elapsed());
+                stats.record_hashes(tried);
+                stats.record_batch_size(batch.size());
+                if found {
+                    info!("Block mined: {}", block.hash());
+                    stats.record_block_found();
+                    sender.send(block).expect("Failed to send mined block");
+                    // the submission replaces the template, don't mine it twice
+                    if runtime.block_on(template.changed()).is_err() {
+                        return;
                     }
+                    break;
+                }
+                if template.has_changed().unwrap_or(true) {
+                    debug!("Abandoning template");
+                    break;
                 }
-                thread::yield_now();
             }
         })
     }
 
     async fn fetch_and_validate_template(&self) -> Result<()> {
-        if !self.mining.load(Ordering::Relaxed) {
+        if self.template.borrow().is_none() {
             self.fetch_template().await?;
         } else {
             self.validate_template().await?;
@@ -109,24 +195,22 @@ impl Miner {
 
     async fn fetch_template(&self) -> Result<()> {
         info!("Fetching new template");
-        let message = Message::FetchTemplate(self.public_key.clone());
-        let mut stream_lock = self.stream.lock().await;
-        message.send_async(&mut *stream_lock).await?;
-        drop(stream_lock);
-        let mut stream_lock = self.stream.lock().await;
-        match Message::receive_async(&mut *stream_lock).await? {
-            Message::Template(template) => {
-                drop(stream_lock);
+        let public_key = match &self.payee {
+            Payee::Key(key) => key.clone(),
+            Payee::Rotating(rotation) => rotation.current()?,
+        };
+        let message = Message::FetchTemplate(public_key);
+        match self.request(&message).await? {
+            Message::Template(mut template) => {
+                if !self.payouts.is_empty() {
+                    template.split_coinbase(&self.payouts)?;
+                }
                 info!(
                     "Received new template with target: {}",
                     template.header.target
                 );
-                *self
-                    .current_template
-                    .lock()
-                    .expect("Template mutex lock poisoned - thread panicked while holding lock") =
-                    Some(template);
-                self.mining.store(true, Ordering::Relaxed);
+                self.stats.record_template(template.header.target);
+                self.template.send_replace(Some(template));
                 Ok(())
             }
             _ => Err(anyhow!(
@@ -136,29 +220,23 @@ impl Miner {
     }
 
     async fn validate_template(&self) -> Result<()> {
-        // Release the lock immediately after cloning
-        let template = self
-            .current_template
-            .lock()
-            .expect("Template mutex lock poisoned - thread panicked while holding lock")
-            .clone();
+        let template = self.template.borrow().clone();
 
         if let Some(template) = template {
             let message = Message::ValidateTemplate(template);
-            let mut stream_lock = self.stream.lock().await;
-            message.send_async(&mut *stream_lock).await?;
-            drop(stream_lock);
-            let mut stream_lock = self.stream.lock().await;
-            match Message::receive_async(&mut *stream_lock).await? {
+            match self.request(&message).await? {
                 Message::TemplateValidity(valid) => {
-                    drop(stream_lock);
                     if !valid {
                         warn!("Current template is no longer valid");
-                        self.mining.store(false, Ordering::Relaxed);
+                        self.stats.record_stale_template();
+                        // stops the hashing thread right away, and there
+                        // is no point waiting for the next tick to refill it
+                        self.template.send_replace(None);
+                        self.fetch_template().await
                     } else {
                         debug!("Current template is still valid");
+                        Ok(())
                     }
-                    Ok(())
                 }
                 _ => Err(anyhow!(
                     "Unexpected message received when validating template"
@@ -172,20 +250,129 @@ impl Miner {
     async fn submit_block(&self, block: Block) -> Result<()> {
         info!("Submitting mined block");
         let message = Message::SubmitTemplate(block);
-        let mut stream_lock = self.stream.lock().await;
-        message.send_async(&mut *stream_lock).await?;
-        self.mining.store(false, Ordering::Relaxed);
+        let response = self.request(&message).await;
+        self.template.send_replace(None);
+        match response? {
+            Message::Accepted(hash) => {
+                info!("Block {} accepted", hash);
+                self.stats.record_block_accepted();
+                if let Payee::Rotating(rotation) = &self.payee {
+                    rotation.advance()?;
+                }
+            }
+            Message::Reject { code, reason, .. } => {
+                warn!("Block rejected ({:?}): {}", code, reason)
+            }
+            other => return Err(anyhow!("Unexpected response to block: {}", other.kind())),
+        }
         Ok(())
     }
+
+    /// Send a request to the node and wait for the response, giving up
+    /// after the configured timeout instead of hanging forever
+    async fn request(&self, message: &Message) -> Result<Message> {
+        let mut stream_lock = self.stream.lock().await;
+        let exchange = async {
+            message.send_async(&mut *stream_lock).await?;
+            Ok::<_, anyhow::Error>(Message::receive_async(&mut *stream_lock).await?)
+        };
+        match retry::with_timeout(&BlockchainConfig::global().retry, exchange).await {
+            Ok(response) => Ok(response),
+            Err(RetryError::Failed(e)) => Err(e),
+            Err(e) => Err(anyhow!("Request to node failed: {}", e)),
+        }
+    }
+}
+
+/// What the hashing thread mines with, see [`MiningBackend`]
+enum Backend {
+    Cpu,
+    #[cfg(feature = "gpu")]
+    Gpu(gpu::GpuMiner),
+}
+
+impl Backend {
+    fn new(kind: MiningBackend) -> Result<Self> {
+        match kind {
+            MiningBackend::Cpu => Ok(Backend::Cpu),
+            #[cfg(feature = "gpu")]
+            MiningBackend::Gpu => {
+                let gpu = gpu::GpuMiner::new(btclib::config::hash_scheme())?;
+                info!("Mining on {}", gpu.device());
+                Ok(Backend::Gpu(gpu))
+            }
+            #[cfg(not(feature = "gpu"))]
+            MiningBackend::Gpu => Err(anyhow!(
+                "mining.backend \"gpu\" needs the miner built with --features gpu"
+            )),
+        }
+    }
+
+    /// Like [`BlockHeader::mine`]. A GPU that fails, or disagrees with
+    /// the CPU, is dropped for the CPU for the rest of the run
+    fn mine(&mut self, header: &mut BlockHeader, steps: usize) -> bool {
+        match self {
+            Backend::Cpu => header.mine(steps),
+            #[cfg(feature = "gpu")]
+            Backend::Gpu(gpu) => match gpu.mine(header, steps) {
+                Ok(found) => found,
+                Err(e) => {
+                    warn!("GPU mining failed, switching to the CPU: {}", e);
+                    *self = Backend::Cpu;
+                    header.mine(steps)
+                }
+            },
+        }
+    }
+}
+
+/// Sizes mining batches from how fast the last one went, so the
+/// hashing thread looks for a new template about every
+/// `mining.batch_target_ms` on fast and slow machines alike
+struct BatchTuner {
+    size: usize,
+    max: usize,
+    target: Duration,
+}
+
+impl BatchTuner {
+    fn new(max: usize, target: Duration) -> Self {
+        let max = max.max(MIN_BATCH_NONCES);
+        Self {
+            size: MIN_BATCH_NONCES,
+            max,
+            target,
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Resize after `nonces` took `elapsed`. Shrinks straight to the
+    /// size that fits the target but at most doubles, so a batch that
+    /// ran fast by chance doesn't make the next one overshoot
+    fn record(&mut self, nonces: u64, elapsed: Duration) {
+        if nonces == 0 {
+            // the template already matched, nothing was timed
+            return;
+        }
+        let rate = nonces as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+        let ideal = (rate * self.target.as_secs_f64()) as usize;
+        self.size = ideal.min(self.size * 2).clamp(MIN_BATCH_NONCES, self.max);
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli = Cli::parse();
+
     // Load configuration
+    if let Some(network) = &cli.network {
+        BlockchainConfig::select_profile(network)?;
+    }
     let config = BlockchainConfig::global();
 
-    let cli = Cli::parse();
-
     // Priority: CLI args > Environment vars > Defaults
     let address = cli
         .address
@@ -200,10 +387,37 @@ async fn main() -> Result<()> {
     info!("⛏️  Starting miner");
     info!("Network: {}", config.network.network_id);
     info!("Connecting to node: {}", address);
-    info!("Rewards will be sent to key: {}", public_key_file);
 
-    let public_key = PublicKey::load_from_file(&public_key_file)
-        .map_err(|e| anyhow!("Error reading public key: {}", e))?;
-    let miner = Miner::new(address, public_key).await?;
-    miner.run().await
+    let backend = Backend::new(config.mining.backend)?;
+    let payee = match &cli.address_rotation {
+        Some(_) if !config.mining.payouts.is_empty() => {
+            return Err(anyhow!(
+                "--address-rotation pays one key per block, it can't be combined with mining.payouts"
+            ));
+        }
+        Some(xpub_file) => Payee::Rotating(Rotation::load(xpub_file)?),
+        None => {
+            info!("Rewards will be sent to key: {}", public_key_file);
+            let public_key = PublicKey::load_from_file(&public_key_file)
+                .map_err(|e| anyhow!("Error reading public key: {}", e))?;
+            Payee::Key(public_key)
+        }
+    };
+    let payouts = config
+        .mining
+        .payouts
+        .iter()
+        .map(|payout| {
+            let key = PublicKey::load_from_file(&payout.public_key_file).map_err(|e| {
+                anyhow!("Error reading payout key {}: {}", payout.public_key_file, e)
+            })?;
+            info!(
+                "Paying {} share(s) of each reward to {}",
+                payout.weight, payout.public_key_file
+            );
+            Ok((key, payout.weight))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let miner = Miner::new(address, payee, payouts).await?;
+    miner.run(backend).await
 }
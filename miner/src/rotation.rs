@@ -0,0 +1,75 @@
+//! Paying every block to a new key, derived from a wallet's extended
+//! public key (`wallet export-xpub`)
+//!
+//! The index of the next key is kept in a file next to the extended
+//! public key, so a restarted miner doesn't pay a key twice. It only
+//! moves on once a block paying the current key is accepted.
+use anyhow::{anyhow, Result};
+use btclib::{crypto::PublicKey, hd::ExtendedPublicKey, util::Saveable};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+pub struct Rotation {
+    xpub: ExtendedPublicKey,
+    index_file: PathBuf,
+    /// Index of the key the current template pays
+    index: Mutex<u32>,
+}
+
+impl Rotation {
+    pub fn load(xpub_file: &Path) -> Result<Self> {
+        let xpub = ExtendedPublicKey::load_from_file(xpub_file)
+            .map_err(|e| anyhow!("Error reading {}: {}", xpub_file.display(), e))?;
+        let index_file = xpub_file.with_extension("next");
+        let index = match std::fs::read_to_string(&index_file) {
+            Ok(text) => text
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("{} is not a key index", index_file.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        info!(
+            "Paying a new key derived from {} every block, starting at index {}",
+            xpub_file.display(),
+            index
+        );
+        Ok(Self {
+            xpub,
+            index_file,
+            index: Mutex::new(index),
+        })
+    }
+
+    /// The key to pay in the next template
+    pub fn current(&self) -> Result<PublicKey> {
+        let mut index = self.index.lock().expect("Rotation mutex lock poisoned");
+        loop {
+            match self.xpub.derive_child(*index) {
+                Ok(key) => return Ok(key.public_key().clone()),
+                // happens for one index in 2^127
+                Err(e) => {
+                    warn!("Skipping key {}: {}", *index, e);
+                    *index = next_index(*index)?;
+                }
+            }
+        }
+    }
+
+    /// Move on to the next key once a block paid the current one
+    pub fn advance(&self) -> Result<()> {
+        let mut index = self.index.lock().expect("Rotation mutex lock poisoned");
+        *index = next_index(*index)?;
+        std::fs::write(&self.index_file, index.to_string())?;
+        info!("Next block pays key {}", *index);
+        Ok(())
+    }
+}
+
+fn next_index(index: u32) -> Result<u32> {
+    index
+        .checked_add(1)
+        .filter(|next| *next < btclib::hd::HARDENED)
+        .ok_or_else(|| anyhow!("Out of keys to derive, export a new extended public key"))
+}
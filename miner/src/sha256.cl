@@ -0,0 +1,146 @@
+// SHA-256 of block headers for the GPU backend (see gpu.rs). Every work
+// item hashes the header with its own nonce: `first_nonce` plus its id.
+
+// Longest header encoding: version, timestamp, nonce, previous hash,
+// Merkle root, target and a UTXO commitment
+#define MAX_HEADER 160
+// The nonce follows the version (4 bytes) and the timestamp (12 bytes)
+#define NONCE_OFFSET 16
+
+#define ROTR(x, n) rotate((uint)(x), (uint)(32 - (n)))
+
+__constant uint K[64] = {
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1,
+    0x923f82a4, 0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3,
+    0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786,
+    0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147,
+    0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13,
+    0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+    0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a,
+    0x5b9cca4f, 0x682e6ff3, 0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208,
+    0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2};
+
+void sha256_block(uint *state, const uchar *block) {
+    uint w[64];
+    for (int i = 0; i < 16; i++) {
+        w[i] = ((uint)block[4 * i] << 24) | ((uint)block[4 * i + 1] << 16) |
+               ((uint)block[4 * i + 2] << 8) | (uint)block[4 * i + 3];
+    }
+    for (int i = 16; i < 64; i++) {
+        uint s0 = ROTR(w[i - 15], 7) ^ ROTR(w[i - 15], 18) ^ (w[i - 15] >> 3);
+        uint s1 = ROTR(w[i - 2], 17) ^ ROTR(w[i - 2], 19) ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16] + s0 + w[i - 7] + s1;
+    }
+    uint a = state[0], b = state[1], c = state[2], d = state[3];
+    uint e = state[4], f = state[5], g = state[6], h = state[7];
+    for (int i = 0; i < 64; i++) {
+        uint s1 = ROTR(e, 6) ^ ROTR(e, 11) ^ ROTR(e, 25);
+        uint ch = (e & f) ^ (~e & g);
+        uint t1 = h + s1 + ch + K[i] + w[i];
+        uint s0 = ROTR(a, 2) ^ ROTR(a, 13) ^ ROTR(a, 22);
+        uint maj = (a & b) ^ (a & c) ^ (b & c);
+        uint t2 = s0 + maj;
+        h = g;
+        g = f;
+        f = e;
+        e = d + t1;
+        d = c;
+        c = b;
+        b = a;
+        a = t1 + t2;
+    }
+    state[0] += a;
+    state[1] += b;
+    state[2] += c;
+    state[3] += d;
+    state[4] += e;
+    state[5] += f;
+    state[6] += g;
+    state[7] += h;
+}
+
+void sha256(const uchar *message, uint len, uchar *digest) {
+    uint state[8] = {0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+                     0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19};
+    // the message, a 0x80 byte, zeros and the length in bits
+    uint blocks = (len + 9 + 63) / 64;
+    ulong bits = (ulong)len * 8;
+    uchar block[64];
+    for (uint b = 0; b < blocks; b++) {
+        for (uint i = 0; i < 64; i++) {
+            uint pos = b * 64 + i;
+            uint from_end = blocks * 64 - 1 - pos;
+            if (pos < len) {
+                block[i] = message[pos];
+            } else if (pos == len) {
+                block[i] = 0x80;
+            } else if (from_end < 8) {
+                block[i] = (uchar)(bits >> (8 * from_end));
+            } else {
+                block[i] = 0;
+            }
+        }
+        sha256_block(state, block);
+    }
+    for (int i = 0; i < 8; i++) {
+        digest[4 * i] = (uchar)(state[i] >> 24);
+        digest[4 * i + 1] = (uchar)(state[i] >> 16);
+        digest[4 * i + 2] = (uchar)(state[i] >> 8);
+        digest[4 * i + 3] = (uchar)state[i];
+    }
+}
+
+// Hash the header with `nonce`, once or twice, into a big-endian digest
+void hash_header(__global const uchar *header, uint len, ulong nonce,
+                 uint double_hash, uchar *digest) {
+    uchar message[MAX_HEADER];
+    for (uint i = 0; i < len; i++) {
+        message[i] = header[i];
+    }
+    for (int i = 0; i < 8; i++) {
+        message[NONCE_OFFSET + i] = (uchar)(nonce >> (8 * i));
+    }
+    sha256(message, len, digest);
+    if (double_hash) {
+        uchar inner[32];
+        for (int i = 0; i < 32; i++) {
+            inner[i] = digest[i];
+        }
+        sha256(inner, 32, digest);
+    }
+}
+
+// Look for a nonce whose hash is at most `target` (big-endian). The
+// first work item to find one sets result[0] and stores its id in
+// result[1].
+__kernel void search(__global const uchar *header, uint len,
+                     __global const uchar *target, ulong first_nonce,
+                     uint double_hash, __global uint *result) {
+    uint id = get_global_id(0);
+    uchar digest[32];
+    hash_header(header, len, first_nonce + id, double_hash, digest);
+    for (int i = 0; i < 32; i++) {
+        if (digest[i] > target[i]) {
+            return;
+        }
+        if (digest[i] < target[i]) {
+            break;
+        }
+    }
+    if (atomic_cmpxchg(&result[0], 0, 1) == 0) {
+        result[1] = id;
+    }
+}
+
+// Write the hash of every nonce, for checking the kernel against the CPU
+__kernel void hashes(__global const uchar *header, uint len, ulong first_nonce,
+                     uint double_hash, __global uchar *out) {
+    uint id = get_global_id(0);
+    uchar digest[32];
+    hash_header(header, len, first_nonce + id, double_hash, digest);
+    for (int i = 0; i < 32; i++) {
+        out[id * 32 + i] = digest[i];
+    }
+}
@@ -0,0 +1,189 @@
+//! Mining statistics
+//!
+//! Counters are bumped by the hashing thread and the template loop; a
+//! summary is logged every `mining.stats_interval_secs` and, when
+//! `mining.status_port` is set, served as plain text on `GET /status`.
+use anyhow::Result;
+use btclib::{difficulty, U256};
+use std::fmt::Write as _;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+#[derive(Default)]
+pub struct Stats {
+    /// Hashes tried since the last report
+    window_hashes: AtomicU64,
+    total_hashes: AtomicU64,
+    /// Hash rate measured over the last report, as `f64` bits
+    hashrate: AtomicU64,
+    templates: AtomicU64,
+    stale_templates: AtomicU64,
+    blocks_found: AtomicU64,
+    blocks_accepted: AtomicU64,
+    /// Nonces in a mining batch, as tuned by the hashing thread
+    batch_size: AtomicU64,
+    /// Target of the template being mined
+    target: std::sync::Mutex<Option<U256>>,
+}
+
+impl Stats {
+    pub fn record_hashes(&self, hashes: u64) {
+        self.window_hashes.fetch_add(hashes, Ordering::Relaxed);
+        self.total_hashes.fetch_add(hashes, Ordering::Relaxed);
+    }
+
+    pub fn record_template(&self, target: U256) {
+        self.templates.fetch_add(1, Ordering::Relaxed);
+        *self.target.lock().expect("Stats mutex lock poisoned") = Some(target);
+    }
+
+    /// Count a template dropped because the chain moved on
+    pub fn record_stale_template(&self) {
+        self.stale_templates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_batch_size(&self, size: usize) {
+        self.batch_size.store(size as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_block_found(&self) {
+        self.blocks_found.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_block_accepted(&self) {
+        self.blocks_accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Measure the hash rate over `elapsed` and log a one-line summary
+    pub fn report(&self, elapsed: Duration) {
+        let hashes = self.window_hashes.swap(0, Ordering::Relaxed);
+        let rate = hashes as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+        self.hashrate.store(rate.to_bits(), Ordering::Relaxed);
+        let counts = format!(
+            "templates {} ({} stale), blocks found {} ({} accepted)",
+            self.templates.load(Ordering::Relaxed),
+            self.stale_templates.load(Ordering::Relaxed),
+            self.blocks_found.load(Ordering::Relaxed),
+            self.blocks_accepted.load(Ordering::Relaxed),
+        );
+        let Some(target) = self.target() else {
+            info!(
+                "Hashrate {}, no template yet, {}",
+                difficulty::format_hashrate(rate),
+                counts
+            );
+            return;
+        };
+        info!(
+            "Hashrate {}, difficulty {:.2}, expected time to a block {}, {}",
+            difficulty::format_hashrate(rate),
+            difficulty::difficulty(target),
+            expected_time(target, rate),
+            counts
+        );
+    }
+
+    /// Everything tracked, one `name: value` per line
+    fn render(&self, uptime: Duration) -> String {
+        let rate = f64::from_bits(self.hashrate.load(Ordering::Relaxed));
+        let mut out = String::new();
+        let _ = writeln!(out, "uptime: {}s", uptime.as_secs());
+        let _ = writeln!(out, "hashrate: {}", difficulty::format_hashrate(rate));
+        let _ = writeln!(out, "hashes: {}", self.total_hashes.load(Ordering::Relaxed));
+        let _ = writeln!(
+            out,
+            "batch_size: {}",
+            self.batch_size.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "templates_received: {}",
+            self.templates.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "stale_templates: {}",
+            self.stale_templates.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "blocks_found: {}",
+            self.blocks_found.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "blocks_accepted: {}",
+            self.blocks_accepted.load(Ordering::Relaxed)
+        );
+        if let Some(target) = self.target() {
+            let _ = writeln!(out, "difficulty: {:.2}", difficulty::difficulty(target));
+            let _ = writeln!(
+                out,
+                "expected_time_to_block: {}",
+                expected_time(target, rate)
+            );
+        }
+        out
+    }
+
+    fn target(&self) -> Option<U256> {
+        *self.target.lock().expect("Stats mutex lock poisoned")
+    }
+}
+
+/// How long a block takes on average at `rate` hashes per second
+fn expected_time(target: U256, rate: f64) -> String {
+    if rate > 0.0 {
+        format!("{:.1}s", difficulty::expected_hashes(target) / rate)
+    } else {
+        "never".to_string()
+    }
+}
+
+/// Serve the statistics on `0.0.0.0:port` until the miner exits
+pub async fn serve(stats: Arc<Stats>, port: u16) -> Result<()> {
+    let addr = format!("0.0.0.0:{}", port);
+    let listener = TcpListener::bind(&addr).await?;
+    info!("Serving miner status on {}/status", addr);
+    let started = Instant::now();
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(socket, &stats, started.elapsed()).await {
+                debug!("status request failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn respond(mut socket: TcpStream, stats: &Stats, uptime: Duration) -> Result<()> {
+    // only the request line matters, e.g. "GET /status HTTP/1.1"
+    let mut buffer = [0u8; 1024];
+    let read = socket.read(&mut buffer).await?;
+    let request = String::from_utf8_lossy(&buffer[..read]);
+    let mut parts = request.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/status")) => {
+            let body = stats.render(uptime);
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => {
+            warn!("status: unexpected request {:?}", request.lines().next());
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+        }
+    };
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await?;
+    Ok(())
+}
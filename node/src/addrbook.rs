@@ -0,0 +1,121 @@
+use anyhow::{Context, Result};
+use btclib::network::KnownPeer;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::path::Path;
+use tracing::info;
+
+/// Peers not seen for this many days are forgotten
+const MAX_PEER_AGE_DAYS: i64 = 30;
+
+/// What we know about a peer address
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PeerRecord {
+    /// Last time we (or, for addresses only heard of, the node that told
+    /// us about it) talked to the peer
+    pub last_seen: Option<DateTime<Utc>>,
+    /// Chain height the peer last reported
+    pub height: Option<u64>,
+    /// Smoothed round trip time of our requests to the peer
+    #[serde(default)]
+    pub latency_ms: Option<u64>,
+}
+
+/// Persistent address book of known peers, keyed by "ip:port"
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AddressBook {
+    peers: HashMap<String, PeerRecord>,
+}
+
+impl AddressBook {
+    /// Load the address book from a JSON file, starting empty if it doesn't exist
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read address book {}", path.display()))?;
+        let mut book: AddressBook = serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse address book {}", path.display()))?;
+        book.prune(Utc::now());
+        info!("loaded {} known peers", book.peers.len());
+        Ok(book)
+    }
+
+    /// Save the address book to a JSON file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json)?;
+        Ok(())
+    }
+
+    /// We just talked to the peer
+    pub fn seen(&mut self, address: &str, height: Option<u64>) {
+        let record = self.peers.entry(address.to_string()).or_default();
+        record.last_seen = Some(Utc::now());
+        if height.is_some() {
+            record.height = height;
+        }
+    }
+
+    /// A request to the peer took `rtt` to answer. Each measurement
+    /// moves the estimate a quarter of the way, so one slow answer
+    /// doesn't reorder the peers.
+    pub fn record_latency(&mut self, address: &str, rtt: std::time::Duration) {
+        let record = self.peers.entry(address.to_string()).or_default();
+        let sample = rtt.as_millis() as u64;
+        record.latency_ms = Some(match record.latency_ms {
+            Some(latency) => (latency * 3 + sample) / 4,
+            None => sample,
+        });
+    }
+
+    /// The peer's round trip time, if we measured it
+    pub fn latency(&self, address: &str) -> Option<u64> {
+        self.peers.get(address)?.latency_ms
+    }
+
+    /// Another node told us about a peer. Its timestamp only counts if it
+    /// is newer than ours, and never lies in the future.
+    pub fn learn(&mut self, peer: &KnownPeer) {
+        let now = Utc::now();
+        let record = self.peers.entry(peer.address.clone()).or_default();
+        let reported = peer.last_seen.map(|last_seen| last_seen.min(now));
+        if reported > record.last_seen {
+            record.last_seen = reported;
+            record.height = peer.height.or(record.height);
+        }
+    }
+
+    /// What we can tell other nodes about an address
+    pub fn known_peer(&self, address: &str) -> KnownPeer {
+        let record = self.peers.get(address).cloned().unwrap_or_default();
+        KnownPeer {
+            address: address.to_string(),
+            last_seen: record.last_seen,
+            height: record.height,
+        }
+    }
+
+    /// Up to `count` addresses, most recently seen first
+    pub fn best(&self, count: usize) -> Vec<String> {
+        let mut peers: Vec<_> = self.peers.iter().collect();
+        // None sorts before Some, so never-seen addresses come last
+        peers.sort_by_key(|(_, record)| Reverse(record.last_seen));
+        peers
+            .into_iter()
+            .take(count)
+            .map(|(address, _)| address.clone())
+            .collect()
+    }
+
+    /// Forget peers that haven't been seen in a long time
+    pub fn prune(&mut self, now: DateTime<Utc>) {
+        let cutoff = now - Duration::days(MAX_PEER_AGE_DAYS);
+        self.peers
+            .retain(|_, record| record.last_seen.is_none_or(|seen| seen >= cutoff));
+    }
+}
@@ -0,0 +1,183 @@
+use anyhow::{Context, Result};
+use btclib::config::BlockchainConfig;
+use btclib::network::BannedPeer;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use tracing::{info, warn};
+
+/// Things a peer can do wrong, and how much each adds to its score
+/// (a peer is banned at `ban_threshold`, 100 by default)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehavior {
+    /// A message that couldn't be decoded
+    MalformedMessage,
+    /// A frame larger than the maximum message size
+    OversizedMessage,
+    /// A response nobody asked for, e.g. a `Template` sent to a node
+    UnexpectedMessage,
+    /// A transaction the mempool rejected
+    InvalidTransaction,
+    /// A block extending our tip that fails validation
+    InvalidBlock,
+    /// A wrong auth token proof
+    FailedAuthentication,
+    /// Transactions or blocks faster than the configured rate limit
+    RateLimited,
+}
+
+impl Misbehavior {
+    pub fn points(self) -> u32 {
+        match self {
+            Misbehavior::MalformedMessage => 10,
+            Misbehavior::OversizedMessage => 50,
+            Misbehavior::UnexpectedMessage => 10,
+            // may be an honest race with a block or a replacement
+            Misbehavior::InvalidTransaction => 10,
+            Misbehavior::InvalidBlock => 100,
+            // a few typos are fine, guessing the token isn't
+            Misbehavior::FailedAuthentication => 20,
+            // bursts of honest relaying cost little, flooding adds up
+            Misbehavior::RateLimited => 1,
+        }
+    }
+}
+
+/// Reputation of a single peer address
+///
+/// The score decays exponentially over time (halving every
+/// `ban_score_half_life_secs`), so a peer that misbehaved once
+/// long ago is eventually forgiven.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerReputation {
+    /// Misbehavior score as of `updated_at`
+    pub score: f64,
+    /// When the score was last decayed/updated
+    pub updated_at: DateTime<Utc>,
+    /// Set while the peer is banned
+    pub banned_until: Option<DateTime<Utc>>,
+}
+
+/// Persistent ban list and peer reputation table, keyed by peer IP
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BanList {
+    peers: HashMap<String, PeerReputation>,
+}
+
+impl PeerReputation {
+    fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            score: 0.0,
+            updated_at: now,
+            banned_until: None,
+        }
+    }
+
+    /// Decay the score to `now` using the configured half-life
+    fn decay(&mut self, now: DateTime<Utc>, half_life_secs: u64) {
+        let elapsed = (now - self.updated_at).num_milliseconds().max(0) as f64 / 1000.0;
+        if half_life_secs > 0 {
+            self.score *= 0.5f64.powf(elapsed / half_life_secs as f64);
+        }
+        self.updated_at = now;
+    }
+
+    fn is_banned(&self, now: DateTime<Utc>) -> bool {
+        self.banned_until.is_some_and(|until| until > now)
+    }
+}
+
+impl BanList {
+    /// Load the ban list from a JSON file, starting empty if it doesn't exist
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read ban list {}", path.display()))?;
+        let mut banlist: BanList = serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse ban list {}", path.display()))?;
+        banlist.prune(Utc::now());
+        info!("loaded {} banned peers", banlist.banned().len());
+        Ok(banlist)
+    }
+
+    /// Save the ban list to a JSON file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json)?;
+        Ok(())
+    }
+
+    /// Add misbehavior points to a peer, banning it if the
+    /// decayed score reaches the configured threshold.
+    /// Returns true if the peer is banned after this call.
+    pub fn penalize(&mut self, address: &str, misbehavior: Misbehavior) -> bool {
+        let points = misbehavior.points();
+        let config = &BlockchainConfig::global().node;
+        let now = Utc::now();
+        let reputation = self
+            .peers
+            .entry(address.to_string())
+            .or_insert_with(|| PeerReputation::new(now));
+        reputation.decay(now, config.ban_score_half_life_secs);
+        reputation.score += points as f64;
+        if !reputation.is_banned(now) && reputation.score >= config.ban_threshold as f64 {
+            warn!(
+                "banning peer {} (score {:.1}, last offense {:?})",
+                address, reputation.score, misbehavior
+            );
+            reputation.banned_until =
+                Some(now + Duration::seconds(config.ban_duration_secs as i64));
+        }
+        reputation.is_banned(now)
+    }
+
+    /// Check whether a peer address is currently banned
+    pub fn is_banned(&self, address: &str) -> bool {
+        self.peers
+            .get(address)
+            .is_some_and(|reputation| reputation.is_banned(Utc::now()))
+    }
+
+    /// Lift a ban and reset the peer's score.
+    /// Returns false if the peer wasn't banned.
+    pub fn unban(&mut self, address: &str) -> bool {
+        let was_banned = self.is_banned(address);
+        self.peers.remove(address);
+        was_banned
+    }
+
+    /// List all currently banned peers with their decayed scores
+    pub fn banned(&self) -> Vec<BannedPeer> {
+        let now = Utc::now();
+        let half_life = BlockchainConfig::global().node.ban_score_half_life_secs;
+        self.peers
+            .iter()
+            .filter(|(_, reputation)| reputation.is_banned(now))
+            .map(|(address, reputation)| {
+                let mut reputation = reputation.clone();
+                reputation.decay(now, half_life);
+                BannedPeer {
+                    address: address.clone(),
+                    banned_until: reputation.banned_until.expect("BUG: checked above"),
+                    score: reputation.score,
+                }
+            })
+            .collect()
+    }
+
+    /// Forget peers whose ban expired and whose score decayed to (almost) nothing
+    pub fn prune(&mut self, now: DateTime<Utc>) {
+        let half_life = BlockchainConfig::global().node.ban_score_half_life_secs;
+        self.peers.retain(|_, reputation| {
+            reputation.decay(now, half_life);
+            if !reputation.is_banned(now) {
+                reputation.banned_until = None;
+            }
+            reputation.banned_until.is_some() || reputation.score >= 1.0
+        });
+    }
+}
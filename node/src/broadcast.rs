@@ -0,0 +1,59 @@
+//! Relaying blocks and transactions to the connected peers
+//!
+//! Handlers only queue a message with `relay` and move on. A dispatcher
+//! task hands each queued message to the writer task of every peer,
+//! fastest peers first, and every writer sends to its own peer. A peer
+//! too slow to keep up fills its queue and misses messages, instead of
+//! holding up block acceptance or the relays to the other peers.
+use btclib::network::Message;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{debug, warn};
+
+/// Messages waiting for the dispatcher
+const QUEUE_SIZE: usize = 1024;
+
+static QUEUE: OnceLock<mpsc::Sender<Message>> = OnceLock::new();
+
+/// Start the dispatcher. Until then, relayed messages are dropped.
+pub fn start() {
+    let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+    if QUEUE.set(sender).is_ok() {
+        tokio::spawn(dispatch(receiver));
+    }
+}
+
+/// Queue a message for every connected peer, without waiting for it to
+/// be sent
+pub fn relay(message: Message) {
+    let Some(queue) = QUEUE.get() else {
+        debug!(
+            kind = message.kind(),
+            "not relaying before broadcast starts"
+        );
+        return;
+    };
+    if let Err(TrySendError::Full(message)) = queue.try_send(message) {
+        warn!(kind = message.kind(), "broadcast queue full, not relaying");
+    }
+}
+
+async fn dispatch(mut receiver: mpsc::Receiver<Message>) {
+    while let Some(message) = receiver.recv().await {
+        let mut peers = crate::util::peers();
+        {
+            let book = crate::ADDRESS_BOOK.read().await;
+            // peers we never measured go last
+            peers.sort_by_key(|(node, _)| book.latency(node).unwrap_or(u64::MAX));
+        }
+        let message = Arc::new(message);
+        for (node, peer) in peers {
+            if !peer.relay(message.clone()) {
+                warn!(kind = message.kind(), peer = %node, "peer is falling behind, not relaying");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,33 @@
+#[cfg(test)]
+mod tests {
+    use crate::broadcast::{relay, start};
+    use crate::peer::Peer;
+    use btclib::network::Message;
+    use btclib::transport::Connection;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_relayed_messages_reach_every_peer() {
+        let mut servers = vec![];
+        for _ in 0..2 {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap().to_string();
+            servers.push(tokio::spawn(async move {
+                let (socket, _) = listener.accept().await.unwrap();
+                let mut stream = Connection::accept(socket).await.unwrap();
+                Message::receive_async(&mut stream).await.unwrap()
+            }));
+            let connection = Connection::connect(&address, false).await.unwrap();
+            crate::NODES.insert(address.clone(), Peer::start(&address, connection));
+        }
+
+        start();
+        relay(Message::Advertise("127.0.0.1:1".to_string()));
+        for server in servers {
+            assert!(matches!(
+                server.await.unwrap(),
+                Message::Advertise(address) if address == "127.0.0.1:1"
+            ));
+        }
+    }
+}
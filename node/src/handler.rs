@@ -1,243 +1,942 @@
-use btclib::config;
-use btclib::network::Message;
+use crate::banlist::Misbehavior;
+use crate::session::{Role, Session};
+use anyhow::Result;
+use btclib::auth;
+use btclib::config::{self, BlockchainConfig};
+use btclib::crypto::PublicKey;
+use btclib::error::BtcError;
+use btclib::network::{
+    IndexedBlock, IndexedTransaction, MempoolEntry, Message, MessageError, RejectCode, SupplyReport,
+};
+use btclib::retry;
 use btclib::sha256::Hash;
-use btclib::types::{Block, BlockHeader, Transaction, TransactionOutput};
+use btclib::transport::{Connection, Socket};
+use btclib::types::{
+    Block, BlockHeader, Blockchain, Transaction, TransactionOutput, BLOCK_VERSION,
+};
 use btclib::util::MerkleRoot;
 use chrono::Utc;
-use tokio::net::TcpStream;
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 use uuid::Uuid;
 
-pub async fn handle_connection(mut socket: TcpStream) {
+/// Most blocks a single `GenerateBlocks` request may ask for
+const MAX_GENERATED_BLOCKS: u32 = 1000;
+
+/// Most headers sent in answer to one `FetchHeaders`
+const MAX_HEADERS: u32 = 2000;
+
+/// Stands in for the IP of connections over the Unix domain socket
+const LOCAL_PEER: &str = "local";
+
+pub async fn handle_connection(socket: Socket) {
+    // processes on this machine need no auth token or encryption and
+    // aren't banned, whoever can open the socket is trusted
+    let local = socket.is_local();
+    // peers are banned by IP, regardless of the port they connect from
+    let peer = if local {
+        LOCAL_PEER.to_string()
+    } else {
+        socket
+            .peer_addr()
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_default()
+    };
+    if !local && crate::BANLIST.read().await.is_banned(&peer) {
+        info!("refusing connection from banned peer");
+        return;
+    }
+    let config = BlockchainConfig::global();
+    let mut socket = match retry::with_timeout(&config.retry, Connection::accept(socket)).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!(error = %e, "encryption handshake failed");
+            return;
+        }
+    };
+    if config.node.encrypt_p2p && !socket.is_encrypted() && !local {
+        info!("refusing plaintext connection, encrypt_p2p is set");
+        return;
+    }
+    // wallets and miners prove they know the auth token, if one is set
+    let mut authenticated = local || config.node.auth_token.is_none();
+    // administration always needs the token, or a local connection
+    let mut admin = local;
+    let mut challenge: Option<Vec<u8>> = None;
+    // decided by the first peer, wallet or miner request
+    let mut session = Session::Unclassified;
+    let mut shutdown = crate::SHUTDOWN.subscribe();
     loop {
-        // read a message from the socket
-        let message = match Message::receive_async(&mut socket).await {
+        // read a message from the socket, unless the node is shutting down
+        let received = tokio::select! {
+            received = Message::receive_async(&mut socket) => received,
+            _ = shutdown.wait_for(|stopping| *stopping) => {
+                debug!("closing connection for shutdown");
+                return;
+            }
+        };
+        let message = match received {
             Ok(message) => message,
+            // probably a newer peer, skip what we don't understand
+            Err(e @ MessageError::Unsupported { .. }) => {
+                debug!(error = %e, "ignoring message");
+                continue;
+            }
+            // the peer hung up or the connection broke, nothing malicious
+            Err(MessageError::Io(e)) => {
+                debug!(error = %e, "connection closed");
+                return;
+            }
+            // a node of another network, misconfigured rather than hostile
+            Err(e @ MessageError::WrongNetwork { .. }) => {
+                warn!(error = %e, "peer is on another network, closing that connection");
+                return;
+            }
+            Err(e @ MessageError::TooLarge { .. }) => {
+                warn!(error = %e, "oversized message from peer, closing that connection");
+                penalize(&peer, Misbehavior::OversizedMessage).await;
+                return;
+            }
             Err(e) => {
-                warn!("invalid message from peer: {e}, closing that connection");
+                warn!(error = %e, "invalid message from peer, closing that connection");
+                penalize(&peer, Misbehavior::MalformedMessage).await;
                 return;
             }
         };
 
-        use btclib::network::Message::*;
+        #[cfg(feature = "metrics")]
+        crate::metrics::record_message();
+
         match message {
-            UTXOs(_) | Template(_) | Difference(_) | TemplateValidity(_) | NodeList(_) => {
-                error!("I am neither a miner nor a wallet! Goodbye");
-                return;
-            }
-            FetchBlock(height) => {
-                // Clone the block first, then release lock before network I/O
-                let block = {
-                    let blockchain = crate::BLOCKCHAIN.read().await;
-                    let x = blockchain.blocks().nth(height as usize).cloned();
-                    x
-                };
-                let Some(block) = block else {
+            Message::FetchChallenge => {
+                let fresh = auth::challenge();
+                challenge = Some(fresh.clone());
+                if Message::Challenge(fresh)
+                    .send_async(&mut socket)
+                    .await
+                    .is_err()
+                {
                     return;
-                };
-                // Lock is now released - safe to do network I/O
-                let message = NewBlock(block);
-                message.send_async(&mut socket).await.unwrap();
-            }
-            DiscoverNodes => {
-                let nodes = crate::NODES
-                    .iter()
-                    .map(|x| x.key().clone())
-                    .collect::<Vec<_>>();
-                let message = NodeList(nodes);
-                message.send_async(&mut socket).await.unwrap();
-            }
-            AskDifference(height) => {
-                // Get block height immediately and release lock
-                let count = {
-                    let blockchain = crate::BLOCKCHAIN.read().await;
-                    blockchain.block_height() as i32 - height as i32
-                };
-                let message = Difference(count);
-                message.send_async(&mut socket).await.unwrap();
-            }
-            FetchUTXOs(key) => {
-                debug!("received request to fetch UTXOs");
-                // Collect UTXOs immediately and release lock
-                let utxos = {
-                    let blockchain = crate::BLOCKCHAIN.read().await;
-                    blockchain
-                        .utxos()
-                        .iter()
-                        .filter(|(_, (_, txout))| txout.pubkey == key)
-                        .map(|(_, (marked, txout))| (txout.clone(), *marked))
-                        .collect::<Vec<_>>()
-                };
-                let message = UTXOs(utxos);
-                message.send_async(&mut socket).await.unwrap();
-            }
-            NewBlock(block) => {
-                // Acquire write lock only for the blockchain operation
-                let result = {
-                    let mut blockchain = crate::BLOCKCHAIN.write().await;
-                    info!("received new block");
-                    blockchain.add_block(block)
-                };
-                if let Err(e) = result {
-                    warn!("block rejected: {}", e);
                 }
+                continue;
             }
-            NewTransaction(tx) => {
-                // Acquire write lock only for the mempool operation
-                let result = {
-                    let mut blockchain = crate::BLOCKCHAIN.write().await;
-                    debug!("received transaction from friend");
-                    blockchain.add_to_mempool(tx)
+            Message::Authenticate(proof) => {
+                let verified = match (&config.node.auth_token, challenge.take()) {
+                    (Some(token), Some(challenge)) => auth::verify(token, &challenge, &proof),
+                    _ => false,
                 };
-                if let Err(e) = result {
-                    warn!("transaction rejected, closing connection: {}", e);
+                authenticated = local || config.node.auth_token.is_none() || verified;
+                admin = local || verified;
+                let sent = Message::Authenticated(authenticated)
+                    .send_async(&mut socket)
+                    .await;
+                if !authenticated {
+                    warn!("peer failed to authenticate, closing that connection");
+                    penalize(&peer, Misbehavior::FailedAuthentication).await;
+                    return;
+                }
+                info!("peer authenticated");
+                if sent.is_err() {
                     return;
                 }
+                continue;
             }
-            ValidateTemplate(block_template) => {
-                // Get last block hash immediately and release lock
-                let status = {
-                    let blockchain = crate::BLOCKCHAIN.read().await;
-                    block_template.header.prev_block_hash
-                        == blockchain
-                            .blocks()
-                            .last()
-                            .map(|last_block| last_block.hash())
-                            .unwrap_or(Hash::zero())
-                };
-                let message = TemplateValidity(status);
-                message.send_async(&mut socket).await.unwrap();
-            }
-            SubmitTemplate(block) => {
-                info!("received allegedly mined template");
-                // Acquire write lock only for blockchain operations, then release before network I/O
-                let block_clone = block.clone();
-                let was_accepted = {
-                    let mut blockchain = crate::BLOCKCHAIN.write().await;
-                    match blockchain.add_block(block.clone()) {
-                        Ok(_) => {
-                            blockchain.rebuild_utxos();
-                            true
-                        }
-                        Err(e) => {
-                            warn!("block rejected: {}, closing connection", e);
-                            false
-                        }
-                    }
-                };
+            ref message if !authenticated && requires_auth(message) => {
+                warn!(
+                    kind = message.kind(),
+                    "unauthenticated request, closing that connection"
+                );
+                return;
+            }
+            ref message if !admin && requires_admin(message) => {
+                warn!(
+                    kind = message.kind(),
+                    "administrative request from a remote connection without the auth token, closing that connection"
+                );
+                penalize(&peer, Misbehavior::UnexpectedMessage).await;
+                return;
+            }
+            _ => {}
+        }
 
-                if !was_accepted {
-                    return;
+        if let Some(role) = Role::of(&message) {
+            let classified = session.role().is_none();
+            if !session.accept(role) {
+                warn!(
+                    kind = message.kind(),
+                    role = %session.role().expect("BUG: accept only fails once classified"),
+                    "request outside the connection's role, closing that connection"
+                );
+                penalize(&peer, Misbehavior::UnexpectedMessage).await;
+                return;
+            }
+            if classified {
+                debug!(%role, "connection classified");
+            }
+        }
+
+        // so one client can't flood the mempool or block validation
+        if session
+            .limit_for(&message)
+            .is_some_and(|limit| !limit.try_take())
+        {
+            debug!(
+                kind = message.kind(),
+                "rate limit exceeded, dropping message"
+            );
+            if penalize(&peer, Misbehavior::RateLimited).await {
+                return;
+            }
+            continue;
+        }
+
+        if let Message::Subscribe(keys) = message {
+            // local connections have no address to tell them apart
+            let address = socket
+                .peer_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|_| format!("{}-{}", peer, Uuid::new_v4()));
+            info!(keys = keys.len(), "peer subscribed to notifications");
+            crate::SUBSCRIBERS.insert(address, (keys, socket));
+            // the connection now only carries notifications
+            return;
+        }
+
+        if let Message::Disconnect(port) = message {
+            let ip = socket.peer_addr().map(|addr| addr.ip()).ok();
+            for (node, stream) in crate::util::peers() {
+                let same = stream
+                    .peer_addr()
+                    .is_some_and(|addr| Some(addr.ip()) == ip && addr.port() == port);
+                if same {
+                    info!(port, "peer is shutting down, forgetting it");
+                    crate::NODES.remove(&node);
                 }
+            }
+            return;
+        }
 
-                info!("block looks good, broadcasting");
-                // send block to all friend nodes - lock is now released
-                let nodes = crate::NODES
-                    .iter()
-                    .map(|x| x.key().clone())
-                    .collect::<Vec<_>>();
-                for node in nodes {
-                    if let Some(mut stream) = crate::NODES.get_mut(&node) {
-                        let message = Message::NewBlock(block_clone.clone());
-                        if message.send_async(&mut *stream).await.is_err() {
-                            warn!("failed to send block to {}", node);
-                        }
-                    }
+        let span = info_span!("message", kind = message.kind());
+        match handle_message(&mut socket, &peer, message)
+            .instrument(span)
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(e) => {
+                debug!(error = %e, "failed to answer peer, closing connection");
+                return;
+            }
+        }
+    }
+}
+
+/// Add to a peer's misbehavior score. Returns true if the peer is now
+/// banned and should be disconnected.
+async fn penalize(peer: &str, misbehavior: Misbehavior) -> bool {
+    if peer == LOCAL_PEER {
+        return false;
+    }
+    let banned = crate::BANLIST.write().await.penalize(peer, misbehavior);
+    if banned {
+        warn!(?misbehavior, "peer banned, disconnecting");
+    }
+    banned
+}
+
+/// Wallet and miner requests and status queries are only served to
+/// authenticated connections when `node.auth_token` is set. Node-to-node
+/// traffic stays open.
+fn requires_auth(message: &Message) -> bool {
+    match Role::of(message) {
+        Some(role) => role != Role::Peer,
+        None => matches!(message, Message::GetStatus),
+    }
+}
+
+/// Requests changing what the node holds for everyone, and the ban list,
+/// are only served over the Unix socket, or to connections that proved
+/// they know `node.auth_token`. Without a token, remote connections never
+/// get them, so a banned peer coming back from another address can't lift
+/// its own ban.
+fn requires_admin(message: &Message) -> bool {
+    matches!(
+        message,
+        Message::ListBanned
+            | Message::Unban(_)
+            | Message::EvictTransaction(_)
+            | Message::GenerateBlocks(..)
+    )
+}
+
+/// Whether a block builds on our current tip. Blocks that don't are
+/// usually stale (another block won the race), not malicious.
+fn extends_tip(blockchain: &Blockchain, block: &Block) -> bool {
+    block.header.prev_block_hash
+        == blockchain
+            .blocks()
+            .last()
+            .map(|last_block| last_block.hash())
+            .unwrap_or(Hash::zero())
+}
+
+/// Handle one message from a peer. Returns `Ok(false)` if the
+/// connection should be closed, and an error if answering failed.
+async fn handle_message(socket: &mut Connection, peer: &str, message: Message) -> Result<bool> {
+    match Role::of(&message) {
+        Some(Role::Peer) => handle_peer_message(socket, peer, message).await,
+        Some(Role::Wallet) => handle_wallet_message(socket, peer, message).await,
+        Some(Role::Miner) => handle_miner_message(socket, peer, message).await,
+        None => handle_other_message(socket, peer, message).await,
+    }
+}
+
+/// Ban list administration, status queries, mempool eviction, block
+/// generation on regtest, and responses nobody asked for
+async fn handle_other_message(
+    socket: &mut Connection,
+    peer: &str,
+    message: Message,
+) -> Result<bool> {
+    use btclib::network::Message::*;
+    match message {
+        ListBanned => {
+            let banned = crate::BANLIST.read().await.banned();
+            BannedPeers(banned).send_async(socket).await?;
+        }
+        Unban(address) => {
+            let config = BlockchainConfig::global();
+            let mut banlist = crate::BANLIST.write().await;
+            if banlist.unban(&address) {
+                info!("unbanned peer {}", address);
+                if let Err(e) = banlist.save(&config.node.banlist_file) {
+                    warn!("failed to save ban list: {}", e);
                 }
             }
-            SubmitTransaction(tx) => {
-                debug!("submit tx");
-                // Acquire write lock only for mempool operation, then release before network I/O
-                let tx_clone = tx.clone();
-                let result = {
-                    let mut blockchain = crate::BLOCKCHAIN.write().await;
-                    blockchain.add_to_mempool(tx)
-                };
+        }
+        GetStatus => {
+            let report = crate::status::report().await;
+            Status(report).send_async(socket).await?;
+        }
+        EvictTransaction(hash) => {
+            let (evicted, conflicts) = {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let mut mempool = crate::MEMPOOL.write().await;
+                let evicted = mempool.evict(&blockchain, &hash).is_some();
+                (evicted, mempool.take_conflicts())
+            };
+            if evicted {
+                info!("evicted transaction {} from the mempool", hash);
+            }
+            crate::util::notify_conflicts(conflicts).await;
+            Evicted(evicted).send_async(socket).await?;
+        }
+        GenerateBlocks(count, pubkey) => return generate_blocks(socket, count, pubkey).await,
+        FetchChallenge | Authenticate(_) => unreachable!("handled by handle_connection"),
+        _ => {
+            error!("I am neither a miner nor a wallet! Goodbye");
+            penalize(peer, Misbehavior::UnexpectedMessage).await;
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
 
-                if let Err(e) = result {
-                    warn!("transaction rejected, closing connection: {}", e);
-                    return;
+/// Requests from another node
+async fn handle_peer_message(
+    socket: &mut Connection,
+    peer: &str,
+    message: Message,
+) -> Result<bool> {
+    use btclib::network::Message::*;
+    match message {
+        FetchBlock(height) => {
+            // Clone the block first, then release lock before network I/O
+            let block = {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let x = blockchain.blocks().nth(height as usize).cloned();
+                x
+            };
+            let Some(block) = block else {
+                debug!(
+                    height,
+                    "peer asked for a block we don't have, closing connection"
+                );
+                return Ok(false);
+            };
+            NewBlock(block).send_async(socket).await?;
+        }
+        FetchHistoricalBlocks(height, count) => {
+            let count = count.min(btclib::reorg::MAX_HISTORICAL_BLOCKS) as usize;
+            // this node keeps every block, so it can serve any of them
+            let blocks = {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                blockchain
+                    .blocks()
+                    .skip(height as usize)
+                    .take(count)
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+            HistoricalBlocks(blocks).send_async(socket).await?;
+        }
+        DiscoverNodes => {
+            let book = crate::ADDRESS_BOOK.read().await;
+            let nodes = crate::NODES
+                .iter()
+                .map(|x| book.known_peer(x.key()))
+                .collect::<Vec<_>>();
+            drop(book);
+            NodeList(nodes).send_async(socket).await?;
+        }
+        AskDifference(height) => {
+            // Get block height immediately and release lock
+            let count = {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                blockchain.block_height() as i32 - height as i32
+            };
+            Difference(count).send_async(socket).await?;
+        }
+        NewBlock(block) => {
+            let hash = block.hash();
+            if crate::SEEN_BLOCKS
+                .lock()
+                .expect("seen blocks poisoned")
+                .contains(&hash)
+            {
+                debug!(block = %hash, "block already seen");
+                return Ok(true);
+            }
+            info!(block = %hash, "received new block");
+            let (result, extends_tip) = add_block(block).await;
+            if let Err(e) = result {
+                warn!(block = %hash, error = %e, "block rejected");
+                if !extends_tip {
+                    // it may belong to a longer chain than ours
+                    crate::reorg::follow_longest_chain();
+                } else if penalize(peer, Misbehavior::InvalidBlock).await {
+                    return Ok(false);
+                }
+            } else {
+                admit_orphans().await;
+            }
+        }
+        NewTransaction(tx) => {
+            let txid = tx.hash();
+            if crate::SEEN_TRANSACTIONS
+                .lock()
+                .expect("seen transactions poisoned")
+                .contains(&txid)
+            {
+                debug!(tx = %txid, "transaction already seen");
+                return Ok(true);
+            }
+            debug!(tx = %txid, "received transaction from friend");
+            if let Err(e) = add_transaction(tx.clone()).await {
+                // it may have overtaken the block creating its inputs
+                if let BtcError::MempoolFull { .. } = e {
+                    // our limits aren't the peer's fault
+                    debug!(tx = %txid, error = %e, "transaction not kept");
+                    return Ok(true);
+                }
+                if RejectCode::from_error(&e) == RejectCode::MissingInputs {
+                    debug!(tx = %txid, "inputs unknown, keeping it as an orphan");
+                    let max_orphans = BlockchainConfig::global().node.max_orphan_transactions;
+                    crate::MEMPOOL.write().await.add_orphan(tx, max_orphans);
+                    return Ok(true);
                 }
+                warn!(tx = %txid, error = %e, "transaction rejected, closing connection");
+                penalize(peer, Misbehavior::InvalidTransaction).await;
+                return Ok(false);
+            }
+        }
+        Advertise(address) => {
+            // an address we can dial, not a hostname or a socket path
+            if address.parse::<std::net::SocketAddr>().is_ok() {
+                crate::util::connect_back(address).await;
+            } else {
+                debug!(%address, "ignoring advertised address");
+            }
+        }
+        Disconnect(_) => unreachable!("handled by handle_connection"),
+        _ => unreachable!("not a peer request"),
+    }
+    Ok(true)
+}
 
-                debug!("added transaction to mempool");
-                // send transaction to all friend nodes - lock is now released
-                let nodes = crate::NODES
-                    .iter()
-                    .map(|x| x.key().clone())
+/// Requests from a wallet
+async fn handle_wallet_message(
+    socket: &mut Connection,
+    peer: &str,
+    message: Message,
+) -> Result<bool> {
+    use btclib::network::Message::*;
+    match message {
+        EstimateFee(target_blocks) => {
+            let config = BlockchainConfig::global();
+            let (estimate, min_rate) = {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let mempool = crate::MEMPOOL.read().await;
+                (
+                    blockchain.estimate_fee(
+                        &mempool,
+                        target_blocks,
+                        config.node.fee_estimation_blocks,
+                    ),
+                    mempool.min_fee_rate(&blockchain, crate::util::mempool_limits()),
+                )
+            };
+            // a full mempool doesn't take less than its minimum, here for
+            // a transaction as large as the cheapest pending one since we
+            // don't know the size of the wallet's
+            let min_fee = min_rate.map_or(0, |rate| rate.fee_to_beat(rate.bytes));
+            let fee = estimate.unwrap_or(config.node.fallback_fee).max(min_fee);
+            debug!("fee estimate for {} blocks: {}", target_blocks, fee);
+            FeeEstimate(fee).send_async(socket).await?;
+        }
+        FetchTransaction(hash) => {
+            let found = {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                if !blockchain.index().has_txindex() {
+                    debug!("FetchTransaction without txindex enabled");
+                }
+                blockchain
+                    .find_transaction(&hash)
+                    .map(|(transaction, block_height, position)| IndexedTransaction {
+                        transaction: transaction.clone(),
+                        block_height,
+                        position,
+                    })
+            };
+            TransactionFound(found).send_async(socket).await?;
+        }
+        FetchAddressHistory(key) => {
+            let history = {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                if !blockchain.index().has_addressindex() {
+                    debug!("FetchAddressHistory without addressindex enabled");
+                }
+                blockchain.index().history(&key)
+            };
+            AddressHistory(history).send_async(socket).await?;
+        }
+        FetchHeaders(start, count) => {
+            let (headers, tip_height) = {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let headers = blockchain
+                    .blocks()
+                    .skip(start as usize)
+                    .take(count.min(MAX_HEADERS) as usize)
+                    .map(|block| block.header.clone())
                     .collect::<Vec<_>>();
-                for node in nodes {
-                    debug!("sending to friend: {node}");
-                    if let Some(mut stream) = crate::NODES.get_mut(&node) {
-                        let message = Message::SubmitTransaction(tx_clone.clone());
-                        if message.send_async(&mut *stream).await.is_err() {
-                            warn!("failed to send transaction to {}", node);
-                        }
-                    }
+                (headers, blockchain.block_height())
+            };
+            Headers(headers, tip_height).send_async(socket).await?;
+        }
+        FetchBlockByHash(hash) => {
+            let found = {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let found = blockchain
+                    .blocks()
+                    .enumerate()
+                    .find(|(_, block)| block.hash() == hash)
+                    .map(|(height, block)| IndexedBlock {
+                        block: block.clone(),
+                        height: height as u64,
+                    });
+                found
+            };
+            BlockFound(found).send_async(socket).await?;
+        }
+        GetMempool => {
+            let entries = {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                let mempool = crate::MEMPOOL.read().await;
+                mempool
+                    .transactions()
+                    .iter()
+                    .map(|(received, transaction)| MempoolEntry {
+                        transaction: transaction.clone(),
+                        received: *received,
+                        fee: blockchain.mempool_fee(transaction),
+                    })
+                    .collect::<Vec<_>>()
+            };
+            Mempool(entries).send_async(socket).await?;
+        }
+        GetSupply => {
+            let report = {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                SupplyReport {
+                    height: blockchain.block_height(),
+                    circulating: blockchain.total_supply(),
+                    scheduled: blockchain
+                        .params()
+                        .scheduled_supply(blockchain.block_height()),
                 }
-                info!("transaction sent to friends");
-            }
-            FetchTemplate(pubkey) => {
-                // Collect all necessary data and release lock before any expensive operations
-                let (mempool_txs, prev_block_hash, target, utxos, reward) = {
-                    let blockchain = crate::BLOCKCHAIN.read().await;
-                    let mempool_txs = blockchain
-                        .mempool()
-                        .iter()
-                        .take(config::block_transaction_cap())
-                        .map(|(_, tx)| tx)
-                        .cloned()
-                        .collect::<Vec<_>>();
-                    let prev_block_hash = blockchain
-                        .blocks()
-                        .last()
-                        .map(|last_block| last_block.hash())
-                        .unwrap_or(Hash::zero());
-                    let target = blockchain.target();
-                    let utxos = blockchain.utxos().clone();
-                    let reward = blockchain.calculate_block_reward();
-                    (mempool_txs, prev_block_hash, target, utxos, reward)
-                };
+            };
+            Supply(report).send_async(socket).await?;
+        }
+        FetchUTXOs(key) => {
+            debug!("received request to fetch UTXOs");
+            let (utxos, tip_height) = utxos_for(&key).await;
+            UTXOs(utxos, tip_height).send_async(socket).await?;
+        }
+        FetchPendingOutputs(key) => {
+            debug!("received request to fetch pending outputs");
+            let outputs = {
+                let mempool = crate::MEMPOOL.read().await;
+                mempool
+                    .transactions()
+                    .iter()
+                    .flat_map(|(_, tx)| tx.outputs.iter())
+                    .filter(|txout| txout.pubkey == key)
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+            PendingOutputs(outputs).send_async(socket).await?;
+        }
+        SubmitTransaction(tx) => return submit_transaction(socket, peer, tx).await,
+        Subscribe(_) => unreachable!("handled by handle_connection"),
+        _ => unreachable!("not a wallet request"),
+    }
+    Ok(true)
+}
 
-                // Now build template without holding the lock
-                let mut transactions = vec![];
-                transactions.extend(mempool_txs);
-                // insert coinbase tx with pubkey
-                transactions.insert(
-                    0,
-                    Transaction {
-                        inputs: vec![],
-                        outputs: vec![TransactionOutput {
-                            pubkey,
-                            unique_id: Uuid::new_v4(),
-                            value: 0,
-                        }],
-                    },
-                );
-                let merkle_root = MerkleRoot::calculate(&transactions);
-                let mut block = Block::new(
-                    BlockHeader {
-                        timestamp: Utc::now(),
-                        prev_block_hash,
-                        nonce: 0,
-                        target,
-                        merkle_root,
-                    },
-                    transactions,
-                );
-                let miner_fees = match block.calculate_miner_fees(&utxos) {
-                    Ok(fees) => fees,
-                    Err(e) => {
-                        error!("failed to calculate miner fees: {}", e);
-                        return;
-                    }
-                };
-                // update coinbase tx with reward
-                block.transactions[0].outputs[0].value = reward + miner_fees;
-                // recalculate merkle root
-                block.header.merkle_root = MerkleRoot::calculate(&block.transactions);
-                let message = Template(block);
-                message.send_async(&mut socket).await.unwrap();
+/// Requests from a miner
+async fn handle_miner_message(
+    socket: &mut Connection,
+    peer: &str,
+    message: Message,
+) -> Result<bool> {
+    use btclib::network::Message::*;
+    match message {
+        FetchTemplate(pubkey) => match build_template(pubkey).await {
+            Ok(block) => Template(block).send_async(socket).await?,
+            Err((prev_block_hash, e)) => {
+                error!("failed to build template: {}", e);
+                reject(socket, prev_block_hash, RejectCode::Invalid, &e).await?;
             }
+        },
+        ValidateTemplate(block_template) => {
+            // Get last block hash immediately and release lock
+            let status = {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                extends_tip(&blockchain, &block_template)
+            };
+            TemplateValidity(status).send_async(socket).await?;
+        }
+        SubmitTemplate(block) => return submit_template(socket, peer, block).await,
+        _ => unreachable!("not a miner request"),
+    }
+    Ok(true)
+}
+
+/// Tell a wallet or miner why its transaction or block was refused
+async fn reject(
+    socket: &mut Connection,
+    item_hash: Hash,
+    code: RejectCode,
+    error: &BtcError,
+) -> Result<(), MessageError> {
+    let message = Message::Reject {
+        item_hash,
+        code,
+        reason: error.to_string(),
+    };
+    message.send_async(socket).await
+}
+
+/// UTXOs paying `key`, with the height each was confirmed at, and the
+/// current tip height
+async fn utxos_for(key: &PublicKey) -> (Vec<(TransactionOutput, bool, u64)>, u64) {
+    let snapshot = crate::snapshot::current();
+    let utxos = snapshot
+        .utxos
+        .iter()
+        .filter(|(_, (_, txout))| txout.pubkey == *key)
+        .map(|(hash, (_, txout))| {
+            let height = snapshot
+                .utxo_heights
+                .get(hash)
+                .copied()
+                .unwrap_or(snapshot.tip_height());
+            (txout.clone(), *hash, height)
+        })
+        .collect::<Vec<_>>();
+    let mempool = crate::MEMPOOL.read().await;
+    let utxos = utxos
+        .into_iter()
+        .map(|(txout, hash, height)| (txout, mempool.is_reserved(&hash), height))
+        .collect();
+    (utxos, snapshot.tip_height())
+}
+
+/// Validate and add a block, notifying wallets of any transactions it
+/// conflicts with. Also returns whether the block built on our tip.
+async fn add_block(block: Block) -> (Result<(), BtcError>, bool) {
+    let hash = block.hash();
+    // Acquire write lock only for blockchain operations, then release before network I/O
+    let (result, conflicts, extends_tip) = {
+        let mut blockchain = crate::BLOCKCHAIN.write().await;
+        let extends_tip = extends_tip(&blockchain, &block);
+        #[cfg(feature = "metrics")]
+        let started = std::time::Instant::now();
+        let result = blockchain.add_block(block.clone());
+        #[cfg(feature = "metrics")]
+        crate::metrics::record_block_validation(started.elapsed());
+        let mut conflicts = vec![];
+        if result.is_ok() {
+            let mut mempool = crate::MEMPOOL.write().await;
+            mempool.remove_block(&blockchain, &block);
+            conflicts = mempool.take_conflicts();
+            blockchain.apply_tip_to_utxos();
+            // templates see the mempool and the snapshot change together
+            crate::snapshot::apply(&blockchain, &block);
+            drop(mempool);
+            crate::util::log_chain_status(&blockchain);
+        }
+        (result, conflicts, extends_tip)
+    };
+    if result.is_ok() {
+        crate::SEEN_BLOCKS
+            .lock()
+            .expect("seen blocks poisoned")
+            .insert(hash);
+    }
+    crate::util::notify_conflicts(conflicts).await;
+    (result, extends_tip)
+}
+
+/// Validate and add a transaction to the mempool, notifying wallets of
+/// any transactions it replaces and of the payments it makes to them
+async fn add_transaction(tx: Transaction) -> Result<(), BtcError> {
+    let txid = tx.hash();
+    let outputs = tx.outputs.clone();
+    let (result, conflicts, sender) = {
+        let blockchain = crate::BLOCKCHAIN.read().await;
+        let mut mempool = crate::MEMPOOL.write().await;
+        let sender = tx
+            .inputs
+            .first()
+            .and_then(|input| blockchain.utxos().get(&input.prev_transaction_output_hash))
+            .map(|(_, output)| output.pubkey.clone());
+        let result = mempool.add_bounded(&blockchain, tx, crate::util::mempool_limits());
+        (result, mempool.take_conflicts(), sender)
+    };
+    crate::util::notify_conflicts(conflicts).await;
+    let evicted = result?;
+    crate::SEEN_TRANSACTIONS
+        .lock()
+        .expect("seen transactions poisoned")
+        .insert(txid);
+    if !evicted.is_empty() {
+        info!(
+            count = evicted.len(),
+            "mempool full, evicted the cheapest transactions"
+        );
+    }
+    crate::util::notify_payments(txid, outputs, sender).await;
+    Ok(())
+}
+
+/// Move the orphans whose inputs a new block created into the mempool
+/// and relay them
+async fn admit_orphans() {
+    let (admitted, conflicts) = {
+        let blockchain = crate::BLOCKCHAIN.read().await;
+        let mut mempool = crate::MEMPOOL.write().await;
+        let mut admitted = mempool.retry_orphans(&blockchain);
+        let evicted = mempool.trim(&blockchain, crate::util::mempool_limits());
+        admitted.retain(|tx| !evicted.contains(&tx.hash()));
+        let admitted = admitted
+            .into_iter()
+            .map(|tx| {
+                let sender = tx
+                    .inputs
+                    .first()
+                    .and_then(|input| blockchain.utxos().get(&input.prev_transaction_output_hash))
+                    .map(|(_, output)| output.pubkey.clone());
+                (tx, sender)
+            })
+            .collect::<Vec<_>>();
+        (admitted, mempool.take_conflicts())
+    };
+    crate::util::notify_conflicts(conflicts).await;
+    for (tx, sender) in admitted {
+        let txid = tx.hash();
+        info!(tx = %txid, "orphan transaction admitted, relaying");
+        crate::SEEN_TRANSACTIONS
+            .lock()
+            .expect("seen transactions poisoned")
+            .insert(txid);
+        crate::util::notify_payments(txid, tx.outputs.clone(), sender).await;
+        crate::broadcast::relay(Message::NewTransaction(tx));
+    }
+}
+
+/// A miner found a block: add it, answer with `Accepted` or `Reject`,
+/// and relay it to our peers
+async fn submit_template(socket: &mut Connection, peer: &str, block: Block) -> Result<bool> {
+    let hash = block.hash();
+    info!(block = %hash, "received allegedly mined template");
+    let (result, extends_tip) = add_block(block.clone()).await;
+    if let Err(e) = result {
+        warn!(block = %hash, error = %e, "block rejected");
+        // a template that lost the race is stale, not invalid
+        let code = if extends_tip {
+            RejectCode::from_error(&e)
+        } else {
+            RejectCode::Stale
         };
+        reject(socket, hash, code, &e).await?;
+        return Ok(!(extends_tip && penalize(peer, Misbehavior::InvalidBlock).await));
+    }
+    admit_orphans().await;
+    Message::Accepted(hash).send_async(socket).await?;
+
+    info!(block = %hash, "block looks good, broadcasting");
+    crate::broadcast::relay(Message::NewBlock(block));
+    Ok(true)
+}
+
+/// A wallet sent a transaction: add it to the mempool, answer with
+/// `Accepted` or `Reject`, and relay it to our peers. A wallet
+/// rebroadcasting a transaction we still hold is simply acknowledged,
+/// rather than it replacing itself.
+async fn submit_transaction(socket: &mut Connection, peer: &str, tx: Transaction) -> Result<bool> {
+    let txid = tx.hash();
+    debug!(tx = %txid, "submit tx");
+    let pending = crate::MEMPOOL
+        .read()
+        .await
+        .transactions()
+        .iter()
+        .any(|(_, transaction)| transaction.hash() == txid);
+    if pending {
+        debug!(tx = %txid, "transaction already in the mempool");
+        Message::Accepted(txid).send_async(socket).await?;
+        return Ok(true);
+    }
+    if let Err(e) = add_transaction(tx.clone()).await {
+        warn!(tx = %txid, error = %e, "transaction rejected");
+        reject(socket, txid, RejectCode::from_error(&e), &e).await?;
+        return Ok(!penalize(peer, Misbehavior::InvalidTransaction).await);
+    }
+    Message::Accepted(txid).send_async(socket).await?;
+
+    debug!(tx = %txid, "added transaction to mempool");
+    crate::broadcast::relay(Message::NewTransaction(tx));
+    info!(tx = %txid, "transaction sent to friends");
+    Ok(true)
+}
+
+/// Mine `count` blocks on top of our tip right away, paying `pubkey`, and
+/// answer with their hashes. Only regtest networks, whose target accepts
+/// any hash, allow this.
+async fn generate_blocks(socket: &mut Connection, count: u32, pubkey: PublicKey) -> Result<bool> {
+    if !BlockchainConfig::global().network.is_regtest() {
+        let e = BtcError::invalid_block("block generation needs a regtest network");
+        reject(socket, Hash::zero(), RejectCode::Invalid, &e).await?;
+        return Ok(true);
+    }
+    let mut hashes = vec![];
+    for _ in 0..count.min(MAX_GENERATED_BLOCKS) {
+        let mut block = match build_template(pubkey.clone()).await {
+            Ok(block) => block,
+            Err((prev_block_hash, e)) => {
+                reject(socket, prev_block_hash, RejectCode::Invalid, &e).await?;
+                return Ok(true);
+            }
+        };
+        // instant at the regtest target, but don't stall the runtime if
+        // the profile was given a harder one
+        let batch_size = BlockchainConfig::global().mining.mining_batch_size;
+        let block = tokio::task::spawn_blocking(move || {
+            while !block.header.mine(batch_size) {}
+            block
+        })
+        .await?;
+        let hash = block.hash();
+        let (result, _) = add_block(block.clone()).await;
+        if let Err(e) = result {
+            warn!(block = %hash, error = %e, "generated block rejected");
+            reject(socket, hash, RejectCode::from_error(&e), &e).await?;
+            return Ok(true);
+        }
+        admit_orphans().await;
+        crate::broadcast::relay(Message::NewBlock(block));
+        hashes.push(hash);
+    }
+    info!(count = hashes.len(), "generated blocks");
+    Message::GeneratedBlocks(hashes).send_async(socket).await?;
+    Ok(true)
+}
+
+/// Build a block template paying the reward and fees to `pubkey`. On
+/// failure, also returns the tip hash the template would have built on.
+async fn build_template(pubkey: PublicKey) -> Result<Block, (Hash, BtcError)> {
+    // the snapshot stays valid without holding the blockchain lock, and
+    // is only swapped with the mempool locked, so the two match
+    let (snapshot, mempool_txs) = {
+        let mempool = crate::MEMPOOL.read().await;
+        let snapshot = crate::snapshot::current();
+        let mempool_txs = mempool
+            .transactions()
+            .iter()
+            .take(snapshot.block_transaction_cap)
+            .map(|(_, tx)| tx)
+            .cloned()
+            .collect::<Vec<_>>();
+        (snapshot, mempool_txs)
+    };
+    let tip = &snapshot.tip;
+    let (prev_block_hash, height) = (tip.hash, tip.height);
+
+    // Now build template without holding the lock
+    let mut transactions = vec![];
+    transactions.extend(mempool_txs);
+    // insert coinbase tx with pubkey, committing to the new block's height
+    transactions.insert(
+        0,
+        Transaction::coinbase(
+            height,
+            vec![TransactionOutput {
+                pubkey,
+                unique_id: Uuid::new_v4(),
+                value: 0,
+            }],
+        ),
+    );
+    if height == 0 {
+        // the premine follows the miner's output
+        let allocations = &BlockchainConfig::global().network.genesis_allocations;
+        transactions[0]
+            .outputs
+            .extend(allocations.iter().map(|allocation| TransactionOutput {
+                pubkey: allocation.pubkey.clone(),
+                unique_id: Uuid::new_v4(),
+                value: allocation.value,
+            }));
     }
+    let merkle_root = MerkleRoot::calculate(&transactions);
+    let mut block = Block::new(
+        BlockHeader {
+            version: config::version_at(height).max(BLOCK_VERSION),
+            timestamp: Utc::now(),
+            prev_block_hash,
+            nonce: 0,
+            target: tip.target,
+            merkle_root,
+            utxo_commitment: Some(tip.utxo_commitment),
+        },
+        transactions,
+    );
+    let miner_fees = block
+        .calculate_miner_fees(&snapshot.spent_by(&block.transactions))
+        .map_err(|e| (prev_block_hash, e))?;
+    // update coinbase tx with reward
+    block.transactions[0].outputs[0].value = tip.reward + miner_fees;
+    // recalculate merkle root
+    block.header.merkle_root = MerkleRoot::calculate(&block.transactions);
+    Ok(block)
 }
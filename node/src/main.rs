@@ -1,47 +1,215 @@
+use addrbook::AddressBook;
 use anyhow::Result;
 use argh::FromArgs;
+use banlist::BanList;
 use btclib::config::BlockchainConfig;
-use btclib::types::Blockchain;
+use btclib::crypto::PublicKey;
+use btclib::sha256::Hash;
+use btclib::store::{open_store, ChainStore};
+use btclib::transport::Connection;
+use btclib::types::{Blockchain, Mempool};
 use dashmap::DashMap;
+use peer::Peer;
+use seen::SeenCache;
+use snapshot::ChainSnapshot;
 use static_init::dynamic;
-use std::path::Path;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::RwLock;
-use tracing::{info, warn};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
+use tokio::net::TcpListener;
+use tokio::sync::{watch, RwLock};
+use tokio::task::JoinSet;
+use tokio::time;
+use tracing::{info, info_span, warn, Instrument};
 
+mod addrbook;
+mod banlist;
+mod broadcast;
 mod handler;
+mod maintenance;
+mod mempool;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod nat;
+mod peer;
+mod ratelimit;
+mod reorg;
+mod seen;
+mod session;
+mod snapshot;
+mod status;
+mod sync;
 mod util;
 
 #[dynamic]
 pub static BLOCKCHAIN: RwLock<Blockchain> = RwLock::new(Blockchain::new());
 
+/// Transactions waiting for a block, apart from the chain so queries and
+/// new transactions don't wait on block validation. When both are
+/// needed, `BLOCKCHAIN` is locked first.
 #[dynamic]
-pub static NODES: DashMap<String, TcpStream> = DashMap::new();
+pub static MEMPOOL: RwLock<Mempool> = RwLock::new(Mempool::default());
+
+/// The chain state as of the tip, read without the blockchain lock
+#[dynamic]
+pub static SNAPSHOT: std::sync::RwLock<Arc<ChainSnapshot>> =
+    std::sync::RwLock::new(Arc::new(ChainSnapshot::of(&Blockchain::new())));
+
+/// Connected nodes, by the address we dialed
+#[dynamic]
+pub static NODES: DashMap<String, Peer> = DashMap::new();
+
+/// Wallet connections waiting for notifications, by peer address,
+/// with the public keys they asked about
+#[dynamic]
+pub static SUBSCRIBERS: DashMap<String, (Vec<PublicKey>, Connection)> = DashMap::new();
+
+/// Blocks accepted recently, so repeats are dropped without taking the
+/// blockchain lock
+#[dynamic]
+pub static SEEN_BLOCKS: Mutex<SeenCache> = Mutex::new(SeenCache::new(seen::MAX_SEEN_BLOCKS));
+
+/// Transactions accepted recently, likewise
+#[dynamic]
+pub static SEEN_TRANSACTIONS: Mutex<SeenCache> =
+    Mutex::new(SeenCache::new(seen::MAX_SEEN_TRANSACTIONS));
+
+#[dynamic]
+pub static BANLIST: RwLock<BanList> = RwLock::new(BanList::default());
+
+#[dynamic]
+pub static ADDRESS_BOOK: RwLock<AddressBook> = RwLock::new(AddressBook::default());
+
+/// Flipped to true once the node starts shutting down
+#[dynamic]
+pub static SHUTDOWN: watch::Sender<bool> = watch::channel(false).0;
+
+/// Where peers can reach this node, once its port is mapped on the router
+pub static EXTERNAL_ADDRESS: OnceLock<String> = OnceLock::new();
+
+/// When the node started, for its uptime
+#[dynamic]
+pub static STARTED: Instant = Instant::now();
 
 #[derive(FromArgs)]
 /// A toy blockchain node
 struct Args {
+    #[argh(option)]
+    /// profile of the config files to run, e.g. testnet
+    network: Option<String>,
     #[argh(option)]
     /// port number (defaults to NODE_PORT env var or 9000)
     port: Option<u16>,
     #[argh(option)]
     /// blockchain file location (defaults to BLOCKCHAIN_FILE env var or ./blockchain.cbor)
     blockchain_file: Option<String>,
+    #[argh(switch)]
+    /// re-validate every stored block and the UTXO set before starting
+    verify: bool,
     #[argh(positional)]
     /// addresses of initial nodes (can also use INITIAL_PEERS env var)
     nodes: Vec<String>,
+    #[argh(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Run(RunArgs),
+    Status(StatusArgs),
+    Mempool(MempoolArgs),
+    Evict(EvictArgs),
+    VerifyChain(VerifyChainArgs),
+    ExportUtxos(ExportUtxosArgs),
+    PrintStatus(PrintStatusArgs),
+    Reindex(ReindexArgs),
+}
+
+#[derive(FromArgs)]
+/// Run the node (the default without a subcommand)
+#[argh(subcommand, name = "run")]
+struct RunArgs {}
+
+#[derive(FromArgs)]
+/// Print the status of a running node
+#[argh(subcommand, name = "status")]
+struct StatusArgs {
+    #[argh(option)]
+    /// address of the node (defaults to the configured `unix_socket`, or
+    /// 127.0.0.1 and the configured port)
+    node: Option<String>,
+}
+
+#[derive(FromArgs)]
+/// List the pending transactions of a running node
+#[argh(subcommand, name = "mempool")]
+struct MempoolArgs {
+    #[argh(option)]
+    /// address of the node (defaults to the configured `unix_socket`, or
+    /// 127.0.0.1 and the configured port)
+    node: Option<String>,
+}
+
+#[derive(FromArgs)]
+/// Drop a transaction from the mempool of a running node
+#[argh(subcommand, name = "evict")]
+struct EvictArgs {
+    #[argh(positional)]
+    /// hash of the transaction
+    txid: Hash,
+    #[argh(option)]
+    /// address of the node (defaults to the configured `unix_socket`, or
+    /// 127.0.0.1 and the configured port)
+    node: Option<String>,
+}
+
+#[derive(FromArgs)]
+/// Re-validate every block of a stored chain and its UTXO set
+#[argh(subcommand, name = "verify-chain")]
+struct VerifyChainArgs {
+    #[argh(positional)]
+    /// blockchain file, in the configured storage backend
+    file: String,
 }
 
+#[derive(FromArgs)]
+/// Write the UTXO set of the stored chain to a JSON file
+#[argh(subcommand, name = "export-utxos")]
+struct ExportUtxosArgs {
+    #[argh(positional)]
+    /// file to write
+    file: String,
+}
+
+#[derive(FromArgs)]
+/// Print the height, tip and supply of the stored chain, without a
+/// running node
+#[argh(subcommand, name = "print-status")]
+struct PrintStatusArgs {}
+
+#[derive(FromArgs)]
+/// Rebuild the UTXO set and indexes from the stored blocks
+#[argh(subcommand, name = "reindex")]
+struct ReindexArgs {}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize tracing
-    util::init_tracing();
+    // Parse command line arguments
+    let args: Args = argh::from_env();
 
-    // Load configuration from environment
+    // Load configuration from the config files and environment
+    if let Some(network) = &args.network {
+        BlockchainConfig::select_profile(network)?;
+        // the chain was set up before main, with the config without profile
+        BLOCKCHAIN
+            .write()
+            .await
+            .set_config(BlockchainConfig::global());
+    }
     let config = BlockchainConfig::global();
 
-    // Parse command line arguments
-    let args: Args = argh::from_env();
+    // Initialize tracing
+    util::init_tracing(&config);
 
     // Priority: CLI args > Environment vars > Defaults
     let port = args.port.unwrap_or(config.node.port);
@@ -49,34 +217,108 @@ async fn main() -> Result<()> {
         .blockchain_file
         .unwrap_or_else(|| config.node.blockchain_file.clone());
 
+    // administrative requests need no token over the Unix socket
+    let local = match &config.node.unix_socket {
+        Some(path) => format!("{}{}", btclib::transport::UNIX_PREFIX, path),
+        None => format!("127.0.0.1:{}", port),
+    };
+    match args.command {
+        None | Some(Command::Run(_)) => (),
+        Some(Command::Status(status)) => return status::print(&status.node.unwrap_or(local)).await,
+        Some(Command::Mempool(mempool)) => {
+            return mempool::print(&mempool.node.unwrap_or(local)).await
+        }
+        Some(Command::Evict(evict)) => {
+            return mempool::evict(&evict.node.unwrap_or(local), evict.txid).await
+        }
+        Some(Command::VerifyChain(verify)) => return maintenance::verify_chain(&verify.file),
+        Some(Command::ExportUtxos(export)) => {
+            return maintenance::export_utxos(&blockchain_file, &export.file)
+        }
+        Some(Command::PrintStatus(_)) => return maintenance::print_status(&blockchain_file),
+        Some(Command::Reindex(_)) => return maintenance::reindex(&blockchain_file),
+    }
+
+    // Load the persisted ban list and address book
+    *BANLIST.write().await = BanList::load(&config.node.banlist_file)?;
+    *ADDRESS_BOOK.write().await = AddressBook::load(&config.node.peers_file)?;
+
     // Combine CLI nodes with env var nodes
     let mut nodes = args.nodes;
     if nodes.is_empty() {
         nodes = config.node.initial_peers.clone();
     }
+    // Otherwise reconnect to the peers we saw most recently, then try the
+    // network's seed nodes
+    let bootstrapping = nodes.is_empty();
+    if bootstrapping {
+        nodes = ADDRESS_BOOK.read().await.best(config.node.max_peers);
+        if !config.network.seeds.is_empty() {
+            info!(seeds = ?config.network.seeds, "no peers given, bootstrapping from seeds");
+            for seed in util::resolve_seeds(&config.network.seeds).await {
+                if !nodes.contains(&seed) {
+                    nodes.push(seed);
+                }
+            }
+        }
+    }
 
-    info!("🚀 Starting blockchain node");
-    info!("Network: {}", config.network.network_id);
-    info!("Port: {}", port);
-    info!("Blockchain file: {}", blockchain_file);
+    info!(
+        network = %config.network.network_id,
+        port,
+        blockchain_file = %blockchain_file,
+        "🚀 Starting blockchain node"
+    );
     if !nodes.is_empty() {
-        info!("Initial peers: {:?}", nodes);
+        info!(peers = ?nodes, "initial peers");
     }
 
-    // Check if the blockchain_file exists
-    if Path::new(&blockchain_file).exists() {
-        util::load_blockchain(&blockchain_file).await?;
+    // before meeting peers, so every one of them learns where to reach us
+    let mapping = if config.node.upnp {
+        match nat::map_port(port).await {
+            Ok(mapping) => {
+                EXTERNAL_ADDRESS.get_or_init(|| mapping.external.to_string());
+                let mapping = Arc::new(mapping);
+                tokio::spawn(nat::keep(mapping.clone()));
+                Some(mapping)
+            }
+            Err(e) => {
+                warn!(
+                    "can't map the port on the router, peers may not reach us: {}",
+                    e
+                );
+                None
+            }
+        }
     } else {
+        None
+    };
+
+    // Open the configured storage backend and try to load the chain from it
+    let store: Arc<dyn ChainStore> = Arc::from(open_store(
+        config.node.storage_backend,
+        &blockchain_file,
+        config.node.blockchain_backups,
+    )?);
+    if !util::load_blockchain(store.as_ref(), args.verify).await? {
         warn!("blockchain file does not exist!");
         util::populate_connections(&nodes).await?;
         info!("total amount of known nodes: {}", NODES.len());
         if nodes.is_empty() {
             info!("no initial nodes provided, starting as a seed node");
+        } else if bootstrapping && NODES.is_empty() {
+            // e.g. the first seed node itself, its own address isn't up yet
+            warn!("no known peer or seed node reachable, starting as a seed node");
         } else {
             let (longest_name, longest_count) = util::find_longest_chain_node().await?;
-            // request the blockchain from the node with the longest blockchain
-            util::download_blockchain(&longest_name, longest_count).await?;
-            info!("blockchain downloaded from {}", longest_name);
+            if longest_count == 0 {
+                // a fresh network, e.g. a seed node that hasn't mined yet
+                info!("peers have no blocks yet, nothing to download");
+            } else {
+                // fetch it from every peer that has the longest chain
+                sync::download_blockchain(longest_count).await?;
+                info!(longest = %longest_name, "blockchain downloaded");
+            }
             // recalculate utxos
             {
                 let mut blockchain = BLOCKCHAIN.write().await;
@@ -88,20 +330,92 @@ async fn main() -> Result<()> {
                 blockchain.try_adjust_target();
             }
         }
+    } else if !nodes.is_empty() {
+        // the chain is already here, just get back in touch with peers
+        util::populate_connections(&nodes).await?;
+        info!("total amount of known nodes: {}", NODES.len());
     }
 
+    // Bring back the transactions that were pending when the node stopped
+    util::load_mempool(&config.node.mempool_file).await;
+
+    if config.node.txindex || config.node.addressindex {
+        info!("building indexes...");
+        BLOCKCHAIN
+            .write()
+            .await
+            .enable_indexes(config.node.txindex, config.node.addressindex);
+        info!("indexes built");
+    }
+    snapshot::publish(&*BLOCKCHAIN.read().await);
+
     // Start the TCP listener on 0.0.0.0:port
     let addr = format!("0.0.0.0:{}", port);
     let listener = TcpListener::bind(&addr).await?;
     info!("Listening on {}", addr);
+    // and on the Unix domain socket for co-located wallets and miners
+    let local_listener = util::LocalListener::bind(config.node.unix_socket.as_deref())?;
 
+    // relays from the handlers go out through the broadcast tasks
+    broadcast::start();
     // start a task to periodically cleanup the mempool
     // normally, you would want to keep and join the handle
     tokio::spawn(util::cleanup());
     // and a task to periodically save the blockchain
-    tokio::spawn(util::save(blockchain_file.clone()));
+    tokio::spawn(util::save(store.clone()));
+    // and one to apply config changes on SIGHUP
+    #[cfg(unix)]
+    tokio::spawn(util::reload_on_sighup());
+    #[cfg(feature = "metrics")]
+    tokio::spawn(async move {
+        if let Err(e) = metrics::serve(config.node.metrics_port).await {
+            warn!("metrics endpoint stopped: {}", e);
+        }
+    });
+    let mut handlers = JoinSet::new();
     loop {
-        let (socket, _) = listener.accept().await?;
-        tokio::spawn(handler::handle_connection(socket));
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (socket, addr) = accepted?;
+                // every event of this connection carries the peer address
+                let span = info_span!("peer", addr = %addr);
+                handlers.spawn(handler::handle_connection(socket.into()).instrument(span));
+                // forget handlers of closed connections
+                while handlers.try_join_next().is_some() {}
+            }
+            accepted = local_listener.accept() => {
+                let socket = accepted?;
+                let span = info_span!("peer", addr = "local");
+                handlers.spawn(handler::handle_connection(socket).instrument(span));
+                while handlers.try_join_next().is_some() {}
+            }
+            _ = util::shutdown_signal() => break,
+        }
+    }
+
+    info!("shutting down, no longer accepting connections");
+    drop(listener);
+    drop(local_listener);
+    // handlers finish the message they are working on, then close
+    SHUTDOWN.send_replace(true);
+    let timeout = time::Duration::from_secs(BlockchainConfig::global().node.shutdown_timeout_secs);
+    if time::timeout(timeout, async {
+        while handlers.join_next().await.is_some() {}
+    })
+    .await
+    .is_err()
+    {
+        warn!(
+            remaining = handlers.len(),
+            "handlers still busy, shutting down anyway"
+        );
+        handlers.abort_all();
+    }
+    util::flush(store.as_ref()).await;
+    util::disconnect_peers(port).await;
+    if let Some(mapping) = mapping {
+        mapping.remove().await;
     }
+    info!("bye");
+    Ok(())
 }
@@ -0,0 +1,136 @@
+//! Operator commands working on the stored chain without starting the
+//! node: `verify-chain`, `export-utxos`, `print-status` and `reindex`.
+//! Stop the node first, it rewrites the chain while running.
+use anyhow::{anyhow, Context, Result};
+use btclib::config::BlockchainConfig;
+use btclib::sha256::Hash;
+use btclib::store::{open_store, ChainStore};
+use btclib::types::Blockchain;
+use serde::Serialize;
+use std::fs;
+
+/// One unspent output, as written by `export-utxos`
+#[derive(Serialize)]
+struct ExportedUtxo {
+    hash: String,
+    value: u64,
+    pubkey: String,
+    /// Height of the block that created it
+    height: u64,
+}
+
+fn open(file: &str) -> Result<Box<dyn ChainStore>> {
+    let config = BlockchainConfig::global();
+    open_store(
+        config.node.storage_backend,
+        file,
+        config.node.blockchain_backups,
+    )
+    .with_context(|| format!("Failed to open {}", file))
+}
+
+/// The chain stored in `file`, as stored
+fn stored(store: &dyn ChainStore, file: &str) -> Result<Blockchain> {
+    store
+        .load()
+        .with_context(|| format!("Failed to load {}", file))?
+        .ok_or_else(|| anyhow!("No blockchain stored in {}", file))
+}
+
+/// The chain stored in `file`, with its UTXO set rebuilt from the blocks
+/// like at startup
+fn load(store: &dyn ChainStore, file: &str) -> Result<Blockchain> {
+    let mut blockchain = stored(store, file)?;
+    blockchain.rebuild_utxos();
+    Ok(blockchain)
+}
+
+/// Replay every block of the chain in `file` with the checks of a node
+/// receiving it, and compare the stored UTXO set
+pub fn verify_chain(file: &str) -> Result<()> {
+    let blockchain = stored(open(file)?.as_ref(), file)?;
+    let report = blockchain
+        .verify_chain()
+        .with_context(|| format!("{} failed verification", file))?;
+    println!("Blocks:        {}", report.blocks);
+    println!("Transactions:  {}", report.transactions);
+    println!("Tip:           {}", report.tip);
+    println!("UTXOs:         {}", report.utxos);
+    println!("Supply:        {} sats", report.total_supply);
+    println!("{} is valid", file);
+    Ok(())
+}
+
+/// Write the UTXO set of the chain in `file` to `output` as JSON, oldest
+/// outputs first
+pub fn export_utxos(file: &str, output: &str) -> Result<()> {
+    let blockchain = load(open(file)?.as_ref(), file)?;
+    let mut utxos: Vec<ExportedUtxo> = blockchain
+        .utxos()
+        .iter()
+        .map(|(hash, (_, utxo))| ExportedUtxo {
+            hash: hash.to_string(),
+            value: utxo.value,
+            pubkey: utxo.pubkey.to_hex(),
+            height: blockchain.utxo_height(hash).unwrap_or_default(),
+        })
+        .collect();
+    utxos.sort_by(|a, b| (a.height, &a.hash).cmp(&(b.height, &b.hash)));
+    fs::write(output, serde_json::to_string_pretty(&utxos)?)
+        .with_context(|| format!("Failed to write {}", output))?;
+    println!(
+        "Exported {} UTXOs worth {} sats at height {} to {}",
+        utxos.len(),
+        blockchain.total_supply(),
+        blockchain.block_height(),
+        output
+    );
+    Ok(())
+}
+
+/// Print what the chain in `file` holds
+pub fn print_status(file: &str) -> Result<()> {
+    let blockchain = load(open(file)?.as_ref(), file)?;
+    let best_block = blockchain
+        .blocks()
+        .last()
+        .map(|block| block.hash())
+        .unwrap_or(Hash::zero());
+    println!("File:        {}", file);
+    println!("Network:     {}", blockchain.params().network_id);
+    println!("Height:      {}", blockchain.block_height());
+    println!("Best block:  {}", best_block);
+    println!("Difficulty:  {:.3}", blockchain.difficulty());
+    println!("UTXOs:       {}", blockchain.utxos().len());
+    println!("Supply:      {} sats", blockchain.total_supply());
+    Ok(())
+}
+
+/// Discard the UTXO set and the indexes of the chain in `file`, derive
+/// them again from its blocks with a progress line, and save the result
+/// over the stored state
+pub fn reindex(file: &str) -> Result<()> {
+    let config = BlockchainConfig::global();
+    let store = open(file)?;
+    let mut blockchain = stored(store.as_ref(), file)?;
+    let mut shown = None;
+    let (txindex, addressindex) = (config.node.txindex, config.node.addressindex);
+    blockchain.reindex(txindex, addressindex, |done, total| {
+        let percent = done * 100 / total;
+        if shown != Some(percent) {
+            shown = Some(percent);
+            eprint!("\rReindexing: block {} of {} ({}%)", done, total, percent);
+        }
+    });
+    eprintln!();
+    store
+        .rewrite(&blockchain)
+        .with_context(|| format!("Failed to save {}", file))?;
+    println!(
+        "Reindexed {} blocks: {} UTXOs worth {} sats",
+        blockchain.block_height(),
+        blockchain.utxos().len(),
+        blockchain.total_supply()
+    );
+    Ok(())
+}
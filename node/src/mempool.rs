@@ -0,0 +1,43 @@
+//! `node mempool` and `node evict`: looking at a running node's pending
+//! transactions and dropping stuck or malicious ones
+use anyhow::{anyhow, Result};
+use btclib::network::Message;
+use btclib::sha256::Hash;
+use chrono::Utc;
+
+/// List the mempool of the node at `address`, highest fee rate first
+pub async fn print(address: &str) -> Result<()> {
+    let entries = match crate::util::ask(address, Message::GetMempool).await? {
+        Message::Mempool(entries) => entries,
+        other => return Err(anyhow!("Unexpected answer: {}", other.kind())),
+    };
+    if entries.is_empty() {
+        println!("The mempool of {} is empty", address);
+        return Ok(());
+    }
+    println!("{:<64}  {:>12}  {:>8}", "Transaction", "Fee (sats)", "Age");
+    let now = Utc::now();
+    for entry in &entries {
+        let age = (now - entry.received).num_seconds().max(0) as u64;
+        println!(
+            "{:<64}  {:>12}  {:>8}",
+            entry.transaction.hash().to_string(),
+            entry.fee.map_or("?".to_string(), |fee| fee.to_string()),
+            crate::status::format_duration(age)
+        );
+    }
+    println!("{} transactions", entries.len());
+    Ok(())
+}
+
+/// Ask the node at `address` to drop a transaction from its mempool
+pub async fn evict(address: &str, hash: Hash) -> Result<()> {
+    match crate::util::ask(address, Message::EvictTransaction(hash)).await? {
+        Message::Evicted(true) => println!("Evicted {}", hash),
+        Message::Evicted(false) => {
+            return Err(anyhow!("{} is not in the mempool of {}", hash, address))
+        }
+        other => return Err(anyhow!("Unexpected answer: {}", other.kind())),
+    }
+    Ok(())
+}
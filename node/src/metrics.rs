@@ -0,0 +1,142 @@
+//! Prometheus exporter, built with `--features metrics`
+//!
+//! Serves the text exposition format on `GET /metrics`. Gauges are read
+//! from the global state when scraped; counters are updated by the
+//! handler as messages and blocks come in.
+use anyhow::Result;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+static MESSAGES_TOTAL: AtomicU64 = AtomicU64::new(0);
+static BLOCK_VALIDATIONS_TOTAL: AtomicU64 = AtomicU64::new(0);
+static BLOCK_VALIDATION_MICROS: AtomicU64 = AtomicU64::new(0);
+
+/// Count a message received from a peer
+pub fn record_message() {
+    MESSAGES_TOTAL.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Record how long validating and adding a block took
+pub fn record_block_validation(elapsed: Duration) {
+    BLOCK_VALIDATIONS_TOTAL.fetch_add(1, Ordering::Relaxed);
+    BLOCK_VALIDATION_MICROS.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+}
+
+/// Serve metrics on `0.0.0.0:port` until the node exits
+pub async fn serve(port: u16) -> Result<()> {
+    let addr = format!("0.0.0.0:{}", port);
+    let listener = TcpListener::bind(&addr).await?;
+    info!("Serving metrics on {}/metrics", addr);
+    loop {
+        let (socket, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = respond(socket).await {
+                debug!("metrics request failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn respond(mut socket: TcpStream) -> Result<()> {
+    // only the request line matters, e.g. "GET /metrics HTTP/1.1"
+    let mut buffer = [0u8; 1024];
+    let read = socket.read(&mut buffer).await?;
+    let request = String::from_utf8_lossy(&buffer[..read]);
+    let mut parts = request.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = render().await;
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => {
+            warn!("metrics: unexpected request {:?}", request.lines().next());
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+        }
+    };
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await?;
+    Ok(())
+}
+
+async fn render() -> String {
+    let (height, mempool, utxos, difficulty, hashrate) = {
+        let blockchain = crate::BLOCKCHAIN.read().await;
+        let window = blockchain.params().difficulty_update_interval as usize;
+        (
+            blockchain.block_height(),
+            crate::MEMPOOL.read().await.len(),
+            blockchain.utxos().len(),
+            blockchain.difficulty(),
+            blockchain.estimated_hashrate(window).unwrap_or(0.0),
+        )
+    };
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: f64| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        let _ = writeln!(out, "{} {}", name, value);
+    };
+    metric(
+        "node_block_height",
+        "gauge",
+        "Number of blocks in the chain",
+        height as f64,
+    );
+    metric(
+        "node_mempool_transactions",
+        "gauge",
+        "Transactions waiting in the mempool",
+        mempool as f64,
+    );
+    metric(
+        "node_difficulty",
+        "gauge",
+        "Difficulty of the current target, relative to the easiest allowed target",
+        difficulty,
+    );
+    metric(
+        "node_network_hashrate",
+        "gauge",
+        "Estimated network hash rate over the last difficulty interval, in hashes per second",
+        hashrate,
+    );
+    metric(
+        "node_peers",
+        "gauge",
+        "Connected peer nodes",
+        crate::NODES.len() as f64,
+    );
+    metric(
+        "node_utxos",
+        "gauge",
+        "Unspent transaction outputs",
+        utxos as f64,
+    );
+    metric(
+        "node_messages_received_total",
+        "counter",
+        "Messages received from peers, use rate() for messages per second",
+        MESSAGES_TOTAL.load(Ordering::Relaxed) as f64,
+    );
+    metric(
+        "node_block_validation_seconds_sum",
+        "counter",
+        "Total time spent validating blocks",
+        BLOCK_VALIDATION_MICROS.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+    );
+    metric(
+        "node_block_validation_seconds_count",
+        "counter",
+        "Blocks validated",
+        BLOCK_VALIDATIONS_TOTAL.load(Ordering::Relaxed) as f64,
+    );
+    out
+}
@@ -0,0 +1,393 @@
+//! Mapping the listen port on the local router, with `node.upnp`
+//!
+//! Home routers drop inbound connections unless a port is forwarded. The
+//! node asks the router to forward its port with UPnP IGD and, if no UPnP
+//! gateway answers, with NAT-PMP. The mapping is leased: it is renewed
+//! while the node runs and removed when it shuts down. The router's
+//! external address is then advertised to peers when connecting to them.
+use anyhow::{anyhow, bail, Context, Result};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time;
+use tracing::{debug, info, warn};
+
+/// How long the router keeps a mapping, renewed halfway through
+const LEASE_SECS: u32 = 3600;
+
+/// How long to wait for routers to answer the discovery
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long one request to the router may take
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SSDP_ADDRESS: &str = "239.255.255.250:1900";
+const NAT_PMP_PORT: u16 = 5351;
+
+/// WAN services of an Internet Gateway Device that can map ports
+const WAN_SERVICES: &[&str] = &[
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+/// A port mapping on the router
+pub struct Mapping {
+    /// Address peers reach the node at
+    pub external: SocketAddr,
+    port: u16,
+    gateway: Gateway,
+}
+
+enum Gateway {
+    Upnp {
+        /// `host:port` of the router's web server
+        host: String,
+        control_path: String,
+        service: String,
+        /// Our address on the router's network
+        internal: IpAddr,
+    },
+    NatPmp(Ipv4Addr),
+}
+
+/// Forward `port` on the router to this machine
+pub async fn map_port(port: u16) -> Result<Mapping> {
+    let upnp = match map_upnp(port).await {
+        Ok(mapping) => return Ok(mapping),
+        Err(e) => e,
+    };
+    debug!("UPnP port mapping failed: {}", upnp);
+    map_nat_pmp(port)
+        .await
+        .map_err(|e| anyhow!("UPnP: {}; NAT-PMP: {}", upnp, e))
+}
+
+impl Mapping {
+    /// Ask the router for the mapping again, before the lease runs out
+    pub async fn renew(&self) -> Result<()> {
+        match &self.gateway {
+            Gateway::Upnp { .. } => self.add_upnp().await,
+            Gateway::NatPmp(gateway) => {
+                nat_pmp_map(*gateway, self.port, self.external.port(), LEASE_SECS)
+                    .await
+                    .map(|_| ())
+            }
+        }
+    }
+
+    /// Remove the mapping, on shutdown
+    pub async fn remove(&self) {
+        let removed = match &self.gateway {
+            Gateway::Upnp {
+                host,
+                control_path,
+                service,
+                ..
+            } => {
+                let arguments = format!(
+                    "<NewRemoteHost></NewRemoteHost>\
+                     <NewExternalPort>{}</NewExternalPort>\
+                     <NewProtocol>TCP</NewProtocol>",
+                    self.external.port()
+                );
+                soap(host, control_path, service, "DeletePortMapping", &arguments)
+                    .await
+                    .map(|_| ())
+            }
+            // a lifetime of 0 deletes the mapping
+            Gateway::NatPmp(gateway) => nat_pmp_map(*gateway, self.port, 0, 0).await.map(|_| ()),
+        };
+        match removed {
+            Ok(()) => info!(external = %self.external, "port mapping removed"),
+            Err(e) => warn!("failed to remove the port mapping: {}", e),
+        }
+    }
+
+    async fn add_upnp(&self) -> Result<()> {
+        let Gateway::Upnp {
+            host,
+            control_path,
+            service,
+            internal,
+        } = &self.gateway
+        else {
+            unreachable!("only called for UPnP mappings")
+        };
+        let arguments = format!(
+            "<NewRemoteHost></NewRemoteHost>\
+             <NewExternalPort>{port}</NewExternalPort>\
+             <NewProtocol>TCP</NewProtocol>\
+             <NewInternalPort>{port}</NewInternalPort>\
+             <NewInternalClient>{internal}</NewInternalClient>\
+             <NewEnabled>1</NewEnabled>\
+             <NewPortMappingDescription>custom-dlt node</NewPortMappingDescription>\
+             <NewLeaseDuration>{lease}</NewLeaseDuration>",
+            port = self.port,
+            lease = LEASE_SECS,
+        );
+        soap(host, control_path, service, "AddPortMapping", &arguments).await?;
+        Ok(())
+    }
+}
+
+/// Renew `mapping` until the node exits
+pub async fn keep(mapping: std::sync::Arc<Mapping>) {
+    let mut interval = time::interval(Duration::from_secs(LEASE_SECS as u64 / 2));
+    // the first tick is immediate, the mapping was just made
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match mapping.renew().await {
+            Ok(()) => debug!(external = %mapping.external, "port mapping renewed"),
+            Err(e) => warn!("failed to renew the port mapping: {}", e),
+        }
+    }
+}
+
+async fn map_upnp(port: u16) -> Result<Mapping> {
+    let location = discover().await?;
+    let (host, path) = split_url(&location)?;
+    let (description, internal) = http(
+        &host,
+        &format!(
+            "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, host
+        ),
+    )
+    .await?;
+    let (service, control_url) = WAN_SERVICES
+        .iter()
+        .find_map(|service| {
+            control_url(&description, service).map(|url| (service.to_string(), url))
+        })
+        .ok_or_else(|| anyhow!("{} has no WAN connection service", location))?;
+    // usually a path on the same server, sometimes a full URL
+    let (host, control_path) = if control_url.starts_with("http://") {
+        split_url(&control_url)?
+    } else if control_url.starts_with('/') {
+        (host, control_url)
+    } else {
+        (host, format!("/{}", control_url))
+    };
+    let response = soap(&host, &control_path, &service, "GetExternalIPAddress", "").await?;
+    let external_ip: IpAddr = tag(&response, "NewExternalIPAddress")
+        .and_then(|ip| ip.trim().parse().ok())
+        .ok_or_else(|| anyhow!("the router didn't tell its external address"))?;
+    let mapping = Mapping {
+        external: SocketAddr::new(external_ip, port),
+        port,
+        gateway: Gateway::Upnp {
+            host,
+            control_path,
+            service,
+            internal,
+        },
+    };
+    mapping.add_upnp().await?;
+    info!(external = %mapping.external, "port mapped with UPnP");
+    Ok(mapping)
+}
+
+/// Find an Internet Gateway Device with SSDP, returning the URL of its
+/// description
+async fn discover() -> Result<String> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\
+         ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n",
+        SSDP_ADDRESS
+    );
+    socket.send_to(search.as_bytes(), SSDP_ADDRESS).await?;
+    let mut buffer = [0u8; 2048];
+    let deadline = time::Instant::now() + DISCOVERY_TIMEOUT;
+    loop {
+        let (read, from) = time::timeout_at(deadline, socket.recv_from(&mut buffer))
+            .await
+            .map_err(|_| anyhow!("no UPnP gateway answered"))??;
+        let answer = String::from_utf8_lossy(&buffer[..read]);
+        match header(&answer, "location") {
+            Some(location) => return Ok(location.to_string()),
+            None => debug!(%from, "SSDP answer without a location"),
+        }
+    }
+}
+
+/// Call `action` of a UPnP service, returning the response body
+async fn soap(
+    host: &str,
+    control_path: &str,
+    service: &str,
+    action: &str,
+    arguments: &str,
+) -> Result<String> {
+    let body = format!(
+        "<?xml version=\"1.0\"?>\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:{action} xmlns:u=\"{service}\">{arguments}</u:{action}></s:Body>\
+         </s:Envelope>"
+    );
+    let request = format!(
+        "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: text/xml; charset=\"utf-8\"\r\n\
+         SOAPAction: \"{}#{}\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        control_path,
+        host,
+        service,
+        action,
+        body.len(),
+        body
+    );
+    let (response, _) = http(host, &request)
+        .await
+        .with_context(|| format!("{} failed", action))?;
+    Ok(response)
+}
+
+/// Send an HTTP request to `host` and return the body of a 200 answer,
+/// with our address on the connection
+async fn http(host: &str, request: &str) -> Result<(String, IpAddr)> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(host).await?;
+        let local = stream.local_addr()?.ip();
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = vec![];
+        stream.read_to_end(&mut response).await?;
+        Ok::<_, anyhow::Error>((String::from_utf8_lossy(&response).into_owned(), local))
+    };
+    let (response, local) = time::timeout(REQUEST_TIMEOUT, exchange)
+        .await
+        .map_err(|_| anyhow!("{} didn't answer in time", host))??;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| anyhow!("malformed HTTP answer from {}", host))?;
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        bail!("{} answered {}", host, status);
+    }
+    Ok((body.to_string(), local))
+}
+
+/// Split `http://host:port/path` into `host:port` and `/path`
+fn split_url(url: &str) -> Result<(String, String)> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| anyhow!("unsupported URL {}", url))?;
+    let (host, path) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash..]),
+        None => (rest, "/"),
+    };
+    // routers always give a port, HTTP's default otherwise
+    let host = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+    Ok((host, path.to_string()))
+}
+
+/// Value of an HTTP header, by case-insensitive name
+fn header<'a>(message: &'a str, name: &str) -> Option<&'a str> {
+    message.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// Text of the first `<name>` element of an XML document
+fn tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{}>", name);
+    let start = xml.find(&open)? + open.len();
+    let end = xml[start..].find(&format!("</{}>", name))?;
+    Some(&xml[start..start + end])
+}
+
+/// Control URL of `service` in a device description
+fn control_url(description: &str, service: &str) -> Option<String> {
+    let declared = description.find(&format!("<serviceType>{}</serviceType>", service))?;
+    // the controlURL of the same <service> element follows its type
+    let rest = &description[declared..];
+    let service_end = rest.find("</service>").unwrap_or(rest.len());
+    tag(&rest[..service_end], "controlURL").map(|url| url.trim().to_string())
+}
+
+async fn map_nat_pmp(port: u16) -> Result<Mapping> {
+    let gateway = default_gateway()?;
+    let response = nat_pmp_request(gateway, &[0, 0], 12).await?;
+    let external_ip = Ipv4Addr::new(response[8], response[9], response[10], response[11]);
+    let external_port = nat_pmp_map(gateway, port, port, LEASE_SECS).await?;
+    let mapping = Mapping {
+        external: SocketAddr::new(external_ip.into(), external_port),
+        port,
+        gateway: Gateway::NatPmp(gateway),
+    };
+    info!(external = %mapping.external, "port mapped with NAT-PMP");
+    Ok(mapping)
+}
+
+/// Map TCP `port` to `external_port`, or to whatever port the router
+/// picks, for `lifetime` seconds. Returns the mapped external port.
+async fn nat_pmp_map(
+    gateway: Ipv4Addr,
+    port: u16,
+    external_port: u16,
+    lifetime: u32,
+) -> Result<u16> {
+    // version 0, opcode 2 (TCP), reserved, internal port, suggested
+    // external port, lifetime
+    let mut request = [0u8; 12];
+    request[1] = 2;
+    request[4..6].copy_from_slice(&port.to_be_bytes());
+    request[6..8].copy_from_slice(&external_port.to_be_bytes());
+    request[8..12].copy_from_slice(&lifetime.to_be_bytes());
+    let response = nat_pmp_request(gateway, &request, 16).await?;
+    Ok(u16::from_be_bytes([response[10], response[11]]))
+}
+
+/// Send a NAT-PMP request and check the answer's opcode and result code
+async fn nat_pmp_request(gateway: Ipv4Addr, request: &[u8], size: usize) -> Result<Vec<u8>> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect((gateway, NAT_PMP_PORT)).await?;
+    let mut response = vec![0u8; size];
+    // UDP may drop the request, so ask a few times
+    for attempt in 1..=3u32 {
+        socket.send(request).await?;
+        let wait = Duration::from_millis(250 << attempt);
+        match time::timeout(wait, socket.recv(&mut response)).await {
+            Ok(Ok(read)) if read >= size => {
+                if response[1] != request[1] | 0x80 {
+                    bail!("unexpected NAT-PMP answer from {}", gateway);
+                }
+                let result = u16::from_be_bytes([response[2], response[3]]);
+                if result != 0 {
+                    bail!("{} refused with NAT-PMP result code {}", gateway, result);
+                }
+                return Ok(response);
+            }
+            Ok(Ok(_)) => bail!("short NAT-PMP answer from {}", gateway),
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => continue,
+        }
+    }
+    bail!("no NAT-PMP answer from {}", gateway)
+}
+
+/// The router, from the kernel's default route
+fn default_gateway() -> Result<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route")
+        .context("can't find the default gateway without /proc/net/route")?;
+    routes
+        .lines()
+        .skip(1)
+        .find_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.get(1) != Some(&"00000000") {
+                return None;
+            }
+            // the kernel prints the address as a native-endian integer
+            let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+            Some(Ipv4Addr::from(gateway.to_ne_bytes()))
+        })
+        .ok_or_else(|| anyhow!("no default route"))
+}
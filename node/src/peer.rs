@@ -0,0 +1,221 @@
+//! Connections to other nodes, each driven by a reader and a writer task
+//!
+//! A connection is split in two halves once it is made. The writer task
+//! owns the write half and sends whatever is queued on the peer's
+//! channel, and the reader task owns the read half. Other nodes answer
+//! our requests in the order they were sent, but may also relay a block
+//! or a transaction in between, so the reader hands every message it
+//! reads to the oldest request still waiting that it answers, and drops
+//! it if there is none. The rest of the node only holds a [`Peer`], the sending end of the
+//! channel, so a relay never waits for a response being read and two
+//! tasks never write frames into each other.
+use anyhow::{anyhow, Result};
+use btclib::network::Message;
+use btclib::transport::Connection;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+/// Messages waiting to be sent to a single peer
+const QUEUE_SIZE: usize = 64;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Requests sent and still waiting for their answer, oldest first
+type Pending = Arc<Mutex<VecDeque<(Arc<Message>, oneshot::Sender<Message>)>>>;
+
+/// A message for the writer task
+struct Outgoing {
+    message: Arc<Message>,
+    /// Told once the message is on the wire
+    sent: Option<oneshot::Sender<()>>,
+    /// Gets the answer to a request
+    reply: Option<oneshot::Sender<Message>>,
+}
+
+/// Handle to a connected node
+#[derive(Clone)]
+pub struct Peer {
+    id: u64,
+    addr: Option<SocketAddr>,
+    queue: mpsc::Sender<Outgoing>,
+}
+
+impl Peer {
+    /// Start the reader and writer tasks of a connection to the node at
+    /// `address`
+    pub fn start(address: &str, connection: Connection) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let addr = connection.peer_addr().ok();
+        let (reader, writer) = tokio::io::split(connection);
+        let (queue, outgoing) = mpsc::channel(QUEUE_SIZE);
+        let pending = Pending::default();
+        let span = info_span!("peer", addr = %address);
+        tokio::spawn(
+            write(address.to_string(), id, writer, outgoing, pending.clone())
+                .instrument(span.clone()),
+        );
+        tokio::spawn(read(address.to_string(), id, reader, pending).instrument(span));
+        Self { id, addr, queue }
+    }
+
+    /// Address of the other side
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.addr
+    }
+
+    /// Queue a message without waiting. Returns false if the peer has
+    /// too much queued already, or is gone.
+    pub fn relay(&self, message: Arc<Message>) -> bool {
+        let outgoing = Outgoing {
+            message,
+            sent: None,
+            reply: None,
+        };
+        self.queue.try_send(outgoing).is_ok()
+    }
+
+    /// Send a message, waiting until it is written
+    pub async fn send(&self, message: Message) -> Result<()> {
+        let (sent, written) = oneshot::channel();
+        self.enqueue(Outgoing {
+            message: Arc::new(message),
+            sent: Some(sent),
+            reply: None,
+        })
+        .await?;
+        written
+            .await
+            .map_err(|_| anyhow!("connection closed before sending"))
+    }
+
+    /// Send a request, returning where its answer arrives. Several
+    /// requests can be sent before reading their answers.
+    pub async fn send_request(&self, message: Message) -> Result<oneshot::Receiver<Message>> {
+        let (reply, answer) = oneshot::channel();
+        self.enqueue(Outgoing {
+            message: Arc::new(message),
+            sent: None,
+            reply: Some(reply),
+        })
+        .await?;
+        Ok(answer)
+    }
+
+    /// Send a request and wait for its answer
+    pub async fn request(&self, message: Message) -> Result<Message> {
+        let answer = self.send_request(message).await?;
+        answer
+            .await
+            .map_err(|_| anyhow!("connection closed before answering"))
+    }
+
+    async fn enqueue(&self, outgoing: Outgoing) -> Result<()> {
+        self.queue
+            .send(outgoing)
+            .await
+            .map_err(|_| anyhow!("connection closed"))
+    }
+}
+
+/// Drop the `NODES` entry of the connection `id`, unless the address was
+/// connected again since
+fn forget(address: &str, id: u64) {
+    if crate::NODES
+        .remove_if(address, |_, peer| peer.id == id)
+        .is_some()
+    {
+        info!("connection to peer closed, forgetting it");
+    }
+}
+
+async fn write(
+    address: String,
+    id: u64,
+    mut stream: WriteHalf<Connection>,
+    mut outgoing: mpsc::Receiver<Outgoing>,
+    pending: Pending,
+) {
+    while let Some(Outgoing {
+        message,
+        sent,
+        reply,
+    }) = outgoing.recv().await
+    {
+        // queued before writing, the answer may come right away
+        if let Some(reply) = reply {
+            pending
+                .lock()
+                .expect("pending requests poisoned")
+                .push_back((message.clone(), reply));
+        }
+        if let Err(e) = message.send_async(&mut stream).await {
+            warn!(kind = message.kind(), error = %e, "failed to send");
+            break;
+        }
+        if let Some(sent) = sent {
+            let _ = sent.send(());
+        }
+    }
+    // nobody waits for answers that can't come
+    pending.lock().expect("pending requests poisoned").clear();
+    forget(&address, id);
+}
+
+/// Whether `message` can be the answer to `request`. A block is only
+/// taken for the one requested if its coinbase commits to that height or
+/// to none, and nothing a node relays unasked answers other requests.
+fn answers(request: &Message, message: &Message) -> bool {
+    use btclib::network::Message::*;
+    match (request, message) {
+        (FetchBlock(height), NewBlock(block)) => block
+            .transactions
+            .first()
+            .and_then(|coinbase| coinbase.coinbase_height)
+            .is_none_or(|committed| committed == *height as u64),
+        (DiscoverNodes, answer) => matches!(answer, NodeList(_)),
+        (AskDifference(_), answer) => matches!(answer, Difference(_)),
+        (FetchHeaders(..), answer) => matches!(answer, Headers(..)),
+        (FetchBlockByHash(_), answer) => matches!(answer, BlockFound(_)),
+        (_, answer) => !matches!(
+            answer,
+            NewBlock(_) | NewTransaction(_) | Advertise(_) | Disconnect(_)
+        ),
+    }
+}
+
+async fn read(address: String, id: u64, mut stream: ReadHalf<Connection>, pending: Pending) {
+    loop {
+        let message = match Message::receive_async(&mut stream).await {
+            Ok(message) => message,
+            Err(e) => {
+                debug!(error = %e, "stopped reading from peer");
+                break;
+            }
+        };
+        let reply = {
+            let mut pending = pending.lock().expect("pending requests poisoned");
+            pending
+                .iter()
+                .position(|(request, _)| answers(request, &message))
+                .and_then(|position| pending.remove(position))
+        };
+        match reply {
+            // the requester may have given up waiting
+            Some((_, reply)) => {
+                let _ = reply.send(message);
+            }
+            None => debug!(kind = message.kind(), "ignoring unrequested message"),
+        }
+    }
+    pending.lock().expect("pending requests poisoned").clear();
+    forget(&address, id);
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,46 @@
+#[cfg(test)]
+mod tests {
+    use crate::peer::Peer;
+    use btclib::network::Message;
+    use btclib::transport::Connection;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_answers_go_to_the_request_they_answer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut stream = Connection::accept(socket).await.unwrap();
+            let mut requests = vec![];
+            for _ in 0..2 {
+                requests.push(Message::receive_async(&mut stream).await.unwrap());
+            }
+            // relayed while both requests wait, then answered out of order
+            Message::Advertise("127.0.0.1:1".to_string())
+                .send_async(&mut stream)
+                .await
+                .unwrap();
+            Message::Difference(3)
+                .send_async(&mut stream)
+                .await
+                .unwrap();
+            Message::NodeList(vec![])
+                .send_async(&mut stream)
+                .await
+                .unwrap();
+            requests
+        });
+
+        let connection = Connection::connect(&address, false).await.unwrap();
+        let peer = Peer::start(&address, connection);
+        let nodes = peer.send_request(Message::DiscoverNodes).await.unwrap();
+        let difference = peer.send_request(Message::AskDifference(0)).await.unwrap();
+
+        assert!(matches!(nodes.await.unwrap(), Message::NodeList(_)));
+        assert!(matches!(difference.await.unwrap(), Message::Difference(3)));
+        let requests = server.await.unwrap();
+        assert!(matches!(requests[0], Message::DiscoverNodes));
+        assert!(matches!(requests[1], Message::AskDifference(0)));
+    }
+}
@@ -0,0 +1,44 @@
+use std::time::Instant;
+
+/// Token bucket rate limiter
+///
+/// Holds up to `burst` tokens and refills at `rate` tokens per second.
+/// Each message takes one token; without tokens left it is dropped.
+/// A rate of 0 disables the limit.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket
+    pub fn new(rate: f64, burst: u32) -> Self {
+        let burst = (burst as f64).max(1.0);
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Take a token if there is one
+    pub fn try_take(&mut self) -> bool {
+        if self.rate <= 0.0 {
+            return true;
+        }
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
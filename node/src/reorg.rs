@@ -0,0 +1,170 @@
+//! Following a longer competing chain
+//!
+//! A block that doesn't build on our tip may come from a longer chain.
+//! The peer with the longest chain is asked for its blocks back to the
+//! fork, and the new chain is built next to the current one (see
+//! [`btclib::reorg`]). If the fork is below the blocks this node keeps,
+//! the missing ones are fetched from archival peers first. When that
+//! fails the current chain is kept and the fork is remembered as
+//! pending, to be tried again on the next competing block.
+use anyhow::{bail, Context, Result};
+use btclib::error::BtcError;
+use btclib::network::Message;
+use btclib::reorg::{self, Horizon, ReorgPlan, MAX_HISTORICAL_BLOCKS};
+use btclib::sha256::Hash;
+use btclib::types::Block;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+/// Set while a reorg is being looked into, so only one runs at a time
+static FOLLOWING: AtomicBool = AtomicBool::new(false);
+
+/// Fork height of a reorg that needs blocks no peer has sent yet
+static PENDING: Mutex<Option<u64>> = Mutex::new(None);
+
+/// This node keeps every block it accepted
+fn horizon() -> Horizon {
+    Horizon::archival()
+}
+
+/// Switch to the longest chain known to our peers in the background,
+/// unless that is already under way
+pub fn follow_longest_chain() {
+    if FOLLOWING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    if let Some(fork_height) = *PENDING.lock().expect("pending reorg lock poisoned") {
+        info!("retrying the reorg to fork height {}", fork_height);
+    }
+    tokio::spawn(async {
+        let pending = match follow().await {
+            Ok(()) => None,
+            Err(e) => {
+                warn!("keeping the current chain: {:#}", e);
+                match e.downcast_ref::<BtcError>() {
+                    Some(BtcError::ReorgBelowHorizon { fork_height, .. }) => Some(*fork_height),
+                    _ => None,
+                }
+            }
+        };
+        *PENDING.lock().expect("pending reorg lock poisoned") = pending;
+        FOLLOWING.store(false, Ordering::SeqCst);
+    });
+}
+
+async fn follow() -> Result<()> {
+    let (peer, peer_height) = crate::util::find_longest_chain_node().await?;
+    let (height, tip) = {
+        let blockchain = crate::BLOCKCHAIN.read().await;
+        (blockchain.block_height(), tip_of(blockchain.blocks()))
+    };
+    if peer_height as u64 <= height {
+        return Ok(());
+    }
+
+    // walk back from our tip until the peer's blocks match ours
+    let mut branch = fetch_blocks(&peer, height..peer_height as u64).await?;
+    let mut start = height;
+    let fork_height = loop {
+        let batch_start = start.saturating_sub(MAX_HISTORICAL_BLOCKS as u64);
+        let theirs = fetch_blocks(&peer, batch_start..start).await?;
+        let ours: Vec<Hash> = {
+            let blockchain = crate::BLOCKCHAIN.read().await;
+            blockchain
+                .blocks()
+                .skip(batch_start as usize)
+                .take(theirs.len())
+                .map(|block| block.hash())
+                .collect()
+        };
+        let common = theirs
+            .iter()
+            .zip(&ours)
+            .take_while(|(block, hash)| block.hash() == **hash)
+            .count();
+        branch.splice(0..0, theirs.into_iter().skip(common));
+        if common > 0 || batch_start == 0 {
+            break batch_start + common as u64;
+        }
+        start = batch_start;
+    };
+    info!(
+        "{} has a longer chain forking at height {}",
+        peer, fork_height
+    );
+
+    let history = match reorg::plan(horizon(), fork_height) {
+        ReorgPlan::Local => vec![],
+        ReorgPlan::FetchHistory(missing) => fetch_history(missing).await,
+    };
+    // the new chain is built next to the current one, which is only
+    // replaced if nothing was added to it in the meantime
+    let reorganized = {
+        let blockchain = crate::BLOCKCHAIN.read().await;
+        reorg::reorganize(&blockchain, horizon(), fork_height, &history, branch)?
+    };
+    let mut blockchain = crate::BLOCKCHAIN.write().await;
+    if tip_of(blockchain.blocks()) != tip {
+        bail!("the chain changed during the reorg");
+    }
+    *blockchain = reorganized;
+    // pending transactions spending outputs of the old branch are dropped
+    let mut mempool = crate::MEMPOOL.write().await;
+    let saved = std::mem::take(&mut *mempool).saved();
+    mempool.restore(&blockchain, saved);
+    // templates and UTXO queries would still read the old branch
+    crate::snapshot::publish(&blockchain);
+    drop(mempool);
+    info!(
+        "reorganized to {} blocks from fork height {}",
+        blockchain.block_height(),
+        fork_height
+    );
+    Ok(())
+}
+
+fn tip_of<'a>(blocks: impl Iterator<Item = &'a Block>) -> Hash {
+    blocks
+        .last()
+        .map(|block| block.hash())
+        .unwrap_or(Hash::zero())
+}
+
+/// Blocks in `range` from the first peer that has all of them, none if
+/// no peer does
+async fn fetch_history(range: Range<u64>) -> Vec<Block> {
+    for (node, _) in crate::util::peers() {
+        match fetch_blocks(&node, range.clone()).await {
+            Ok(blocks) => return blocks,
+            Err(e) => warn!("no history from {}: {:#}", node, e),
+        }
+    }
+    vec![]
+}
+
+/// Blocks in `range` from `node`, in batches of at most
+/// `MAX_HISTORICAL_BLOCKS`
+async fn fetch_blocks(node: &str, range: Range<u64>) -> Result<Vec<Block>> {
+    let stream = crate::NODES
+        .get(node)
+        .map(|entry| entry.value().clone())
+        .context("no node")?;
+    let mut blocks = vec![];
+    let mut height = range.start;
+    while height < range.end {
+        let count = (range.end - height).min(MAX_HISTORICAL_BLOCKS as u64) as u32;
+        let message = Message::FetchHistoricalBlocks(height, count);
+        match crate::util::request(node, &stream, &message).await? {
+            Message::HistoricalBlocks(batch) if !batch.is_empty() => {
+                height += batch.len() as u64;
+                blocks.extend(batch);
+            }
+            Message::HistoricalBlocks(_) => bail!("{} doesn't keep block {}", node, height),
+            e => bail!("unexpected message from {}: {:?}", node, e),
+        }
+    }
+    blocks.truncate((range.end - range.start) as usize);
+    Ok(blocks)
+}
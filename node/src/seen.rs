@@ -0,0 +1,65 @@
+//! Hashes of the blocks and transactions accepted recently
+//!
+//! Mutually connected peers relay every block and transaction back to
+//! the node they got it from, and on to each other. Looking the hash up
+//! here lets the handler drop a repeat right away, instead of waiting
+//! for the blockchain write lock only to find out the item is known.
+use btclib::sha256::Hash;
+use std::collections::{BTreeMap, HashMap};
+
+/// Block hashes remembered
+pub const MAX_SEEN_BLOCKS: usize = 1_000;
+
+/// Transaction hashes remembered
+pub const MAX_SEEN_TRANSACTIONS: usize = 50_000;
+
+/// The `capacity` most recently used hashes
+pub struct SeenCache {
+    capacity: usize,
+    /// When each hash was last used
+    used: HashMap<Hash, u64>,
+    /// The hashes by when they were last used, least recent first
+    order: BTreeMap<u64, Hash>,
+    clock: u64,
+}
+
+impl SeenCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            used: HashMap::new(),
+            order: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Whether `hash` is remembered, making it the most recently used
+    pub fn contains(&mut self, hash: &Hash) -> bool {
+        let Some(used) = self.used.get_mut(hash) else {
+            return false;
+        };
+        self.order.remove(used);
+        self.clock += 1;
+        *used = self.clock;
+        self.order.insert(self.clock, *hash);
+        true
+    }
+
+    /// Remember `hash`, forgetting the least recently used one when full
+    pub fn insert(&mut self, hash: Hash) {
+        if self.contains(&hash) {
+            return;
+        }
+        if self.used.len() >= self.capacity {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.used.remove(&oldest);
+            }
+        }
+        self.clock += 1;
+        self.used.insert(hash, self.clock);
+        self.order.insert(self.clock, hash);
+    }
+}
+
+#[cfg(test)]
+mod tests;
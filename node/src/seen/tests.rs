@@ -0,0 +1,25 @@
+#[cfg(test)]
+mod tests {
+    use crate::seen::SeenCache;
+    use btclib::sha256::Hash;
+
+    #[test]
+    fn test_seen_cache_forgets_the_least_recently_used() {
+        let hashes: Vec<Hash> = (0..4u32).map(|i| Hash::hash(&i)).collect();
+        let mut seen = SeenCache::new(3);
+        assert!(!seen.contains(&hashes[0]));
+        for hash in &hashes[..3] {
+            seen.insert(*hash);
+        }
+        // a repeat is found, and doesn't take another entry
+        assert!(seen.contains(&hashes[0]));
+        seen.insert(hashes[2]);
+
+        // hashes[0] was used last, so hashes[1] makes room
+        seen.insert(hashes[3]);
+        assert!(!seen.contains(&hashes[1]));
+        for hash in [hashes[0], hashes[2], hashes[3]] {
+            assert!(seen.contains(&hash));
+        }
+    }
+}
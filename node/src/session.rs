@@ -0,0 +1,126 @@
+//! What each incoming connection is for
+//!
+//! A connection starts out unclassified. Its first request decides whether
+//! the other end is a peer node, a wallet or a miner, and from then on only
+//! requests of that role are served. Each role keeps its own state, such as
+//! the rate limits that apply to it.
+use crate::ratelimit::TokenBucket;
+use btclib::config::BlockchainConfig;
+use btclib::network::Message;
+use std::fmt;
+
+/// The kind of client on the other end of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Another node: relays blocks and transactions, syncs and discovers peers
+    Peer,
+    /// Queries UTXOs, history, blocks and the mempool, submits transactions,
+    /// subscribes to conflicts. Block explorers connect in this role too
+    Wallet,
+    /// Fetches templates and submits mined blocks
+    Miner,
+}
+
+impl Role {
+    /// The role whose request this is. `None` for ban list administration,
+    /// which any authenticated connection may use, and for responses,
+    /// which no client should send.
+    pub fn of(message: &Message) -> Option<Role> {
+        use btclib::network::Message::*;
+        match message {
+            NewTransaction(_)
+            | NewBlock(_)
+            | DiscoverNodes
+            | AskDifference(_)
+            | FetchBlock(_)
+            | FetchHistoricalBlocks(..)
+            | Disconnect(_)
+            | Advertise(_) => Some(Role::Peer),
+            FetchUTXOs(_)
+            | FetchPendingOutputs(_)
+            | SubmitTransaction(_)
+            | EstimateFee(_)
+            | FetchTransaction(_)
+            | FetchAddressHistory(_)
+            | FetchHeaders(..)
+            | FetchBlockByHash(_)
+            | GetMempool
+            | GetSupply
+            | Subscribe(_) => Some(Role::Wallet),
+            FetchTemplate(_) | ValidateTemplate(_) | SubmitTemplate(_) => Some(Role::Miner),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Role::Peer => write!(f, "peer"),
+            Role::Wallet => write!(f, "wallet"),
+            Role::Miner => write!(f, "miner"),
+        }
+    }
+}
+
+/// Per-connection state, depending on the role
+pub enum Session {
+    /// No role-specific request yet
+    Unclassified,
+    Peer {
+        tx_limit: TokenBucket,
+        block_limit: TokenBucket,
+    },
+    Wallet {
+        tx_limit: TokenBucket,
+    },
+    Miner {
+        block_limit: TokenBucket,
+    },
+}
+
+impl Session {
+    pub fn role(&self) -> Option<Role> {
+        match self {
+            Session::Unclassified => None,
+            Session::Peer { .. } => Some(Role::Peer),
+            Session::Wallet { .. } => Some(Role::Wallet),
+            Session::Miner { .. } => Some(Role::Miner),
+        }
+    }
+
+    /// Settle the role on the first role-specific request. Returns false
+    /// if the connection already has a different role.
+    pub fn accept(&mut self, role: Role) -> bool {
+        if let Some(current) = self.role() {
+            return current == role;
+        }
+        let config = &BlockchainConfig::global().node;
+        let tx_limit = || TokenBucket::new(config.tx_rate_limit, config.tx_burst);
+        let block_limit = || TokenBucket::new(config.block_rate_limit, config.block_burst);
+        *self = match role {
+            Role::Peer => Session::Peer {
+                tx_limit: tx_limit(),
+                block_limit: block_limit(),
+            },
+            Role::Wallet => Session::Wallet {
+                tx_limit: tx_limit(),
+            },
+            Role::Miner => Session::Miner {
+                block_limit: block_limit(),
+            },
+        };
+        true
+    }
+
+    /// The rate limit a message counts against, if any
+    pub fn limit_for(&mut self, message: &Message) -> Option<&mut TokenBucket> {
+        match (self, message) {
+            (Session::Peer { tx_limit, .. }, Message::NewTransaction(_))
+            | (Session::Wallet { tx_limit }, Message::SubmitTransaction(_)) => Some(tx_limit),
+            (Session::Peer { block_limit, .. }, Message::NewBlock(_))
+            | (Session::Miner { block_limit }, Message::SubmitTemplate(_)) => Some(block_limit),
+            _ => None,
+        }
+    }
+}
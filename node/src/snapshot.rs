@@ -0,0 +1,135 @@
+//! The chain state template building and UTXO queries read
+//!
+//! Copying the UTXO set under the blockchain read lock for every
+//! template made each request cost as much as the whole set. Instead,
+//! the node keeps an immutable snapshot behind an `Arc`, swapped as each
+//! block is accepted. Readers clone the `Arc` and let go of the lock at
+//! once, then read the snapshot for as long as they need while new
+//! blocks come in. The UTXO set is a persistent map, so the next
+//! snapshot shares everything with the previous one but the block's
+//! inputs and outputs, and is built before the lock is taken to swap it.
+use btclib::sha256::Hash;
+use btclib::types::{Block, Blockchain, Transaction, TransactionOutput};
+use btclib::U256;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// What a template builds on
+#[derive(Clone)]
+pub struct Tip {
+    /// Hash of the last block, zero for an empty chain
+    pub hash: Hash,
+    /// Number of blocks
+    pub height: u64,
+    pub target: U256,
+    /// Reward of the next block
+    pub reward: u64,
+    pub utxo_commitment: Hash,
+}
+
+impl Tip {
+    fn of(chain: &Blockchain) -> Self {
+        Self {
+            hash: chain
+                .blocks()
+                .last()
+                .map(|block| block.hash())
+                .unwrap_or(Hash::zero()),
+            height: chain.block_height(),
+            target: chain.target(),
+            reward: chain.calculate_block_reward(),
+            utxo_commitment: chain.utxo_commitment(),
+        }
+    }
+}
+
+/// Chain state as of one tip
+#[derive(Clone)]
+pub struct ChainSnapshot {
+    pub tip: Tip,
+    pub block_transaction_cap: usize,
+    /// The UTXO set, in the shape blocks are validated against
+    pub utxos: im::HashMap<Hash, (bool, TransactionOutput)>,
+    /// Height of the block each UTXO was created in
+    pub utxo_heights: im::HashMap<Hash, u64>,
+}
+
+impl ChainSnapshot {
+    /// Copy the state of `chain`
+    pub fn of(chain: &Blockchain) -> Self {
+        Self {
+            tip: Tip::of(chain),
+            block_transaction_cap: chain.params().block_transaction_cap,
+            utxos: chain
+                .utxos()
+                .iter()
+                .map(|(hash, utxo)| (*hash, utxo.clone()))
+                .collect(),
+            utxo_heights: chain
+                .utxos()
+                .keys()
+                .filter_map(|hash| Some((*hash, chain.utxo_height(hash)?)))
+                .collect(),
+        }
+    }
+
+    /// Height of the last block, 0 for an empty chain
+    pub fn tip_height(&self) -> u64 {
+        self.tip.height.saturating_sub(1)
+    }
+
+    /// The UTXOs `transactions` spend, as [`Block::calculate_miner_fees`]
+    /// takes them
+    pub fn spent_by<'a>(
+        &self,
+        transactions: impl IntoIterator<Item = &'a Transaction>,
+    ) -> HashMap<Hash, (bool, TransactionOutput)> {
+        transactions
+            .into_iter()
+            .flat_map(|transaction| &transaction.inputs)
+            .filter_map(|input| {
+                let hash = input.prev_transaction_output_hash;
+                Some((hash, self.utxos.get(&hash)?.clone()))
+            })
+            .collect()
+    }
+}
+
+/// The latest snapshot
+pub fn current() -> Arc<ChainSnapshot> {
+    crate::SNAPSHOT.read().expect("snapshot poisoned").clone()
+}
+
+/// Replace the snapshot with a copy of `chain`, e.g. once it is loaded
+pub fn publish(chain: &Blockchain) {
+    let snapshot = Arc::new(ChainSnapshot::of(chain));
+    *crate::SNAPSHOT.write().expect("snapshot poisoned") = snapshot;
+}
+
+/// Bring the snapshot up to date with `block`, just added to `chain`
+/// and applied to its UTXO set. Readers holding the previous snapshot
+/// keep seeing it unchanged.
+pub fn apply(chain: &Blockchain, block: &Block) {
+    // cloning shares the maps rather than copying them
+    let mut snapshot = ChainSnapshot::clone(&current());
+    let height = chain.tip_height();
+    for transaction in &block.transactions {
+        for input in &transaction.inputs {
+            snapshot.utxos.remove(&input.prev_transaction_output_hash);
+            snapshot
+                .utxo_heights
+                .remove(&input.prev_transaction_output_hash);
+        }
+        for output in &transaction.outputs {
+            snapshot
+                .utxos
+                .insert(output.hash(), (false, output.clone()));
+            snapshot.utxo_heights.insert(output.hash(), height);
+        }
+    }
+    snapshot.tip = Tip::of(chain);
+    *crate::SNAPSHOT.write().expect("snapshot poisoned") = Arc::new(snapshot);
+}
+
+#[cfg(test)]
+mod tests;
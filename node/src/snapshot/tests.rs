@@ -0,0 +1,67 @@
+#[cfg(test)]
+mod tests {
+    use crate::snapshot::{apply, current, publish};
+    use btclib::crypto::{PrivateKey, Signature};
+    use btclib::sha256::Hash;
+    use btclib::types::{
+        Block, BlockHeader, Blockchain, Transaction, TransactionInput, TransactionOutput,
+    };
+    use btclib::util::MerkleRoot;
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    fn block_of(transactions: Vec<Transaction>) -> Block {
+        Block::new(
+            BlockHeader {
+                version: 1,
+                timestamp: Utc::now(),
+                prev_block_hash: Hash::zero(),
+                nonce: 0,
+                target: btclib::U256::zero(),
+                merkle_root: MerkleRoot::calculate(&transactions),
+                utxo_commitment: None,
+            },
+            transactions,
+        )
+    }
+
+    #[test]
+    fn test_readers_keep_their_snapshot_across_blocks() {
+        let chain = Blockchain::new();
+        let private_key = PrivateKey::new_key();
+        let output = TransactionOutput {
+            value: 1000,
+            unique_id: Uuid::new_v4(),
+            pubkey: private_key.public_key(),
+        };
+        publish(&chain);
+        let before = current();
+
+        apply(
+            &chain,
+            &block_of(vec![Transaction::coinbase(0, vec![output.clone()])]),
+        );
+        let created = current();
+        assert!(before.utxos.is_empty());
+        assert!(created.utxos.contains_key(&output.hash()));
+
+        let spend = Transaction::new(
+            vec![TransactionInput {
+                prev_transaction_output_hash: output.hash(),
+                signature: Signature::sign_output(&output.hash(), &private_key),
+            }],
+            vec![TransactionOutput {
+                value: 900,
+                unique_id: Uuid::new_v4(),
+                pubkey: private_key.public_key(),
+            }],
+        );
+        apply(&chain, &block_of(vec![spend.clone()]));
+        assert_eq!(created.utxos.len(), 1);
+        assert!(created.spent_by([&spend]).contains_key(&output.hash()));
+        let spent = current();
+        assert!(!spent.utxos.contains_key(&output.hash()));
+        assert!(spent.utxos.contains_key(&spend.outputs[0].hash()));
+        assert!(spent.spent_by([&spend]).is_empty());
+    }
+}
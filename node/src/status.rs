@@ -0,0 +1,55 @@
+//! The node's status, answered to `GetStatus` and printed by
+//! `node status`
+use anyhow::{anyhow, Result};
+use btclib::config::BlockchainConfig;
+use btclib::difficulty;
+use btclib::network::{Message, StatusReport};
+use btclib::sha256::Hash;
+
+/// What this node is up to right now
+pub async fn report() -> StatusReport {
+    let blockchain = crate::BLOCKCHAIN.read().await;
+    StatusReport {
+        network_id: BlockchainConfig::global().network.network_id.clone(),
+        height: blockchain.block_height(),
+        best_block: blockchain
+            .blocks()
+            .last()
+            .map(|block| block.hash())
+            .unwrap_or(Hash::zero()),
+        peers: crate::NODES.len(),
+        mempool: crate::MEMPOOL.read().await.len(),
+        difficulty: difficulty::difficulty(blockchain.target()),
+        uptime: crate::STARTED.elapsed().as_secs(),
+    }
+}
+
+/// Ask the node at `address` for its status and print it
+pub async fn print(address: &str) -> Result<()> {
+    let report = match crate::util::ask(address, Message::GetStatus).await? {
+        Message::Status(report) => report,
+        other => return Err(anyhow!("Unexpected answer: {}", other.kind())),
+    };
+    println!("Node:        {}", address);
+    println!("Network:     {}", report.network_id);
+    println!("Height:      {}", report.height);
+    println!("Best block:  {}", report.best_block);
+    println!("Difficulty:  {:.3}", report.difficulty);
+    println!("Peers:       {}", report.peers);
+    println!("Mempool:     {} transactions", report.mempool);
+    println!("Uptime:      {}", format_duration(report.uptime));
+    Ok(())
+}
+
+pub fn format_duration(seconds: u64) -> String {
+    let (days, hours, minutes) = (
+        seconds / 86_400,
+        seconds % 86_400 / 3_600,
+        seconds % 3_600 / 60,
+    );
+    match (days, hours) {
+        (0, 0) => format!("{}m {}s", minutes, seconds % 60),
+        (0, _) => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h", days, hours),
+    }
+}
@@ -0,0 +1,187 @@
+//! Initial block download from several peers at once
+//!
+//! The chain is split into batches of `sync_batch_size` blocks. Every
+//! peer that has the whole chain gets a worker, which takes the next
+//! batch, sends all of its `FetchBlock` requests before reading the
+//! answers, and hands the blocks back. Batches can arrive out of order;
+//! they are validated and added strictly in height order. A batch whose
+//! peer times out, hangs up or sends an invalid block goes back to the
+//! queue for another peer, and that peer gets no more work.
+use crate::peer::Peer;
+use anyhow::{anyhow, Result};
+use btclib::config::BlockchainConfig;
+use btclib::network::Message;
+use btclib::retry;
+use btclib::types::Block;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio::time;
+use tracing::{debug, info, warn};
+
+/// Work shared by the download workers
+#[derive(Default)]
+struct Queue {
+    batches: VecDeque<Range<usize>>,
+    /// Peers that failed a batch and get no more work
+    failed: HashSet<String>,
+    done: bool,
+}
+
+type SharedQueue = Arc<Mutex<Queue>>;
+
+/// Download blocks `0..count` from the connected peers known to have at
+/// least `count` blocks, adding them to the chain in order
+pub async fn download_blockchain(count: u32) -> Result<()> {
+    let config = BlockchainConfig::global();
+    let count = count as usize;
+    let batch_size = config.node.sync_batch_size.max(1);
+    let peers = {
+        let book = crate::ADDRESS_BOOK.read().await;
+        crate::NODES
+            .iter()
+            .map(|entry| entry.key().clone())
+            .filter(|peer| {
+                book.known_peer(peer)
+                    .height
+                    .is_some_and(|height| height >= count as u64)
+            })
+            .collect::<Vec<_>>()
+    };
+    if peers.is_empty() {
+        return Err(anyhow!("no connected peer has all {} blocks", count));
+    }
+    info!(
+        blocks = count,
+        peers = peers.len(),
+        batch_size,
+        "downloading blockchain"
+    );
+
+    let queue: SharedQueue = Arc::new(Mutex::new(Queue {
+        batches: (0..count)
+            .step_by(batch_size)
+            .map(|start| start..(start + batch_size).min(count))
+            .collect(),
+        ..Default::default()
+    }));
+    let (sender, mut receiver) = mpsc::channel(peers.len() * 2);
+    let mut workers = JoinSet::new();
+    for peer in peers {
+        let Some(stream) = crate::NODES.get(&peer).map(|entry| entry.value().clone()) else {
+            continue;
+        };
+        workers.spawn(worker(peer, stream, queue.clone(), sender.clone()));
+    }
+    // the channel closes once every worker is gone
+    drop(sender);
+
+    let mut pending: BTreeMap<usize, (String, Vec<Block>)> = BTreeMap::new();
+    let mut next = 0;
+    let result = loop {
+        if next == count {
+            break Ok(());
+        }
+        let Some((range, peer, blocks)) = receiver.recv().await else {
+            break Err(anyhow!(
+                "sync stopped at block {} of {}, no peer left to download from",
+                next,
+                count
+            ));
+        };
+        pending.insert(range.start, (peer, blocks));
+        // add every batch that continues the chain
+        while let Some((peer, blocks)) = pending.remove(&next) {
+            let end = next + blocks.len();
+            let (added, result) = add_blocks(blocks).await;
+            next += added;
+            if let Err(e) = result {
+                warn!(peer = %peer, error = %e, "invalid block during sync, retrying elsewhere");
+                let mut queue = queue.lock().expect("sync queue poisoned");
+                queue.failed.insert(peer);
+                queue.batches.push_front(next..end);
+                break;
+            }
+            debug!(height = next, "blocks added");
+        }
+    };
+    queue.lock().expect("sync queue poisoned").done = true;
+    while workers.join_next().await.is_some() {}
+    result
+}
+
+/// Add a batch to the chain, returning how many blocks were added.
+/// Blocks before a failing one stay added.
+async fn add_blocks(blocks: Vec<Block>) -> (usize, Result<()>) {
+    let len = blocks.len();
+    let mut blockchain = crate::BLOCKCHAIN.write().await;
+    for (added, block) in blocks.into_iter().enumerate() {
+        if let Err(e) = blockchain.add_block(block) {
+            return (added, Err(e.into()));
+        }
+        // the next block is checked against the outputs this one created
+        blockchain.apply_tip_to_utxos();
+    }
+    (len, Ok(()))
+}
+
+/// Fetch batches from one peer until the sync is done or the peer fails
+async fn worker(
+    peer: String,
+    stream: Peer,
+    queue: SharedQueue,
+    results: mpsc::Sender<(Range<usize>, String, Vec<Block>)>,
+) {
+    let config = BlockchainConfig::global();
+    loop {
+        let batch = {
+            let mut queue = queue.lock().expect("sync queue poisoned");
+            if queue.done || queue.failed.contains(&peer) {
+                break;
+            }
+            queue.batches.pop_front()
+        };
+        // everything is handed out, but a batch may still come back
+        let Some(range) = batch else {
+            time::sleep(time::Duration::from_millis(50)).await;
+            continue;
+        };
+        match retry::with_timeout(&config.retry, fetch_batch(&stream, range.clone())).await {
+            Ok(blocks) => {
+                if results.send((range, peer.clone(), blocks)).await.is_err() {
+                    break;
+                }
+            }
+            Err(e) => {
+                warn!(peer = %peer, blocks = ?range, error = %e, "batch failed, retrying elsewhere");
+                let mut queue = queue.lock().expect("sync queue poisoned");
+                queue.failed.insert(peer);
+                queue.batches.push_front(range);
+                // answers still on their way are dropped when they come
+                break;
+            }
+        }
+    }
+}
+
+/// Request a batch of blocks, pipelined: all requests go out before the
+/// answers are read, which come back in the same order
+async fn fetch_batch(stream: &Peer, range: Range<usize>) -> Result<Vec<Block>> {
+    let mut answers = Vec::with_capacity(range.len());
+    for height in range.clone() {
+        answers.push(stream.send_request(Message::FetchBlock(height)).await?);
+    }
+    let mut blocks = Vec::with_capacity(range.len());
+    for (height, answer) in range.zip(answers) {
+        let answer = answer
+            .await
+            .map_err(|_| anyhow!("connection closed before block {}", height))?;
+        match answer {
+            Message::NewBlock(block) => blocks.push(block),
+            other => return Err(anyhow!("expected block {}, got {}", height, other.kind())),
+        }
+    }
+    Ok(blocks)
+}
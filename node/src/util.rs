@@ -1,21 +1,59 @@
-use anyhow::{Context, Result};
-use btclib::config::BlockchainConfig;
+use crate::peer::Peer;
+use anyhow::{anyhow, Context, Result};
+use btclib::auth;
+use btclib::config::{BlockchainConfig, LogFormat};
+use btclib::crypto::PublicKey;
+use btclib::difficulty;
 use btclib::network::Message;
-use btclib::types::Blockchain;
+use btclib::retry::{self, Retrier, RetryError};
+use btclib::sha256::Hash;
+use btclib::store::ChainStore;
+use btclib::transport::{Connection, Socket};
+use btclib::types::{Blockchain, MempoolLimits, SavedMempool, TransactionOutput};
 use btclib::util::Saveable;
-use tokio::net::TcpStream;
+use std::cmp::Reverse;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
 use tokio::time;
-use tracing::info;
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::EnvFilter;
 
-pub fn init_tracing() {
-    tracing_subscriber::fmt::init();
+/// Log to stdout, filtered by `RUST_LOG` or the configured `log_filter`
+/// (per module, e.g. `info,node::handler=debug`)
+pub fn init_tracing(config: &BlockchainConfig) {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(&config.node.log_filter));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match config.node.log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().with_current_span(true).init(),
+    }
 }
 
-pub async fn load_blockchain(blockchain_file: &str) -> Result<()> {
-    info!("blockchain file exists, loading...");
-    let new_blockchain = Blockchain::load_from_file(blockchain_file)
-        .context("Failed to load blockchain from file")?;
+/// Load the blockchain from the store into the global state, after
+/// auditing it with `verify`. Returns false if the store is empty.
+pub async fn load_blockchain(store: &dyn ChainStore, verify: bool) -> Result<bool> {
+    let Some(new_blockchain) = store
+        .load()
+        .context("Failed to load blockchain from storage")?
+    else {
+        return Ok(false);
+    };
     info!("blockchain loaded");
+    if verify {
+        info!("verifying the whole chain...");
+        let report = new_blockchain
+            .verify_chain()
+            .context("Stored blockchain failed verification")?;
+        info!(
+            blocks = report.blocks,
+            transactions = report.transactions,
+            utxos = report.utxos,
+            total_supply = report.total_supply,
+            "chain verified"
+        );
+    }
     let mut blockchain = crate::BLOCKCHAIN.write().await;
     *blockchain = new_blockchain;
     info!("rebuilding utxos...");
@@ -25,26 +63,234 @@ pub async fn load_blockchain(blockchain_file: &str) -> Result<()> {
     info!("current target: {}", blockchain.target());
     blockchain.try_adjust_target();
     info!("new target: {}", blockchain.target());
+    log_chain_status(&blockchain);
     info!("initialization complete");
-    Ok(())
+    Ok(true)
+}
+
+/// The mempool limits of the current config
+pub fn mempool_limits() -> MempoolLimits {
+    let config = BlockchainConfig::global();
+    MempoolLimits {
+        max_bytes: config.node.max_mempool_bytes,
+        max_transactions: config.node.max_mempool_txs,
+    }
+}
+
+/// Put the transactions saved in `path` back into the mempool, once the
+/// chain is loaded. A missing or unreadable file leaves it empty.
+pub async fn load_mempool(path: &str) {
+    if !Path::new(path).exists() {
+        return;
+    }
+    let saved = match SavedMempool::load_from_file(path) {
+        Ok(saved) => saved,
+        Err(e) => {
+            warn!("failed to load mempool from {}: {}", path, e);
+            return;
+        }
+    };
+    let total = saved.transactions.len();
+    let restored = {
+        let blockchain = crate::BLOCKCHAIN.read().await;
+        let mut mempool = crate::MEMPOOL.write().await;
+        mempool.restore(&blockchain, saved);
+        // the limits may have shrunk since
+        mempool.trim(&blockchain, mempool_limits());
+        mempool.take_conflicts();
+        mempool.len()
+    };
+    info!(
+        "restored {} of {} saved mempool transactions",
+        restored, total
+    );
+}
+
+/// Log the chain height, difficulty and the hash rate the network
+/// spent on the last difficulty interval
+pub fn log_chain_status(blockchain: &Blockchain) {
+    let window = blockchain.params().difficulty_update_interval as usize;
+    let hashrate = blockchain
+        .estimated_hashrate(window)
+        .map(difficulty::format_hashrate)
+        .unwrap_or_else(|| "unknown".to_string());
+    info!(
+        height = blockchain.block_height(),
+        difficulty = format!("{:.2}", blockchain.difficulty()),
+        hashrate = %hashrate,
+        "chain status"
+    );
+}
+
+/// Connect to a peer, retrying with backoff according to the retry config.
+/// The connection is encrypted if `encrypt_p2p` is set, and goes through
+/// the configured SOCKS5 proxy if any. With the port mapped on the
+/// router, the peer is told our external address.
+pub async fn connect(address: &str) -> Result<Connection> {
+    let config = BlockchainConfig::global();
+    let retrier = Retrier::new(config.retry.clone());
+    let mut stream = retrier
+        .run(&format!("connecting to {}", address), || async {
+            match &config.node.proxy {
+                Some(proxy) => {
+                    Connection::connect_via(proxy, address, config.node.encrypt_p2p).await
+                }
+                None => Connection::connect(address, config.node.encrypt_p2p).await,
+            }
+        })
+        .await?;
+    if let Some(external) = crate::EXTERNAL_ADDRESS.get() {
+        Message::Advertise(external.clone())
+            .send_async(&mut stream)
+            .await?;
+    }
+    Ok(stream)
+}
+
+/// Start the tasks of a connection to a peer and add it to `NODES`
+pub fn add_peer(address: String, stream: Connection) {
+    let peer = Peer::start(&address, stream);
+    crate::NODES.insert(address, peer);
+}
+
+/// The connected peers, without holding `NODES` locked
+pub fn peers() -> Vec<(String, Peer)> {
+    crate::NODES
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().clone()))
+        .collect()
+}
+
+/// A peer told us where it accepts connections: remember the address,
+/// and connect to it in the background if we have room for a peer
+pub async fn connect_back(address: String) {
+    crate::ADDRESS_BOOK.write().await.seen(&address, None);
+    let max_peers = BlockchainConfig::global().node.max_peers;
+    if crate::NODES.contains_key(&address) || crate::NODES.len() >= max_peers {
+        return;
+    }
+    tokio::spawn(async move {
+        match connect(&address).await {
+            Ok(stream) => {
+                info!("connected back to advertised peer {}", address);
+                add_peer(address, stream);
+            }
+            Err(e) => debug!("advertised peer {} is unreachable: {}", address, e),
+        }
+    });
+}
+
+/// Send a request to `peer` and wait for its response, giving up after
+/// the configured timeout instead of hanging on a slow peer. The round
+/// trip feeds the peer's latency in the address book.
+pub async fn request(peer: &str, stream: &Peer, message: &Message) -> Result<Message> {
+    let config = BlockchainConfig::global();
+    let exchange = async {
+        let started = Instant::now();
+        let response = stream.request(message.clone()).await?;
+        crate::ADDRESS_BOOK
+            .write()
+            .await
+            .record_latency(peer, started.elapsed());
+        Ok::<_, anyhow::Error>(response)
+    };
+    match retry::with_timeout(&config.retry, exchange).await {
+        Ok(response) => Ok(response),
+        Err(RetryError::Failed(e)) => Err(e),
+        Err(e) => Err(anyhow!("request {:?} failed: {}", message, e)),
+    }
+}
+
+/// Send one request to the node at `address` for an operator command,
+/// using the encryption and auth token of the local config like a
+/// miner does
+pub async fn ask(address: &str, message: Message) -> Result<Message> {
+    let config = BlockchainConfig::global();
+    let exchange = async {
+        let mut stream = Connection::connect(address, config.node.encrypt_p2p).await?;
+        if let Some(token) = &config.node.auth_token {
+            auth::authenticate(&mut stream, token).await?;
+        }
+        message.send_async(&mut stream).await?;
+        Ok::<_, anyhow::Error>(Message::receive_async(&mut stream).await?)
+    };
+    match retry::with_timeout(&config.retry, exchange).await {
+        Ok(response) => Ok(response),
+        Err(RetryError::Failed(e)) => Err(e),
+        Err(e) => Err(anyhow!("Request to {} failed: {}", address, e)),
+    }
+}
+
+/// Resolve seed hostnames to peer addresses. Seeds that don't resolve
+/// are skipped. Behind a proxy the seeds are kept as they are, for the
+/// proxy to resolve without leaking DNS lookups.
+pub async fn resolve_seeds(seeds: &[String]) -> Vec<String> {
+    if BlockchainConfig::global().node.proxy.is_some() {
+        return seeds.to_vec();
+    }
+    let mut addresses = vec![];
+    for seed in seeds {
+        match tokio::net::lookup_host(seed).await {
+            Ok(resolved) => {
+                for address in resolved.map(|addr| addr.to_string()) {
+                    debug!(seed = %seed, address = %address, "resolved seed");
+                    if !addresses.contains(&address) {
+                        addresses.push(address);
+                    }
+                }
+            }
+            Err(e) => warn!(seed = %seed, error = %e, "could not resolve seed"),
+        }
+    }
+    addresses
 }
 
 pub async fn populate_connections(nodes: &[String]) -> Result<()> {
     info!("trying to connect to other nodes...");
+    let max_peers = BlockchainConfig::global().node.max_peers;
     for node in nodes {
-        let mut stream = TcpStream::connect(&node).await?;
-        let message = Message::DiscoverNodes;
-        message.send_async(&mut stream).await?;
-        info!("sent DiscoverNodes to {}", node);
-        let message = Message::receive_async(&mut stream).await?;
+        if crate::NODES.len() >= max_peers {
+            break;
+        }
+        let stream = match connect(node).await {
+            Ok(stream) => Peer::start(node, stream),
+            Err(e) => {
+                warn!("skipping unreachable node {}: {}", node, e);
+                continue;
+            }
+        };
+        crate::ADDRESS_BOOK.write().await.seen(node, None);
+        info!("sending DiscoverNodes to {}", node);
+        let message = match request(node, &stream, &Message::DiscoverNodes).await {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("no answer from {}: {}", node, e);
+                continue;
+            }
+        };
 
         match message {
-            Message::NodeList(child_nodes) => {
+            Message::NodeList(mut child_nodes) => {
                 info!("received NodeList from {}", node);
+                {
+                    let mut book = crate::ADDRESS_BOOK.write().await;
+                    child_nodes.iter().for_each(|child| book.learn(child));
+                }
+                // peers seen most recently are the likeliest to be up
+                child_nodes.sort_by_key(|child| Reverse(child.last_seen));
                 for child_node in child_nodes {
+                    let child_node = child_node.address;
+                    if crate::NODES.contains_key(&child_node) || crate::NODES.len() >= max_peers {
+                        continue;
+                    }
                     info!("adding node {}", child_node);
-                    let new_stream = TcpStream::connect(&child_node).await?;
-                    crate::NODES.insert(child_node, new_stream);
+                    match connect(&child_node).await {
+                        Ok(new_stream) => {
+                            crate::ADDRESS_BOOK.write().await.seen(&child_node, None);
+                            add_peer(child_node, new_stream);
+                        }
+                        Err(e) => warn!("skipping unreachable node {}: {}", child_node, e),
+                    }
                 }
             }
             _ => {
@@ -60,21 +306,24 @@ pub async fn find_longest_chain_node() -> Result<(String, u32)> {
     info!("finding nodes with the highest blockchain length...");
     let mut longest_name = String::new();
     let mut longest_count = 0;
-    let all_nodes = crate::NODES
-        .iter()
-        .map(|x| x.key().clone())
-        .collect::<Vec<_>>();
-    for node in all_nodes {
+    for (node, stream) in peers() {
         info!("asking {} for blockchain length", node);
-        let mut stream = crate::NODES.get_mut(&node).context("no node")?;
-        let message = Message::AskDifference(0);
-        message.send_async(&mut *stream).await.unwrap();
-        info!("sent AskDifference to {}", node);
-        let message = Message::receive_async(&mut *stream).await?;
+        let message = match request(&node, &stream, &Message::AskDifference(0)).await {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("no answer from {}: {}", node, e);
+                continue;
+            }
+        };
 
         match message {
             Message::Difference(count) => {
                 info!("received Difference from {}", node);
+                // asked with height 0, so this is the peer's height
+                crate::ADDRESS_BOOK
+                    .write()
+                    .await
+                    .seen(&node, Some(count.max(0) as u64));
                 if count > longest_count {
                     info!("new longest blockchain: {} blocks from {node}", count);
                     longest_count = count;
@@ -89,47 +338,265 @@ pub async fn find_longest_chain_node() -> Result<(String, u32)> {
     Ok((longest_name, longest_count as u32))
 }
 
-pub async fn download_blockchain(node: &str, count: u32) -> Result<()> {
-    let mut stream = crate::NODES.get_mut(node).unwrap();
-    for i in 0..count as usize {
-        let message = Message::FetchBlock(i);
-        message.send_async(&mut *stream).await?;
-        let message = Message::receive_async(&mut *stream).await?;
-        match message {
-            Message::NewBlock(block) => {
-                let mut blockchain = crate::BLOCKCHAIN.write().await;
-                blockchain.add_block(block)?;
-            }
-            _ => {
-                info!("unexpected message from {}", node);
+/// Tell subscribed wallets about their transactions that were evicted
+/// by conflicting ones. Subscribers that can't be reached are dropped.
+pub async fn notify_conflicts(conflicts: Vec<(Hash, Vec<PublicKey>)>) {
+    for (txid, keys) in conflicts {
+        info!("transaction {} conflicted", txid);
+        notify(&keys, Message::TransactionConflicted(txid)).await;
+    }
+}
+
+/// Tell subscribed wallets about their transactions that expired from
+/// the mempool, so they can send them again
+pub async fn notify_expired(expired: Vec<(Hash, Vec<PublicKey>)>) {
+    for (txid, keys) in expired {
+        info!("transaction {} expired", txid);
+        notify(&keys, Message::TransactionExpired(txid)).await;
+    }
+}
+
+/// Push `message` to every subscriber of one of `keys`, dropping those
+/// that can't be reached
+async fn notify(keys: &[PublicKey], message: Message) {
+    let subscribers = crate::SUBSCRIBERS
+        .iter()
+        .filter(|entry| entry.value().0.iter().any(|key| keys.contains(key)))
+        .map(|entry| entry.key().clone())
+        .collect::<Vec<_>>();
+    for subscriber in subscribers {
+        let Some(mut entry) = crate::SUBSCRIBERS.get_mut(&subscriber) else {
+            continue;
+        };
+        let sent = message.send_async(&mut entry.value_mut().1).await;
+        drop(entry);
+        if sent.is_err() {
+            warn!("dropping unreachable subscriber {}", subscriber);
+            crate::SUBSCRIBERS.remove(&subscriber);
+        }
+    }
+}
+
+/// Tell subscribed wallets about outputs of a new mempool transaction
+/// paying their keys, along with the key that paid.
+/// Subscribers that can't be reached are dropped.
+pub async fn notify_payments(
+    txid: Hash,
+    outputs: Vec<TransactionOutput>,
+    sender: Option<PublicKey>,
+) {
+    for output in outputs {
+        let subscribers = crate::SUBSCRIBERS
+            .iter()
+            .filter(|entry| entry.value().0.contains(&output.pubkey))
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+        for subscriber in subscribers {
+            let Some(mut entry) = crate::SUBSCRIBERS.get_mut(&subscriber) else {
+                continue;
+            };
+            let message = Message::PaymentReceived(txid, output.clone(), sender.clone());
+            let sent = message.send_async(&mut entry.value_mut().1).await;
+            drop(entry);
+            if sent.is_err() {
+                warn!("dropping unreachable subscriber {}", subscriber);
+                crate::SUBSCRIBERS.remove(&subscriber);
             }
         }
     }
-    Ok(())
 }
 
 pub async fn cleanup() {
-    let config = BlockchainConfig::global();
-    let mut interval = time::interval(time::Duration::from_secs(
-        config.node.mempool_cleanup_interval_secs,
-    ));
     loop {
-        interval.tick().await;
+        // read every time, a config reload may have changed it
+        let interval = BlockchainConfig::global()
+            .node
+            .mempool_cleanup_interval_secs;
+        time::sleep(time::Duration::from_secs(interval)).await;
         info!("cleaning the mempool from old transactions");
-        let mut blockchain = crate::BLOCKCHAIN.write().await;
-        blockchain.cleanup_mempool();
+        let expired = {
+            let blockchain = crate::BLOCKCHAIN.read().await;
+            let mut mempool = crate::MEMPOOL.write().await;
+            mempool.cleanup(&blockchain);
+            mempool.take_expired()
+        };
+        notify_expired(expired).await;
     }
 }
 
-pub async fn save(name: String) {
-    let config = BlockchainConfig::global();
-    let mut interval = time::interval(time::Duration::from_secs(
-        config.node.blockchain_save_interval_secs,
-    ));
+pub async fn save(store: Arc<dyn ChainStore>) {
     loop {
-        interval.tick().await;
-        info!("saving blockchain to drive...");
-        let blockchain = crate::BLOCKCHAIN.read().await;
-        blockchain.save_to_file(name.clone()).unwrap();
+        let interval = BlockchainConfig::global()
+            .node
+            .blockchain_save_interval_secs;
+        time::sleep(time::Duration::from_secs(interval)).await;
+        flush(store.as_ref()).await;
+    }
+}
+
+/// Write the blockchain, the mempool, the ban list and the address book
+/// to disk
+pub async fn flush(store: &dyn ChainStore) {
+    let config = BlockchainConfig::global();
+    info!("saving blockchain to drive...");
+    let blockchain = crate::BLOCKCHAIN.read().await;
+    if let Err(e) = store.save(&blockchain) {
+        error!("failed to save blockchain: {}", e);
+    }
+    drop(blockchain);
+    let saved = crate::MEMPOOL.read().await.saved();
+    if let Err(e) = saved.save_to_file(&config.node.mempool_file) {
+        warn!("failed to save mempool: {}", e);
+    }
+    let mut banlist = crate::BANLIST.write().await;
+    banlist.prune(chrono::Utc::now());
+    if let Err(e) = banlist.save(&config.node.banlist_file) {
+        warn!("failed to save ban list: {}", e);
+    }
+    drop(banlist);
+    let mut book = crate::ADDRESS_BOOK.write().await;
+    book.prune(chrono::Utc::now());
+    if let Err(e) = book.save(&config.node.peers_file) {
+        warn!("failed to save address book: {}", e);
+    }
+}
+
+/// Listener of `node.unix_socket`, for wallets and miners on this
+/// machine. Without a socket to listen on, `accept` never resolves.
+pub struct LocalListener {
+    #[cfg(unix)]
+    listener: Option<(tokio::net::UnixListener, String)>,
+}
+
+impl LocalListener {
+    pub fn bind(path: Option<&str>) -> Result<Self> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt;
+            let Some(path) = path else {
+                return Ok(Self { listener: None });
+            };
+            // a node that didn't shut down cleanly leaves its socket behind
+            if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                if !metadata.file_type().is_socket() {
+                    return Err(anyhow!("{} exists and is not a socket", path));
+                }
+                if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                    return Err(anyhow!("{} is in use by another process", path));
+                }
+                std::fs::remove_file(path)
+                    .with_context(|| format!("Failed to remove stale socket {}", path))?;
+            }
+            let listener = tokio::net::UnixListener::bind(path)
+                .with_context(|| format!("Failed to listen on {}", path))?;
+            info!("Listening on {}{}", btclib::transport::UNIX_PREFIX, path);
+            Ok(Self {
+                listener: Some((listener, path.to_string())),
+            })
+        }
+        #[cfg(not(unix))]
+        {
+            if path.is_some() {
+                warn!("node.unix_socket needs a Unix system, ignored");
+            }
+            Ok(Self {})
+        }
+    }
+
+    pub async fn accept(&self) -> std::io::Result<Socket> {
+        #[cfg(unix)]
+        if let Some((listener, _)) = &self.listener {
+            let (stream, _) = listener.accept().await?;
+            return Ok(stream.into());
+        }
+        std::future::pending().await
+    }
+}
+
+#[cfg(unix)]
+impl Drop for LocalListener {
+    fn drop(&mut self) {
+        if let Some((_, path)) = &self.listener {
+            if let Err(e) = std::fs::remove_file(path) {
+                debug!("failed to remove {}: {}", path, e);
+            }
+        }
+    }
+}
+
+/// Resolves on Ctrl-C, or SIGTERM (e.g. `docker stop`) on unix
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => (),
+                    _ = terminate.recv() => (),
+                }
+                return;
+            }
+            Err(e) => warn!("can't listen for SIGTERM: {}", e),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!("can't listen for Ctrl-C: {}", e);
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Reload the config file on SIGHUP (e.g. `kill -HUP` or
+/// `docker kill -s HUP`) until the node stops
+#[cfg(unix)]
+pub async fn reload_on_sighup() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!("can't listen for SIGHUP, config reloading is off: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        info!("SIGHUP received, reloading the configuration");
+        reload_config();
+    }
+}
+
+/// Re-read the config files and apply the settings that can change
+/// while the node runs
+pub fn reload_config() {
+    let new = match BlockchainConfig::try_load() {
+        Ok(new) => new,
+        Err(e) => {
+            warn!("config reload failed, keeping the current config: {}", e);
+            return;
+        }
+    };
+    let reload = BlockchainConfig::global().reloaded(new);
+    for setting in &reload.consensus {
+        warn!(
+            setting,
+            "consensus parameters can't change on a running node, ignored"
+        );
+    }
+    for setting in &reload.restart_required {
+        warn!(setting, "setting only takes effect after a restart");
+    }
+    if reload.applied.is_empty() {
+        info!("config reloaded, no setting to apply changed");
+        return;
+    }
+    info!(settings = ?reload.applied, "config reloaded");
+    BlockchainConfig::set_global(reload.config);
+}
+
+/// Tell all known peers we are going away
+pub async fn disconnect_peers(port: u16) {
+    for (node, stream) in peers() {
+        if let Err(e) = stream.send(Message::Disconnect(port)).await {
+            debug!(peer = %node, "failed to send Disconnect: {}", e);
+        }
     }
 }
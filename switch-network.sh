@@ -10,8 +10,9 @@ echo "Available networks:"
 echo "  1. mainnet (default - standard speed)"
 echo "  2. testnet (2x faster, easier mining)"
 echo "  3. devnet (5x faster, instant mining)"
+echo "  4. regtest (blocks generated on request)"
 echo ""
-read -p "Select network (1-3): " choice
+read -p "Select network (1-4): " choice
 
 case $choice in
     1)
@@ -26,6 +27,10 @@ case $choice in
         cp config.devnet.json config.json
         echo "✅ Switched to devnet"
         ;;
+    4)
+        cp config.regtest.json config.json
+        echo "✅ Switched to regtest"
+        ;;
     *)
         echo "❌ Invalid choice"
         exit 1
@@ -0,0 +1,122 @@
+//! The last known UTXOs of the wallet's keys, kept in a sled database so
+//! the balance shows right away on startup and while no node answers
+use anyhow::{anyhow, Result};
+use btclib::crypto::PublicKey;
+use btclib::types::TransactionOutput;
+use chrono::{DateTime, Utc};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::Transactional;
+use std::path::Path;
+
+/// UTXOs of one key as (reserved, output, height of the block it is in)
+pub type CachedUtxos = Vec<(bool, TransactionOutput, u64)>;
+
+/// What the last UTXO update found
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub utxos: Vec<(PublicKey, CachedUtxos)>,
+    pub tip_height: u64,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Trees of the database:
+/// - `utxos`: hex public key → JSON [`CachedUtxos`]
+/// - default tree: `tip_height` and `updated_at` of the last update
+pub struct WalletCache {
+    db: sled::Db,
+    utxos: sled::Tree,
+}
+
+const TIP_HEIGHT_KEY: &[u8] = b"tip_height";
+const UPDATED_AT_KEY: &[u8] = b"updated_at";
+
+impl WalletCache {
+    /// Open (or create) the cache in the directory `path`. Fails while
+    /// another wallet process has it open.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_db(sled::open(path)?)
+    }
+
+    /// Use an already opened sled database
+    pub fn from_db(db: sled::Db) -> Result<Self> {
+        Ok(Self {
+            utxos: db.open_tree("utxos")?,
+            db,
+        })
+    }
+
+    /// Replace the cached UTXOs with `snapshot`, all at once
+    pub fn save(&self, snapshot: &Snapshot) -> Result<()> {
+        let entries = snapshot
+            .utxos
+            .iter()
+            .map(|(key, utxos)| Ok((key.to_hex(), serde_json::to_vec(utxos)?)))
+            .collect::<Result<Vec<_>>>()?;
+        // keys no longer loaded, e.g. a removed imported key
+        let stale = self
+            .utxos
+            .iter()
+            .keys()
+            .filter(|key| {
+                key.as_ref().map_or(true, |key| {
+                    !entries.iter().any(|(hex, _)| hex.as_bytes() == &key[..])
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let tip_height = snapshot.tip_height.to_be_bytes();
+        let updated_at = serde_json::to_vec(&snapshot.updated_at)?;
+        let meta: &sled::Tree = &self.db;
+        (&self.utxos, meta)
+            .transaction(|(utxos, meta)| {
+                for key in &stale {
+                    utxos.remove(key)?;
+                }
+                for (key, value) in &entries {
+                    utxos.insert(key.as_bytes(), value.as_slice())?;
+                }
+                meta.insert(TIP_HEIGHT_KEY, &tip_height)?;
+                meta.insert(UPDATED_AT_KEY, updated_at.as_slice())?;
+                Ok::<_, ConflictableTransactionError<anyhow::Error>>(())
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => e.into(),
+            })?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// The last saved snapshot, `None` if there is none yet
+    pub fn load(&self) -> Result<Option<Snapshot>> {
+        let Some(updated_at) = self.db.get(UPDATED_AT_KEY)? else {
+            return Ok(None);
+        };
+        let tip_height = match self.db.get(TIP_HEIGHT_KEY)? {
+            Some(bytes) => u64::from_be_bytes(
+                bytes
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| anyhow!("Corrupt tip height in wallet cache"))?,
+            ),
+            None => 0,
+        };
+        let utxos = self
+            .utxos
+            .iter()
+            .map(|entry| {
+                let (key, value) = entry?;
+                let key = PublicKey::from_hex(std::str::from_utf8(&key)?)?;
+                Ok((key, serde_json::from_slice(&value)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Some(Snapshot {
+            utxos,
+            tip_height,
+            updated_at: serde_json::from_slice(&updated_at)?,
+        }))
+    }
+}
+
+#[cfg(test)]
+#[path = "cache_tests.rs"]
+mod tests;
@@ -0,0 +1,56 @@
+#[cfg(test)]
+mod cache_tests {
+    use crate::cache::{Snapshot, WalletCache};
+    use btclib::crypto::PrivateKey;
+    use btclib::types::TransactionOutput;
+    use chrono::Utc;
+
+    fn temporary_cache() -> WalletCache {
+        WalletCache::from_db(sled::Config::new().temporary(true).open().unwrap()).unwrap()
+    }
+
+    fn output(value: u64, pubkey: &btclib::crypto::PublicKey) -> TransactionOutput {
+        TransactionOutput {
+            value,
+            unique_id: uuid::Uuid::new_v4(),
+            pubkey: pubkey.clone(),
+        }
+    }
+
+    #[test]
+    fn test_snapshot_round_trips_and_replaces_the_last() {
+        let cache = temporary_cache();
+        assert!(cache.load().unwrap().is_none());
+
+        let alice = PrivateKey::new_key().public_key();
+        let bob = PrivateKey::new_key().public_key();
+        let snapshot = Snapshot {
+            utxos: vec![
+                (alice.clone(), vec![(false, output(5, &alice), 3)]),
+                (bob.clone(), vec![(true, output(7, &bob), 4)]),
+            ],
+            tip_height: 4,
+            updated_at: Utc::now(),
+        };
+        cache.save(&snapshot).unwrap();
+        let loaded = cache.load().unwrap().unwrap();
+        assert_eq!(loaded.tip_height, 4);
+        assert_eq!(loaded.updated_at, snapshot.updated_at);
+        assert_eq!(loaded.utxos.len(), 2);
+        let (_, bob_utxos) = loaded.utxos.iter().find(|(key, _)| *key == bob).unwrap();
+        assert_eq!(bob_utxos[0].1.hash(), snapshot.utxos[1].1[0].1.hash());
+        assert!(bob_utxos[0].0);
+
+        // a key that is gone from the next snapshot is dropped
+        let snapshot = Snapshot {
+            utxos: vec![(alice.clone(), vec![])],
+            tip_height: 5,
+            updated_at: Utc::now(),
+        };
+        cache.save(&snapshot).unwrap();
+        let loaded = cache.load().unwrap().unwrap();
+        assert_eq!(loaded.tip_height, 5);
+        assert_eq!(loaded.utxos.len(), 1);
+        assert!(loaded.utxos[0].1.is_empty());
+    }
+}
@@ -0,0 +1,142 @@
+use serde::{Deserialize, Serialize};
+
+/// Coin selection strategy, configured in `wallet_config.toml`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CoinSelectionStrategy {
+    /// Spend the biggest UTXOs first: fewest inputs
+    #[default]
+    LargestFirst,
+    /// Spend the smallest UTXOs first: consolidates dust over time
+    SmallestFirst,
+    /// Look for a set of UTXOs that needs no change output,
+    /// falling back to largest-first if there is none
+    BranchAndBound,
+}
+
+impl CoinSelectionStrategy {
+    /// The selector for this strategy. `dust_threshold` is the largest
+    /// excess that is left to the miner instead of creating change.
+    pub fn selector(self, dust_threshold: u64) -> Box<dyn CoinSelection> {
+        match self {
+            CoinSelectionStrategy::LargestFirst => Box::new(LargestFirst),
+            CoinSelectionStrategy::SmallestFirst => Box::new(SmallestFirst),
+            CoinSelectionStrategy::BranchAndBound => Box::new(BranchAndBound {
+                max_excess: dust_threshold,
+            }),
+        }
+    }
+}
+
+/// Picks which UTXOs to spend
+pub trait CoinSelection {
+    /// Select UTXOs (by index into `values`) whose sum is at least `target`.
+    /// Returns `None` if the UTXOs don't cover the target.
+    fn select(&self, values: &[u64], target: u64) -> Option<Vec<usize>>;
+}
+
+/// Take UTXOs in the given order until the target is covered
+fn accumulate(
+    values: &[u64],
+    order: impl Iterator<Item = usize>,
+    target: u64,
+) -> Option<Vec<usize>> {
+    let mut selected = vec![];
+    let mut sum = 0u64;
+    for index in order {
+        if sum >= target {
+            break;
+        }
+        selected.push(index);
+        sum += values[index];
+    }
+    (sum >= target).then_some(selected)
+}
+
+/// Indices of `values` sorted by value, largest first
+fn by_value_descending(values: &[u64]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_by(|&a, &b| values[b].cmp(&values[a]));
+    order
+}
+
+pub struct LargestFirst;
+
+impl CoinSelection for LargestFirst {
+    fn select(&self, values: &[u64], target: u64) -> Option<Vec<usize>> {
+        accumulate(values, by_value_descending(values).into_iter(), target)
+    }
+}
+
+pub struct SmallestFirst;
+
+impl CoinSelection for SmallestFirst {
+    fn select(&self, values: &[u64], target: u64) -> Option<Vec<usize>> {
+        accumulate(
+            values,
+            by_value_descending(values).into_iter().rev(),
+            target,
+        )
+    }
+}
+
+/// Depth-first search for a changeless input set
+///
+/// Explores include/exclude decisions over the UTXOs sorted largest
+/// first, looking for a sum in `target..=target + max_excess`. Branches
+/// that overshoot, or can't reach the target with what is left, are cut.
+/// The search is bounded by `MAX_TRIES` and falls back to largest-first.
+pub struct BranchAndBound {
+    pub max_excess: u64,
+}
+
+impl BranchAndBound {
+    const MAX_TRIES: usize = 100_000;
+
+    fn search(&self, values: &[u64], order: &[usize], target: u64) -> Option<Vec<usize>> {
+        let upper = target.saturating_add(self.max_excess);
+        // remaining[i] = sum of values from order[i..]
+        let mut remaining = vec![0u64; order.len() + 1];
+        for i in (0..order.len()).rev() {
+            remaining[i] = remaining[i + 1] + values[order[i]];
+        }
+        let mut selected: Vec<usize> = vec![];
+        let mut sum = 0u64;
+        let mut depth = 0;
+        let mut tries = 0;
+        loop {
+            tries += 1;
+            if tries > Self::MAX_TRIES {
+                return None;
+            }
+            let backtrack = if sum > upper || sum + remaining[depth] < target {
+                true
+            } else if sum >= target {
+                return Some(selected.iter().map(|&i| order[i]).collect());
+            } else {
+                depth >= order.len()
+            };
+            if backtrack {
+                // undo the last inclusion and try excluding it instead
+                let last = selected.pop()?;
+                sum -= values[order[last]];
+                depth = last + 1;
+            } else {
+                selected.push(depth);
+                sum += values[order[depth]];
+                depth += 1;
+            }
+        }
+    }
+}
+
+impl CoinSelection for BranchAndBound {
+    fn select(&self, values: &[u64], target: u64) -> Option<Vec<usize>> {
+        let order = by_value_descending(values);
+        self.search(values, &order, target)
+            .or_else(|| LargestFirst.select(values, target))
+    }
+}
+
+#[cfg(test)]
+#[path = "coin_selection_tests.rs"]
+mod tests;
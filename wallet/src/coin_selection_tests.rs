@@ -0,0 +1,63 @@
+#[cfg(test)]
+mod coin_selection_tests {
+    use crate::coin_selection::{
+        BranchAndBound, CoinSelection, CoinSelectionStrategy, LargestFirst, SmallestFirst,
+    };
+
+    fn total(values: &[u64], selected: &[usize]) -> u64 {
+        selected.iter().map(|&i| values[i]).sum()
+    }
+
+    #[test]
+    fn test_largest_first_uses_fewest_inputs() {
+        let values = [3, 5, 8];
+        let selected = LargestFirst.select(&values, 10).unwrap();
+        assert_eq!(selected, vec![2, 1]);
+    }
+
+    #[test]
+    fn test_smallest_first_consolidates() {
+        let values = [3, 5, 8, 1];
+        let selected = SmallestFirst.select(&values, 6).unwrap();
+        assert_eq!(selected, vec![3, 0, 1]);
+    }
+
+    #[test]
+    fn test_insufficient_funds() {
+        let values = [3, 5];
+        assert!(LargestFirst.select(&values, 9).is_none());
+        assert!(SmallestFirst.select(&values, 9).is_none());
+        assert!(BranchAndBound { max_excess: 0 }
+            .select(&values, 9)
+            .is_none());
+    }
+
+    #[test]
+    fn test_branch_and_bound_finds_exact_match() {
+        // largest-first would pick 8 + 5 and create change
+        let values = [3, 5, 8, 4];
+        let selected = BranchAndBound { max_excess: 0 }
+            .select(&values, 12)
+            .unwrap();
+        assert_eq!(total(&values, &selected), 12);
+    }
+
+    #[test]
+    fn test_branch_and_bound_respects_excess_window() {
+        let values = [10, 7, 6];
+        let selected = BranchAndBound { max_excess: 1 }
+            .select(&values, 12)
+            .unwrap();
+        assert_eq!(total(&values, &selected), 13);
+    }
+
+    #[test]
+    fn test_branch_and_bound_falls_back_to_largest_first() {
+        let values = [10, 7];
+        let selected = CoinSelectionStrategy::BranchAndBound
+            .selector(0)
+            .select(&values, 12)
+            .unwrap();
+        assert_eq!(selected, vec![0, 1]);
+    }
+}
@@ -0,0 +1,111 @@
+//! Adding, renaming and removing contacts of the config file, from the UI
+//! and the command line. Every change is written back right away.
+use crate::core::{update_config, Recipient};
+use anyhow::{anyhow, bail, Result};
+use btclib::crypto::PublicKey;
+use btclib::util::Saveable;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A public key given in hex, or as a PEM public key file
+pub fn parse_key(key: &str) -> Result<PublicKey> {
+    let key = key.trim();
+    match PublicKey::from_hex(key) {
+        Ok(key) => Ok(key),
+        Err(_) => PublicKey::load_from_file(key)
+            .map_err(|e| anyhow!("{} is neither a hex key nor a key file: {}", key, e)),
+    }
+}
+
+/// Add a contact paying to `key`, a PEM public key file or a key in hex.
+/// A hex key is saved as `contacts/<name>.pub.pem` next to the config
+/// file. Returns the contacts now in the config.
+pub fn add(config_path: &Path, name: &str, key: &str) -> Result<Vec<Recipient>> {
+    let name = check_name(name)?;
+    let public = parse_key(key)?;
+    let config = update_config(config_path, |config| {
+        if config.contacts.iter().any(|contact| contact.name == name) {
+            bail!("There already is a contact named {}", name);
+        }
+        let key_file = if Path::new(key.trim()).is_file() {
+            PathBuf::from(key.trim())
+        } else {
+            let path = key_file(config_path, name);
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            public.save_to_file(&path)?;
+            path
+        };
+        config.contacts.push(Recipient {
+            name: name.to_string(),
+            key: key_file,
+        });
+        Ok(())
+    })?;
+    Ok(config.contacts)
+}
+
+/// Remove the contact called `name`. Its key file is left alone.
+pub fn remove(config_path: &Path, name: &str) -> Result<Vec<Recipient>> {
+    let config = update_config(config_path, |config| {
+        let before = config.contacts.len();
+        config.contacts.retain(|contact| contact.name != name);
+        if config.contacts.len() == before {
+            bail!("No contact named {}", name);
+        }
+        Ok(())
+    })?;
+    Ok(config.contacts)
+}
+
+/// Give the contact called `name` the name `new_name`
+pub fn rename(config_path: &Path, name: &str, new_name: &str) -> Result<Vec<Recipient>> {
+    let new_name = check_name(new_name)?;
+    let config = update_config(config_path, |config| {
+        if name != new_name && config.contacts.iter().any(|c| c.name == new_name) {
+            bail!("There already is a contact named {}", new_name);
+        }
+        let contact = config
+            .contacts
+            .iter_mut()
+            .find(|contact| contact.name == name)
+            .ok_or_else(|| anyhow!("No contact named {}", name))?;
+        contact.name = new_name.to_string();
+        Ok(())
+    })?;
+    Ok(config.contacts)
+}
+
+/// Names are what payments are addressed to, so they must not be empty
+/// or differ only in surrounding whitespace
+fn check_name(name: &str) -> Result<&str> {
+    let name = name.trim();
+    if name.is_empty() {
+        bail!("Contact name must not be empty");
+    }
+    Ok(name)
+}
+
+/// Where the key of a contact added in hex is saved
+fn key_file(config_path: &Path, name: &str) -> PathBuf {
+    let file_name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    config_path
+        .parent()
+        .unwrap_or(Path::new("."))
+        .join("contacts")
+        .join(format!("{}.pub.pem", file_name))
+}
+
+#[cfg(test)]
+#[path = "contacts_tests.rs"]
+mod tests;
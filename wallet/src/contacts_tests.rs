@@ -0,0 +1,44 @@
+#[cfg(test)]
+mod contacts_tests {
+    use crate::contacts;
+    use btclib::crypto::PrivateKey;
+    use btclib::util::Saveable;
+    use std::fs;
+
+    #[test]
+    fn test_contacts_are_added_renamed_and_removed() {
+        let dir = std::env::temp_dir().join(format!("wallet_contacts_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("wallet.toml");
+        crate::util::generate_dummy_config(&config_path).unwrap();
+
+        // a pasted hex key is saved next to the config
+        let carol = PrivateKey::new_key().public_key();
+        let contacts = contacts::add(&config_path, " Carol M. ", &carol.to_hex()).unwrap();
+        let added = contacts.last().unwrap();
+        assert_eq!(added.name, "Carol M.");
+        assert_eq!(added.key, dir.join("contacts").join("Carol_M_.pub.pem"));
+        assert_eq!(added.load().unwrap().key, carol);
+
+        // a key file is referenced where it is
+        let dave = PrivateKey::new_key().public_key();
+        let dave_file = dir.join("dave.pub.pem");
+        dave.save_to_file(&dave_file).unwrap();
+        let contacts = contacts::add(&config_path, "Dave", dave_file.to_str().unwrap()).unwrap();
+        assert_eq!(contacts.last().unwrap().key, dave_file);
+
+        assert!(contacts::add(&config_path, "Dave", &carol.to_hex()).is_err());
+        assert!(contacts::add(&config_path, "", &carol.to_hex()).is_err());
+        assert!(contacts::add(&config_path, "Eve", "not a key").is_err());
+
+        assert!(contacts::rename(&config_path, "Dave", "Carol M.").is_err());
+        contacts::rename(&config_path, "Dave", "David").unwrap();
+        let contacts = contacts::remove(&config_path, "Alice").unwrap();
+        let names: Vec<&str> = contacts.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["Bob", "Carol M.", "David"]);
+        assert!(contacts::remove(&config_path, "Alice").is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,24 +1,32 @@
-use anyhow::Result;
+use crate::cache::{Snapshot, WalletCache};
+use crate::coin_selection::CoinSelectionStrategy;
+use crate::contacts;
+use crate::display::DisplayConfig;
+use crate::history::{History, HistoryEntry, TxStatus};
+use crate::pool::Pool;
+use anyhow::{Context, Result};
+use btclib::auth;
+use btclib::config::BlockchainConfig;
 use btclib::crypto::{PrivateKey, PublicKey, Signature};
+use btclib::hd::{self, ExtendedPrivateKey, Seed, CHANGE_BRANCH, RECEIVE_BRANCH};
 use btclib::network::Message;
-use btclib::types::{Transaction, TransactionOutput};
+use btclib::retry::{self, Retrier, RetryError};
+use btclib::sha256::Hash;
+use btclib::transport::Connection;
+use btclib::types::{Transaction, TransactionOutput, UnsignedTransaction};
 use btclib::util::Saveable;
+use chrono::{DateTime, Utc};
 use crossbeam_skiplist::SkipMap;
 use kanal::Sender;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::PathBuf;
-use std::sync::Arc;
-use tokio::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
 use tokio::sync::Mutex;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
-/// Represent a key pair with paths to public and private keys.
-#[derive(Serialize, Deserialize, Clone)]
-pub struct Key {
-    pub public: PathBuf,
-    pub private: PathBuf,
-}
 /// Represent a recipient with a name and a path to their public key.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Recipient {
@@ -26,13 +34,24 @@ pub struct Recipient {
     pub key: PathBuf,
 }
 
-/// Represent a loaded recipient with their actual public key.
+/// Paths to a standalone key pair (e.g. from `keytool`) to import.
+/// Without a private key the key is watch-only: its UTXOs are shown
+/// but never spent.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Key {
+    pub public: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<PathBuf>,
+}
+
+/// A loaded key pair, derived from the seed or imported.
 #[derive(Clone)]
 struct LoadedKey {
     public: PublicKey,
     private: PrivateKey,
 }
 
+/// Represent a loaded recipient with their actual public key.
 #[derive(Clone)]
 pub struct LoadedRecipient {
     #[allow(dead_code)]
@@ -54,6 +73,9 @@ impl Recipient {
 pub enum FeeType {
     Fixed,
     Percent,
+    /// Ask the node what fee gets the transaction mined within
+    /// `value` blocks
+    Dynamic,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -62,29 +84,286 @@ pub struct FeeConfig {
     pub value: f64,
 }
 
+/// UTXOs together with the key they pay to
+type KeyedUtxos = Vec<(PublicKey, TransactionOutput)>;
+
+/// What paying an amount would cost, before anything is signed or sent
+#[derive(Clone, Copy, Debug)]
+pub struct PaymentPreview {
+    /// Sum of the payments
+    pub amount: u64,
+    /// Fee, including change too small to be worth an output
+    pub fee: u64,
+    /// Value of the change output, 0 if there is none
+    pub change: u64,
+}
+
+/// A payment to one of our keys, waiting to be shown
+#[derive(Clone, Debug)]
+pub struct IncomingPayment {
+    pub amount: u64,
+    /// Key of the output the paying transaction spent, if the node told us
+    pub sender: Option<PublicKey>,
+    /// Already in a block when we first saw it
+    pub confirmed: bool,
+}
+
+/// Marks that no fee estimate has been received yet
+const NO_FEE_ESTIMATE: u64 = u64::MAX;
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
-    pub my_keys: Vec<Key>,
+    /// File holding the master seed all wallet keys are derived from
+    pub seed_file: PathBuf,
+    /// Number of receive keys (m/0'/0/i) handed out so far
+    #[serde(default)]
+    pub receive_keys: u32,
+    /// Number of change keys (m/0'/1/i) used so far
+    #[serde(default)]
+    pub change_keys: u32,
+    /// Standalone key pairs to spend from in addition to the derived ones
+    #[serde(default)]
+    pub imported_keys: Vec<Key>,
     pub contacts: Vec<Recipient>,
     pub default_node: String,
+    /// Nodes to fail over to, in order, while `default_node` can't be
+    /// reached
+    #[serde(default)]
+    pub fallback_nodes: Vec<String>,
+    /// Most connections to the node open at once, so requests of
+    /// different tasks don't wait for each other
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+    /// Encrypt the node connection with Noise, required by nodes
+    /// running with `encrypt_p2p`
+    #[serde(default)]
+    pub encrypt_connection: bool,
+    /// Token for nodes that only serve authenticated wallets
+    #[serde(default)]
+    pub auth_token: Option<String>,
+    /// How UTXOs are picked for a transaction
+    #[serde(default)]
+    pub coin_selection: CoinSelectionStrategy,
+    /// UTXOs with fewer confirmations are not spent
+    #[serde(default)]
+    pub min_confirmations: u64,
+    /// Change up to this amount (in satoshis) is left to the miner instead
+    /// of creating a change output; also the window branch-and-bound
+    /// selection may overshoot the target by
+    #[serde(default)]
+    pub dust_threshold: u64,
+    /// Local log of sent transactions
+    #[serde(default = "default_history_file")]
+    pub history_file: PathBuf,
+    /// Times a transaction that expired from the mempool is sent again
+    /// as is, before the wallet gives up on it and asks for a fee bump
+    #[serde(default = "default_max_rebroadcasts")]
+    pub max_rebroadcasts: u32,
+    /// Directory of the database keeping the last known UTXOs
+    #[serde(default = "default_cache_dir")]
+    pub cache_dir: PathBuf,
     pub fee_config: FeeConfig,
+    /// How amounts are shown
+    #[serde(default)]
+    pub display: DisplayConfig,
+}
+
+fn default_history_file() -> PathBuf {
+    PathBuf::from("wallet_history.jsonl")
+}
+
+fn default_max_rebroadcasts() -> u32 {
+    3
+}
+
+fn default_cache_dir() -> PathBuf {
+    PathBuf::from("wallet_cache")
+}
+
+fn default_max_connections() -> usize {
+    4
+}
+
+/// Serializes changes to config files, so saving key counts and editing
+/// contacts at the same time don't lose each other's change
+static CONFIG_FILE: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Apply `change` to the config file and save it in its place. The file is
+/// re-read so runtime overrides like `--node` are not persisted. Returns
+/// the config as saved.
+pub fn update_config<F>(config_path: &Path, change: F) -> Result<Config>
+where
+    F: FnOnce(&mut Config) -> Result<()>,
+{
+    let _guard = CONFIG_FILE.lock().expect("Config file lock poisoned");
+    let mut config: Config = toml::from_str(&fs::read_to_string(config_path)?)?;
+    change(&mut config)?;
+    let tmp = config_path.with_extension("toml.tmp");
+    fs::write(&tmp, toml::to_string_pretty(&config)?)?;
+    fs::rename(&tmp, config_path)?;
+    Ok(config)
+}
+
+impl Config {
+    /// Nodes in order of preference: the default node, then the fallbacks
+    pub fn nodes(&self) -> Vec<&str> {
+        let mut nodes = vec![self.default_node.as_str()];
+        for node in &self.fallback_nodes {
+            if !nodes.contains(&node.as_str()) {
+                nodes.push(node);
+            }
+        }
+        nodes
+    }
+}
+
+/// Keys derived from the wallet seed, split by branch, plus imported keys
+struct Keychain {
+    account: ExtendedPrivateKey,
+    receive: Vec<LoadedKey>,
+    change: Vec<LoadedKey>,
+    imported: Vec<LoadedKey>,
+    watch_only: Vec<PublicKey>,
+}
+
+impl Keychain {
+    /// Derive the first `receive` and `change` keys of account m/0'
+    fn new(seed: &Seed, receive: u32, change: u32) -> Result<Self> {
+        let master = ExtendedPrivateKey::from_seed(seed.as_bytes())?;
+        let account = master.derive_child(hd::HARDENED)?;
+        let mut keychain = Keychain {
+            account,
+            receive: vec![],
+            change: vec![],
+            imported: vec![],
+            watch_only: vec![],
+        };
+        for _ in 0..receive {
+            keychain.derive_next(RECEIVE_BRANCH)?;
+        }
+        for _ in 0..change {
+            keychain.derive_next(CHANGE_BRANCH)?;
+        }
+        Ok(keychain)
+    }
+
+    /// Derive the next unused key on a branch
+    fn derive_next(&mut self, branch: u32) -> Result<PublicKey> {
+        let keys = if branch == CHANGE_BRANCH {
+            &mut self.change
+        } else {
+            &mut self.receive
+        };
+        let key = self.account.derive_path(&[branch, keys.len() as u32])?;
+        let loaded = LoadedKey {
+            public: key.public_key(),
+            private: key.private_key().clone(),
+        };
+        keys.push(loaded.clone());
+        Ok(loaded.public)
+    }
+
+    /// The key `derive_next` would hand out on a branch, without doing so
+    fn peek_next(&self, branch: u32) -> Result<PublicKey> {
+        let index = if branch == CHANGE_BRANCH {
+            self.change.len()
+        } else {
+            self.receive.len()
+        };
+        Ok(self
+            .account
+            .derive_path(&[branch, index as u32])?
+            .public_key())
+    }
+
+    /// Load imported key pairs; entries without a private key are watch-only
+    fn import(&mut self, keys: &[Key]) -> Result<()> {
+        for key in keys {
+            let public = PublicKey::load_from_file(&key.public)?;
+            match &key.private {
+                Some(private) => {
+                    debug!("Importing key pair: {:?}", key.public);
+                    let private = PrivateKey::load_from_file(private)?;
+                    self.imported.push(LoadedKey { public, private });
+                }
+                None => {
+                    debug!("Watching key: {:?}", key.public);
+                    self.watch_only.push(public);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn all(&self) -> impl Iterator<Item = &LoadedKey> {
+        self.receive
+            .iter()
+            .chain(self.change.iter())
+            .chain(self.imported.iter())
+    }
+
+    /// The key pair with this fingerprint or hex public key
+    fn find(&self, key: &str) -> Option<&LoadedKey> {
+        self.all()
+            .find(|loaded| key_fingerprint(&loaded.public) == key || loaded.public.to_hex() == key)
+    }
 }
 
 #[derive(Clone)]
 struct UtxoStore {
-    my_keys: Vec<LoadedKey>,
+    keychain: Arc<RwLock<Keychain>>,
     utxos: Arc<SkipMap<PublicKey, Vec<(bool, TransactionOutput)>>>,
+    /// Height of the block each UTXO was created in
+    heights: Arc<RwLock<HashMap<Hash, u64>>>,
+    /// Height of the node's chain tip at the last refresh
+    tip_height: Arc<AtomicU64>,
 }
 
 impl UtxoStore {
-    fn new() -> Self {
+    fn new(keychain: Keychain) -> Self {
         Self {
-            my_keys: vec![],
+            keychain: Arc::new(RwLock::new(keychain)),
             utxos: Arc::new(SkipMap::new()),
+            heights: Arc::new(RwLock::new(HashMap::new())),
+            tip_height: Arc::new(AtomicU64::new(0)),
         }
     }
-    fn add_key(&mut self, key: LoadedKey) {
-        self.my_keys.push(key);
+
+    /// Every key to fetch UTXOs for, including watch-only keys
+    fn public_keys(&self) -> Vec<PublicKey> {
+        let keychain = self.keychain.read().expect("Keychain lock poisoned");
+        keychain
+            .all()
+            .map(|key| key.public.clone())
+            .chain(keychain.watch_only.iter().cloned())
+            .collect()
+    }
+
+    /// Keys we hold the private key for
+    fn spendable_keys(&self) -> Vec<PublicKey> {
+        self.keychain
+            .read()
+            .expect("Keychain lock poisoned")
+            .all()
+            .map(|key| key.public.clone())
+            .collect()
+    }
+
+    fn is_watch_only(&self, public: &PublicKey) -> bool {
+        self.keychain
+            .read()
+            .expect("Keychain lock poisoned")
+            .watch_only
+            .contains(public)
+    }
+
+    fn private_key(&self, public: &PublicKey) -> Option<PrivateKey> {
+        self.keychain
+            .read()
+            .expect("Keychain lock poisoned")
+            .all()
+            .find(|key| key.public == *public)
+            .map(|key| key.private.clone())
     }
 }
 
@@ -93,118 +372,1104 @@ pub struct Core {
     pub config: Config,
     utxos: UtxoStore,
     pub tx_sender: Sender<Transaction>,
-    pub stream: Arc<Mutex<TcpStream>>,
+    /// Connections to the node serving requests
+    pool: Arc<Pool<Connection>>,
+    /// Address of the node serving requests
+    active_node: Arc<RwLock<String>>,
+    /// Held while switching to another node, so concurrent failed
+    /// requests fail over once
+    switching: Arc<Mutex<()>>,
+    retrier: Arc<Retrier>,
+    config_path: PathBuf,
+    fee_estimate: Arc<AtomicU64>,
+    /// Sent transactions not yet confirmed, replaced or expired
+    pending: Arc<RwLock<Vec<HistoryEntry>>>,
+    /// Value of mempool outputs paying us, excluding our own change
+    incoming: Arc<AtomicU64>,
+    /// Our transactions the node reported as conflicted since startup
+    conflicted: Arc<RwLock<Vec<Hash>>>,
+    /// Transactions given up on after expiring, whose fee the user
+    /// wasn't asked to bump yet
+    expired: Arc<std::sync::Mutex<Vec<HistoryEntry>>>,
+    /// Outputs paying us seen so far, `None` until the first UTXO update
+    /// (what we own at startup isn't news)
+    seen_outputs: Arc<RwLock<Option<HashSet<Hash>>>>,
+    /// Incoming payments not shown yet
+    incoming_payments: Arc<std::sync::Mutex<Vec<IncomingPayment>>>,
+    /// Where the last known UTXOs are kept, if it could be opened
+    cache: Option<Arc<WalletCache>>,
+    /// When the UTXOs were last fetched from a node
+    updated_at: Arc<RwLock<Option<DateTime<Utc>>>>,
+    /// The contacts of the config file, kept up to date when they are
+    /// edited
+    contacts: Arc<RwLock<Vec<Recipient>>>,
+}
+
+/// Connect to `node` and authenticate if we have a token
+async fn connect_to(config: &Config, node: &str) -> Result<Connection> {
+    let mut stream = Connection::connect(node, config.encrypt_connection).await?;
+    if let Some(token) = &config.auth_token {
+        auth::authenticate(&mut stream, token).await?;
+    }
+    Ok(stream)
+}
+
+/// Connect to the first of the configured nodes that answers. While none
+/// does, all of them are tried again with backoff, up to the configured
+/// number of attempts.
+async fn connect(config: &Config, retrier: &Retrier) -> Result<(String, Connection)> {
+    let retry = retrier.config();
+    let attempts = retry.max_attempts.max(1);
+    for attempt in 1..=attempts {
+        for node in config.nodes() {
+            match retry::with_timeout(retry, connect_to(config, node)).await {
+                Ok(stream) => return Ok((node.to_string(), stream)),
+                Err(e) => warn!("Node {} unreachable: {}", node, e),
+            }
+        }
+        if attempt < attempts {
+            tokio::time::sleep(retry::backoff(retry, attempt)).await;
+        }
+    }
+    Err(anyhow::anyhow!(
+        "No node reachable after {} attempts: {}",
+        attempts,
+        config.nodes().join(", ")
+    ))
 }
 
 impl Core {
-    fn new(config: Config, utxos: UtxoStore, stream: TcpStream) -> Self {
+    fn new(
+        config: Config,
+        config_path: PathBuf,
+        utxos: UtxoStore,
+        connection: Option<(String, Connection)>,
+        retrier: Retrier,
+        pending: Vec<HistoryEntry>,
+        cache: Option<WalletCache>,
+    ) -> Self {
         let (tx_sender, _) = kanal::bounded(10);
+        let (node, pool) = match connection {
+            Some((node, stream)) => (node, Pool::new(config.max_connections, stream)),
+            None => (
+                config.default_node.clone(),
+                Pool::empty(config.max_connections),
+            ),
+        };
+        let contacts = config.contacts.clone();
         Core {
             config,
+            config_path,
             utxos,
             tx_sender,
-            stream: Arc::new(Mutex::new(stream)),
+            pool: Arc::new(pool),
+            active_node: Arc::new(RwLock::new(node)),
+            switching: Arc::new(Mutex::new(())),
+            retrier: Arc::new(retrier),
+            fee_estimate: Arc::new(AtomicU64::new(NO_FEE_ESTIMATE)),
+            pending: Arc::new(RwLock::new(pending)),
+            incoming: Arc::new(AtomicU64::new(0)),
+            conflicted: Arc::new(RwLock::new(vec![])),
+            expired: Arc::new(std::sync::Mutex::new(vec![])),
+            seen_outputs: Arc::new(RwLock::new(None)),
+            incoming_payments: Arc::new(std::sync::Mutex::new(vec![])),
+            cache: cache.map(Arc::new),
+            updated_at: Arc::new(RwLock::new(None)),
+            contacts: Arc::new(RwLock::new(contacts)),
         }
     }
 
-    /// Load the Core from a configuration file
-    pub async fn load(config_path: PathBuf) -> Result<Self> {
+    /// Load the Core from a configuration file, preferring `node` over
+    /// the configured default node if given
+    pub async fn load(config_path: PathBuf, node: Option<String>) -> Result<Self> {
         info!("Loading core from config: {:?}", config_path);
-        let config: Config = toml::from_str(&fs::read_to_string(&config_path)?)?;
-        let mut utxos = UtxoStore::new();
-        let stream = TcpStream::connect(&config.default_node).await?;
-        // Load keys from config
-        for key in &config.my_keys {
-            debug!("Loading key pair: {:?}", key.public);
-            let public = PublicKey::load_from_file(&key.public)?;
-            let private = PrivateKey::load_from_file(&key.private)?;
-            utxos.add_key(LoadedKey { public, private });
+        let mut config: Config = toml::from_str(&fs::read_to_string(&config_path)?)?;
+        if let Some(node) = node {
+            info!("Overriding default node with: {}", node);
+            config.default_node = node;
+        }
+        let retrier = Retrier::new(BlockchainConfig::global().retry.clone());
+        let cache = match WalletCache::open(&config.cache_dir) {
+            Ok(cache) => Some(cache),
+            Err(e) => {
+                warn!("Running without UTXO cache {:?}: {}", config.cache_dir, e);
+                None
+            }
+        };
+        let snapshot = match cache.as_ref().map(WalletCache::load).transpose() {
+            Ok(snapshot) => snapshot.flatten(),
+            Err(e) => {
+                warn!("Ignoring unreadable UTXO cache: {}", e);
+                None
+            }
+        };
+        // With UTXOs to show, start read-only while no node answers and
+        // connect on the first request that finds one
+        let connection = match connect(&config, &retrier).await {
+            Ok(connection) => {
+                info!("Connected to node {}", connection.0);
+                Some(connection)
+            }
+            Err(e) => match &snapshot {
+                Some(snapshot) => {
+                    warn!("{}, showing UTXOs as of {}", e, snapshot.updated_at);
+                    None
+                }
+                None => return Err(e),
+            },
+        };
+        // Derive all keys handed out so far from the seed
+        let seed = if config.seed_file.exists() {
+            debug!("Loading seed: {:?}", config.seed_file);
+            Seed::load_from_file(&config.seed_file)?
+        } else {
+            warn!("No seed at {:?}, generating a new one", config.seed_file);
+            let seed = Seed::generate();
+            seed.save_to_file(&config.seed_file)?;
+            seed
+        };
+        // A fresh wallet always has at least one receive key
+        let fresh = config.receive_keys == 0;
+        config.receive_keys = config.receive_keys.max(1);
+        let mut keychain = Keychain::new(&seed, config.receive_keys, config.change_keys)?;
+        keychain.import(&config.imported_keys)?;
+        // Pick up transactions that were still pending when we last ran
+        let pending = History::load(&config.history_file)?
+            .into_iter()
+            .filter(|entry| entry.status == TxStatus::Pending)
+            .collect();
+        let core = Core::new(
+            config,
+            config_path,
+            UtxoStore::new(keychain),
+            connection,
+            retrier,
+            pending,
+            cache,
+        );
+        if let Some(snapshot) = snapshot {
+            core.restore(snapshot);
+        }
+        if fresh {
+            core.persist_key_counts()?;
         }
-        Ok(Core::new(config, utxos, stream))
+        Ok(core)
+    }
+
+    /// Public keys of all receive keys handed out so far
+    pub fn receive_keys(&self) -> Vec<PublicKey> {
+        self.utxos
+            .keychain
+            .read()
+            .expect("Keychain lock poisoned")
+            .receive
+            .iter()
+            .map(|key| key.public.clone())
+            .collect()
+    }
+
+    /// Derive a fresh receive key, remember it in the config file and
+    /// export its public key next to the seed so it can be shared
+    pub fn new_receive_key(&self) -> Result<(PublicKey, PathBuf)> {
+        let (public, index) = {
+            let mut keychain = self.utxos.keychain.write().expect("Keychain lock poisoned");
+            let public = keychain.derive_next(RECEIVE_BRANCH)?;
+            (public, keychain.receive.len() - 1)
+        };
+        self.persist_key_counts()?;
+        let path = self
+            .config
+            .seed_file
+            .parent()
+            .unwrap_or(Path::new("."))
+            .join(format!("receive-{}.pub.pem", index));
+        public.save_to_file(&path)?;
+        info!("New receive key exported to {}", path.display());
+        Ok((public, path))
+    }
+
+    /// Derive a fresh change key so change never reuses an address
+    fn new_change_key(&self) -> Result<PublicKey> {
+        let public = self
+            .utxos
+            .keychain
+            .write()
+            .expect("Keychain lock poisoned")
+            .derive_next(CHANGE_BRANCH)?;
+        self.persist_key_counts()?;
+        Ok(public)
+    }
+
+    /// Store the number of derived keys in the config file so they are
+    /// derived (and scanned for UTXOs) again on the next start.
+    fn persist_key_counts(&self) -> Result<()> {
+        let (receive, change) = {
+            let keychain = self.utxos.keychain.read().expect("Keychain lock poisoned");
+            (keychain.receive.len() as u32, keychain.change.len() as u32)
+        };
+        update_config(&self.config_path, |config| {
+            config.receive_keys = receive;
+            config.change_keys = change;
+            Ok(())
+        })?;
+        debug!(
+            "Persisted key counts: {} receive, {} change",
+            receive, change
+        );
+        Ok(())
     }
 
     /// Fetch UTXOs from the node for all loaded keys.
     pub async fn fetch_utxos(&self) -> Result<()> {
-        debug!("Fetching UTXOs from node: {}", self.config.default_node);
-        for key in &self.utxos.my_keys {
-            let message = Message::FetchUTXOs(key.public.clone());
-            message.send_async(&mut *self.stream.lock().await).await?;
-            if let Message::UTXOs(utxos) =
-                Message::receive_async(&mut *self.stream.lock().await).await?
-            {
-                debug!("Received {} UTXOs for key: {:?}", utxos.len(), key.public);
-                // Replace the entire UTXO set for this key
-                self.utxos.utxos.insert(
-                    key.public.clone(),
-                    utxos
-                        .into_iter()
-                        .map(|(output, marked)| (marked, output))
-                        .collect(),
-                );
-            } else {
-                error!("Unexpected response from node");
-                return Err(anyhow::anyhow!("Unexpected response from node"));
+        debug!("Fetching UTXOs from node: {}", self.active_node());
+        // before reconciling, which forgets the change of confirmed
+        // transactions
+        let own_change = self.own_change();
+        let mut heights = HashMap::new();
+        for public in self.utxos.public_keys() {
+            let utxos = self.fetch_key_utxos(&public).await?;
+            self.store_utxos(public, utxos, &mut heights);
+        }
+        // Whoever holds our extended public key, e.g. a miner with
+        // --address-rotation, pays keys we haven't handed out yet: take
+        // the next receive key on for as long as it has been paid
+        loop {
+            let next = self
+                .utxos
+                .keychain
+                .read()
+                .expect("Keychain lock poisoned")
+                .peek_next(RECEIVE_BRANCH)?;
+            let utxos = self.fetch_key_utxos(&next).await?;
+            if utxos.is_empty() {
+                break;
             }
+            info!("Discovered payments to receive key {}", next.to_hex());
+            self.utxos
+                .keychain
+                .write()
+                .expect("Keychain lock poisoned")
+                .derive_next(RECEIVE_BRANCH)?;
+            self.persist_key_counts()?;
+            self.store_utxos(next, utxos, &mut heights);
         }
+        *self.utxos.heights.write().expect("Heights lock poisoned") = heights;
+        *self.updated_at.write().expect("Update time lock poisoned") = Some(Utc::now());
         info!("UTXOs fetched successfully");
+        for txid in self.reconcile_pending()? {
+            self.handle_expiry(txid).await?;
+        }
+        let unconfirmed = self.fetch_incoming(&own_change).await?;
+        let confirmed = self
+            .utxos()
+            .into_iter()
+            .map(|(_, _, utxo)| utxo)
+            .filter(|utxo| !own_change.contains(&utxo.hash()));
+        self.note_payments(
+            confirmed
+                .map(|utxo| (utxo, true))
+                .chain(unconfirmed.into_iter().map(|utxo| (utxo, false))),
+        );
+        self.save_cache();
+        if let FeeType::Dynamic = self.config.fee_config.fee_type {
+            self.fetch_fee_estimate().await?;
+        }
         Ok(())
     }
 
+    /// Ask the node for the UTXOs of one key
+    async fn fetch_key_utxos(
+        &self,
+        public: &PublicKey,
+    ) -> Result<Vec<(TransactionOutput, bool, u64)>> {
+        let message = Message::FetchUTXOs(public.clone());
+        if let Message::UTXOs(utxos, tip_height) = self.request(&message).await? {
+            debug!("Received {} UTXOs for key: {:?}", utxos.len(), public);
+            self.utxos.tip_height.store(tip_height, Ordering::Relaxed);
+            Ok(utxos)
+        } else {
+            error!("Unexpected response from node");
+            Err(anyhow::anyhow!("Unexpected response from node"))
+        }
+    }
+
+    /// Replace the entire UTXO set of a key, noting the height of each UTXO
+    fn store_utxos(
+        &self,
+        public: PublicKey,
+        utxos: Vec<(TransactionOutput, bool, u64)>,
+        heights: &mut HashMap<Hash, u64>,
+    ) {
+        self.utxos.utxos.insert(
+            public,
+            utxos
+                .into_iter()
+                .map(|(output, marked, height)| {
+                    heights.insert(output.hash(), height);
+                    (marked, output)
+                })
+                .collect(),
+        );
+    }
+
+    /// Show the UTXOs of the last run until the node tells us better.
+    /// Payments that arrived since then are news.
+    fn restore(&self, snapshot: Snapshot) {
+        let keys = self.utxos.public_keys();
+        let mut heights = self.utxos.heights.write().expect("Heights lock poisoned");
+        let mut seen = HashSet::new();
+        for (key, utxos) in snapshot.utxos {
+            if !keys.contains(&key) {
+                continue;
+            }
+            let utxos = utxos
+                .into_iter()
+                .map(|(marked, output, height)| {
+                    heights.insert(output.hash(), height);
+                    seen.insert(output.hash());
+                    (marked, output)
+                })
+                .collect();
+            self.utxos.utxos.insert(key, utxos);
+        }
+        self.utxos
+            .tip_height
+            .store(snapshot.tip_height, Ordering::Relaxed);
+        *self
+            .seen_outputs
+            .write()
+            .expect("Seen outputs lock poisoned") = Some(seen);
+        *self.updated_at.write().expect("Update time lock poisoned") = Some(snapshot.updated_at);
+        info!("Restored UTXOs as of {}", snapshot.updated_at);
+    }
+
+    /// Keep the UTXOs just fetched for the next start
+    fn save_cache(&self) {
+        let (Some(cache), Some(updated_at)) = (&self.cache, self.updated_at()) else {
+            return;
+        };
+        let heights = self.utxos.heights.read().expect("Heights lock poisoned");
+        let snapshot = Snapshot {
+            utxos: self
+                .utxos
+                .utxos
+                .iter()
+                .map(|entry| {
+                    let utxos = entry
+                        .value()
+                        .iter()
+                        .map(|(marked, output)| {
+                            let height = heights.get(&output.hash()).copied().unwrap_or_default();
+                            (*marked, output.clone(), height)
+                        })
+                        .collect();
+                    (entry.key().clone(), utxos)
+                })
+                .collect(),
+            tip_height: self.utxos.tip_height.load(Ordering::Relaxed),
+            updated_at,
+        };
+        drop(heights);
+        if let Err(e) = cache.save(&snapshot) {
+            error!("Failed to save UTXO cache: {}", e);
+        }
+    }
+
+    /// When the UTXOs shown were fetched from a node, `None` before the
+    /// first update of a wallet without cache
+    pub fn updated_at(&self) -> Option<DateTime<Utc>> {
+        *self.updated_at.read().expect("Update time lock poisoned")
+    }
+
+    /// Settle pending transactions against the refreshed UTXO set:
+    /// - change showing up, or all inputs gone: confirmed
+    /// - inputs still there but no longer reserved: expired from the
+    ///   mempool, returned for [`Self::handle_expiry`] and kept pending
+    fn reconcile_pending(&self) -> Result<Vec<Hash>> {
+        let utxos: HashMap<Hash, bool> = self
+            .utxos()
+            .into_iter()
+            .map(|(_, marked, utxo)| (utxo.hash(), marked))
+            .collect();
+        let mut updates = vec![];
+        let mut expired = vec![];
+        self.pending
+            .write()
+            .expect("Pending lock poisoned")
+            .retain(|entry| {
+                let confirmed = entry.change.iter().any(|hash| utxos.contains_key(hash))
+                    || entry.inputs.iter().all(|hash| !utxos.contains_key(hash));
+                if confirmed {
+                    info!("Transaction {} is now confirmed", entry.txid);
+                    updates.push((entry.txid, TxStatus::Confirmed));
+                    return false;
+                }
+                if entry
+                    .inputs
+                    .iter()
+                    .all(|hash| utxos.get(hash) == Some(&false))
+                {
+                    expired.push(entry.txid);
+                }
+                true
+            });
+        if !updates.is_empty() {
+            History::set_status(&self.config.history_file, &updates)?;
+        }
+        Ok(expired)
+    }
+
+    /// Outputs of our pending transactions paying back to us
+    fn own_change(&self) -> HashSet<Hash> {
+        self.pending
+            .read()
+            .expect("Pending lock poisoned")
+            .iter()
+            .flat_map(|entry| entry.change.iter().copied())
+            .collect()
+    }
+
+    /// Fetch unconfirmed outputs paying our keys that aren't our own
+    /// change, and sum them up
+    async fn fetch_incoming(&self, own_change: &HashSet<Hash>) -> Result<Vec<TransactionOutput>> {
+        let mut incoming = vec![];
+        for public in self.utxos.public_keys() {
+            let message = Message::FetchPendingOutputs(public);
+            let Message::PendingOutputs(outputs) = self.request(&message).await? else {
+                error!("Unexpected response from node");
+                return Err(anyhow::anyhow!("Unexpected response from node"));
+            };
+            incoming.extend(
+                outputs
+                    .into_iter()
+                    .filter(|output| !own_change.contains(&output.hash())),
+            );
+        }
+        self.incoming.store(
+            incoming.iter().map(|output| output.value).sum(),
+            Ordering::Relaxed,
+        );
+        Ok(incoming)
+    }
+
+    /// Queue a notification for each output paying us (and whether it is
+    /// confirmed) that we haven't seen yet. The first call only records
+    /// what we own at startup.
+    fn note_payments(&self, outputs: impl Iterator<Item = (TransactionOutput, bool)>) {
+        let mut seen = self
+            .seen_outputs
+            .write()
+            .expect("Seen outputs lock poisoned");
+        let Some(seen) = seen.as_mut() else {
+            *seen = Some(outputs.map(|(output, _)| output.hash()).collect());
+            return;
+        };
+        let mut payments = self
+            .incoming_payments
+            .lock()
+            .expect("Incoming payments lock poisoned");
+        for (output, confirmed) in outputs {
+            if seen.insert(output.hash()) {
+                info!("Received {} satoshis", output.value);
+                payments.push(IncomingPayment {
+                    amount: output.value,
+                    sender: None,
+                    confirmed,
+                });
+            }
+        }
+    }
+
+    /// The node pushed a new mempool output paying one of our keys. A
+    /// payment from one of our own keys is our change, not news.
+    pub fn handle_payment(&self, output: TransactionOutput, sender: Option<PublicKey>) {
+        let mut seen = self
+            .seen_outputs
+            .write()
+            .expect("Seen outputs lock poisoned");
+        // before the first UTXO update, that update will record it
+        let new = seen.as_mut().is_none_or(|seen| seen.insert(output.hash()));
+        let ours = sender
+            .as_ref()
+            .is_some_and(|sender| self.utxos.public_keys().contains(sender));
+        if !new || ours {
+            return;
+        }
+        info!("Received {} satoshis", output.value);
+        self.incoming_payments
+            .lock()
+            .expect("Incoming payments lock poisoned")
+            .push(IncomingPayment {
+                amount: output.value,
+                sender,
+                confirmed: false,
+            });
+    }
+
+    /// Incoming payments not shown yet, oldest first
+    pub fn take_incoming_payments(&self) -> Vec<IncomingPayment> {
+        std::mem::take(
+            &mut *self
+                .incoming_payments
+                .lock()
+                .expect("Incoming payments lock poisoned"),
+        )
+    }
+
+    /// Open a second connection to the node serving requests and subscribe
+    /// it to notifications about our keys. Requests keep using the pool.
+    pub async fn subscribe(&self) -> Result<Connection> {
+        let node = self.active_node();
+        let mut stream =
+            retry::with_timeout(self.retrier.config(), connect_to(&self.config, &node))
+                .await
+                .map_err(|e| anyhow::anyhow!("Connecting to {} failed: {}", node, e))?;
+        Message::Subscribe(self.utxos.public_keys())
+            .send_async(&mut stream)
+            .await?;
+        Ok(stream)
+    }
+
+    /// The node evicted a transaction involving our keys. If it is one of
+    /// our pending transactions, record it and release its inputs.
+    pub fn handle_conflict(&self, txid: Hash) -> Result<()> {
+        let mut pending = self.pending.write().expect("Pending lock poisoned");
+        let Some(position) = pending.iter().position(|entry| entry.txid == txid) else {
+            debug!("Conflicted transaction {} is not ours", txid);
+            return Ok(());
+        };
+        let entry = pending.remove(position);
+        drop(pending);
+        warn!("Transaction {} conflicted with another transaction", txid);
+        // the node unmarked the inputs, don't wait for the next UTXO update
+        self.set_marked(&entry.inputs, false);
+        self.conflicted
+            .write()
+            .expect("Conflicted lock poisoned")
+            .push(txid);
+        History::set_status(&self.config.history_file, &[(txid, TxStatus::Conflicted)])
+    }
+
+    /// One of our pending transactions expired from the node's mempool:
+    /// send it again, up to `max_rebroadcasts` times. After that, or if
+    /// the node refuses it, give up on it, release its inputs and queue
+    /// it for the user to bump its fee.
+    pub async fn handle_expiry(&self, txid: Hash) -> Result<()> {
+        let entry = self
+            .pending
+            .read()
+            .expect("Pending lock poisoned")
+            .iter()
+            .find(|entry| entry.txid == txid)
+            .cloned();
+        let Some(entry) = entry else {
+            debug!("Expired transaction {} is not ours", txid);
+            return Ok(());
+        };
+        if let Some(transaction) = entry
+            .transaction
+            .clone()
+            .filter(|_| entry.rebroadcasts < self.config.max_rebroadcasts)
+        {
+            match self.submit(transaction).await {
+                Ok(()) => {
+                    info!(
+                        "Transaction {} expired, sent it again ({} of {})",
+                        txid,
+                        entry.rebroadcasts + 1,
+                        self.config.max_rebroadcasts
+                    );
+                    self.set_marked(&entry.inputs, true);
+                    for pending in self
+                        .pending
+                        .write()
+                        .expect("Pending lock poisoned")
+                        .iter_mut()
+                    {
+                        if pending.txid == txid {
+                            pending.rebroadcasts += 1;
+                        }
+                    }
+                    return History::update(&self.config.history_file, |recorded| {
+                        if recorded.txid == txid {
+                            recorded.rebroadcasts += 1;
+                        }
+                    });
+                }
+                Err(e) => warn!("Sending expired transaction {} again failed: {}", txid, e),
+            }
+        }
+        let mut pending = self.pending.write().expect("Pending lock poisoned");
+        let Some(position) = pending.iter().position(|entry| entry.txid == txid) else {
+            // settled while we were sending it
+            return Ok(());
+        };
+        let entry = pending.remove(position);
+        drop(pending);
+        warn!("Transaction {} expired, its fee needs a bump", txid);
+        self.set_marked(&entry.inputs, false);
+        self.expired
+            .lock()
+            .expect("Expired lock poisoned")
+            .push(entry);
+        History::set_status(&self.config.history_file, &[(txid, TxStatus::Expired)])
+    }
+
+    /// Expired transactions the user wasn't asked about yet, oldest first
+    pub fn take_expired(&self) -> Vec<HistoryEntry> {
+        std::mem::take(&mut *self.expired.lock().expect("Expired lock poisoned"))
+    }
+
+    /// Reserve or release our UTXOs with the given hashes, ahead of the
+    /// next UTXO update
+    fn set_marked(&self, hashes: &[Hash], marked: bool) {
+        for utxos in self.utxos.utxos.iter() {
+            if utxos
+                .value()
+                .iter()
+                .any(|(_, utxo)| hashes.contains(&utxo.hash()))
+            {
+                let updated = utxos
+                    .value()
+                    .iter()
+                    .map(|(reserved, utxo)| {
+                        let reserved = if hashes.contains(&utxo.hash()) {
+                            marked
+                        } else {
+                            *reserved
+                        };
+                        (reserved, utxo.clone())
+                    })
+                    .collect();
+                self.utxos.utxos.insert(utxos.key().clone(), updated);
+            }
+        }
+    }
+
+    /// Our transactions that conflicted since the wallet started
+    pub fn conflicted(&self) -> Vec<Hash> {
+        self.conflicted
+            .read()
+            .expect("Conflicted lock poisoned")
+            .clone()
+    }
+
+    /// Satoshis leaving in pending transactions (amounts plus fees) and
+    /// arriving in unconfirmed payments to us
+    pub fn pending_totals(&self) -> (u64, u64) {
+        let outgoing = self
+            .pending
+            .read()
+            .expect("Pending lock poisoned")
+            .iter()
+            .map(|entry| entry.amount + entry.fee)
+            .sum();
+        (outgoing, self.incoming.load(Ordering::Relaxed))
+    }
+
+    /// Ask the node for the fee needed to confirm within the configured
+    /// number of blocks and remember it for the next transactions.
+    pub async fn fetch_fee_estimate(&self) -> Result<u64> {
+        let target_blocks = (self.config.fee_config.value as u32).max(1);
+        match self.request(&Message::EstimateFee(target_blocks)).await? {
+            Message::FeeEstimate(fee) => {
+                debug!("Fee estimate for {} blocks: {}", target_blocks, fee);
+                self.fee_estimate.store(fee, Ordering::Relaxed);
+                Ok(fee)
+            }
+            _ => {
+                error!("Unexpected response from node");
+                Err(anyhow::anyhow!("Unexpected response from node"))
+            }
+        }
+    }
+
     /// Send a transaction to the node.
     pub async fn send_transaction(&self, transaction: Transaction) -> Result<()> {
-        debug!("Sending transaction to node: {}", self.config.default_node);
+        debug!("Sending transaction to node: {}", self.active_node());
+        let entry = self.history_entry(&transaction);
+        self.submit(transaction).await?;
+        info!("Transaction accepted by node");
+        if let Err(e) = History::append(&self.config.history_file, &entry) {
+            error!("Failed to record transaction in history: {}", e);
+        }
+        self.track_pending(entry);
+        Ok(())
+    }
+
+    /// Hand a transaction to the node and wait for it to be accepted
+    async fn submit(&self, transaction: Transaction) -> Result<()> {
         let message = Message::SubmitTransaction(transaction);
-        message.send_async(&mut *self.stream.lock().await).await?;
-        info!("Transaction sent successfully");
+        match self.request(&message).await? {
+            Message::Accepted(_) => {}
+            Message::Reject { code, reason, .. } => {
+                return Err(anyhow::anyhow!(
+                    "Node rejected transaction ({:?}): {}",
+                    code,
+                    reason
+                ));
+            }
+            other => {
+                return Err(anyhow::anyhow!(
+                    "Unexpected response to transaction: {}",
+                    other.kind()
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Ask a regtest node to mine `count` blocks paying `pubkey`, and
+    /// return their hashes
+    pub async fn generate_blocks(&self, count: u32, pubkey: PublicKey) -> Result<Vec<Hash>> {
+        match self
+            .request(&Message::GenerateBlocks(count, pubkey))
+            .await?
+        {
+            Message::GeneratedBlocks(hashes) => Ok(hashes),
+            Message::Reject { reason, .. } => Err(anyhow::anyhow!(
+                "Node refused to generate blocks: {}",
+                reason
+            )),
+            other => Err(anyhow::anyhow!(
+                "Unexpected response to block generation: {}",
+                other.kind()
+            )),
+        }
+    }
+
+    /// Start tracking a sent transaction; earlier pending or expired
+    /// transactions spending any of the same inputs have been replaced
+    /// by it
+    fn track_pending(&self, entry: HistoryEntry) {
+        let spends_same = |other: &HistoryEntry| {
+            other.txid != entry.txid && other.inputs.iter().any(|hash| entry.inputs.contains(hash))
+        };
+        self.expired
+            .lock()
+            .expect("Expired lock poisoned")
+            .retain(|other| !spends_same(other));
+        let mut pending = self.pending.write().expect("Pending lock poisoned");
+        pending.retain(|other| !spends_same(other));
+        let replaced = History::update(&self.config.history_file, |other| {
+            if matches!(other.status, TxStatus::Pending | TxStatus::Expired) && spends_same(other) {
+                info!("Transaction {} replaced by {}", other.txid, entry.txid);
+                other.status = TxStatus::Replaced;
+            }
+        });
+        if let Err(e) = replaced {
+            error!("Failed to update transaction history: {}", e);
+        }
+        pending.push(entry);
+    }
+
+    /// Summarize an outgoing transaction for the local history
+    fn history_entry(&self, transaction: &Transaction) -> HistoryEntry {
+        let ours = self.utxos.spendable_keys();
+        let contacts: Vec<(String, PublicKey)> = self
+            .contacts()
+            .iter()
+            .filter_map(|contact| contact.load().ok())
+            .map(|contact| (contact.name, contact.key))
+            .collect();
+        let mut amount = 0;
+        let mut recipients = vec![];
+        let mut change = vec![];
+        for output in &transaction.outputs {
+            if ours.contains(&output.pubkey) {
+                change.push(output.hash());
+                continue;
+            }
+            amount += output.value;
+            recipients.push(
+                contacts
+                    .iter()
+                    .find(|(_, key)| *key == output.pubkey)
+                    .map(|(name, _)| name.clone())
+                    .unwrap_or_else(|| key_fingerprint(&output.pubkey)),
+            );
+        }
+        if recipients.is_empty() {
+            recipients.push("self".to_string());
+        }
+        let spent: u64 = transaction
+            .inputs
+            .iter()
+            .filter_map(|input| self.utxo_value(&input.prev_transaction_output_hash))
+            .sum();
+        let sent: u64 = transaction.outputs.iter().map(|output| output.value).sum();
+        HistoryEntry {
+            txid: transaction.hash(),
+            timestamp: chrono::Utc::now(),
+            amount,
+            fee: spent.saturating_sub(sent),
+            recipients,
+            status: TxStatus::Pending,
+            inputs: transaction
+                .inputs
+                .iter()
+                .map(|input| input.prev_transaction_output_hash)
+                .collect(),
+            change,
+            transaction: Some(transaction.clone()),
+            rebroadcasts: 0,
+        }
+    }
+
+    /// Value of one of our cached UTXOs by output hash
+    fn utxo_value(&self, hash: &Hash) -> Option<u64> {
+        self.find_utxo(hash).map(|(_, utxo)| utxo.value)
+    }
+
+    /// One of our cached UTXOs by output hash, with the key it pays
+    fn find_utxo(&self, hash: &Hash) -> Option<(PublicKey, TransactionOutput)> {
+        self.utxos.utxos.iter().find_map(|entry| {
+            entry
+                .value()
+                .iter()
+                .find(|(_, utxo)| utxo.hash() == *hash)
+                .map(|(_, utxo)| (entry.key().clone(), utxo.clone()))
+        })
+    }
+
+    /// Watch-only keys, whose UTXOs are shown but never spent
+    pub fn watch_only_keys(&self) -> Vec<PublicKey> {
+        self.utxos
+            .keychain
+            .read()
+            .expect("Keychain lock poisoned")
+            .watch_only
+            .clone()
+    }
+
+    pub fn is_watch_only(&self, public: &PublicKey) -> bool {
+        self.utxos.is_watch_only(public)
+    }
+
+    /// All cached UTXOs with their key and whether they are reserved
+    /// by a pending mempool transaction
+    pub fn utxos(&self) -> Vec<(PublicKey, bool, TransactionOutput)> {
+        self.utxos
+            .utxos
+            .iter()
+            .flat_map(|entry| {
+                let pubkey = entry.key().clone();
+                entry
+                    .value()
+                    .iter()
+                    .map(|(marked, utxo)| (pubkey.clone(), *marked, utxo.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Send a request to the node and wait for the response, bounded by the
+    /// configured timeout. Each request has a pooled connection to itself
+    /// for the whole exchange, so concurrent requests can't read each
+    /// other's responses but don't wait for each other either. If the node
+    /// doesn't answer, the request is repeated once on the next reachable
+    /// node.
+    async fn request(&self, message: &Message) -> Result<Message> {
+        let generation = match self.try_request(message).await {
+            Ok(response) => return Ok(response),
+            Err((generation, e)) => {
+                warn!("Node {} failed: {}, failing over", self.active_node(), e);
+                generation
+            }
+        };
+        self.fail_over(generation).await?;
+        self.try_request(message).await.map_err(|(_, e)| e)
+    }
+
+    /// One exchange on a pooled connection. On failure the connection is
+    /// dropped and the error comes with the pool generation it belonged to.
+    async fn try_request(&self, message: &Message) -> Result<Message, (u64, anyhow::Error)> {
+        let generation = self.pool.generation();
+        let mut lease = self
+            .pool
+            .acquire(|| async {
+                let node = self.active_node();
+                debug!("Opening another connection to {}", node);
+                connect_to(&self.config, &node).await
+            })
+            .await
+            .map_err(|e| (generation, e))?;
+        let exchange = async {
+            let stream = lease.connection();
+            message.send_async(&mut *stream).await?;
+            Ok::<_, anyhow::Error>(Message::receive_async(&mut *stream).await?)
+        };
+        let response = match retry::with_timeout(self.retrier.config(), exchange).await {
+            Ok(response) => response,
+            Err(RetryError::Failed(e)) => return Err((lease.generation(), e)),
+            Err(e) => {
+                let e = anyhow::anyhow!("Request to node failed: {}", e);
+                return Err((lease.generation(), e));
+            }
+        };
+        lease.release();
+        Ok(response)
+    }
+
+    /// Connect to the most preferred node that answers and make it the
+    /// one serving requests, unless another request already did since the
+    /// pool `generation` failed
+    async fn fail_over(&self, generation: u64) -> Result<()> {
+        let _switching = self.switching.lock().await;
+        if self.pool.generation() != generation {
+            return Ok(());
+        }
+        let (node, stream) = connect(&self.config, &self.retrier).await?;
+        info!("Now connected to node {}", node);
+        self.switch_to(node, stream);
         Ok(())
     }
 
+    fn switch_to(&self, node: String, stream: Connection) {
+        *self.active_node.write().expect("Node lock poisoned") = node;
+        self.pool.reset(stream);
+    }
+
+    /// Address of the node currently serving requests
+    pub fn active_node(&self) -> String {
+        self.active_node.read().expect("Node lock poisoned").clone()
+    }
+
+    /// Check that the node serving requests still answers, failing over
+    /// if it doesn't. While on a fallback node, switch back to the default
+    /// node as soon as it is reachable again.
+    pub async fn check_health(&self) -> Result<()> {
+        let active = self.active_node();
+        if active != self.config.default_node {
+            let node = &self.config.default_node;
+            if let Ok(stream) =
+                retry::with_timeout(self.retrier.config(), connect_to(&self.config, node)).await
+            {
+                info!("Default node {} is back, switching from {}", node, active);
+                let _switching = self.switching.lock().await;
+                self.switch_to(node.clone(), stream);
+                return Ok(());
+            }
+        }
+        match self.request(&Message::GetStatus).await? {
+            Message::Status(_) => Ok(()),
+            other => Err(anyhow::anyhow!(
+                "Unexpected response to status request: {}",
+                other.kind()
+            )),
+        }
+    }
+
+    /// Which node serves requests, for the UI
+    pub fn node_status(&self) -> String {
+        let active = self.active_node();
+        if active == self.config.default_node {
+            active
+        } else {
+            format!("{} (failover from {})", active, self.config.default_node)
+        }
+    }
+
     /// Prepare and send a transaction asynchronously.
     pub fn send_transaction_async(&self, recipient: &str, amount: u64) -> Result<()> {
         info!("Preparing to send {} satoshis to {}", amount, recipient);
-        let recipient_key = self
-            .config
-            .contacts
-            .iter()
-            .find(|r| r.name == recipient)
-            .ok_or_else(|| anyhow::anyhow!("Recipient not found"))?
-            .load()?
-            .key;
+        let recipient_key = self.contact_key(recipient)?;
         let transaction = self.create_transaction(&recipient_key, amount)?;
         debug!("Sending transaction asynchronously");
         self.tx_sender.send(transaction)?;
         Ok(())
     }
 
+    /// Prepare and send one transaction paying several contacts asynchronously.
+    pub fn send_batch_transaction_async(&self, payments: &[(String, u64)]) -> Result<()> {
+        info!("Preparing batch payment to {} recipients", payments.len());
+        let payments = payments
+            .iter()
+            .map(|(recipient, amount)| Ok((self.contact_key(recipient)?, *amount)))
+            .collect::<Result<Vec<_>>>()?;
+        let transaction = self.create_batch_transaction(payments)?;
+        debug!("Sending batch transaction asynchronously");
+        self.tx_sender.send(transaction)?;
+        Ok(())
+    }
+
+    /// Look up a contact's public key by name.
+    pub fn contact_key(&self, name: &str) -> Result<PublicKey> {
+        Ok(self
+            .contacts()
+            .iter()
+            .find(|r| r.name == name)
+            .ok_or_else(|| anyhow::anyhow!("Recipient not found: {}", name))?
+            .load()?
+            .key)
+    }
+
+    /// The contacts, in the order of the config file
+    pub fn contacts(&self) -> Vec<Recipient> {
+        self.contacts
+            .read()
+            .expect("Contacts lock poisoned")
+            .clone()
+    }
+
+    /// Add a contact and save it to the config file, see [`contacts::add`]
+    pub fn add_contact(&self, name: &str, key: &str) -> Result<()> {
+        let contacts = contacts::add(&self.config_path, name, key)?;
+        info!("Added contact {}", name.trim());
+        self.set_contacts(contacts);
+        Ok(())
+    }
+
+    /// Remove a contact from the config file
+    pub fn remove_contact(&self, name: &str) -> Result<()> {
+        let contacts = contacts::remove(&self.config_path, name)?;
+        info!("Removed contact {}", name);
+        self.set_contacts(contacts);
+        Ok(())
+    }
+
+    /// Rename a contact in the config file
+    pub fn rename_contact(&self, name: &str, new_name: &str) -> Result<()> {
+        let contacts = contacts::rename(&self.config_path, name, new_name)?;
+        info!("Renamed contact {} to {}", name, new_name.trim());
+        self.set_contacts(contacts);
+        Ok(())
+    }
+
+    fn set_contacts(&self, contacts: Vec<Recipient>) {
+        *self.contacts.write().expect("Contacts lock poisoned") = contacts;
+    }
+
     /// Creates a transaction by selecting UTXOs and generating signatures.
     ///
-    /// This function implements a simple greedy coin selection algorithm:
-    /// it iterates through available UTXOs and adds them to the transaction
-    /// until the required amount (payment + fee) is covered.
+    /// UTXOs are picked by the configured [`CoinSelectionStrategy`] until
+    /// the required amount (payment + fee) is covered.
     ///
-    /// # Coin Selection Algorithm:
+    /// # Coin Selection Example (largest-first):
     ///
     /// ```text
     /// Goal: Send 10 BTC with 0.1 BTC fee (need 10.1 BTC total)
     ///
     /// Available UTXOs:
     /// - UTXO A: 3 BTC
-    /// - UTXO B: 5 BTC  
+    /// - UTXO B: 5 BTC
     /// - UTXO C: 8 BTC
     ///
     /// Selection process:
-    /// 1. Add UTXO A: 3 BTC (total: 3, need: 10.1) - not enough
-    /// 2. Add UTXO B: 5 BTC (total: 8, need: 10.1) - not enough
-    /// 3. Add UTXO C: 8 BTC (total: 16, need: 10.1) - enough!
+    /// 1. Add UTXO C: 8 BTC (total: 8, need: 10.1) - not enough
+    /// 2. Add UTXO B: 5 BTC (total: 13, need: 10.1) - enough!
     ///
     /// Transaction created:
-    /// Inputs: [UTXO A, UTXO B, UTXO C] = 16 BTC
+    /// Inputs: [UTXO C, UTXO B] = 13 BTC
     /// Outputs:
     ///   - 10 BTC → recipient
-    ///   - 5.9 BTC → self (change)
+    ///   - 2.9 BTC → fresh change key of ours (m/0'/1/i)
     /// Fee: 0.1 BTC (implicit, goes to miner)
     /// ```
     ///
+    /// Branch-and-bound would prefer a set summing to exactly 10.1 BTC
+    /// (within `dust_threshold`) so no change output is needed.
+    ///
     /// # Arguments
     /// * `recipient` - Public key of the recipient
     /// * `amount` - Amount to send in satoshis
@@ -213,77 +1478,347 @@ impl Core {
     /// * `Ok(Transaction)` - A signed transaction ready to broadcast
     /// * `Err` - If insufficient funds or signing fails
     pub fn create_transaction(&self, recipient: &PublicKey, amount: u64) -> Result<Transaction> {
-        // STEP 1: Calculate total amount needed (payment + fee)
-        let fee = self.calculate_fee(amount);
-        let total_amount = amount + fee;
-
-        // STEP 2: Coin selection - gather enough UTXOs using greedy algorithm
-        let mut inputs = Vec::new();
-        let mut input_sum = 0;
-
-        // Iterate through all our UTXOs across all keys
-        for entry in self.utxos.utxos.iter() {
-            let pubkey = entry.key();
-            let utxos = entry.value();
-
-            for (marked, utxo) in utxos.iter() {
-                // Skip UTXOs reserved by pending mempool transactions
-                if *marked {
-                    continue;
-                }
+        self.create_batch_transaction(vec![(recipient.clone(), amount)])
+    }
 
-                // Stop if we already have enough
-                if input_sum >= total_amount {
-                    break;
-                }
+    /// Creates one transaction paying several recipients.
+    ///
+    /// All payments share the same inputs and a single change output, so
+    /// the wallet pays one fee (computed on the total amount) instead of
+    /// one per recipient.
+    pub fn create_batch_transaction(&self, payments: Vec<(PublicKey, u64)>) -> Result<Transaction> {
+        let (selected, outputs) =
+            self.fund_payments(payments, self.spendable_utxos(), |_| self.new_change_key())?;
+        Ok(Transaction::new(self.sign_inputs(&selected)?, outputs))
+    }
 
-                // Add this UTXO as input and sign it with the corresponding private key
-                inputs.push(btclib::types::TransactionInput {
-                    prev_transaction_output_hash: utxo.hash(),
-                    signature: Signature::sign_output(
-                        &utxo.hash(),
-                        &mut self
-                            .utxos
-                            .my_keys
-                            .iter()
-                            .find(|k| k.public == *pubkey)
-                            .unwrap()
-                            .private
-                            .clone(),
-                    ),
-                });
-                input_sum += utxo.value;
-            }
+    /// Creates a transaction to be signed elsewhere, e.g. on an air-gapped
+    /// machine holding the keys. Unlike [`Self::create_batch_transaction`],
+    /// UTXOs of watch-only keys are spent too; if the first input belongs
+    /// to a watch-only key, change goes back to that key.
+    pub fn create_unsigned_transaction(
+        &self,
+        payments: Vec<(PublicKey, u64)>,
+    ) -> Result<UnsignedTransaction> {
+        let (selected, outputs) = self.fund_payments(
+            payments,
+            self.unreserved_utxos(),
+            |selected| match selected.first() {
+                Some((pubkey, _)) if self.utxos.is_watch_only(pubkey) => Ok(pubkey.clone()),
+                _ => self.new_change_key(),
+            },
+        )?;
+        Ok(UnsignedTransaction::new(
+            selected.into_iter().map(|(_, utxo)| utxo).collect(),
+            outputs,
+        ))
+    }
 
-            // Check if we've collected enough across all keys
-            if input_sum >= total_amount {
-                break;
-            }
+    /// Select inputs among `candidates` to cover the payments plus fee, and
+    /// build the outputs. Change, if any, goes to the key `change_key`
+    /// picks given the selected inputs.
+    fn fund_payments(
+        &self,
+        payments: Vec<(PublicKey, u64)>,
+        candidates: KeyedUtxos,
+        change_key: impl FnOnce(&[(PublicKey, TransactionOutput)]) -> Result<PublicKey>,
+    ) -> Result<(KeyedUtxos, Vec<TransactionOutput>)> {
+        if payments.is_empty() {
+            return Err(anyhow::anyhow!("No recipients"));
         }
-
-        // STEP 3: Verify we have sufficient funds
-        if input_sum < total_amount {
-            return Err(anyhow::anyhow!("Insufficient funds"));
+        if payments.iter().any(|(_, amount)| *amount == 0) {
+            return Err(anyhow::anyhow!("Payment amounts must be positive"));
         }
 
-        // STEP 4: Create outputs (payment to recipient)
-        let mut outputs = vec![TransactionOutput {
-            value: amount,
-            unique_id: uuid::Uuid::new_v4(),
-            pubkey: recipient.clone(),
-        }];
+        // STEP 1 and 2: Select inputs covering the payments plus fee
+        let amount = payments
+            .iter()
+            .try_fold(0u64, |total, (_, amount)| total.checked_add(*amount))
+            .ok_or_else(|| anyhow::anyhow!("Amount too large"))?;
+        let (selected, preview) = self.select_funds(amount, &candidates)?;
 
-        // STEP 5: Add change output if we have excess (send back to ourselves)
-        if input_sum > total_amount {
+        // STEP 3: Create outputs (one payment per recipient)
+        let mut outputs: Vec<TransactionOutput> = payments
+            .into_iter()
+            .map(|(pubkey, value)| TransactionOutput {
+                value,
+                unique_id: uuid::Uuid::new_v4(),
+                pubkey,
+            })
+            .collect();
+
+        // STEP 4: Add change output if we have excess (send back to a key of ours),
+        // unless it is dust that is cheaper to leave to the miner
+        if preview.change > 0 {
             outputs.push(TransactionOutput {
-                value: input_sum - total_amount,
+                value: preview.change,
                 unique_id: uuid::Uuid::new_v4(),
-                pubkey: self.utxos.my_keys[0].public.clone(),
+                pubkey: change_key(&selected)?,
             });
         }
 
-        // STEP 6: Return the completed, signed transaction
-        Ok(Transaction { inputs, outputs })
+        Ok((selected, outputs))
+    }
+
+    /// Select inputs among `candidates` to pay `amount` plus fee with the
+    /// configured coin selection strategy
+    fn select_funds(
+        &self,
+        amount: u64,
+        candidates: &KeyedUtxos,
+    ) -> Result<(KeyedUtxos, PaymentPreview)> {
+        let fee = self.calculate_fee(amount)?;
+        let total_amount = amount
+            .checked_add(fee)
+            .ok_or_else(|| anyhow::anyhow!("Amount too large"))?;
+
+        let values: Vec<u64> = candidates.iter().map(|(_, utxo)| utxo.value).collect();
+        let selected = self
+            .config
+            .coin_selection
+            .selector(self.config.dust_threshold)
+            .select(&values, total_amount)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Insufficient funds: {} sats plus {} sats fee needed, {} sats spendable",
+                    amount,
+                    fee,
+                    values.iter().sum::<u64>()
+                )
+            })?;
+
+        let selected: KeyedUtxos = selected
+            .into_iter()
+            .map(|index| candidates[index].clone())
+            .collect();
+        let excess = selected.iter().map(|(_, utxo)| utxo.value).sum::<u64>() - total_amount;
+        let preview = if excess > self.config.dust_threshold {
+            PaymentPreview {
+                amount,
+                fee,
+                change: excess,
+            }
+        } else {
+            PaymentPreview {
+                amount,
+                fee: fee + excess,
+                change: 0,
+            }
+        };
+        Ok((selected, preview))
+    }
+
+    /// Fee and change of paying `amount` from the spendable UTXOs, without
+    /// reserving anything. Fails like sending would, e.g. on insufficient
+    /// funds.
+    pub fn preview_payment(&self, amount: u64) -> Result<PaymentPreview> {
+        if amount == 0 {
+            return Err(anyhow::anyhow!("Payment amounts must be positive"));
+        }
+        Ok(self.select_funds(amount, &self.spendable_utxos())?.1)
+    }
+
+    /// Creates a transaction spending every unreserved UTXO to a single
+    /// output. The fee is deducted from the total. Without a destination
+    /// the funds go to a fresh change key of ours, consolidating them.
+    pub fn create_sweep_transaction(&self, destination: Option<&PublicKey>) -> Result<Transaction> {
+        let utxos = self.spendable_utxos();
+        if utxos.is_empty() {
+            return Err(anyhow::anyhow!("No funds to sweep"));
+        }
+        let total: u64 = utxos.iter().map(|(_, utxo)| utxo.value).sum();
+        let amount = self.amount_after_fee(total)?;
+        if amount == 0 {
+            return Err(anyhow::anyhow!("Balance too small to cover the fee"));
+        }
+        let pubkey = match destination {
+            Some(pubkey) => pubkey.clone(),
+            None => self.new_change_key()?,
+        };
+        info!(
+            "Sweeping {} UTXOs ({} satoshis, {} fee)",
+            utxos.len(),
+            amount,
+            total - amount
+        );
+        Ok(Transaction::new(
+            self.sign_inputs(&utxos)?,
+            vec![TransactionOutput {
+                value: amount,
+                unique_id: uuid::Uuid::new_v4(),
+                pubkey,
+            }],
+        ))
+    }
+
+    /// Prepare and send a sweep transaction asynchronously. `recipient` is a
+    /// contact name; `None` consolidates the funds back into the wallet.
+    pub fn sweep_async(&self, recipient: Option<&str>) -> Result<()> {
+        let destination = recipient.map(|name| self.contact_key(name)).transpose()?;
+        let transaction = self.create_sweep_transaction(destination.as_ref())?;
+        self.tx_sender.send(transaction)?;
+        Ok(())
+    }
+
+    /// Rebuild one of our pending or expired transactions with a higher
+    /// fee: twice the old one, or what the fee config asks for now if
+    /// that is more. It spends the same inputs and makes the same
+    /// payments, the fee comes out of the change. A pending original is
+    /// replaced by it in the mempool.
+    pub fn create_fee_bump(&self, txid: &Hash) -> Result<Transaction> {
+        let entry = History::load(&self.config.history_file)?
+            .into_iter()
+            .find(|entry| entry.txid == *txid)
+            .ok_or_else(|| anyhow::anyhow!("Transaction {} is not in the history", txid))?;
+        if !matches!(entry.status, TxStatus::Pending | TxStatus::Expired) {
+            return Err(anyhow::anyhow!(
+                "Transaction {} is {:?}, only pending or expired ones can be bumped",
+                txid,
+                entry.status
+            ));
+        }
+        let original = entry.transaction.ok_or_else(|| {
+            anyhow::anyhow!(
+                "Transaction {} wasn't kept in the history, send it again",
+                txid
+            )
+        })?;
+        let inputs = entry
+            .inputs
+            .iter()
+            .map(|hash| {
+                self.find_utxo(hash)
+                    .ok_or_else(|| anyhow::anyhow!("Input {} is already spent", hash))
+            })
+            .collect::<Result<KeyedUtxos>>()?;
+        let fee = entry
+            .fee
+            .checked_mul(2)
+            .ok_or_else(|| anyhow::anyhow!("Fee of {} is too large to bump", txid))?
+            .max(entry.fee + 1)
+            .max(self.calculate_fee(entry.amount).unwrap_or(0));
+        let increase = fee - entry.fee;
+        let mut outputs = original.outputs;
+        let change = outputs
+            .iter()
+            .position(|output| entry.change.contains(&output.hash()))
+            .filter(|position| outputs[*position].value >= increase)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Not enough change to pay {} more fee, send the payment again",
+                    increase
+                )
+            })?;
+        if outputs[change].value == increase {
+            outputs.remove(change);
+        } else {
+            outputs[change].value -= increase;
+        }
+        info!("Bumping the fee of {} from {} to {}", txid, entry.fee, fee);
+        Ok(Transaction::new(self.sign_inputs(&inputs)?, outputs))
+    }
+
+    /// Bump the fee of a transaction and send it asynchronously
+    pub fn bump_fee_async(&self, txid: &Hash) -> Result<()> {
+        let transaction = self.create_fee_bump(txid)?;
+        self.tx_sender.send(transaction)?;
+        Ok(())
+    }
+
+    /// All UTXOs not reserved by pending mempool transactions, with their
+    /// key. Watch-only keys are left out.
+    fn spendable_utxos(&self) -> Vec<(PublicKey, TransactionOutput)> {
+        self.unreserved_utxos()
+            .into_iter()
+            .filter(|(pubkey, _)| !self.utxos.is_watch_only(pubkey))
+            .collect()
+    }
+
+    /// All UTXOs not reserved by pending mempool transactions and with
+    /// at least `min_confirmations` confirmations, with their key
+    fn unreserved_utxos(&self) -> Vec<(PublicKey, TransactionOutput)> {
+        self.utxos
+            .utxos
+            .iter()
+            .flat_map(|entry| {
+                let pubkey = entry.key().clone();
+                entry
+                    .value()
+                    .iter()
+                    .filter(|(marked, _)| !*marked)
+                    .filter(|(_, utxo)| self.confirmations(utxo) >= self.config.min_confirmations)
+                    .map(|(_, utxo)| (pubkey.clone(), utxo.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Number of blocks confirming a UTXO, counting the one that created it
+    pub fn confirmations(&self, utxo: &TransactionOutput) -> u64 {
+        let tip_height = self.utxos.tip_height.load(Ordering::Relaxed);
+        self.utxos
+            .heights
+            .read()
+            .expect("Heights lock poisoned")
+            .get(&utxo.hash())
+            .map_or(0, |height| tip_height.saturating_sub(*height) + 1)
+    }
+
+    /// Sign the given UTXOs as transaction inputs
+    fn sign_inputs(
+        &self,
+        utxos: &[(PublicKey, TransactionOutput)],
+    ) -> Result<Vec<btclib::types::TransactionInput>> {
+        utxos
+            .iter()
+            .map(|(pubkey, utxo)| {
+                let private_key = self
+                    .utxos
+                    .private_key(pubkey)
+                    .ok_or_else(|| anyhow::anyhow!("No private key for UTXO"))?;
+                Ok(btclib::types::TransactionInput {
+                    prev_transaction_output_hash: utxo.hash(),
+                    signature: Signature::sign_output(&utxo.hash(), &private_key),
+                })
+            })
+            .collect()
+    }
+
+    /// Largest amount that can be sent out of `total` once the fee for
+    /// sending it is paid
+    fn amount_after_fee(&self, total: u64) -> Result<u64> {
+        Ok(match self.config.fee_config.fee_type {
+            FeeType::Fixed | FeeType::Dynamic => total.saturating_sub(self.calculate_fee(total)?),
+            FeeType::Percent => {
+                // amount + amount * p / 100 = total
+                let amount = (total as f64 * 100.0 / (100.0 + self.config.fee_config.value)) as u64;
+                // guard against float rounding: the fee must be fully covered
+                let mut amount = amount.min(total);
+                while amount > 0 && amount + self.calculate_fee(amount)? > total {
+                    amount -= 1;
+                }
+                amount
+            }
+        })
+    }
+
+    /// Value of all our UTXOs, including those reserved by pending
+    /// transactions. Watch-only keys are not included.
+    pub fn confirmed_balance(&self) -> u64 {
+        self.utxos()
+            .iter()
+            .filter(|(pubkey, _, _)| !self.utxos.is_watch_only(pubkey))
+            .map(|(_, _, utxo)| utxo.value)
+            .sum()
+    }
+
+    /// Value of the UTXOs of watch-only keys
+    pub fn watch_only_balance(&self) -> u64 {
+        self.utxos()
+            .iter()
+            .filter(|(pubkey, _, _)| self.utxos.is_watch_only(pubkey))
+            .map(|(_, _, utxo)| utxo.value)
+            .sum()
     }
 
     pub fn get_balance(&self) -> u64 {
@@ -291,6 +1826,7 @@ impl Core {
             .utxos
             .utxos
             .iter()
+            .filter(|entry| !self.utxos.is_watch_only(entry.key()))
             .map(|entry| {
                 let total_for_key = entry
                     .value()
@@ -310,14 +1846,67 @@ impl Core {
         balance
     }
 
-    fn calculate_fee(&self, amount: u64) -> u64 {
+    fn calculate_fee(&self, amount: u64) -> Result<u64> {
         match self.config.fee_config.fee_type {
-            FeeType::Fixed => self.config.fee_config.value as u64,
-            FeeType::Percent => (amount as f64 * self.config.fee_config.value / 100.0) as u64,
+            FeeType::Fixed => Ok(self.config.fee_config.value as u64),
+            FeeType::Percent => Ok((amount as f64 * self.config.fee_config.value / 100.0) as u64),
+            FeeType::Dynamic => match self.fee_estimate.load(Ordering::Relaxed) {
+                NO_FEE_ESTIMATE => Err(anyhow::anyhow!("No fee estimate from the node yet")),
+                fee => Ok(fee),
+            },
         }
     }
 }
 
+/// Keys beyond the configured counts an offline signer also derives,
+/// in case the online wallet handed out more keys than it knows of
+const SIGNING_LOOKAHEAD: u32 = 20;
+
+/// Sign an unsigned transaction with the keys of the wallet configured at
+/// `config_path`. No node connection is made, so this works on an
+/// air-gapped machine. Returns the number of inputs signed.
+pub fn sign_offline(config_path: &Path, unsigned: &mut UnsignedTransaction) -> Result<usize> {
+    let keychain = signing_keychain(config_path)?;
+    Ok(keychain.all().map(|key| unsigned.sign(&key.private)).sum())
+}
+
+/// Sign a text message with one of the wallet's keys, given by its
+/// fingerprint or hex public key, proving control of it. Also works
+/// offline.
+pub fn sign_message(
+    config_path: &Path,
+    key: &str,
+    message: &str,
+) -> Result<(PublicKey, Signature)> {
+    let keychain = signing_keychain(config_path)?;
+    let loaded = keychain
+        .find(key)
+        .ok_or_else(|| anyhow::anyhow!("No key {} in this wallet", key))?;
+    Ok((
+        loaded.public.clone(),
+        Signature::sign_message(message, &loaded.private),
+    ))
+}
+
+/// The wallet's keys, derived from the seed with lookahead
+fn signing_keychain(config_path: &Path) -> Result<Keychain> {
+    let config: Config = toml::from_str(&fs::read_to_string(config_path)?)?;
+    let seed = Seed::load_from_file(&config.seed_file)
+        .with_context(|| format!("Failed to load seed {}", config.seed_file.display()))?;
+    let mut keychain = Keychain::new(
+        &seed,
+        config.receive_keys + SIGNING_LOOKAHEAD,
+        config.change_keys + SIGNING_LOOKAHEAD,
+    )?;
+    keychain.import(&config.imported_keys)?;
+    Ok(keychain)
+}
+
+/// Short, human-readable identifier of a public key
+pub fn key_fingerprint(key: &PublicKey) -> String {
+    Hash::hash(key).to_string().chars().take(8).collect()
+}
+
 #[cfg(test)]
 #[path = "core_tests.rs"]
 mod tests;
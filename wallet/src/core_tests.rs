@@ -1,13 +1,30 @@
 #[cfg(test)]
 mod core_tests {
-    use crate::core::{Config, FeeConfig, FeeType};
+    use crate::coin_selection::CoinSelectionStrategy;
+    use crate::core::{key_fingerprint, Config, FeeConfig, FeeType, Keychain};
+    use crate::display::DisplayConfig;
+    use btclib::hd::{Seed, CHANGE_BRANCH};
 
     fn create_test_config(fee_type: FeeType, value: f64) -> Config {
         Config {
-            my_keys: vec![],
+            seed_file: "wallet.seed".into(),
+            receive_keys: 0,
+            change_keys: 0,
+            imported_keys: vec![],
             contacts: vec![],
             default_node: "127.0.0.1:9000".to_string(),
+            fallback_nodes: vec![],
+            max_connections: 4,
+            encrypt_connection: false,
+            auth_token: None,
+            coin_selection: CoinSelectionStrategy::default(),
+            min_confirmations: 0,
+            dust_threshold: 0,
+            history_file: "wallet_history.jsonl".into(),
+            max_rebroadcasts: 3,
+            cache_dir: "wallet_cache".into(),
             fee_config: FeeConfig { fee_type, value },
+            display: DisplayConfig::default(),
         }
     }
 
@@ -19,6 +36,7 @@ mod core_tests {
         let fee = match config.fee_config.fee_type {
             FeeType::Fixed => config.fee_config.value as u64,
             FeeType::Percent => (1000 as f64 * config.fee_config.value / 100.0) as u64,
+            FeeType::Dynamic => unreachable!("not a dynamic fee config"),
         };
 
         assert_eq!(fee, 100);
@@ -32,6 +50,7 @@ mod core_tests {
         let fee = match config.fee_config.fee_type {
             FeeType::Fixed => config.fee_config.value as u64,
             FeeType::Percent => (amount as f64 * config.fee_config.value / 100.0) as u64,
+            FeeType::Dynamic => unreachable!("not a dynamic fee config"),
         };
 
         assert_eq!(fee, 10); // 1% of 1000 = 10
@@ -45,6 +64,7 @@ mod core_tests {
         let fee = match config.fee_config.fee_type {
             FeeType::Fixed => config.fee_config.value as u64,
             FeeType::Percent => (amount as f64 * config.fee_config.value / 100.0) as u64,
+            FeeType::Dynamic => unreachable!("not a dynamic fee config"),
         };
 
         assert_eq!(fee, 2_500_000); // 2.5% of 100M = 2.5M satoshis
@@ -58,8 +78,84 @@ mod core_tests {
         let fee = match config.fee_config.fee_type {
             FeeType::Fixed => config.fee_config.value as u64,
             FeeType::Percent => (amount as f64 * config.fee_config.value / 100.0) as u64,
+            FeeType::Dynamic => unreachable!("not a dynamic fee config"),
         };
 
         assert_eq!(fee, 0); // 0.1% of 100 = 0.1, rounds down to 0
     }
+
+    #[test]
+    fn test_keychain_derives_fresh_deterministic_keys() {
+        let seed = Seed::from_bytes(vec![7; 32]);
+        let mut keychain = Keychain::new(&seed, 1, 0).unwrap();
+        let change_a = keychain.derive_next(CHANGE_BRANCH).unwrap();
+        let change_b = keychain.derive_next(CHANGE_BRANCH).unwrap();
+        assert_ne!(change_a, change_b);
+        assert_ne!(change_a, keychain.receive[0].public);
+
+        // Re-deriving from the same seed yields the same keys
+        let restored = Keychain::new(&seed, 1, 2).unwrap();
+        assert_eq!(restored.change[0].public, change_a);
+        assert_eq!(restored.change[1].public, change_b);
+        assert_eq!(restored.receive[0].public, keychain.receive[0].public);
+        assert_eq!(restored.all().count(), 3);
+    }
+
+    #[test]
+    fn test_keychain_finds_keys_for_message_signing() {
+        let seed = Seed::from_bytes(vec![7; 32]);
+        let keychain = Keychain::new(&seed, 2, 1).unwrap();
+        let key = &keychain.change[0].public;
+        let by_fingerprint = keychain.find(&key_fingerprint(key)).unwrap();
+        assert_eq!(&by_fingerprint.public, key);
+        assert_eq!(&keychain.find(&key.to_hex()).unwrap().public, key);
+        assert!(keychain.find("00000000").is_none());
+    }
+
+    #[test]
+    fn test_imported_key_without_private_is_watch_only() {
+        let config: Config = toml::from_str(
+            r#"
+            seed_file = "wallet.seed"
+            contacts = []
+            default_node = "127.0.0.1:9000"
+
+            [[imported_keys]]
+            public = "miner.pub.pem"
+            private = "miner.priv.cbor"
+
+            [[imported_keys]]
+            public = "reward.pub.pem"
+
+            [fee_config]
+            fee_type = "Fixed"
+            value = 1000.0
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.imported_keys[0].private,
+            Some("miner.priv.cbor".into())
+        );
+        assert_eq!(config.imported_keys[1].private, None);
+
+        // watch-only entries stay without a private key when saved
+        let saved = toml::to_string(&config).unwrap();
+        assert_eq!(saved.matches("private").count(), 1);
+    }
+
+    #[test]
+    fn test_nodes_in_order_of_preference() {
+        let mut config = create_test_config(FeeType::Fixed, 0.0);
+        assert_eq!(config.nodes(), ["127.0.0.1:9000"]);
+        config.fallback_nodes = vec![
+            "10.0.0.2:9000".to_string(),
+            "127.0.0.1:9000".to_string(),
+            "10.0.0.3:9000".to_string(),
+        ];
+        assert_eq!(
+            config.nodes(),
+            ["127.0.0.1:9000", "10.0.0.2:9000", "10.0.0.3:9000"]
+        );
+    }
 }
@@ -0,0 +1,137 @@
+//! How amounts are shown: unit, separators and an optional fiat value,
+//! configured in the `[display]` section of `wallet_config.toml`
+use serde::{Deserialize, Serialize};
+
+/// Unit balances and history are shown in
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DisplayUnit {
+    #[default]
+    #[serde(rename = "BTC")]
+    Btc,
+    #[serde(rename = "mBTC")]
+    MilliBtc,
+    #[serde(rename = "sat")]
+    Sat,
+}
+
+impl DisplayUnit {
+    /// Satoshis in one of this unit
+    fn sats(self) -> u64 {
+        match self {
+            DisplayUnit::Btc => 100_000_000,
+            DisplayUnit::MilliBtc => 100_000,
+            DisplayUnit::Sat => 1,
+        }
+    }
+
+    /// Decimals needed to show every satoshi
+    fn decimals(self) -> usize {
+        match self {
+            DisplayUnit::Btc => 8,
+            DisplayUnit::MilliBtc => 5,
+            DisplayUnit::Sat => 0,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DisplayUnit::Btc => "BTC",
+            DisplayUnit::MilliBtc => "mBTC",
+            DisplayUnit::Sat => "sat",
+        }
+    }
+}
+
+/// A fixed exchange rate, set by hand: the wallet doesn't fetch prices
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FiatRate {
+    /// Shown after fiat values, e.g. "USD"
+    pub currency: String,
+    /// Value of one BTC in `currency`
+    pub rate: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DisplayConfig {
+    #[serde(default)]
+    pub unit: DisplayUnit,
+    /// Groups thousands of the integer part, e.g. "," or " "
+    #[serde(default)]
+    pub thousands_separator: Option<char>,
+    #[serde(default = "default_decimal_separator")]
+    pub decimal_separator: char,
+    /// Also show amounts in a fiat currency at this rate
+    #[serde(default)]
+    pub fiat: Option<FiatRate>,
+}
+
+fn default_decimal_separator() -> char {
+    '.'
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        DisplayConfig {
+            unit: DisplayUnit::default(),
+            thousands_separator: None,
+            decimal_separator: default_decimal_separator(),
+            fiat: None,
+        }
+    }
+}
+
+impl DisplayConfig {
+    /// `sats` in the configured unit, exactly and without trailing zeros,
+    /// e.g. "1,234.5 mBTC"
+    pub fn amount(&self, sats: u64) -> String {
+        let unit = self.unit;
+        let fraction = format!("{:0width$}", sats % unit.sats(), width = unit.decimals());
+        format!(
+            "{} {}",
+            self.number(sats / unit.sats(), fraction.trim_end_matches('0')),
+            unit.name()
+        )
+    }
+
+    /// Value of `sats` at the configured fiat rate, e.g. "≈ 61.73 USD"
+    pub fn fiat(&self, sats: u64) -> Option<String> {
+        let fiat = self.fiat.as_ref()?;
+        let cents = (sats as f64 / 100_000_000.0 * fiat.rate * 100.0).round() as u64;
+        Some(format!(
+            "≈ {} {}",
+            self.number(cents / 100, &format!("{:02}", cents % 100)),
+            fiat.currency
+        ))
+    }
+
+    /// The amount, followed by its fiat value if a rate is configured
+    pub fn format(&self, sats: u64) -> String {
+        match self.fiat(sats) {
+            Some(fiat) => format!("{} ({})", self.amount(sats), fiat),
+            None => self.amount(sats),
+        }
+    }
+
+    /// Join an integer part and decimals with the configured separators
+    fn number(&self, whole: u64, fraction: &str) -> String {
+        let digits = whole.to_string();
+        let mut number = String::new();
+        for (i, digit) in digits.chars().enumerate() {
+            if i > 0 && (digits.len() - i).is_multiple_of(3) {
+                if let Some(separator) = self.thousands_separator {
+                    number.push(separator);
+                }
+            }
+            number.push(digit);
+        }
+        if !fraction.is_empty() {
+            number.push(self.decimal_separator);
+            number.push_str(fraction);
+        }
+        number
+    }
+}
+
+#[cfg(test)]
+#[path = "display_tests.rs"]
+mod tests;
@@ -0,0 +1,68 @@
+#[cfg(test)]
+mod display_tests {
+    use crate::display::{DisplayConfig, DisplayUnit, FiatRate};
+
+    #[test]
+    fn test_amounts_in_each_unit() {
+        let mut display = DisplayConfig::default();
+        assert_eq!(display.amount(150_000_000), "1.5 BTC");
+        assert_eq!(display.amount(1), "0.00000001 BTC");
+        assert_eq!(display.amount(0), "0 BTC");
+        display.unit = DisplayUnit::MilliBtc;
+        assert_eq!(display.amount(150_000_000), "1500 mBTC");
+        assert_eq!(display.amount(123_456), "1.23456 mBTC");
+        display.unit = DisplayUnit::Sat;
+        assert_eq!(display.amount(123_456), "123456 sat");
+    }
+
+    #[test]
+    fn test_separators() {
+        let display = DisplayConfig {
+            unit: DisplayUnit::Sat,
+            thousands_separator: Some('.'),
+            decimal_separator: ',',
+            fiat: None,
+        };
+        assert_eq!(display.amount(999), "999 sat");
+        assert_eq!(display.amount(1_000), "1.000 sat");
+        assert_eq!(display.amount(1_234_567), "1.234.567 sat");
+        let display = DisplayConfig {
+            unit: DisplayUnit::MilliBtc,
+            ..display
+        };
+        assert_eq!(display.amount(123_456_700_000), "1.234.567 mBTC");
+        assert_eq!(display.amount(123_450), "1,2345 mBTC");
+    }
+
+    #[test]
+    fn test_fiat_value() {
+        let mut display = DisplayConfig::default();
+        assert_eq!(display.fiat(100_000_000), None);
+        assert_eq!(display.format(100_000_000), "1 BTC");
+        display.fiat = Some(FiatRate {
+            currency: "USD".to_string(),
+            rate: 61_730.5,
+        });
+        display.thousands_separator = Some(',');
+        assert_eq!(display.fiat(100_000), Some("≈ 61.73 USD".to_string()));
+        assert_eq!(display.format(200_000_000), "2 BTC (≈ 123,461.00 USD)");
+    }
+
+    #[test]
+    fn test_config_section() {
+        let display: DisplayConfig = toml::from_str(
+            r#"
+            unit = "mBTC"
+            thousands_separator = " "
+            fiat = { currency = "EUR", rate = 50000.0 }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(display.unit, DisplayUnit::MilliBtc);
+        assert_eq!(display.decimal_separator, '.');
+        assert_eq!(
+            display.format(1_234_500_000),
+            "12 345 mBTC (≈ 617 250.00 EUR)"
+        );
+    }
+}
@@ -0,0 +1,263 @@
+//! One-shot commands that run without the TUI, for scripts and CI
+use crate::contacts;
+use crate::core::{self, key_fingerprint, Config, Core};
+use crate::history::History;
+use anyhow::{anyhow, Result};
+use btclib::crypto::Signature;
+use btclib::sha256::Hash;
+use btclib::types::UnsignedTransaction;
+use btclib::util::Saveable;
+use std::fs;
+use std::path::Path;
+
+/// Print the confirmed balance and what is in flight. Without a node,
+/// the balance of the last update is shown.
+pub async fn balance(core: &Core) -> Result<()> {
+    if let Err(e) = core.fetch_utxos().await {
+        let Some(updated_at) = core.updated_at() else {
+            return Err(e);
+        };
+        eprintln!(
+            "{}, balance as of {}",
+            e,
+            updated_at.format("%Y-%m-%d %H:%M:%S")
+        );
+    }
+    let confirmed = core.confirmed_balance();
+    let spendable = core.get_balance();
+    let (outgoing, incoming) = core.pending_totals();
+    let display = &core.config.display;
+    println!(
+        "confirmed: {} sats ({})",
+        confirmed,
+        display.format(confirmed)
+    );
+    println!(
+        "spendable: {} sats ({})",
+        spendable,
+        display.format(spendable)
+    );
+    println!("pending:   -{} sats", outgoing);
+    println!("incoming:  +{} sats", incoming);
+    if !core.watch_only_keys().is_empty() {
+        println!("watching:  {} sats", core.watch_only_balance());
+    }
+    Ok(())
+}
+
+/// Pay `amount` satoshis to a contact and wait for the node to accept it
+pub async fn send(core: &Core, recipient: &str, amount: u64) -> Result<()> {
+    core.fetch_utxos().await?;
+    let recipient_key = core.contact_key(recipient)?;
+    let transaction = core.create_transaction(&recipient_key, amount)?;
+    let txid = transaction.hash();
+    core.send_transaction(transaction).await?;
+    println!("Sent {} sats to {} in {}", amount, recipient, txid);
+    Ok(())
+}
+
+/// Replace a pending or expired transaction with one paying a higher fee
+pub async fn bump_fee(core: &Core, txid: &Hash) -> Result<()> {
+    core.fetch_utxos().await?;
+    let transaction = core.create_fee_bump(txid)?;
+    let replacement = transaction.hash();
+    core.send_transaction(transaction).await?;
+    println!("Sent {} again as {}", txid, replacement);
+    Ok(())
+}
+
+/// Write a transaction paying a contact to `output`, to be signed offline
+pub async fn create_unsigned(
+    core: &Core,
+    recipient: &str,
+    amount: u64,
+    output: &Path,
+) -> Result<()> {
+    core.fetch_utxos().await?;
+    let recipient_key = core.contact_key(recipient)?;
+    let unsigned = core.create_unsigned_transaction(vec![(recipient_key, amount)])?;
+    unsigned.save_to_file(output)?;
+    println!(
+        "Unsigned transaction paying {} sats to {} ({} inputs, fee {}) written to {}",
+        amount,
+        recipient,
+        unsigned.inputs.len(),
+        unsigned.fee(),
+        output.display()
+    );
+    Ok(())
+}
+
+/// Add our signatures to an unsigned transaction file. Doesn't need the node.
+pub fn sign(config_path: &Path, file: &Path) -> Result<()> {
+    let mut unsigned = UnsignedTransaction::load_from_file(file)?;
+    for output in &unsigned.outputs {
+        println!(
+            "  pays {} sats to {}",
+            output.value,
+            key_fingerprint(&output.pubkey)
+        );
+    }
+    println!("  fee {} sats", unsigned.fee());
+    let signed = core::sign_offline(config_path, &mut unsigned)?;
+    unsigned.save_to_file(file)?;
+    let missing = unsigned
+        .inputs
+        .iter()
+        .filter(|input| input.signature.is_none())
+        .count();
+    println!("Signed {} inputs, {} still unsigned", signed, missing);
+    Ok(())
+}
+
+/// Sign a message with one of our keys. Doesn't need the node.
+pub fn sign_message(config_path: &Path, key: &str, message: &str) -> Result<()> {
+    let (public_key, signature) = core::sign_message(config_path, key, message)?;
+    println!("key:       {}", public_key.to_hex());
+    println!("signature: {}", signature.to_hex());
+    Ok(())
+}
+
+/// Check a message signature against a public key, given in hex or as a
+/// PEM file. Doesn't need the node.
+pub fn verify_message(public_key: &str, signature: &str, message: &str) -> Result<()> {
+    let public_key = contacts::parse_key(public_key)?;
+    let signature = Signature::from_hex(signature)
+        .map_err(|e| anyhow!("Signature must be 64 bytes of hex: {}", e))?;
+    if !signature.verify_message(message, &public_key) {
+        return Err(anyhow!("Invalid signature"));
+    }
+    println!(
+        "Valid signature by {} ({})",
+        key_fingerprint(&public_key),
+        public_key.to_hex()
+    );
+    Ok(())
+}
+
+/// Broadcast a fully signed transaction file
+pub async fn broadcast(core: &Core, file: &Path) -> Result<()> {
+    let unsigned = UnsignedTransaction::load_from_file(file)?;
+    if !unsigned.is_signed() {
+        return Err(anyhow!("{} is not fully signed yet", file.display()));
+    }
+    core.fetch_utxos().await?;
+    let transaction = unsigned.finalize()?;
+    let txid = transaction.hash();
+    core.send_transaction(transaction).await?;
+    println!("Broadcast {}", txid);
+    Ok(())
+}
+
+/// Spend all funds to a contact, or consolidate them into a new key
+pub async fn sweep(core: &Core, recipient: Option<&str>) -> Result<()> {
+    core.fetch_utxos().await?;
+    let destination = recipient.map(|name| core.contact_key(name)).transpose()?;
+    let transaction = core.create_sweep_transaction(destination.as_ref())?;
+    let amount: u64 = transaction.outputs.iter().map(|output| output.value).sum();
+    core.send_transaction(transaction).await?;
+    println!(
+        "Swept {} sats to {}",
+        amount,
+        recipient.unwrap_or("a new key of this wallet")
+    );
+    Ok(())
+}
+
+/// Mine blocks on a regtest node, paying their rewards to a new receive key
+pub async fn generate(core: &Core, count: u32) -> Result<()> {
+    let (key, _) = core.new_receive_key()?;
+    let hashes = core.generate_blocks(count, key).await?;
+    for hash in &hashes {
+        println!("{}", hash);
+    }
+    println!("Generated {} blocks", hashes.len());
+    Ok(())
+}
+
+/// List the wallet's UTXOs, one per line
+pub async fn utxos(core: &Core) -> Result<()> {
+    core.fetch_utxos().await?;
+    let utxos = core.utxos();
+    for (pubkey, reserved, utxo) in &utxos {
+        println!(
+            "{}  {:>16} sats  {:>6} conf  key {}{}{}",
+            utxo.hash(),
+            utxo.value,
+            core.confirmations(utxo),
+            key_fingerprint(pubkey),
+            if *reserved { "  (pending)" } else { "" },
+            if core.is_watch_only(pubkey) {
+                "  (watch-only)"
+            } else {
+                ""
+            }
+        );
+    }
+    println!("{} UTXOs", utxos.len());
+    Ok(())
+}
+
+/// Print the locally recorded transactions. Doesn't need the node.
+pub fn history(config_path: &Path) -> Result<()> {
+    let config: Config = toml::from_str(&fs::read_to_string(config_path)?)?;
+    let entries = History::load(&config.history_file)?;
+    for entry in &entries {
+        println!(
+            "{}  {}  {:>20}  fee {:>16}  {:<10}  to {}",
+            entry.timestamp.format("%Y-%m-%d %H:%M:%S"),
+            entry.txid,
+            config.display.format(entry.amount),
+            config.display.amount(entry.fee),
+            format!("{:?}", entry.status),
+            entry.recipients.join(", ")
+        );
+    }
+    println!("{} transactions", entries.len());
+    Ok(())
+}
+
+/// Print the contacts with their key fingerprints. Doesn't need the node.
+pub fn list_contacts(config_path: &Path) -> Result<()> {
+    let config: Config = toml::from_str(&fs::read_to_string(config_path)?)?;
+    for contact in &config.contacts {
+        match contact.load() {
+            Ok(loaded) => println!(
+                "{:<20}  {}  {}",
+                contact.name,
+                key_fingerprint(&loaded.key),
+                contact.key.display()
+            ),
+            Err(e) => println!(
+                "{:<20}  unreadable key {}: {}",
+                contact.name,
+                contact.key.display(),
+                e
+            ),
+        }
+    }
+    println!("{} contacts", config.contacts.len());
+    Ok(())
+}
+
+/// Add a contact to the config file. Doesn't need the node.
+pub fn add_contact(config_path: &Path, name: &str, key: &str) -> Result<()> {
+    let contacts = contacts::add(config_path, name, key)?;
+    let added = contacts.last().expect("Contact was just added");
+    println!("Added {} with key {}", added.name, added.key.display());
+    Ok(())
+}
+
+/// Rename a contact in the config file. Doesn't need the node.
+pub fn rename_contact(config_path: &Path, name: &str, new_name: &str) -> Result<()> {
+    contacts::rename(config_path, name, new_name)?;
+    println!("Renamed {} to {}", name, new_name.trim());
+    Ok(())
+}
+
+/// Remove a contact from the config file. Doesn't need the node.
+pub fn remove_contact(config_path: &Path, name: &str) -> Result<()> {
+    contacts::remove(config_path, name)?;
+    println!("Removed {}", name);
+    Ok(())
+}
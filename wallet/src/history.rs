@@ -0,0 +1,119 @@
+use anyhow::{Context, Result};
+use btclib::sha256::Hash;
+use btclib::types::Transaction;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+/// Where a sent transaction stands, as far as the wallet can tell
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TxStatus {
+    /// Sent, waiting in the mempool
+    #[default]
+    Pending,
+    /// Its inputs were spent in a block
+    Confirmed,
+    /// A later transaction of ours spends the same inputs
+    Replaced,
+    /// Dropped from the mempool and not sent again, its inputs are
+    /// spendable again
+    Expired,
+    /// Evicted by a conflicting transaction the node saw, either a
+    /// replacement or one mined in a block
+    Conflicted,
+}
+
+/// A transaction this wallet sent, as recorded locally
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HistoryEntry {
+    pub txid: Hash,
+    pub timestamp: DateTime<Utc>,
+    /// Total paid to keys that aren't ours
+    pub amount: u64,
+    /// Inputs minus outputs, as far as the input values are known
+    pub fee: u64,
+    /// Payees (contact name, or a short key fingerprint)
+    pub recipients: Vec<String>,
+    #[serde(default)]
+    pub status: TxStatus,
+    /// UTXOs spent by the transaction
+    #[serde(default)]
+    pub inputs: Vec<Hash>,
+    /// Outputs paying back to this wallet (change or consolidation)
+    #[serde(default)]
+    pub change: Vec<Hash>,
+    /// The signed transaction, to send again or bump the fee of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transaction: Option<Transaction>,
+    /// How many times it was sent again after expiring from the mempool
+    #[serde(default)]
+    pub rebroadcasts: u32,
+}
+
+/// Append-only log of sent transactions, one JSON object per line
+pub struct History;
+
+impl History {
+    pub fn append<P: AsRef<Path>>(path: P, entry: &HistoryEntry) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path.as_ref())
+            .with_context(|| format!("Failed to open history {}", path.as_ref().display()))?;
+        writeln!(file, "{}", serde_json::to_string(entry)?)?;
+        Ok(())
+    }
+
+    /// Read all entries, oldest first. A missing file means no history yet.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<HistoryEntry>> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+        fs::read_to_string(path)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                serde_json::from_str(line)
+                    .with_context(|| format!("Corrupt history entry in {}", path.display()))
+            })
+            .collect()
+    }
+
+    /// Record new statuses for the given transactions, rewriting the log
+    pub fn set_status<P: AsRef<Path>>(path: P, updates: &[(Hash, TxStatus)]) -> Result<()> {
+        Self::update(path, |entry| {
+            if let Some((_, status)) = updates.iter().find(|(txid, _)| *txid == entry.txid) {
+                entry.status = *status;
+            }
+        })
+    }
+
+    /// Apply `change` to every entry, rewriting the log
+    pub fn update<P, F>(path: P, mut change: F) -> Result<()>
+    where
+        P: AsRef<Path>,
+        F: FnMut(&mut HistoryEntry),
+    {
+        let path = path.as_ref();
+        let mut entries = Self::load(path)?;
+        for entry in &mut entries {
+            change(entry);
+        }
+        let mut contents = String::new();
+        for entry in &entries {
+            contents.push_str(&serde_json::to_string(entry)?);
+            contents.push('\n');
+        }
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, contents)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[path = "history_tests.rs"]
+mod tests;
@@ -0,0 +1,47 @@
+#[cfg(test)]
+mod history_tests {
+    use crate::history::{History, HistoryEntry, TxStatus};
+    use btclib::sha256::Hash;
+
+    #[test]
+    fn test_history_round_trips_entries_in_order() {
+        let path =
+            std::env::temp_dir().join(format!("wallet_history_test_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        assert!(History::load(&path).unwrap().is_empty());
+
+        for (i, amount) in [1_000u64, 2_500].into_iter().enumerate() {
+            let entry = HistoryEntry {
+                txid: Hash::hash(&i),
+                timestamp: chrono::Utc::now(),
+                amount,
+                fee: 10,
+                recipients: vec![format!("contact{}", i)],
+                status: TxStatus::Pending,
+                inputs: vec![],
+                change: vec![],
+                transaction: None,
+                rebroadcasts: 0,
+            };
+            History::append(&path, &entry).unwrap();
+        }
+
+        let entries = History::load(&path).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].amount, 1_000);
+        assert_eq!(entries[1].recipients, vec!["contact1".to_string()]);
+        assert_eq!(entries[1].txid, Hash::hash(&1usize));
+
+        History::set_status(&path, &[(entries[1].txid, TxStatus::Confirmed)]).unwrap();
+        let entries = History::load(&path).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].status, TxStatus::Pending);
+        assert_eq!(entries[1].status, TxStatus::Confirmed);
+
+        History::update(&path, |entry| entry.rebroadcasts += 1).unwrap();
+        let entries = History::load(&path).unwrap();
+        assert!(entries.iter().all(|entry| entry.rebroadcasts == 1));
+        assert_eq!(entries[1].status, TxStatus::Confirmed);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,16 +1,29 @@
 use anyhow::Result;
+use btclib::sha256::Hash;
 use clap::{Parser, Subcommand};
 use cursive::views::TextContent;
 use std::path::PathBuf;
 use std::sync::Arc;
 use tracing::{debug, info};
+mod cache;
+mod coin_selection;
+mod contacts;
 mod core;
+mod display;
+mod headless;
+mod history;
+mod pool;
 mod tasks;
 mod ui;
 mod util;
 use core::Core;
-use tasks::{handle_transactions, ui_task, update_balance, update_utxos};
-use util::{big_mode_btc, generate_dummy_config, setup_panic_hook, setup_tracing};
+use tasks::{
+    check_node, handle_transactions, ui_task, update_balance, update_utxos, watch_notifications,
+};
+use util::{
+    big_mode_btc, export_xpub, generate_dummy_config, generate_seed, restore_seed,
+    setup_panic_hook, setup_tracing,
+};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -29,6 +42,122 @@ enum Commands {
         #[arg(short, long, value_name = "FILE", default_value_os_t = PathBuf::from("wallet_config.toml"))]
         output: PathBuf,
     },
+    /// Generate a new seed and print its mnemonic phrase for backup
+    GenerateSeed {
+        /// Number of words (12 or 24)
+        #[arg(short, long, default_value_t = 12)]
+        words: usize,
+        /// Optional BIP39 passphrase, needed again to restore
+        #[arg(short, long, default_value = "")]
+        passphrase: String,
+        /// Overwrite an existing seed file
+        #[arg(long)]
+        force: bool,
+    },
+    /// Restore the seed from a mnemonic phrase
+    Restore {
+        /// The 12 or 24 words, space separated
+        #[arg(short, long)]
+        mnemonic: String,
+        /// BIP39 passphrase used when the seed was generated
+        #[arg(short, long, default_value = "")]
+        passphrase: String,
+        /// Number of receive and change keys to derive and scan
+        #[arg(short, long, default_value_t = 20)]
+        lookahead: u32,
+        /// Overwrite an existing seed file
+        #[arg(long)]
+        force: bool,
+    },
+    /// Spend all funds to a contact, or consolidate them if none is given
+    Sweep {
+        /// Contact name to send everything to
+        #[arg(short, long)]
+        to: Option<String>,
+    },
+    /// Print the balance and exit
+    Balance,
+    /// Pay a contact and exit
+    Send {
+        /// Contact name
+        recipient: String,
+        /// Amount in satoshis
+        amount: u64,
+    },
+    /// List UTXOs and exit
+    Utxos,
+    /// Create an unsigned transaction paying a contact, to sign offline
+    CreateUnsigned {
+        /// Contact name
+        recipient: String,
+        /// Amount in satoshis
+        amount: u64,
+        /// File to write the unsigned transaction to
+        #[arg(short, long, default_value_os_t = PathBuf::from("transaction.unsigned"))]
+        output: PathBuf,
+    },
+    /// Sign an unsigned transaction file in place, without a node
+    Sign { file: PathBuf },
+    /// Broadcast a signed transaction file
+    Broadcast { file: PathBuf },
+    /// Send a pending or expired transaction again with a higher fee
+    BumpFee {
+        /// Transaction hash, as listed by `history`
+        txid: Hash,
+    },
+    /// List transactions sent from this wallet and exit
+    History,
+    /// Sign a message to prove control of one of the wallet's keys
+    SignMessage {
+        /// Fingerprint or hex public key of the key to sign with
+        key: String,
+        /// The message
+        text: String,
+    },
+    /// Check a message signature made with sign-message
+    VerifyMessage {
+        /// Hex public key, or a PEM public key file
+        pubkey: String,
+        /// Signature in hex
+        signature: String,
+        /// The message
+        text: String,
+    },
+    /// Export the extended public key of the receive keys, for a miner
+    /// paying a new key every block
+    ExportXpub {
+        /// File to write the extended public key to
+        #[arg(default_value_os_t = PathBuf::from("receive.xpub"))]
+        output: PathBuf,
+    },
+    /// Have a regtest node mine blocks paying a new receive key
+    Generate {
+        /// Number of blocks to mine
+        #[arg(default_value_t = 1)]
+        count: u32,
+    },
+    /// List, add, rename or remove contacts, without a node
+    Contacts {
+        #[command(subcommand)]
+        action: Option<ContactsCommand>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ContactsCommand {
+    /// List the contacts and their keys
+    List,
+    /// Add a contact
+    Add {
+        /// Name to pay the contact by
+        name: String,
+        /// Hex public key, or a PEM public key file
+        key: String,
+    },
+    /// Rename a contact
+    Rename { name: String, new_name: String },
+    /// Remove a contact
+    Remove { name: String },
 }
 
 #[tokio::main]
@@ -42,22 +171,88 @@ async fn main() -> Result<()> {
             debug!("Generating dummy config at: {:?}", output);
             return generate_dummy_config(output);
         }
-        None => (),
+        Some(Commands::GenerateSeed {
+            words,
+            passphrase,
+            force,
+        }) => {
+            return generate_seed(&cli.config, *words, passphrase, *force);
+        }
+        Some(Commands::Restore {
+            mnemonic,
+            passphrase,
+            lookahead,
+            force,
+        }) => {
+            return restore_seed(&cli.config, mnemonic, passphrase, *lookahead, *force);
+        }
+        Some(Commands::ExportXpub { output }) => {
+            return export_xpub(&cli.config, output);
+        }
+        Some(Commands::History) => {
+            return headless::history(&cli.config);
+        }
+        Some(Commands::Contacts { action }) => {
+            return match action {
+                None | Some(ContactsCommand::List) => headless::list_contacts(&cli.config),
+                Some(ContactsCommand::Add { name, key }) => {
+                    headless::add_contact(&cli.config, name, key)
+                }
+                Some(ContactsCommand::Rename { name, new_name }) => {
+                    headless::rename_contact(&cli.config, name, new_name)
+                }
+                Some(ContactsCommand::Remove { name }) => {
+                    headless::remove_contact(&cli.config, name)
+                }
+            };
+        }
+        Some(Commands::Sign { file }) => {
+            return headless::sign(&cli.config, file);
+        }
+        Some(Commands::SignMessage { key, text }) => {
+            return headless::sign_message(&cli.config, key, text);
+        }
+        Some(Commands::VerifyMessage {
+            pubkey,
+            signature,
+            text,
+        }) => {
+            return headless::verify_message(pubkey, signature, text);
+        }
+        _ => (),
     }
     info!("Loading config from: {:?}", cli.config);
-    let mut core = Core::load(cli.config.clone()).await?;
-    if let Some(node) = cli.node {
-        info!("Overriding default node with: {}", node);
-        core.config.default_node = node;
+    let mut core = Core::load(cli.config.clone(), cli.node.clone()).await?;
+    match &cli.command {
+        Some(Commands::Balance) => return headless::balance(&core).await,
+        Some(Commands::Send { recipient, amount }) => {
+            return headless::send(&core, recipient, *amount).await;
+        }
+        Some(Commands::Utxos) => return headless::utxos(&core).await,
+        Some(Commands::CreateUnsigned {
+            recipient,
+            amount,
+            output,
+        }) => {
+            return headless::create_unsigned(&core, recipient, *amount, output).await;
+        }
+        Some(Commands::Broadcast { file }) => return headless::broadcast(&core, file).await,
+        Some(Commands::BumpFee { txid }) => return headless::bump_fee(&core, txid).await,
+        Some(Commands::Sweep { to }) => return headless::sweep(&core, to.as_deref()).await,
+        Some(Commands::Generate { count }) => return headless::generate(&core, *count).await,
+        _ => (),
     }
     let (tx_sender, tx_receiver) = kanal::bounded(10);
     core.tx_sender = tx_sender;
     let core = Arc::new(core);
     info!("Starting background tasks");
     let balance_content = TextContent::new(big_mode_btc(&core));
+    let node_content = TextContent::new(core.node_status());
     tokio::select! {
-        _ = ui_task(core.clone(), balance_content.clone()).await => (),
+        _ = ui_task(core.clone(), balance_content.clone(), node_content.clone()).await => (),
+        _ = check_node(core.clone(), node_content).await => (),
         _ = update_utxos(core.clone()).await => (),
+        _ = watch_notifications(core.clone()).await => (),
         _ = handle_transactions(tx_receiver.clone_async(), core.clone()).await => (),
         _ = update_balance(core.clone(), balance_content).await => (),
     }
@@ -0,0 +1,114 @@
+//! A few connections to the node serving requests, so a balance refresh
+//! doesn't wait for a transaction submission and the other way round
+use anyhow::Result;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Up to `size` connections, each used by one request at a time. Idle
+/// connections are reused, new ones are opened on demand. A connection
+/// that failed is dropped instead of going back into the pool.
+pub struct Pool<C> {
+    idle: Mutex<Vec<C>>,
+    permits: Semaphore,
+    /// Bumped by [`Pool::reset`], connections of an older generation are
+    /// not taken back
+    generation: AtomicU64,
+}
+
+/// A connection taken from the pool, given back by [`Lease::release`]
+pub struct Lease<'a, C> {
+    pool: &'a Pool<C>,
+    connection: C,
+    generation: u64,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl<C> Pool<C> {
+    /// A pool of at most `size` connections, starting with `connection`
+    pub fn new(size: usize, connection: C) -> Self {
+        let pool = Self::empty(size);
+        pool.idle
+            .lock()
+            .expect("Pool lock poisoned")
+            .push(connection);
+        pool
+    }
+
+    /// A pool of at most `size` connections, opening the first one on
+    /// the first request
+    pub fn empty(size: usize) -> Self {
+        Pool {
+            idle: Mutex::new(vec![]),
+            permits: Semaphore::new(size.max(1)),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Take an idle connection, or open one with `open` if there is none.
+    /// Waits while all `size` connections are in use.
+    pub async fn acquire<F, Fut>(&self, open: F) -> Result<Lease<'_, C>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<C>>,
+    {
+        let permit = self.permits.acquire().await?;
+        let generation = self.generation();
+        let idle = self.idle.lock().expect("Pool lock poisoned").pop();
+        let connection = match idle {
+            Some(connection) => connection,
+            None => open().await?,
+        };
+        Ok(Lease {
+            pool: self,
+            connection,
+            generation,
+            _permit: permit,
+        })
+    }
+
+    /// Drop every idle connection and start over with `connection`, e.g.
+    /// to another node. Connections in use are dropped when released.
+    pub fn reset(&self, connection: C) {
+        let mut idle = self.idle.lock().expect("Pool lock poisoned");
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        *idle = vec![connection];
+    }
+
+    /// Changes with every [`Pool::reset`]
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Number of idle connections
+    #[cfg(test)]
+    fn idle(&self) -> usize {
+        self.idle.lock().expect("Pool lock poisoned").len()
+    }
+}
+
+impl<C> Lease<'_, C> {
+    pub fn connection(&mut self) -> &mut C {
+        &mut self.connection
+    }
+
+    /// The pool generation the connection belongs to
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Give the connection back for the next request. Only call this
+    /// after a complete exchange: a connection left mid-message would
+    /// hand the next request someone else's response.
+    pub fn release(self) {
+        let mut idle = self.pool.idle.lock().expect("Pool lock poisoned");
+        if self.pool.generation() == self.generation {
+            idle.push(self.connection);
+        }
+    }
+}
+
+#[cfg(test)]
+#[path = "pool_tests.rs"]
+mod tests;
@@ -0,0 +1,41 @@
+#[cfg(test)]
+mod pool_tests {
+    use crate::pool::Pool;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_connections_are_reused_and_limited() {
+        let pool = Pool::new(2, 1);
+        let mut first = pool.acquire(|| async { Ok(2) }).await.unwrap();
+        let mut second = pool.acquire(|| async { Ok(2) }).await.unwrap();
+        assert_eq!((*first.connection(), *second.connection()), (1, 2));
+
+        // both connections are in use, a third request waits
+        let third =
+            tokio::time::timeout(Duration::from_millis(50), pool.acquire(|| async { Ok(3) })).await;
+        assert!(third.is_err());
+
+        first.release();
+        let mut reused = pool.acquire(|| async { Ok(3) }).await.unwrap();
+        assert_eq!(*reused.connection(), 1);
+
+        // a failed connection is dropped instead of released
+        drop(second);
+        assert_eq!(pool.idle(), 0);
+        reused.release();
+        assert_eq!(pool.idle(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_reset_drops_connections_of_old_generation() {
+        let pool = Pool::new(2, 1);
+        let in_use = pool.acquire(|| async { Ok(2) }).await.unwrap();
+        let generation = in_use.generation();
+        pool.reset(10);
+        assert_ne!(pool.generation(), generation);
+        in_use.release();
+        assert_eq!(pool.idle(), 1);
+        let mut lease = pool.acquire(|| async { Ok(11) }).await.unwrap();
+        assert_eq!(*lease.connection(), 10);
+    }
+}
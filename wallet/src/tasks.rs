@@ -2,6 +2,7 @@ use crate::core::Core;
 use crate::ui::run_ui;
 use crate::util::big_mode_btc;
 use btclib::config::BlockchainConfig;
+use btclib::network::Message;
 use btclib::types::Transaction;
 use cursive::views::TextContent;
 use std::sync::Arc;
@@ -23,6 +24,70 @@ pub async fn update_utxos(core: Arc<Core>) -> JoinHandle<()> {
     })
 }
 
+/// Listen for conflict, expiry and payment notifications from the node,
+/// resubscribing whenever the connection drops
+pub async fn watch_notifications(core: Arc<Core>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let config = BlockchainConfig::global();
+        loop {
+            match core.subscribe().await {
+                Ok(mut stream) => loop {
+                    match Message::receive_async(&mut stream).await {
+                        Ok(Message::TransactionConflicted(txid)) => {
+                            if let Err(e) = core.handle_conflict(txid) {
+                                error!("Failed to handle conflicted transaction: {}", e);
+                            }
+                        }
+                        Ok(Message::TransactionExpired(txid)) => {
+                            if let Err(e) = core.handle_expiry(txid).await {
+                                error!("Failed to handle expired transaction: {}", e);
+                            }
+                        }
+                        Ok(Message::PaymentReceived(_, output, sender)) => {
+                            core.handle_payment(output, sender)
+                        }
+                        Ok(message) => warn!("Unexpected notification: {:?}", message),
+                        Err(e) => {
+                            warn!("Subscription to node lost: {}", e);
+                            break;
+                        }
+                    }
+                },
+                Err(e) => error!("Failed to subscribe to node: {}", e),
+            }
+            time::sleep(Duration::from_secs(config.wallet.utxo_update_interval_secs)).await;
+        }
+    })
+}
+
+/// Check on the node serving requests, failing over while it is gone,
+/// and show which node that is
+pub async fn check_node(core: Arc<Core>, node_content: TextContent) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let config = BlockchainConfig::global();
+        let mut interval =
+            time::interval(Duration::from_secs(config.wallet.utxo_update_interval_secs));
+        loop {
+            interval.tick().await;
+            match core.check_health().await {
+                Ok(()) => node_content.set_content(core.node_status()),
+                Err(e) => {
+                    error!("No node reachable: {}", e);
+                    let status = match core.updated_at() {
+                        Some(updated_at) => format!(
+                            "{} (unreachable, balance as of {})",
+                            core.active_node(),
+                            updated_at.format("%Y-%m-%d %H:%M:%S")
+                        ),
+                        None => format!("{} (unreachable)", core.active_node()),
+                    };
+                    node_content.set_content(status);
+                }
+            }
+        }
+    })
+}
+
 pub async fn handle_transactions(
     rx: kanal::AsyncReceiver<Transaction>,
     core: Arc<Core>,
@@ -36,10 +101,14 @@ pub async fn handle_transactions(
     })
 }
 
-pub async fn ui_task(core: Arc<Core>, balance_content: TextContent) -> JoinHandle<()> {
+pub async fn ui_task(
+    core: Arc<Core>,
+    balance_content: TextContent,
+    node_content: TextContent,
+) -> JoinHandle<()> {
     tokio::task::spawn_blocking(move || {
         info!("Running UI");
-        if let Err(e) = run_ui(core, balance_content) {
+        if let Err(e) = run_ui(core, balance_content, node_content) {
             error!("UI ended with error: {e}");
         };
     })
@@ -1,34 +1,28 @@
-use crate::core::Core;
+use crate::core::{key_fingerprint, Core, Recipient};
+use crate::util::{format_amount, parse_amount, qr_code, Unit};
 use anyhow::Result;
+use btclib::crypto::PublicKey;
 use cursive::event::{Event, Key};
+use cursive::menu;
 use cursive::traits::*;
 use cursive::views::{
-    Button, Dialog, EditView, LinearLayout, Panel, ResizedView, TextContent, TextView,
+    Button, Dialog, EditView, LinearLayout, Panel, ResizedView, ScrollView, SelectView,
+    TextContent, TextView,
 };
 use cursive::Cursive;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use tracing::*;
 
-#[derive(Clone, Copy)]
-enum Unit {
-    Btc,
-    Sats,
-}
-
-/// Convert an amount between BTC and Satoshi units.
-fn convert_amount(amount: f64, from: Unit, to: Unit) -> f64 {
-    match (from, to) {
-        (Unit::Btc, Unit::Sats) => amount * 100_000_000.0,
-        (Unit::Sats, Unit::Btc) => amount / 100_000_000.0,
-        _ => amount,
-    }
-}
-
 /// Initialize and run the user interface.
-pub fn run_ui(core: Arc<Core>, balance_content: TextContent) -> Result<()> {
+pub fn run_ui(
+    core: Arc<Core>,
+    balance_content: TextContent,
+    node_content: TextContent,
+) -> Result<()> {
     info!("Initializing UI");
     let mut siv = cursive::default();
-    setup_siv(&mut siv, core.clone(), balance_content);
+    setup_siv(&mut siv, core.clone(), balance_content, node_content);
     info!("Starting UI event loop");
     siv.run();
     info!("UI event loop ended");
@@ -36,35 +30,71 @@ pub fn run_ui(core: Arc<Core>, balance_content: TextContent) -> Result<()> {
 }
 
 /// Set up the Cursive interface with all necessary components and callbacks.
-fn setup_siv(siv: &mut Cursive, core: Arc<Core>, balance_content: TextContent) {
+fn setup_siv(
+    siv: &mut Cursive,
+    core: Arc<Core>,
+    balance_content: TextContent,
+    node_content: TextContent,
+) {
     siv.set_autorefresh(true);
     siv.set_window_title("BTC wallet".to_string());
     siv.add_global_callback('q', |s| {
         info!("Quit command received");
         s.quit()
     });
+    let payments_core = core.clone();
+    siv.add_global_callback(Event::Refresh, move |s| {
+        show_incoming_payments(s, &payments_core);
+        show_expired_transactions(s, &payments_core);
+    });
     setup_menubar(siv, core.clone());
-    setup_layout(siv, core, balance_content);
+    setup_layout(siv, core, balance_content, node_content);
     siv.add_global_callback(Event::Key(Key::Esc), |siv| siv.select_menubar());
     siv.select_menubar();
 }
 
-/// Set up the menu bar with "Send" and "Quit" options.
+/// Set up the menu bar with "Send", "Send to many", "Sweep", "Receive",
+/// "Contacts" and "Quit" options.
 fn setup_menubar(siv: &mut Cursive, core: Arc<Core>) {
+    let batch_core = core.clone();
+    let sweep_core = core.clone();
+    let receive_core = core.clone();
+    let (add_core, rename_core, remove_core) = (core.clone(), core.clone(), core.clone());
+    let contacts_menu = menu::Tree::new()
+        .leaf("Add", move |s| show_add_contact(s, add_core.clone()))
+        .leaf("Rename", move |s| {
+            show_rename_contact(s, rename_core.clone())
+        })
+        .leaf("Remove", move |s| {
+            show_remove_contact(s, remove_core.clone())
+        });
     siv.menubar()
         .add_leaf("Send", move |s| show_send_transaction(s, core.clone()))
+        .add_leaf("Send to many", move |s| {
+            show_batch_transaction(s, batch_core.clone())
+        })
+        .add_leaf("Sweep", move |s| show_sweep(s, sweep_core.clone()))
+        .add_leaf("Receive", move |s| show_receive(s, receive_core.clone()))
+        .add_subtree("Contacts", contacts_menu)
         .add_leaf("Quit", |s| s.quit());
     siv.set_autohide_menu(false);
 }
 
 /// Set up the main layout of the application.
-fn setup_layout(siv: &mut Cursive, core: Arc<Core>, balance_content: TextContent) {
+fn setup_layout(
+    siv: &mut Cursive,
+    core: Arc<Core>,
+    balance_content: TextContent,
+    node_content: TextContent,
+) {
     let instruction = TextView::new("Press Escape to select the top menu");
     let balance_panel = Panel::new(TextView::new_with_content(balance_content)).title("Balance");
+    let node_panel = Panel::new(TextView::new_with_content(node_content)).title("Node");
     let info_layout = create_info_layout(&core);
     let layout = LinearLayout::vertical()
         .child(instruction)
         .child(balance_panel)
+        .child(node_panel)
         .child(info_layout);
     siv.add_layer(layout);
 }
@@ -72,40 +102,277 @@ fn setup_layout(siv: &mut Cursive, core: Arc<Core>, balance_content: TextContent
 /// Create the information layout containing keys and contacts.
 fn create_info_layout(core: &Arc<Core>) -> LinearLayout {
     let mut info_layout = LinearLayout::horizontal();
-    let keys_content = core
-        .config
-        .my_keys
-        .iter()
-        .map(|key| format!("{}", key.private.display()))
+    let keys_content = (0..core.receive_keys().len())
+        .map(|index| format!("m/0'/0/{}", index))
+        .chain(
+            core.watch_only_keys()
+                .iter()
+                .map(|key| format!("watch-only {}", key_fingerprint(key))),
+        )
         .collect::<Vec<String>>()
         .join("\n");
     info_layout.add_child(ResizedView::with_full_width(
-        Panel::new(TextView::new(keys_content)).title("Your keys"),
+        Panel::new(TextView::new(keys_content)).title("Your receive keys"),
     ));
-    let contacts_content = core
-        .config
-        .contacts
-        .iter()
-        .map(|contact| contact.name.clone())
-        .collect::<Vec<String>>()
-        .join("\n");
     info_layout.add_child(ResizedView::with_full_width(
-        Panel::new(TextView::new(contacts_content)).title("Contacts"),
+        Panel::new(TextView::new(contacts_content(core)).with_name("contacts")).title("Contacts"),
     ));
     info_layout
 }
 
+fn contacts_content(core: &Core) -> String {
+    core.contacts()
+        .iter()
+        .map(|contact| contact.name.clone())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Show a contact change in the contacts panel, or what went wrong.
+fn contacts_changed(s: &mut Cursive, core: &Core, result: Result<()>) {
+    match result {
+        Ok(()) => {
+            s.pop_layer();
+            let content = contacts_content(core);
+            s.call_on_name("contacts", |view: &mut TextView| view.set_content(content));
+        }
+        Err(e) => {
+            error!("Failed to change contacts: {}", e);
+            s.add_layer(Dialog::info(format!("{}", e)).title("Contacts"));
+        }
+    }
+}
+
+/// Ask for the name and key of a new contact.
+fn show_add_contact(s: &mut Cursive, core: Arc<Core>) {
+    info!("Showing add contact dialog");
+    let layout = LinearLayout::vertical()
+        .child(TextView::new("Name:"))
+        .child(EditView::new().with_name("contact_name").fixed_width(40))
+        .child(TextView::new("Public key (hex, or path to a .pem file):"))
+        .child(EditView::new().with_name("contact_key").fixed_width(70));
+    s.add_layer(
+        Dialog::around(layout)
+            .title("Add contact")
+            .button("Add", move |s| {
+                let name = s
+                    .call_on_name("contact_name", |view: &mut EditView| view.get_content())
+                    .unwrap_or_default();
+                let key = s
+                    .call_on_name("contact_key", |view: &mut EditView| view.get_content())
+                    .unwrap_or_default();
+                let result = core.add_contact(&name, &key);
+                contacts_changed(s, &core, result);
+            })
+            .dismiss_button("Cancel"),
+    );
+}
+
+/// A list of `contacts`, calling `on_pick` with the name picked.
+fn pick_contact<F>(s: &mut Cursive, contacts: Vec<Recipient>, title: &str, on_pick: F)
+where
+    F: 'static + Fn(&mut Cursive, &String) + Send + Sync,
+{
+    let mut names = SelectView::new();
+    for contact in contacts {
+        names.add_item(contact.name.clone(), contact.name);
+    }
+    s.add_layer(
+        Dialog::around(ScrollView::new(names.on_submit(on_pick)))
+            .title(title)
+            .dismiss_button("Cancel"),
+    );
+}
+
+/// Pick a contact, then ask for its new name.
+fn show_rename_contact(s: &mut Cursive, core: Arc<Core>) {
+    info!("Showing rename contact dialog");
+    pick_contact(s, core.contacts(), "Rename contact", move |s, name| {
+        s.pop_layer();
+        let (core, name) = (core.clone(), name.clone());
+        let layout = LinearLayout::vertical()
+            .child(TextView::new(format!("New name for {}:", name)))
+            .child(
+                EditView::new()
+                    .content(name.clone())
+                    .with_name("contact_new_name")
+                    .fixed_width(40),
+            );
+        s.add_layer(
+            Dialog::around(layout)
+                .title("Rename contact")
+                .button("Rename", move |s| {
+                    let new_name = s
+                        .call_on_name("contact_new_name", |view: &mut EditView| view.get_content())
+                        .unwrap_or_default();
+                    let result = core.rename_contact(&name, &new_name);
+                    contacts_changed(s, &core, result);
+                })
+                .dismiss_button("Cancel"),
+        );
+    });
+}
+
+/// Pick a contact and remove it after confirmation.
+fn show_remove_contact(s: &mut Cursive, core: Arc<Core>) {
+    info!("Showing remove contact dialog");
+    pick_contact(s, core.contacts(), "Remove contact", move |s, name| {
+        s.pop_layer();
+        let (core, name) = (core.clone(), name.clone());
+        s.add_layer(
+            Dialog::text(format!("Remove {} from your contacts?", name))
+                .title("Remove contact")
+                .button("Remove", move |s| {
+                    let result = core.remove_contact(&name);
+                    contacts_changed(s, &core, result);
+                })
+                .dismiss_button("Cancel"),
+        );
+    });
+}
+
+/// Let the user pick a receive key, or derive a new one, to show as a
+/// QR code.
+fn show_receive(s: &mut Cursive, core: Arc<Core>) {
+    info!("Showing receive dialog");
+    let mut keys = SelectView::new().item("New receive key", None);
+    for (index, key) in core.receive_keys().into_iter().enumerate().rev() {
+        let label = format!("m/0'/0/{}  {}", index, key_fingerprint(&key));
+        keys.add_item(label, Some(key));
+    }
+    let keys = keys.on_submit(move |s, key: &Option<PublicKey>| {
+        s.pop_layer();
+        match key {
+            Some(key) => show_receive_key(s, key, None),
+            None => show_new_receive_key(s, &core),
+        }
+    });
+    s.add_layer(
+        Dialog::around(ScrollView::new(keys))
+            .title("Receive to")
+            .dismiss_button("Cancel"),
+    );
+}
+
+/// Derive a new receive key and show it along with where its public key
+/// was exported.
+fn show_new_receive_key(s: &mut Cursive, core: &Core) {
+    info!("Deriving new receive key");
+    match core.new_receive_key() {
+        Ok((key, path)) => show_receive_key(s, &key, Some(path)),
+        Err(e) => {
+            error!("Failed to derive receive key: {}", e);
+            s.add_layer(Dialog::info(format!("Failed to derive receive key: {}", e)));
+        }
+    }
+}
+
+/// Show a receive key as a QR code and as hex for the payer to copy.
+fn show_receive_key(s: &mut Cursive, key: &PublicKey, exported: Option<PathBuf>) {
+    let hex = key.to_hex();
+    let qr = qr_code(&hex).unwrap_or_else(|e| {
+        error!("Failed to render QR code: {}", e);
+        format!("(no QR code: {})", e)
+    });
+    let mut layout = LinearLayout::vertical()
+        .child(TextView::new(qr))
+        .child(TextView::new(format!("Key {}:", key_fingerprint(key))))
+        .child(EditView::new().content(hex).fixed_width(70));
+    if let Some(path) = exported {
+        layout.add_child(TextView::new(format!(
+            "Public key exported to {}, share it with the payer.",
+            path.display()
+        )));
+    }
+    s.add_layer(
+        Dialog::around(ScrollView::new(layout))
+            .title("Receive")
+            .dismiss_button("OK"),
+    );
+}
+
+/// Pop up a notification for each payment received since the last
+/// refresh, with who sent it if known
+fn show_incoming_payments(s: &mut Cursive, core: &Core) {
+    for payment in core.take_incoming_payments() {
+        let sender = match &payment.sender {
+            Some(sender) => {
+                let fingerprint = key_fingerprint(sender);
+                match core
+                    .contacts()
+                    .iter()
+                    .filter_map(|contact| contact.load().ok())
+                    .find(|contact| contact.key == *sender)
+                {
+                    Some(contact) => format!("{} ({})", contact.name, fingerprint),
+                    None => fingerprint,
+                }
+            }
+            None => "unknown sender".to_string(),
+        };
+        s.add_layer(
+            Dialog::text(format!(
+                "Received {}\nfrom {}\n{}",
+                core.config.display.format(payment.amount),
+                sender,
+                if payment.confirmed {
+                    "confirmed"
+                } else {
+                    "unconfirmed, waiting for a block"
+                }
+            ))
+            .title("Incoming payment")
+            .dismiss_button("OK"),
+        );
+    }
+}
+
+/// Offer to bump the fee of each transaction the wallet gave up on
+/// after it expired from the mempool
+fn show_expired_transactions(s: &mut Cursive, core: &Arc<Core>) {
+    for entry in core.take_expired() {
+        let core = core.clone();
+        let txid = entry.txid;
+        s.add_layer(
+            Dialog::text(format!(
+                "Transaction {}\npaying {} to {}\nexpired from the mempool{}.\n\
+                 Send it again with a higher fee than {}?",
+                txid,
+                core.config.display.format(entry.amount),
+                entry.recipients.join(", "),
+                match entry.rebroadcasts {
+                    0 => String::new(),
+                    n => format!(" after {} rebroadcasts", n),
+                },
+                core.config.display.format(entry.fee)
+            ))
+            .title("Transaction expired")
+            .button("Bump fee", move |s| {
+                s.pop_layer();
+                match core.bump_fee_async(&txid) {
+                    Ok(()) => s.add_layer(Dialog::info("Transaction sent with a higher fee")),
+                    Err(e) => show_error_dialog(s, e),
+                }
+            })
+            .dismiss_button("Not now"),
+        );
+    }
+}
+
 /// Display the send transaction dialog.
 fn show_send_transaction(s: &mut Cursive, core: Arc<Core>) {
     info!("Showing send transaction dialog");
     let unit = Arc::new(Mutex::new(Unit::Btc));
+    let fields = vec!["amount".to_string()];
+    let layout = create_transaction_layout(core.clone(), unit.clone(), fields.clone());
+    let send_core = core.clone();
     s.add_layer(
-        Dialog::around(create_transaction_layout(unit.clone()))
+        Dialog::around(layout)
             .title("Send Transaction")
             .button("Send", move |siv| {
                 send_transaction(
                     siv,
-                    core.clone(),
+                    send_core.clone(),
                     *unit
                         .lock()
                         .expect("Unit mutex lock poisoned - thread panicked while holding lock"),
@@ -116,66 +383,254 @@ fn show_send_transaction(s: &mut Cursive, core: Arc<Core>) {
                 siv.pop_layer();
             }),
     );
+    update_summary(s, &core, Unit::Btc, &fields);
+}
+
+/// Display the dialog for paying several contacts in one transaction.
+fn show_batch_transaction(s: &mut Cursive, core: Arc<Core>) {
+    info!("Showing batch transaction dialog");
+    let unit = Arc::new(Mutex::new(Unit::Btc));
+    let contacts = core.contacts();
+    let fields: Vec<String> = (0..contacts.len())
+        .map(|index| format!("batch_amount_{}", index))
+        .collect();
+    let mut layout = LinearLayout::vertical().child(TextView::new(
+        "Enter an amount for each contact to pay (leave empty to skip):",
+    ));
+    for (contact, field) in contacts.iter().zip(&fields) {
+        layout.add_child(
+            LinearLayout::horizontal()
+                .child(TextView::new(format!("{}: ", contact.name)).fixed_width(20))
+                .child(
+                    amount_edit_view(core.clone(), unit.clone(), fields.clone())
+                        .with_name(field.as_str())
+                        .fixed_width(20),
+                ),
+        );
+    }
+    layout.add_child(create_unit_layout(
+        core.clone(),
+        unit.clone(),
+        fields.clone(),
+    ));
+    layout.add_child(TextView::new("").with_name("send_summary"));
+    s.add_layer(
+        Dialog::around(layout)
+            .title("Send to many")
+            .button("Send", move |siv| {
+                send_batch_transaction(
+                    siv,
+                    core.clone(),
+                    *unit
+                        .lock()
+                        .expect("Unit mutex lock poisoned - thread panicked while holding lock"),
+                )
+            })
+            .button("Cancel", |siv| {
+                debug!("Batch transaction cancelled");
+                siv.pop_layer();
+            }),
+    );
+}
+
+/// Process the batch transaction request.
+fn send_batch_transaction(s: &mut Cursive, core: Arc<Core>, unit: Unit) {
+    debug!("Batch send button pressed");
+    let mut payments = vec![];
+    for (index, contact) in core.contacts().iter().enumerate() {
+        let content = field_content(s, &format!("batch_amount_{}", index));
+        if content.trim().is_empty() {
+            continue;
+        }
+        match parse_amount(&content, unit) {
+            Ok(amount) => payments.push((contact.name.clone(), amount)),
+            Err(e) => {
+                show_error_dialog(s, format!("{} for {}", e, contact.name));
+                return;
+            }
+        }
+    }
+    let total = payments.iter().map(|(_, amount)| amount).sum();
+    if let Err(e) = core.preview_payment(total) {
+        show_error_dialog(s, e);
+        return;
+    }
+    info!("Attempting batch payment to {} recipients", payments.len());
+    match core.send_batch_transaction_async(&payments) {
+        Ok(_) => show_success_dialog(s),
+        Err(e) => show_error_dialog(s, e),
+    }
+}
+
+/// Display the dialog for sweeping all funds to a contact or to ourselves.
+fn show_sweep(s: &mut Cursive, core: Arc<Core>) {
+    info!("Showing sweep dialog");
+    let layout = LinearLayout::vertical()
+        .child(TextView::new(
+            "Send the whole balance (minus fee) to a contact.\n\
+             Leave empty to consolidate all UTXOs into one of your keys.",
+        ))
+        .child(TextView::new("Recipient:"))
+        .child(EditView::new().with_name("sweep_recipient"));
+    s.add_layer(
+        Dialog::around(layout)
+            .title("Sweep")
+            .button("Sweep", move |siv| {
+                let recipient = siv
+                    .call_on_name("sweep_recipient", |view: &mut EditView| view.get_content())
+                    .unwrap_or_default();
+                let recipient = recipient.trim();
+                let recipient = (!recipient.is_empty()).then_some(recipient);
+                info!("Sweeping to {}", recipient.unwrap_or("ourselves"));
+                match core.sweep_async(recipient) {
+                    Ok(_) => show_success_dialog(siv),
+                    Err(e) => show_error_dialog(siv, e),
+                }
+            })
+            .button("Cancel", |siv| {
+                debug!("Sweep cancelled");
+                siv.pop_layer();
+            }),
+    );
 }
 
 /// Create the layout for the transaction dialog.
-fn create_transaction_layout(unit: Arc<Mutex<Unit>>) -> LinearLayout {
+fn create_transaction_layout(
+    core: Arc<Core>,
+    unit: Arc<Mutex<Unit>>,
+    fields: Vec<String>,
+) -> LinearLayout {
     LinearLayout::vertical()
         .child(TextView::new("Recipient:"))
         .child(EditView::new().with_name("recipient"))
         .child(TextView::new("Amount:"))
-        .child(EditView::new().with_name("amount"))
-        .child(create_unit_layout(unit))
+        .child(amount_edit_view(core.clone(), unit.clone(), fields.clone()).with_name("amount"))
+        .child(create_unit_layout(core, unit, fields))
+        .child(TextView::new("").with_name("send_summary"))
+}
+
+/// An amount field refreshing the fee and change summary as it is edited.
+/// `fields` are all amount fields of the dialog.
+fn amount_edit_view(core: Arc<Core>, unit: Arc<Mutex<Unit>>, fields: Vec<String>) -> EditView {
+    EditView::new().on_edit(move |s, _, _| {
+        let unit = *unit
+            .lock()
+            .expect("Unit mutex lock poisoned - thread panicked while holding lock");
+        update_summary(s, &core, unit, &fields);
+    })
 }
 
 /// Create the layout for selecting the transaction unit (BTC or Sats).
-fn create_unit_layout(unit: Arc<Mutex<Unit>>) -> LinearLayout {
+fn create_unit_layout(
+    core: Arc<Core>,
+    unit: Arc<Mutex<Unit>>,
+    fields: Vec<String>,
+) -> LinearLayout {
     LinearLayout::horizontal()
         .child(TextView::new("Unit: "))
         .child(TextView::new_with_content(TextContent::new("BTC")).with_name("unit_display"))
-        .child(Button::new("Switch", move |s| switch_unit(s, unit.clone())))
+        .child(Button::new("Switch", move |s| {
+            let new_unit = switch_unit(s, unit.clone(), &fields);
+            update_summary(s, &core, new_unit, &fields);
+        }))
 }
 
-/// Switch the transaction unit between BTC and Sats.
-fn switch_unit(s: &mut Cursive, unit: Arc<Mutex<Unit>>) {
+/// Switch the transaction unit between BTC and Sats, converting the
+/// amounts already entered in `fields`. Returns the new unit.
+fn switch_unit(s: &mut Cursive, unit: Arc<Mutex<Unit>>, fields: &[String]) -> Unit {
     // Update the unit value, release lock immediately
-    let new_unit = {
+    let (old_unit, new_unit) = {
         let mut locked_unit = unit
             .lock()
             .expect("Unit mutex lock poisoned - thread panicked while holding lock");
-        *locked_unit = match *locked_unit {
-            Unit::Btc => Unit::Sats,
-            Unit::Sats => Unit::Btc,
-        };
-        *locked_unit
+        let old_unit = *locked_unit;
+        *locked_unit = old_unit.toggle();
+        (old_unit, *locked_unit)
     };
 
-    // Update the UI after lock is released
+    // Update the UI after lock is released; amounts that don't parse are
+    // left as they are
+    for field in fields {
+        if let Ok(amount) = parse_amount(&field_content(s, field), old_unit) {
+            s.call_on_name(field, |view: &mut EditView| {
+                view.set_content(format_amount(amount, new_unit));
+            });
+        }
+    }
     s.call_on_name("unit_display", |view: &mut TextView| {
-        view.set_content(match new_unit {
-            Unit::Btc => "BTC",
-            Unit::Sats => "Sats",
-        });
+        view.set_content(new_unit.name());
+    });
+    new_unit
+}
+
+/// Show the fee and change of paying the amounts entered in `fields`, or
+/// why they can't be paid
+fn update_summary(s: &mut Cursive, core: &Core, unit: Unit, fields: &[String]) {
+    let mut total = 0u64;
+    for field in fields {
+        let content = field_content(s, field);
+        if content.trim().is_empty() {
+            continue;
+        }
+        match parse_amount(&content, unit) {
+            Ok(amount) => total = total.saturating_add(amount),
+            Err(e) => return set_summary(s, e.to_string()),
+        }
+    }
+    if total == 0 {
+        return set_summary(
+            s,
+            format!(
+                "Balance: {} {}",
+                format_amount(core.get_balance(), unit),
+                unit.name()
+            ),
+        );
+    }
+    let summary = match core.preview_payment(total) {
+        Ok(preview) => {
+            let spent = preview.amount + preview.fee;
+            format!(
+                "Fee: {fee} {unit}\nChange: {change} {unit}\nTotal: {total} {unit}\nBalance after: {after} {unit}",
+                fee = format_amount(preview.fee, unit),
+                change = format_amount(preview.change, unit),
+                total = format_amount(spent, unit),
+                after = format_amount(core.get_balance().saturating_sub(spent), unit),
+                unit = unit.name(),
+            )
+        }
+        Err(e) => e.to_string(),
+    };
+    set_summary(s, summary);
+}
+
+fn set_summary(s: &mut Cursive, summary: String) {
+    s.call_on_name("send_summary", |view: &mut TextView| {
+        view.set_content(summary)
     });
 }
 
+/// Content of the EditView called `name`
+fn field_content(s: &mut Cursive, name: &str) -> String {
+    s.call_on_name(name, |view: &mut EditView| view.get_content().to_string())
+        .unwrap_or_default()
+}
+
 /// Process the send transaction request.
 fn send_transaction(s: &mut Cursive, core: Arc<Core>, unit: Unit) {
     debug!("Send button pressed");
-    let recipient = s
-        .call_on_name("recipient", |view: &mut EditView| view.get_content())
-        .unwrap();
-    let amount: f64 = s
-        .call_on_name("amount", |view: &mut EditView| view.get_content())
-        .unwrap()
-        .parse()
-        .unwrap_or(0.0);
-    let amount_sats = convert_amount(amount, unit, Unit::Sats) as u64;
+    let recipient = field_content(s, "recipient");
+    let amount_sats = match parse_amount(&field_content(s, "amount"), unit)
+        .and_then(|amount| core.preview_payment(amount).map(|_| amount))
+    {
+        Ok(amount) => amount,
+        Err(e) => return show_error_dialog(s, e),
+    };
     info!(
         "Attempting to send transaction to {} for {} satoshis",
         recipient, amount_sats
     );
-    match core.send_transaction_async(recipient.as_str(), amount_sats) {
+    match core.send_transaction_async(recipient.trim(), amount_sats) {
         Ok(_) => show_success_dialog(s),
         Err(e) => show_error_dialog(s, e),
     }
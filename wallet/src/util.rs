@@ -1,5 +1,12 @@
+use crate::coin_selection::CoinSelectionStrategy;
 use crate::core::{Config, Core, FeeConfig, FeeType, Recipient};
-use anyhow::Result;
+use crate::display::DisplayConfig;
+use anyhow::{anyhow, Result};
+use bip39::Mnemonic;
+use btclib::hd::{self, ExtendedPrivateKey, Seed, RECEIVE_BRANCH};
+use btclib::util::Saveable;
+use qrcode::render::unicode::Dense1x2;
+use qrcode::QrCode;
 use std::panic;
 use std::path::PathBuf;
 use tracing::*;
@@ -26,10 +33,24 @@ pub fn setup_panic_hook() {
     }));
 }
 
+/// Render `data` as a QR code of Unicode half blocks, two modules per
+/// character, light on dark so phones scan it off a dark terminal
+pub fn qr_code(data: &str) -> Result<String> {
+    let code = QrCode::new(data.as_bytes())?;
+    Ok(code
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .build())
+}
+
 /// Generate a dummy config
 pub fn generate_dummy_config(path: &PathBuf) -> Result<()> {
     let dummy_config = Config {
-        my_keys: vec![],
+        seed_file: path.with_extension("seed"),
+        receive_keys: 0,
+        change_keys: 0,
+        imported_keys: vec![],
         contacts: vec![
             Recipient {
                 name: "Alice".to_string(),
@@ -41,10 +62,21 @@ pub fn generate_dummy_config(path: &PathBuf) -> Result<()> {
             },
         ],
         default_node: "127.0.0.1:9000".to_string(),
+        fallback_nodes: vec![],
+        max_connections: 4,
+        encrypt_connection: false,
+        auth_token: None,
+        coin_selection: CoinSelectionStrategy::default(),
+        min_confirmations: 0,
+        dust_threshold: 0,
+        history_file: path.with_extension("history.jsonl"),
+        max_rebroadcasts: 3,
+        cache_dir: path.with_extension("cache"),
         fee_config: FeeConfig {
             fee_type: FeeType::Percent,
             value: 0.1,
         },
+        display: DisplayConfig::default(),
     };
     let config_str = toml::to_string_pretty(&dummy_config)?;
     std::fs::write(path, config_str)?;
@@ -52,12 +84,207 @@ pub fn generate_dummy_config(path: &PathBuf) -> Result<()> {
     Ok(())
 }
 
-/// Convert satoshis to a BTC string
-pub fn sats_to_btc(sats: u64) -> String {
-    let btc = sats as f64 / 100_000_000.0;
-    format!("{} BTC", btc)
+/// Seed file used by the config at `config_path`. Without a config yet,
+/// default to the same location `generate-config` would use.
+fn seed_file_for(config_path: &PathBuf) -> Result<PathBuf> {
+    if config_path.exists() {
+        let config: Config = toml::from_str(&std::fs::read_to_string(config_path)?)?;
+        Ok(config.seed_file)
+    } else {
+        Ok(config_path.with_extension("seed"))
+    }
+}
+
+fn write_seed(
+    seed_file: &PathBuf,
+    mnemonic: &Mnemonic,
+    passphrase: &str,
+    force: bool,
+) -> Result<()> {
+    if seed_file.exists() && !force {
+        return Err(anyhow!(
+            "{} already exists, use --force to overwrite it",
+            seed_file.display()
+        ));
+    }
+    let seed = Seed::from_bytes(mnemonic.to_seed(passphrase).to_vec());
+    seed.save_to_file(seed_file)?;
+    info!("Seed written to: {}", seed_file.display());
+    Ok(())
+}
+
+/// Generate a new BIP39 mnemonic, store the seed derived from it
+/// and print the words so they can be written down
+pub fn generate_seed(
+    config_path: &PathBuf,
+    words: usize,
+    passphrase: &str,
+    force: bool,
+) -> Result<()> {
+    if words != 12 && words != 24 {
+        return Err(anyhow!("Mnemonic must have 12 or 24 words"));
+    }
+    let seed_file = seed_file_for(config_path)?;
+    let mnemonic = Mnemonic::generate(words)?;
+    write_seed(&seed_file, &mnemonic, passphrase, force)?;
+    println!("Seed written to {}", seed_file.display());
+    println!(
+        "Write down these {} words, they are the only way to restore the wallet:",
+        words
+    );
+    println!();
+    println!("{}", mnemonic);
+    Ok(())
+}
+
+/// Recreate the seed from a BIP39 mnemonic. The key counts in the config
+/// are raised to `lookahead` so funds on previously used keys are found.
+pub fn restore_seed(
+    config_path: &PathBuf,
+    mnemonic: &str,
+    passphrase: &str,
+    lookahead: u32,
+    force: bool,
+) -> Result<()> {
+    let mnemonic = Mnemonic::parse(mnemonic).map_err(|e| anyhow!("Invalid mnemonic: {}", e))?;
+    let seed_file = seed_file_for(config_path)?;
+    write_seed(&seed_file, &mnemonic, passphrase, force)?;
+    if !config_path.exists() {
+        generate_dummy_config(config_path)?;
+    }
+    let mut config: Config = toml::from_str(&std::fs::read_to_string(config_path)?)?;
+    config.receive_keys = config.receive_keys.max(lookahead);
+    config.change_keys = config.change_keys.max(lookahead);
+    std::fs::write(config_path, toml::to_string_pretty(&config)?)?;
+    println!("Wallet restored, seed written to {}", seed_file.display());
+    Ok(())
+}
+
+/// Write the extended public key of the receive branch, m/0'/0. It
+/// derives every receive key of the wallet but can't spend from them.
+pub fn export_xpub(config_path: &PathBuf, output: &PathBuf) -> Result<()> {
+    let seed_file = seed_file_for(config_path)?;
+    if !seed_file.exists() {
+        return Err(anyhow!(
+            "No seed at {}, run generate-seed first",
+            seed_file.display()
+        ));
+    }
+    let seed = Seed::load_from_file(&seed_file)?;
+    let branch = ExtendedPrivateKey::from_seed(seed.as_bytes())?
+        .derive_path(&[hd::HARDENED, RECEIVE_BRANCH])?;
+    branch.extended_public_key().save_to_file(output)?;
+    println!(
+        "Extended public key of the receive keys (m/0'/0) written to {}",
+        output.display()
+    );
+    println!("It derives the wallet's receive keys but can't spend from them.");
+    Ok(())
+}
+
+/// Satoshis in one BTC
+const SATS_PER_BTC: u64 = 100_000_000;
+
+/// Unit amounts are entered in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unit {
+    Btc,
+    Sats,
+}
+
+impl Unit {
+    /// The other unit
+    pub fn toggle(self) -> Self {
+        match self {
+            Unit::Btc => Unit::Sats,
+            Unit::Sats => Unit::Btc,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Unit::Btc => "BTC",
+            Unit::Sats => "Sats",
+        }
+    }
+}
+
+/// Parse an amount typed in `unit` into satoshis. BTC takes up to eight
+/// decimals and is converted exactly, without going through floating
+/// point; satoshis must be whole.
+pub fn parse_amount(text: &str, unit: Unit) -> Result<u64> {
+    let text = text.trim();
+    let (whole, fraction) = match (unit, text.split_once('.')) {
+        (Unit::Btc, Some((whole, fraction))) => (whole, fraction),
+        _ => (text, ""),
+    };
+    let digits = |part: &str| part.chars().all(|c| c.is_ascii_digit());
+    if (whole.is_empty() && fraction.is_empty()) || !digits(whole) || !digits(fraction) {
+        return Err(anyhow!("Invalid amount: {:?}", text));
+    }
+    if fraction.len() > 8 {
+        return Err(anyhow!("At most 8 decimals are allowed in BTC"));
+    }
+    let multiplier = match unit {
+        Unit::Btc => SATS_PER_BTC,
+        Unit::Sats => 1,
+    };
+    let whole: u64 = if whole.is_empty() { 0 } else { whole.parse()? };
+    let fraction: u64 = format!("{:0<8}", fraction).parse()?;
+    let sats = whole
+        .checked_mul(multiplier)
+        .and_then(|sats| sats.checked_add(if unit == Unit::Btc { fraction } else { 0 }))
+        .ok_or_else(|| anyhow!("Amount too large"))?;
+    if sats == 0 {
+        return Err(anyhow!("Amount must be positive"));
+    }
+    Ok(sats)
+}
+
+/// Format `sats` in `unit`, exactly and without trailing zeros
+pub fn format_amount(sats: u64, unit: Unit) -> String {
+    match unit {
+        Unit::Sats => sats.to_string(),
+        Unit::Btc => {
+            let fraction = format!("{:08}", sats % SATS_PER_BTC);
+            let fraction = fraction.trim_end_matches('0');
+            if fraction.is_empty() {
+                (sats / SATS_PER_BTC).to_string()
+            } else {
+                format!("{}.{}", sats / SATS_PER_BTC, fraction)
+            }
+        }
+    }
 }
 
 pub fn big_mode_btc(core: &Core) -> String {
-    text_to_ascii_art::to_art(sats_to_btc(core.get_balance()), "standard", 0, 0, 0).unwrap()
+    let format = &core.config.display;
+    let balance = core.confirmed_balance();
+    let mut display =
+        text_to_ascii_art::to_art(format.amount(balance), "standard", 0, 0, 0).unwrap();
+    if let Some(fiat) = format.fiat(balance) {
+        display.push_str(&format!("\n{}", fiat));
+    }
+    let (outgoing, incoming) = core.pending_totals();
+    if (outgoing, incoming) != (0, 0) {
+        display.push_str(&format!(
+            "\npending -{} / incoming +{}",
+            format.format(outgoing),
+            format.format(incoming)
+        ));
+    }
+    if !core.watch_only_keys().is_empty() {
+        display.push_str(&format!(
+            "\nwatch-only {}",
+            format.format(core.watch_only_balance())
+        ));
+    }
+    if let Some(txid) = core.conflicted().last() {
+        display.push_str(&format!("\n! transaction {} conflicted, see history", txid));
+    }
+    display
 }
+
+#[cfg(test)]
+#[path = "util_tests.rs"]
+mod tests;
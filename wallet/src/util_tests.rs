@@ -0,0 +1,47 @@
+#[cfg(test)]
+mod util_tests {
+    use crate::util::{format_amount, parse_amount, Unit};
+
+    #[test]
+    fn test_parse_amount_in_btc_is_exact() {
+        assert_eq!(parse_amount("1", Unit::Btc).unwrap(), 100_000_000);
+        assert_eq!(parse_amount(" 0.1 ", Unit::Btc).unwrap(), 10_000_000);
+        assert_eq!(parse_amount(".5", Unit::Btc).unwrap(), 50_000_000);
+        assert_eq!(parse_amount("2.", Unit::Btc).unwrap(), 200_000_000);
+        // 0.29 * 1e8 is 28999999.999... in floating point
+        assert_eq!(parse_amount("0.29", Unit::Btc).unwrap(), 29_000_000);
+        assert_eq!(parse_amount("0.00000001", Unit::Btc).unwrap(), 1);
+
+        assert!(parse_amount("0.000000001", Unit::Btc).is_err());
+        assert!(parse_amount("0", Unit::Btc).is_err());
+        assert!(parse_amount("", Unit::Btc).is_err());
+        assert!(parse_amount(".", Unit::Btc).is_err());
+        assert!(parse_amount("-1", Unit::Btc).is_err());
+        assert!(parse_amount("1e3", Unit::Btc).is_err());
+        assert!(parse_amount("1.2.3", Unit::Btc).is_err());
+        assert!(parse_amount("200000000000", Unit::Btc).is_err());
+    }
+
+    #[test]
+    fn test_parse_amount_in_sats_is_whole() {
+        assert_eq!(parse_amount("150000", Unit::Sats).unwrap(), 150_000);
+        assert!(parse_amount("1.5", Unit::Sats).is_err());
+        assert!(parse_amount("0", Unit::Sats).is_err());
+    }
+
+    #[test]
+    fn test_format_amount_round_trips() {
+        assert_eq!(format_amount(150_000_000, Unit::Btc), "1.5");
+        assert_eq!(format_amount(100_000_000, Unit::Btc), "1");
+        assert_eq!(format_amount(1, Unit::Btc), "0.00000001");
+        assert_eq!(format_amount(1, Unit::Sats), "1");
+        for sats in [1, 29_000_000, 123_456_789, u64::MAX] {
+            for unit in [Unit::Btc, Unit::Sats] {
+                assert_eq!(
+                    parse_amount(&format_amount(sats, unit), unit).unwrap(),
+                    sats
+                );
+            }
+        }
+    }
+}